@@ -216,6 +216,12 @@ impl ApiServer {
             }
             VmmAction::Pause => Some((&METRICS.latencies_us.pause_vm, "pause vm")),
             VmmAction::Resume => Some((&METRICS.latencies_us.resume_vm, "resume vm")),
+            VmmAction::SendMigration(_) => {
+                Some((&METRICS.latencies_us.send_migration, "send migration"))
+            }
+            VmmAction::ReceiveMigration(_) => {
+                Some((&METRICS.latencies_us.receive_migration, "receive migration"))
+            }
             _ => None,
         };
 
@@ -223,6 +229,7 @@ impl ApiServer {
             VmmAction::StartMicroVm => "Running".to_string(),
             VmmAction::Pause => "Paused".to_string(),
             VmmAction::Resume => "Running".to_string(),
+            VmmAction::SendMigration(_) => "Paused".to_string(),
             _ => self.instance_info.state.clone(),
         };
         self.api_request_sender
@@ -246,7 +253,9 @@ impl ApiServer {
 
     fn check_for_fatal_error(&mut self, response: &std::result::Result<VmmData, VmmActionError>) {
         // Errors considered as fatal are added here
-        if let Err(VmmActionError::LoadSnapshot(_)) = response {
+        if let Err(VmmActionError::LoadSnapshot(_)) | Err(VmmActionError::ReceiveMigration(_)) =
+            response
+        {
             self.vmm_fatal_error = true;
         }
     }
@@ -342,7 +351,7 @@ mod tests {
     use vmm::builder::StartMicrovmError;
     use vmm::rpc_interface::VmmActionError;
     use vmm::vmm_config::instance_info::InstanceInfo;
-    use vmm::vmm_config::snapshot::CreateSnapshotParams;
+    use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemoryFileFormat};
 
     #[test]
     fn test_error_messages() {
@@ -420,6 +429,9 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemoryFileFormat::Raw,
+                live: false,
+                encryption: None,
                 version: None,
             })),
             start_time_us,
@@ -434,6 +446,9 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemoryFileFormat::Raw,
+                live: false,
+                encryption: None,
                 version: None,
             })),
             start_time_us,
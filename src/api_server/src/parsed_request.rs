@@ -7,15 +7,18 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
-use crate::request::drive::{parse_patch_drive, parse_put_drive};
+use crate::request::drive::{parse_delete_drive, parse_patch_drive, parse_put_drive};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
 use crate::request::metrics::parse_put_metrics;
+use crate::request::migration::parse_put_migration;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::net::{
+    parse_delete_net_capture, parse_patch_net, parse_put_net, parse_put_net_capture,
+};
 use crate::request::snapshot::parse_patch_vm_state;
 use crate::request::snapshot::parse_put_snapshot;
 use crate::request::vsock::parse_put_vsock;
@@ -68,7 +71,13 @@ impl ParsedRequest {
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
+            (Method::Put, "migration", Some(body)) => parse_put_migration(body, path_tokens.get(1)),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
+            (Method::Put, "network-interfaces", Some(body))
+                if path_tokens.get(2) == Some(&"capture") =>
+            {
+                parse_put_net_capture(body, path_tokens.get(1))
+            }
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
             }
@@ -84,6 +93,13 @@ impl ParsedRequest {
             }
             (Method::Patch, "vm", Some(body)) => parse_patch_vm_state(body),
             (Method::Patch, _, None) => method_to_error(Method::Patch),
+            (Method::Delete, "drives", None) => parse_delete_drive(path_tokens.get(1)),
+            (Method::Delete, "network-interfaces", None)
+                if path_tokens.get(2) == Some(&"capture") =>
+            {
+                parse_delete_net_capture(path_tokens.get(1))
+            }
+            (Method::Delete, _, Some(_)) => method_to_error(Method::Delete),
             (method, unknown_uri, _) => {
                 Err(Error::InvalidPathMethod(unknown_uri.to_string(), method))
             }
@@ -150,12 +166,18 @@ fn log_received_api_request(api_description: String) {
 ///
 /// # Arguments
 ///
-/// * `method` - one of `GET`, `PATCH`, `PUT`
+/// * `method` - one of `GET`, `PATCH`, `PUT`, `DELETE`
 /// * `path` - path of the API request
 /// * `body` - body of the API request
+///
+/// The bodies of the MMDS requests, which can be large, and of the snapshot requests, which can
+/// hold the encryption key of the snapshot files, are left out.
 fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
     match (path, body) {
         ("/mmds", Some(_)) | (_, None) => format!("{:?} request on {:?}", method, path),
+        (path, Some(_)) if path.starts_with("/snapshot/") => {
+            format!("{:?} request on {:?}", method, path)
+        }
         (_, Some(value)) => format!(
             "{:?} request on {:?} with body {:?}",
             method,
@@ -182,6 +204,10 @@ pub(crate) fn method_to_error(method: Method) -> Result<ParsedRequest, Error> {
             StatusCode::BadRequest,
             "Empty PATCH request.".to_string(),
         )),
+        Method::Delete => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "DELETE request cannot have a body.".to_string(),
+        )),
     }
 }
 
@@ -390,6 +416,29 @@ pub(crate) mod tests {
         };
     }
 
+    #[test]
+    fn test_invalid_delete() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"DELETE /drives/string HTTP/1.1\r\n\
+                Content-Type: text/plain\r\n\
+                Content-Length: 4\r\n\r\nbody",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Err(Error::Generic(StatusCode::BadRequest, err_msg)) => {
+                if err_msg != "DELETE request cannot have a body." {
+                    panic!("DELETE request with body.");
+                }
+            }
+            _ => panic!("DELETE request with body."),
+        };
+    }
+
     #[test]
     fn test_error_into_response() {
         // Generic error.
@@ -503,6 +552,19 @@ pub(crate) mod tests {
             describe(Method::Put, "path", Some(&Body::new("body"))),
             "Put request on \"path\" with body \"body\""
         );
+
+        // The encryption key of snapshot requests is not logged.
+        let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let body = format!(
+            "{{ \"snapshot_path\": \"foo\", \"mem_file_path\": \"bar\", \
+             \"encryption\": {{ \"key\": \"{}\" }} }}",
+            key
+        );
+        for path in &["/snapshot/create", "/snapshot/load"] {
+            let description = describe(Method::Put, path, Some(&Body::new(body.clone())));
+            assert_eq!(description, format!("Put request on {:?}", path));
+            assert!(!description.contains(key));
+        }
     }
 
     #[test]
@@ -881,6 +943,25 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_migration() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+
+        sender
+            .write_all(
+                b"PUT /migration/send HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 21\r\n\r\n{ \
+                \"uds_path\": \"foo\" \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_vm() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -968,6 +1049,52 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_delete_drives() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(b"DELETE /drives/string HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_net_capture() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /network-interfaces/string/capture HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 50\r\n\r\n{ \
+                \"iface_id\": \"string\", \
+                \"capture_path\": \"string\" \
+                }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        sender
+            .write_all(b"DELETE /network-interfaces/string/capture HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        // Network interfaces cannot be removed.
+        sender
+            .write_all(b"DELETE /network-interfaces/string HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_patch_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -66,14 +66,17 @@ pub(crate) fn parse_patch_drive(
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
     // - rate_limiter
+    // - resize
     if block_device_update_cfg.path_on_host.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
+        && block_device_update_cfg.resize.is_none()
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, rate_limiter, \
+                 resize.",
             ),
         ));
     }
@@ -83,6 +86,20 @@ pub(crate) fn parse_patch_drive(
     )))
 }
 
+pub(crate) fn parse_delete_drive(id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
+    METRICS.delete_api_requests.drive_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.delete_api_requests.drive_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    Ok(ParsedRequest::new_sync(VmmAction::RemoveBlockDevice(
+        id.to_string(),
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -209,6 +226,38 @@ mod tests {
         }"#;
         // Validate that parse_patch_drive fails for invalid rate limiter cfg.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        let body = r#"{
+            "drive_id": "foo",
+            "resize": {
+                "size_bytes": 1048576
+            }
+        }"#;
+        // Validate that resizing the drive works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert_eq!(cfg.resize.unwrap().size_bytes, Some(1_048_576));
+                assert!(cfg.path_on_host.is_none());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
+        let body = r#"{
+            "drive_id": "foo",
+            "resize": {}
+        }"#;
+        // Validate that the drive can take the size of its backing file.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
+
+        let body = r#"{
+            "drive_id": "foo",
+            "resize": {
+                "size": 1048576
+            }
+        }"#;
+        // Validate that parse_patch_drive fails for invalid resize cfg.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
     }
 
     #[test]
@@ -246,5 +295,145 @@ mod tests {
         assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
 
         assert!(parse_put_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        // PUT with an I/O engine.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Async"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with a cache type.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "cache_type": "Writeback"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with an image format.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "format": "Qcow2"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with an overlay.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "overlay_path": "overlay"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with several request queues.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "num_queues": 4
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with a vhost-user backend instead of a host path.
+        let body = r#"{
+                "drive_id": "1000",
+                "vhost_user_socket": "backend.sock",
+                "is_root_device": false,
+                "is_read_only": false
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with an NBD server instead of a host path.
+        let body = r#"{
+                "drive_id": "1000",
+                "is_root_device": false,
+                "is_read_only": false,
+                "nbd": {
+                    "tcp_address": "192.168.0.2:10809",
+                    "export_name": "disk"
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with an unknown NBD server field.
+        let body = r#"{
+                "drive_id": "1000",
+                "is_root_device": false,
+                "is_read_only": false,
+                "nbd": {
+                    "host": "server"
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
+
+        // PUT with a hash tree.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": true,
+                "verity": {
+                    "hash_tree_path": "dummy.verity",
+                    "hash_offset": 4096,
+                    "root_hash": "00",
+                    "salt": "00"
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with a hash tree missing its root hash.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": true,
+                "verity": {
+                    "hash_tree_path": "dummy.verity"
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
+
+        // PUT with an invalid image format.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "format": "vmdk"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
+
+        // PUT with an invalid I/O engine.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Threaded"
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
+    }
+
+    #[test]
+    fn test_parse_delete_drive_request() {
+        assert!(parse_delete_drive(None).is_err());
+        assert!(parse_delete_drive(Some(&"bad id")).is_err());
+
+        match vmm_action_from_request(parse_delete_drive(Some(&"foo")).unwrap()) {
+            VmmAction::RemoveBlockDevice(id) => assert_eq!(id, "foo"),
+            _ => panic!("Test failed: Invalid parameters"),
+        }
     }
 }
@@ -112,7 +112,8 @@ mod tests {
                 "vcpu_count": 8,
                 "mem_size_mib": 1024,
                 "ht_enabled": true,
-                "track_dirty_pages": true
+                "track_dirty_pages": true,
+                "hotplug_slots": 2
               }"#;
         let expected_config = VmConfig {
             vcpu_count: Some(8),
@@ -120,6 +121,7 @@ mod tests {
             ht_enabled: Some(true),
             cpu_template: None,
             track_dirty_pages: true,
+            hotplug_slots: 2,
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -145,6 +147,7 @@ mod tests {
                 ht_enabled: Some(true),
                 cpu_template: Some(CpuFeaturesTemplate::T2),
                 track_dirty_pages: true,
+                hotplug_slots: 0,
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -170,6 +173,11 @@ mod tests {
                 "track_dirty_pages": true
               }"#;
         assert!(parse_patch_machine_config(&Body::new(body)).is_err());
+        // Neither is "hotplug_slots".
+        let body = r#"{
+                "hotplug_slots": 2
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_err());
 
         // On aarch64, CPU template is also not patch compatible.
         let body = r#"{
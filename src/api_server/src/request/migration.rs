@@ -0,0 +1,88 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+use crate::request::{Method, StatusCode};
+use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+pub(crate) fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match request_type_from_path {
+        Some(&request_type) => match request_type {
+            "send" => Ok(ParsedRequest::new_sync(VmmAction::SendMigration(
+                serde_json::from_slice::<SendMigrationParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
+            "receive" => Ok(ParsedRequest::new_sync(VmmAction::ReceiveMigration(
+                serde_json::from_slice::<ReceiveMigrationParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
+            _ => Err(Error::InvalidPathMethod(
+                format!("/migration/{}", request_type),
+                Method::Put,
+            )),
+        },
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing migration operation type.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_put_migration() {
+        let mut body = r#"{
+                "uds_path": "foo"
+              }"#;
+
+        let expected_cfg = SendMigrationParams {
+            uds_path: Some(PathBuf::from("foo")),
+            tcp_address: None,
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "tcp_address": "127.0.0.1:4000",
+                "resume_vm": true
+              }"#;
+
+        let expected_cfg = ReceiveMigrationParams {
+            uds_path: None,
+            tcp_address: Some("127.0.0.1:4000".parse().unwrap()),
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        };
+        match vmm_action_from_request(
+            parse_put_migration(&Body::new(body), Some(&"receive")).unwrap(),
+        ) {
+            VmmAction::ReceiveMigration(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // Sending a microVM doesn't restore it.
+        assert!(parse_put_migration(&Body::new(body), Some(&"send")).is_err());
+
+        body = r#"{
+                "tcp_address": "localhost"
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"send")).is_err());
+
+        assert!(parse_put_migration(&Body::new(body), Some(&"invalid")).is_err());
+        assert!(parse_put_migration(&Body::new(body), None).is_err());
+    }
+}
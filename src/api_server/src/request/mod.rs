@@ -9,6 +9,7 @@ pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
 pub mod metrics;
+pub mod migration;
 pub mod mmds;
 pub mod net;
 pub mod snapshot;
@@ -5,7 +5,9 @@ use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
 use crate::request::{Body, StatusCode};
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::net::{
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig,
+};
 
 pub(crate) fn parse_put_net(
     body: &Body,
@@ -64,6 +66,50 @@ pub(crate) fn parse_patch_net(
     )))
 }
 
+pub(crate) fn parse_put_net_capture(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.network_capture_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.network_capture_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let capture_cfg = serde_json::from_slice::<NetworkCaptureConfig>(body.raw()).map_err(|e| {
+        METRICS.put_api_requests.network_capture_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+    if id != capture_cfg.iface_id {
+        METRICS.put_api_requests.network_capture_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The id from the path does not match the id from the body!".to_string(),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::StartNetworkCapture(
+        capture_cfg,
+    )))
+}
+
+pub(crate) fn parse_delete_net_capture(
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.delete_api_requests.network_capture_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.delete_api_requests.network_capture_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    Ok(ParsedRequest::new_sync(VmmAction::StopNetworkCapture(
+        id.to_string(),
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,8 +134,25 @@ mod tests {
             VmmAction::InsertNetworkDevice(netif) => assert_eq!(netif, netif_clone),
             _ => panic!("Test failed."),
         }
+        assert_eq!(netif_clone.num_queue_pairs, 1);
+        assert!(!netif_clone.vhost);
 
-        // 4. Serde error for invalid field (bytes instead of bandwidth).
+        // 4. Success case with several queue pairs, using vhost-net.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "num_queue_pairs": 4,
+                "vhost": true
+              }"#;
+        match vmm_action_from_request(parse_put_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::InsertNetworkDevice(netif) => {
+                assert_eq!(netif.num_queue_pairs, 4);
+                assert!(netif.vhost);
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 5. Serde error for invalid field (bytes instead of bandwidth).
         let body = r#"
         {
             "iface_id": "foo",
@@ -150,4 +213,47 @@ mod tests {
         }"#;
         assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
     }
+
+    #[test]
+    fn test_parse_put_net_capture_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "capture_path": "/tmp/foo.pcapng"
+              }"#;
+        // 1. Exercise infamous "The id from the path does not match id from the body!".
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"bar")).is_err());
+        // 2. The `id_from_path` cannot be None.
+        assert!(parse_put_net_capture(&Body::new(body), None).is_err());
+
+        // 3. Success case.
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::StartNetworkCapture(capture_cfg) => {
+                assert_eq!(capture_cfg.iface_id, "foo");
+                assert_eq!(
+                    capture_cfg.capture_path,
+                    std::path::PathBuf::from("/tmp/foo.pcapng")
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 4. Serde error for a missing path.
+        let body = r#"{
+                "iface_id": "foo"
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+    }
+
+    #[test]
+    fn test_parse_delete_net_capture_request() {
+        assert!(parse_delete_net_capture(None).is_err());
+        assert!(parse_delete_net_capture(Some(&"bad id")).is_err());
+
+        match vmm_action_from_request(parse_delete_net_capture(Some(&"foo")).unwrap()) {
+            VmmAction::StopNetworkCapture(id) => assert_eq!(id, "foo"),
+            _ => panic!("Test failed."),
+        }
+    }
 }
@@ -51,7 +51,10 @@ mod tests {
     #[test]
     fn test_parse_put_snapshot() {
         use std::path::PathBuf;
-        use vmm::vmm_config::snapshot::SnapshotType;
+        use vmm::vmm_config::snapshot::{
+            DeviceOverrides, DriveOverride, MemoryFileFormat, NetworkInterfaceOverride,
+            SnapshotEncryptionConfig, SnapshotType, VsockOverride,
+        };
 
         let mut body = r#"{
                 "snapshot_type": "Diff",
@@ -64,6 +67,9 @@ mod tests {
             snapshot_type: SnapshotType::Diff,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: false,
+            encryption: None,
             version: Some(String::from("0.23.0")),
         };
 
@@ -83,6 +89,81 @@ mod tests {
             snapshot_type: SnapshotType::Full,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: false,
+            encryption: None,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_format": "Compressed"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemoryFileFormat::Compressed,
+            live: false,
+            encryption: None,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "live": true
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: true,
+            encryption: None,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "encryption": { "key_fd": 7 }
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: false,
+            encryption: Some(SnapshotEncryptionConfig {
+                key: None,
+                key_fd: Some(7),
+            }),
             version: None,
         };
 
@@ -108,8 +189,13 @@ mod tests {
         let mut expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         };
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
         {
@@ -126,8 +212,13 @@ mod tests {
         expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: true,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -145,8 +236,13 @@ mod tests {
         expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: false,
             resume_vm: true,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -155,6 +251,155 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_layers": ["baz", "qux"]
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: vec![PathBuf::from("baz"), PathBuf::from("qux")],
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_layers": ["baz"],
+                "verify_memory_checksums": true
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: vec![PathBuf::from("baz")],
+            verify_memory_checksums: true,
+            uffd_socket_path: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "uffd_socket_path": "baz"
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: Some(PathBuf::from("baz")),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "device_overrides": {
+                    "drives": [{ "drive_id": "rootfs", "path_on_host": "baz" }],
+                    "network_interfaces": [{ "iface_id": "eth0", "host_dev_name": "tap1" }],
+                    "vsock": { "uds_path": "qux" }
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides {
+                drives: vec![DriveOverride {
+                    drive_id: String::from("rootfs"),
+                    path_on_host: String::from("baz"),
+                }],
+                network_interfaces: vec![NetworkInterfaceOverride {
+                    iface_id: String::from("eth0"),
+                    host_dev_name: String::from("tap1"),
+                }],
+                vsock: Some(VsockOverride {
+                    uds_path: String::from("qux"),
+                }),
+            },
+            encryption: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "encryption": { "key": "baz" }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: Some(SnapshotEncryptionConfig {
+                key: Some(String::from("baz")),
+                key_fd: None,
+            }),
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let invalid_body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "device_overrides": {
+                    "drives": [{ "drive_id": "rootfs", "is_read_only": true }]
+                }
+              }"#;
+        assert!(parse_put_snapshot(&Body::new(invalid_body), Some(&"load")).is_err());
+
         assert!(parse_put_snapshot(&Body::new(body), Some(&"invalid")).is_err());
         assert!(parse_put_snapshot(&Body::new(body), None).is_err());
     }
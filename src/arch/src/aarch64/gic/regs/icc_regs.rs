@@ -5,6 +5,7 @@ use crate::aarch64::gic::regs::{GicRegState, SimpleReg, VgicRegEngine};
 use crate::aarch64::gic::{Error, Result};
 use kvm_bindings::*;
 use kvm_ioctls::DeviceFd;
+use serde::Serialize;
 
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -77,7 +78,7 @@ impl SimpleReg {
 }
 
 /// Structure for serializing the state of the Vgic ICC regs
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct VgicSysRegsState {
     main_icc_regs: Vec<GicRegState<u64>>,
     ap_icc_regs: Vec<Option<GicRegState<u64>>>,
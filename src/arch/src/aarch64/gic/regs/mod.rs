@@ -5,13 +5,14 @@ mod redist_regs;
 use crate::aarch64::gic::{Error, Result};
 use kvm_bindings::kvm_device_attr;
 use kvm_ioctls::DeviceFd;
+use serde::Serialize;
 use std::fmt::Debug;
 use std::iter::StepBy;
 use std::ops::Range;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct GicRegState<T: Versionize> {
     pub(crate) chunks: Vec<T>,
 }
@@ -164,14 +165,14 @@ impl MmioReg for SimpleReg {
 }
 
 /// Structure used for serializing the state of the GIC registers
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicState {
     dist: Vec<GicRegState<u32>>,
     gic_vcpu_states: Vec<GicVcpuState>,
 }
 
 /// Structure used for serializing the state of the GIC registers for a specific vCPU
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicVcpuState {
     rdist: Vec<GicRegState<u32>>,
     icc: icc_regs::VgicSysRegsState,
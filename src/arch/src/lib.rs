@@ -7,6 +7,7 @@
 use std::fmt;
 use std::result;
 
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
@@ -34,7 +35,7 @@ pub use crate::x86_64::{
 pub type Result<T> = result::Result<T, Error>;
 
 /// Types of devices that can get attached to this platform.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, Versionize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, Serialize, Versionize)]
 pub enum DeviceType {
     /// Device Type: Virtio.
     Virtio(u32),
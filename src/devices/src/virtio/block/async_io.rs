@@ -0,0 +1,240 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Asynchronous execution of the block requests on the backing file, through an io_uring.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use logger::{error, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::eventfd::EventFd;
+use utils::io_uring::{IoUring, Operation};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::device::DiskProperties;
+use super::request::{ExecuteError, Request, RequestType};
+use super::{QUEUE_SIZE, SECTOR_SHIFT};
+
+/// The engine executing the I/O of a block device on its backing file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum FileEngineType {
+    /// Requests are executed one at a time, on the thread running the device emulation.
+    Sync,
+    /// Reads, writes and flushes are submitted to an io_uring and complete asynchronously,
+    /// possibly out of order.
+    Async,
+}
+
+impl Default for FileEngineType {
+    fn default() -> Self {
+        FileEngineType::Sync
+    }
+}
+
+/// A request submitted to the io_uring, waiting for its completion.
+pub(crate) struct PendingRequest {
+    pub queue_index: usize,
+    pub head_index: u16,
+    pub request_type: RequestType,
+    pub data_addr: GuestAddress,
+    pub data_len: u32,
+    pub status_addr: GuestAddress,
+    // Time at which the request was popped from its queue, in microseconds.
+    pub start_us: u64,
+}
+
+/// Submits the block requests to an io_uring and collects their completions, which are
+/// signaled through `completion_evt`.
+pub(crate) struct AsyncIo {
+    ring: IoUring,
+    completion_evt: EventFd,
+    pending: HashMap<u64, PendingRequest>,
+    next_user_data: u64,
+}
+
+impl AsyncIo {
+    pub fn new(num_queues: usize) -> io::Result<Self> {
+        // A request is in flight until it is returned to the guest, so the ring never holds
+        // more requests than the queues.
+        let ring = IoUring::new(u32::from(QUEUE_SIZE) * num_queues as u32)?;
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        ring.register_eventfd(completion_evt.as_raw_fd())?;
+
+        Ok(AsyncIo {
+            ring,
+            completion_evt,
+            pending: HashMap::new(),
+            next_user_data: 0,
+        })
+    }
+
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Specifies if no request is in flight.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Specifies if requests have to wait for completions before being pushed.
+    pub fn is_full(&self) -> bool {
+        self.ring.is_full()
+    }
+
+    /// Pushes the read, write or flush `request`, received on the queue at `queue_index` in
+    /// the descriptor chain starting at `head_index`, and popped at `start_us`. Returns
+    /// `Ok(false)` if the ring is full.
+    pub fn push(
+        &mut self,
+        request: &Request,
+        queue_index: usize,
+        head_index: u16,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+        start_us: u64,
+    ) -> Result<bool, ExecuteError> {
+        request.check_bounds(disk)?;
+
+        let user_data = self.next_user_data;
+        // The asynchronous engine is never used for disks served by an NBD server.
+        let fd = disk.file().unwrap().as_raw_fd();
+        let offset = request.sector << SECTOR_SHIFT;
+        let op = match request.request_type {
+            RequestType::In => Operation::read(
+                fd,
+                guest_buffer(mem, request.data_addr, request.data_len)
+                    .map_err(ExecuteError::Read)?,
+                request.data_len,
+                offset,
+                user_data,
+            ),
+            RequestType::Out => Operation::write(
+                fd,
+                guest_buffer(mem, request.data_addr, request.data_len)
+                    .map_err(ExecuteError::Write)?,
+                request.data_len,
+                offset,
+                user_data,
+            ),
+            _ => Operation::fsync(fd, user_data),
+        };
+
+        // Safe because the guest memory stays mapped while the device is activated, and the
+        // backing file is only replaced once the ring is drained.
+        if unsafe { self.ring.push(op) }.is_err() {
+            return Ok(false);
+        }
+        self.pending.insert(
+            user_data,
+            PendingRequest {
+                queue_index,
+                head_index,
+                request_type: request.request_type,
+                data_addr: request.data_addr,
+                data_len: request.data_len,
+                status_addr: request.status_addr,
+                start_us,
+            },
+        );
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        Ok(true)
+    }
+
+    /// Submits the pushed requests to the kernel.
+    pub fn submit(&mut self) {
+        // The requests which the kernel did not take stay in the ring, and are submitted
+        // along with the next ones.
+        if let Err(e) = self.ring.submit() {
+            error!("Failed to submit block requests: {:?}", e);
+            METRICS.block.event_fails.inc();
+        }
+    }
+
+    /// Submits the pushed requests and waits for all the requests in flight to complete.
+    pub fn drain(&mut self) -> io::Result<()> {
+        self.ring.submit_and_wait_all()
+    }
+
+    /// Pops the next completed request, along with the number of bytes it wrote to the
+    /// guest memory or its error.
+    pub fn pop(
+        &mut self,
+        mem: &GuestMemoryMmap,
+    ) -> Option<(PendingRequest, Result<u32, ExecuteError>)> {
+        loop {
+            let completion = self.ring.pop()?;
+            let request = match self.pending.remove(&completion.user_data()) {
+                Some(request) => request,
+                None => {
+                    error!(
+                        "Unexpected block request completion: {}",
+                        completion.user_data()
+                    );
+                    continue;
+                }
+            };
+
+            let data_len = request.data_len;
+            let result = match (request.request_type, completion.result()) {
+                (RequestType::In, Ok(len)) if len == data_len => {
+                    mark_dirty(mem, request.data_addr, len);
+                    METRICS.block.read_bytes.add(len as usize);
+                    METRICS.block.read_count.inc();
+                    Ok(data_len)
+                }
+                (RequestType::In, Ok(len)) => {
+                    mark_dirty(mem, request.data_addr, len);
+                    Err(ExecuteError::Read(GuestMemoryError::PartialBuffer {
+                        expected: data_len as usize,
+                        completed: len as usize,
+                    }))
+                }
+                (RequestType::In, Err(e)) => Err(ExecuteError::Read(GuestMemoryError::IOError(e))),
+                (RequestType::Out, Ok(len)) if len == data_len => {
+                    METRICS.block.write_bytes.add(len as usize);
+                    METRICS.block.write_count.inc();
+                    Ok(0)
+                }
+                (RequestType::Out, Ok(len)) => {
+                    Err(ExecuteError::Write(GuestMemoryError::PartialBuffer {
+                        expected: data_len as usize,
+                        completed: len as usize,
+                    }))
+                }
+                (RequestType::Out, Err(e)) => {
+                    Err(ExecuteError::Write(GuestMemoryError::IOError(e)))
+                }
+                (_, Ok(_)) => {
+                    METRICS.block.flush_count.inc();
+                    Ok(0)
+                }
+                (_, Err(e)) => Err(ExecuteError::Flush(e)),
+            };
+            return Some((request, result));
+        }
+    }
+}
+
+// Returns the host address of the guest buffer `[addr, addr + len)`.
+fn guest_buffer(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: u32,
+) -> Result<*mut u8, GuestMemoryError> {
+    Ok(mem.get_slice(addr, len as usize)?.as_ptr())
+}
+
+// The kernel writes the guest memory behind the back of the dirty page tracking.
+fn mark_dirty(mem: &GuestMemoryMmap, addr: GuestAddress, len: u32) {
+    if let Some(region) = mem.find_region(addr) {
+        region.mark_dirty_pages(
+            addr.unchecked_offset_from(region.start_addr()) as usize,
+            len as usize,
+        );
+    }
+}
@@ -8,46 +8,237 @@
 use std::cmp;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use logger::{error, warn, IncMetric, METRICS};
+use logger::{error, warn, BlockDriveMetrics, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
+use utils::time::{get_time_us, ClockType};
 use virtio_gen::virtio_blk::*;
-use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK, VIRTIO_MMIO_INT_VRING},
+    async_io::{AsyncIo, FileEngineType},
+    direct_io::{self, AlignedBuffer},
+    fd_pool::{BlockFdPool, BlockFds},
+    nbd::{NbdClient, NbdConfig},
+    overlay::{CowOverlay, OverlayConfig},
+    qcow::QcowFile,
     request::*,
-    Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    verity::{Error as VerityError, HashTree, VerityConfig},
+    Error, CONFIG_SPACE_SIZE, DISCARD_SECTOR_ALIGNMENT, MAX_DISCARD_SECTORS, MAX_DISCARD_SEGMENTS,
+    MAX_NUM_QUEUES, QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE,
 };
 
 use crate::virtio::VIRTIO_MMIO_INT_CONFIG;
 use crate::Error as DeviceError;
 
+// Offsets of the fields of `struct virtio_blk_config` in the configuration space.
+const CONFIG_CAPACITY_OFFSET: usize = 0;
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+const CONFIG_MAX_DISCARD_SECTORS_OFFSET: usize = 36;
+const CONFIG_MAX_DISCARD_SEG_OFFSET: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT_OFFSET: usize = 44;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS_OFFSET: usize = 48;
+const CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET: usize = 52;
+const CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET: usize = 56;
+
+/// The caching of the data written by the guest, on the host.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum CacheType {
+    /// The data goes through the host page cache, and is never explicitly synced to the
+    /// host storage. The device does not offer the flush feature.
+    Unsafe,
+    /// The data goes through the host page cache, and is synced to the host storage when
+    /// the guest flushes the device.
+    Writeback,
+    /// The backing file is opened with `O_DIRECT`, bypassing the host page cache, and is
+    /// synced to the host storage when the guest flushes the device.
+    Direct,
+}
+
+impl Default for CacheType {
+    fn default() -> Self {
+        CacheType::Unsafe
+    }
+}
+
+/// The format of the disk image in the backing file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ImageFormat {
+    /// The backing file holds the content of the disk as is.
+    Raw,
+    /// The backing file is a qcow2 image, possibly backed by a chain of raw or qcow2 images.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Raw
+    }
+}
+
+/// The disk image, as accessed by the synchronous requests.
+pub trait DiskImage: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> DiskImage for T {}
+
+/// The storage holding the data of the disk.
+pub(crate) enum DiskBacking {
+    /// A local backing file.
+    File(File),
+    /// An export of an NBD server.
+    Nbd(NbdClient),
+}
+
+impl Read for DiskBacking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DiskBacking::File(file) => file.read(buf),
+            DiskBacking::Nbd(nbd) => nbd.read(buf),
+        }
+    }
+}
+
+impl Write for DiskBacking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DiskBacking::File(file) => file.write(buf),
+            DiskBacking::Nbd(nbd) => nbd.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DiskBacking::File(file) => file.flush(),
+            DiskBacking::Nbd(nbd) => nbd.flush(),
+        }
+    }
+}
+
+impl Seek for DiskBacking {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            DiskBacking::File(file) => file.seek(pos),
+            DiskBacking::Nbd(nbd) => nbd.seek(pos),
+        }
+    }
+}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
+    // Empty for disks served by an NBD server.
     file_path: String,
-    file: File,
+    backing: DiskBacking,
+    // Only present for qcow2 images, which map the disk onto the backing file.
+    qcow: Option<QcowFile>,
+    // Only present for drives writing to a copy-on-write overlay of the backing file.
+    overlay: Option<CowOverlay>,
+    // Only present for read only drives verified against a hash tree.
+    verity: Option<HashTree>,
     nsectors: u64,
     image_id: Vec<u8>,
+    cache_type: CacheType,
 }
 
 impl DiskProperties {
-    pub fn new(disk_image_path: String, is_disk_read_only: bool) -> io::Result<Self> {
-        let mut disk_image = OpenOptions::new()
+    pub fn new(
+        disk_image_path: String,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+        image_format: ImageFormat,
+        overlay: Option<OverlayConfig>,
+    ) -> io::Result<Self> {
+        if overlay.is_some() && image_format != ImageFormat::Raw {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Overlays are only supported for raw images.",
+            ));
+        }
+
+        let mut open_options = OpenOptions::new();
+        // The base image of an overlay is shared, and is never written to.
+        open_options
             .read(true)
-            .write(!is_disk_read_only)
-            .open(PathBuf::from(&disk_image_path))?;
-        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+            .write(!is_disk_read_only && overlay.is_none());
+        if cache_type == CacheType::Direct {
+            open_options.custom_flags(libc::O_DIRECT);
+        }
+        let mut disk_image = open_options.open(PathBuf::from(&disk_image_path))?;
+        let (qcow, disk_size) = match image_format {
+            ImageFormat::Raw => (None, disk_image.seek(SeekFrom::End(0))? as u64),
+            ImageFormat::Qcow2 => {
+                // The qcow2 image only accesses the file through positional reads and writes.
+                let qcow = QcowFile::open(
+                    disk_image.try_clone()?,
+                    Path::new(&disk_image_path),
+                    is_disk_read_only,
+                )?;
+                let disk_size = qcow.virtual_size();
+                (Some(qcow), disk_size)
+            }
+        };
+        let overlay = overlay
+            .map(|config| CowOverlay::open(disk_image.try_clone()?, config))
+            .transpose()?;
+
+        Ok(Self {
+            nsectors: Self::disk_size_to_nsectors(disk_size),
+            image_id: Self::build_disk_image_id(&disk_image),
+            file_path: disk_image_path,
+            backing: DiskBacking::File(disk_image),
+            qcow,
+            overlay,
+            verity: None,
+            cache_type,
+        })
+    }
+
+    /// Sets up the properties of a raw disk served by the NBD server described by `config`.
+    pub fn new_nbd(
+        config: NbdConfig,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+    ) -> io::Result<Self> {
+        // There is no host page cache to bypass.
+        if cache_type == CacheType::Direct {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The Direct cache type is not supported for disks served by an NBD server.",
+            ));
+        }
+        let nbd = NbdClient::connect(config)?;
+        if nbd.is_read_only() && !is_disk_read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The NBD export is read only.",
+            ));
+        }
+
+        Ok(Self {
+            nsectors: Self::disk_size_to_nsectors(nbd.size()),
+            image_id: Self::disk_image_id(&nbd.config().describe()),
+            file_path: String::new(),
+            backing: DiskBacking::Nbd(nbd),
+            qcow: None,
+            overlay: None,
+            verity: None,
+            cache_type,
+        })
+    }
 
-        // We only support disk size, which uses the first two words of the configuration space.
-        // If the image is not a multiple of the sector size, the tail bits are not exposed.
+    // We only support disk size, which uses the first two words of the configuration space.
+    // If the image is not a multiple of the sector size, the tail bits are not exposed.
+    fn disk_size_to_nsectors(disk_size: u64) -> u64 {
         if disk_size % SECTOR_SIZE != 0 {
             warn!(
                 "Disk size {} is not a multiple of sector size {}; \
@@ -55,21 +246,81 @@ impl DiskProperties {
                 disk_size, SECTOR_SIZE
             );
         }
+        disk_size >> SECTOR_SHIFT
+    }
 
-        Ok(Self {
-            nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
-            file_path: disk_image_path,
-            file: disk_image,
-        })
+    /// Provides the local backing file, which disks served by an NBD server do not have.
+    pub fn file(&self) -> Option<&File> {
+        match self.backing {
+            DiskBacking::File(ref file) => Some(file),
+            DiskBacking::Nbd(_) => None,
+        }
+    }
+
+    /// Provides the file receiving the writes, which is the overlay file for drives having
+    /// an overlay, and the backing file otherwise.
+    pub fn data_file(&self) -> Option<&File> {
+        match self.overlay {
+            Some(ref overlay) => Some(overlay.overlay_file()),
+            None => self.file(),
+        }
+    }
+
+    /// Provides the client of the NBD server the disk is served by, if any.
+    pub fn nbd(&self) -> Option<&NbdClient> {
+        match self.backing {
+            DiskBacking::File(_) => None,
+            DiskBacking::Nbd(ref nbd) => Some(nbd),
+        }
+    }
+
+    /// Provides the disk image, which is the backing file itself for raw images.
+    pub fn image_mut(&mut self) -> &mut dyn DiskImage {
+        match (self.qcow.as_mut(), self.overlay.as_mut()) {
+            (Some(qcow), _) => qcow,
+            (None, Some(overlay)) => overlay,
+            (None, None) => &mut self.backing,
+        }
+    }
+
+    pub fn overlay(&self) -> Option<&CowOverlay> {
+        self.overlay.as_ref()
     }
 
-    pub fn file(&self) -> &File {
-        &self.file
+    /// Provides the hash tree the disk is verified against, if any.
+    pub fn verity(&self) -> Option<&HashTree> {
+        self.verity.as_ref()
     }
 
-    pub fn file_mut(&mut self) -> &mut File {
-        &mut self.file
+    /// Reads `buf.len()` bytes at `offset` of the disk, checking the data blocks holding them
+    /// against the hash tree of the disk.
+    pub fn read_verified(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VerityError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let block_size = self
+            .verity
+            .as_ref()
+            .map_or(SECTOR_SIZE, HashTree::data_block_size);
+        let start = offset - offset % block_size;
+        let end = (offset + buf.len() as u64 + block_size - 1) / block_size * block_size;
+
+        // The blocks are read at once, into a buffer suiting direct I/O.
+        let mut data = AlignedBuffer::new((end - start) as usize);
+        let image = self.image_mut();
+        image
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| image.read_exact(data.as_mut_slice()))
+            .map_err(VerityError::ReadData)?;
+        if let Some(tree) = self.verity.as_mut() {
+            for (i, block) in data.as_mut_slice().chunks(block_size as usize).enumerate() {
+                tree.verify(start / block_size + i as u64, block)?;
+            }
+        }
+
+        let head = (offset - start) as usize;
+        buf.copy_from_slice(&data.as_mut_slice()[head..head + buf.len()]);
+        Ok(())
     }
 
     pub fn nsectors(&self) -> u64 {
@@ -80,6 +331,123 @@ impl DiskProperties {
         &self.image_id
     }
 
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match self.qcow {
+            Some(_) => ImageFormat::Qcow2,
+            None => ImageFormat::Raw,
+        }
+    }
+
+    /// Grows the backing file to `size` bytes if provided, and updates the disk size from the
+    /// size of the backing file. Only raw images written to directly can be resized.
+    pub fn resize(&mut self, size: Option<u64>) -> io::Result<()> {
+        // The hash tree only covers the disk it was built for.
+        if self.verity.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Drives verified against a hash tree cannot be resized.",
+            ));
+        }
+        let file = match (self.qcow.as_ref(), self.overlay.as_ref(), &mut self.backing) {
+            (None, None, DiskBacking::File(file)) => file,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Only raw images without an overlay can be resized.",
+                ))
+            }
+        };
+        if let Some(size) = size {
+            // The guest would lose the data past the new end of the disk.
+            if size < file.metadata()?.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The backing file of a drive cannot be shrunk.",
+                ));
+            }
+            file.set_len(size)?;
+        }
+
+        let disk_size = file.seek(SeekFrom::End(0))?;
+        self.nsectors = Self::disk_size_to_nsectors(disk_size);
+        Ok(())
+    }
+
+    /// Specifies if ranges of the disk can be deallocated or zeroed in place, which is only
+    /// the case of raw images written to directly, and of NBD exports which can be trimmed.
+    pub fn supports_discard(&self) -> bool {
+        self.qcow.is_none()
+            && self.overlay.is_none()
+            && self.nbd().map_or(true, NbdClient::supports_trim)
+    }
+
+    /// Makes the data written to the backing file durable, unless the cache type does not
+    /// offer this guarantee.
+    pub fn sync(&mut self) -> io::Result<()> {
+        // The L1, L2 and refcount tables of qcow2 images have to be written back as well.
+        if let Some(qcow) = self.qcow.as_mut() {
+            return match self.cache_type {
+                CacheType::Unsafe => qcow.flush(),
+                CacheType::Writeback | CacheType::Direct => qcow.sync_all(),
+            };
+        }
+        let mut data_file = match (self.overlay.as_ref(), &mut self.backing) {
+            (Some(overlay), _) => overlay.overlay_file(),
+            (None, DiskBacking::File(file)) => &*file,
+            (None, DiskBacking::Nbd(nbd)) => {
+                return match self.cache_type {
+                    CacheType::Unsafe => Ok(()),
+                    CacheType::Writeback | CacheType::Direct => nbd.sync(),
+                }
+            }
+        };
+        data_file.flush()?;
+        match self.cache_type {
+            CacheType::Unsafe => Ok(()),
+            CacheType::Writeback | CacheType::Direct => data_file.sync_all(),
+        }
+    }
+
+    /// Deallocates the byte range `[offset, offset + len)` of the backing file, which then
+    /// reads as zeroes, or trims it from the NBD export.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match self.backing {
+            DiskBacking::File(ref file) => fallocate(
+                file,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            ),
+            DiskBacking::Nbd(ref mut nbd) => nbd.trim(offset, len),
+        }
+    }
+
+    /// Zeroes the byte range `[offset, offset + len)` of the backing file, deallocating it
+    /// if `unmap` is set.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let file = match self.backing {
+            DiskBacking::File(ref mut file) => file,
+            DiskBacking::Nbd(ref mut nbd) => return nbd.write_zeroes(offset, len, unmap),
+        };
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE
+        };
+        match fallocate(file, mode, offset, len) {
+            // Not all filesystems can zero ranges in place.
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                file.seek(SeekFrom::Start(offset))?;
+                direct_io::write_zeroes(file, len)
+            }
+            result => result,
+        }
+    }
+
     fn build_device_id(disk_file: &File) -> result::Result<String, Error> {
         let blk_metadata = disk_file.metadata().map_err(Error::GetFileMetadata)?;
         // This is how kvmtool does it.
@@ -93,19 +461,22 @@ impl DiskProperties {
     }
 
     fn build_disk_image_id(disk_file: &File) -> Vec<u8> {
-        let mut default_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
         match Self::build_device_id(disk_file) {
             Err(_) => {
                 warn!("Could not generate device id. We'll use a default.");
+                vec![0; VIRTIO_BLK_ID_BYTES as usize]
             }
-            Ok(m) => {
-                // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-                // This will also zero out any leftover bytes.
-                let disk_id = m.as_bytes();
-                let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-                default_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
-            }
+            Ok(m) => Self::disk_image_id(&m),
         }
+    }
+
+    fn disk_image_id(device_id: &str) -> Vec<u8> {
+        let mut default_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+        // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+        // This will also zero out any leftover bytes.
+        let disk_id = device_id.as_bytes();
+        let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
+        default_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy]);
         default_id
     }
 
@@ -116,21 +487,54 @@ impl DiskProperties {
 
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
-    pub fn virtio_block_config_space(&self) -> Vec<u8> {
+    /// on the backing file size, with the number of request queues, and
+    /// with the discard and write zeroes limits.
+    pub fn virtio_block_config_space(&self, num_queues: u16) -> Vec<u8> {
         // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
+        let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+        config[CONFIG_CAPACITY_OFFSET..CONFIG_CAPACITY_OFFSET + 8]
+            .copy_from_slice(&self.nsectors.to_le_bytes());
+        config[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2]
+            .copy_from_slice(&num_queues.to_le_bytes());
+        for &(offset, value) in &[
+            (CONFIG_MAX_DISCARD_SECTORS_OFFSET, MAX_DISCARD_SECTORS),
+            (CONFIG_MAX_DISCARD_SEG_OFFSET, MAX_DISCARD_SEGMENTS),
+            (
+                CONFIG_DISCARD_SECTOR_ALIGNMENT_OFFSET,
+                DISCARD_SECTOR_ALIGNMENT,
+            ),
+            (CONFIG_MAX_WRITE_ZEROES_SECTORS_OFFSET, MAX_DISCARD_SECTORS),
+            (CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET, MAX_DISCARD_SEGMENTS),
+        ] {
+            config[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
         }
+        config[CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET] = 1;
         config
     }
 }
 
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // Safe because the file descriptor is valid and the return value is checked.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     // Host file and properties.
     pub(crate) disk: DiskProperties,
+    // Only present when using the asynchronous I/O engine.
+    pub(crate) async_io: Option<AsyncIo>,
 
     // Virtio fields.
     pub(crate) avail_features: u64,
@@ -142,7 +546,7 @@ pub struct Block {
     pub(crate) queues: Vec<Queue>,
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     pub(crate) interrupt_evt: EventFd,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
 
     // Implementation specific fields.
@@ -150,12 +554,17 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
+    pub(crate) metrics: Arc<BlockDriveMetrics>,
 }
 
 impl Block {
-    /// Create a new virtio block device that operates on the given file.
+    /// Create a new virtio block device that operates on the given file, or on the export
+    /// of the NBD server described by `nbd`, with `num_queues` request queues. The reads of
+    /// read only devices are checked against the hash tree described by `verity` if set.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable. The file descriptors of the device are
+    /// taken from `fd_pool` if set, instead of being created.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         partuuid: Option<String>,
@@ -163,62 +572,148 @@ impl Block {
         is_disk_read_only: bool,
         is_disk_root: bool,
         rate_limiter: RateLimiter,
+        cache_type: CacheType,
+        file_engine_type: FileEngineType,
+        image_format: ImageFormat,
+        overlay: Option<OverlayConfig>,
+        num_queues: usize,
+        nbd: Option<NbdConfig>,
+        verity: Option<VerityConfig>,
+        fd_pool: Option<&mut BlockFdPool>,
     ) -> io::Result<Block> {
-        let disk_properties = DiskProperties::new(disk_image_path, is_disk_read_only)?;
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid number of block queues: {}.", num_queues),
+            ));
+        }
+        let mut disk_properties = match nbd {
+            Some(config) => {
+                // The requests to the server are issued synchronously, on raw exports.
+                if file_engine_type == FileEngineType::Async
+                    || image_format != ImageFormat::Raw
+                    || overlay.is_some()
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Disks served by an NBD server must be raw images without an overlay, \
+                         using the Sync I/O engine.",
+                    ));
+                }
+                DiskProperties::new_nbd(config, is_disk_read_only, cache_type)?
+            }
+            None => DiskProperties::new(
+                disk_image_path,
+                is_disk_read_only,
+                cache_type,
+                image_format,
+                overlay,
+            )?,
+        };
+        if let Some(config) = verity {
+            // The asynchronous reads land in the guest memory before they could be checked.
+            if !is_disk_read_only || file_engine_type == FileEngineType::Async {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Hash trees are only supported on read only drives using the Sync I/O \
+                     engine.",
+                ));
+            }
+            let disk_size = disk_properties.nsectors() << SECTOR_SHIFT;
+            disk_properties.verity = Some(HashTree::open(config, disk_size)?);
+        }
+        let fds = match fd_pool {
+            Some(pool) => pool.take(num_queues, file_engine_type)?,
+            None => BlockFds::new(num_queues, file_engine_type)?,
+        };
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if cache_type != CacheType::Unsafe {
+            avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
+        }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else if disk_properties.supports_discard() {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
+            metrics: METRICS.block_drives.get_or_insert(&id),
             id,
             root_device: is_disk_root,
             partuuid,
             rate_limiter,
-            config_space: disk_properties.virtio_block_config_space(),
+            config_space: disk_properties.virtio_block_config_space(num_queues as u16),
             disk: disk_properties,
+            async_io: fds.async_io,
             avail_features,
             acked_features: 0u64,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
-            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)?,
-            queue_evts,
+            interrupt_evt: fds.interrupt_evt,
+            queue_evts: fds.queue_evts,
             queues,
             device_state: DeviceState::Inactive,
-            activate_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            activate_evt: fds.activate_evt,
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(e) = self.queue_evts[0].read() {
+        if let Err(e) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", e);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
-        } else {
-            self.process_virtio_queues();
+            self.metrics.rate_limiter_throttled_events.inc();
+        } else if self.process_queue(queue_index) {
+            let _ = self.signal_used_queue();
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        if self.process_queue(0) {
+        let mut used_any = false;
+        for queue_index in 0..self.queues.len() {
+            used_any |= self.process_queue(queue_index);
+        }
+        if used_any {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    pub(crate) fn process_async_completion_event(&mut self) {
+        // The event is only registered when using the asynchronous I/O engine.
+        let async_io = self.async_io.as_mut().unwrap();
+        if let Err(e) = async_io.completion_evt().read() {
+            error!("Failed to get async completion event: {:?}", e);
+            METRICS.block.event_fails.inc();
+            return;
+        }
+
+        let was_full = async_io.is_full();
+        if self.process_async_completions() {
             let _ = self.signal_used_queue();
         }
+        // The requests left in the queue while the io_uring was full can now be pushed.
+        if was_full && !self.rate_limiter.is_blocked() {
+            self.process_virtio_queues();
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         METRICS.block.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
-        if self.rate_limiter.event_handler().is_ok() && self.process_queue(0) {
-            let _ = self.signal_used_queue();
+        // and restart processing the queues.
+        if self.rate_limiter.event_handler().is_ok() {
+            self.process_virtio_queues();
         }
     }
 
@@ -230,6 +725,7 @@ impl Block {
         };
         let queue = &mut self.queues[queue_index];
         let mut used_any = false;
+        let mut pushed_any = false;
         while let Some(head) = queue.pop(mem) {
             let len;
             match Request::parse(&head, mem) {
@@ -241,6 +737,7 @@ impl Block {
                         // avail ring, for later processing.
                         queue.undo_pop();
                         METRICS.block.rate_limiter_throttled_events.inc();
+                        self.metrics.rate_limiter_throttled_events.inc();
                         break;
                     }
                     // Exercise the rate limiter only if this request is of data transfer type.
@@ -259,60 +756,58 @@ impl Block {
                             // avail ring, for later processing.
                             queue.undo_pop();
                             METRICS.block.rate_limiter_throttled_events.inc();
+                            self.metrics.rate_limiter_throttled_events.inc();
                             break;
                         }
                     }
 
-                    let status = match request.execute(&mut self.disk, mem) {
-                        Ok(l) => {
-                            // Account for the status byte as well.
-                            // With a non-faulty driver, we shouldn't get to the point where we
-                            // overflow here (since data len must be a multiple of 512 bytes, so
-                            // it can't be u32::MAX). In the future, this should be fixed at the
-                            // request parsing level, so no data will actually be transferred in
-                            // scenarios like this one.
-                            if let Some(l) = l.checked_add(1) {
-                                len = l;
-                                VIRTIO_BLK_S_OK
-                            } else {
-                                len = l;
-                                VIRTIO_BLK_S_IOERR
-                            }
-                        }
-                        Err(e) => {
-                            METRICS.block.invalid_reqs_count.inc();
-                            match e {
-                                ExecuteError::Read(GuestMemoryError::PartialBuffer {
-                                    completed,
-                                    expected,
-                                }) => {
-                                    error!(
-                                        "Failed to execute virtio block read request: can only \
-                                        write {} of {} bytes.",
-                                        completed, expected
-                                    );
-                                    METRICS.block.read_bytes.add(completed);
-                                    // This can not overflow since `completed` < data len which is
-                                    // an u32.
-                                    len = completed as u32 + 1;
+                    let start_us = get_time_us(ClockType::Monotonic);
+                    let result = match self.async_io.as_mut() {
+                        Some(async_io) if request.is_async(&self.disk) => {
+                            match async_io.push(
+                                &request,
+                                queue_index,
+                                head.index,
+                                &self.disk,
+                                mem,
+                                start_us,
+                            ) {
+                                // The request is returned to the guest once it completes.
+                                Ok(true) => {
+                                    pushed_any = true;
+                                    continue;
                                 }
-                                _ => {
-                                    error!("Failed to execute virtio block request: {:?}", e);
-                                    // Status byte only.
-                                    len = 1;
+                                // The io_uring is full: return this descriptor chain to the
+                                // avail ring, until requests in flight complete.
+                                Ok(false) => {
+                                    self.rate_limiter.manual_replenish(1, TokenType::Ops);
+                                    if request.request_type != RequestType::Flush {
+                                        self.rate_limiter.manual_replenish(
+                                            u64::from(request.data_len),
+                                            TokenType::Bytes,
+                                        );
+                                    }
+                                    queue.undo_pop();
+                                    break;
                                 }
-                            };
-                            e.status()
+                                Err(e) => Err(e),
+                            }
                         }
+                        _ => request.execute(&mut self.disk, mem),
                     };
-
-                    if let Err(e) = mem.write_obj(status, request.status_addr) {
-                        error!("Failed to write virtio block status: {:?}", e)
-                    }
+                    Self::account_request(
+                        &self.metrics,
+                        request.request_type,
+                        request.data_len,
+                        &result,
+                        get_time_us(ClockType::Monotonic) - start_us,
+                    );
+                    len = Self::complete_request(mem, request.status_addr, result);
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
                     METRICS.block.execute_fails.inc();
+                    self.metrics.execute_fails.inc();
                     len = 0;
                 }
             }
@@ -326,13 +821,165 @@ impl Block {
             used_any = true;
         }
 
-        if !used_any {
+        if pushed_any {
+            // Safe to unwrap because requests are only pushed to the asynchronous I/O engine.
+            self.async_io.as_mut().unwrap().submit();
+        } else if !used_any {
             METRICS.block.no_avail_buffer.inc();
         }
 
         used_any
     }
 
+    // Returns the requests completed by the asynchronous I/O engine to the guest.
+    fn process_async_completions(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // Requests are only pushed once the device is activated.
+            DeviceState::Inactive => return false,
+        };
+        let async_io = match self.async_io.as_mut() {
+            Some(async_io) => async_io,
+            None => return false,
+        };
+        let mut used_any = false;
+        while let Some((request, result)) = async_io.pop(mem) {
+            Self::account_request(
+                &self.metrics,
+                request.request_type,
+                request.data_len,
+                &result,
+                get_time_us(ClockType::Monotonic) - request.start_us,
+            );
+            let len = Self::complete_request(mem, request.status_addr, result);
+            self.queues[request.queue_index]
+                .add_used(mem, request.head_index, len)
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to add available descriptor head {}: {}",
+                        request.head_index, e
+                    )
+                });
+            used_any = true;
+        }
+        used_any
+    }
+
+    // Accounts a request returned to the guest `latency_us` microseconds after it was popped
+    // from its queue, in the metrics of the drive.
+    fn account_request(
+        metrics: &BlockDriveMetrics,
+        request_type: RequestType,
+        data_len: u32,
+        result: &result::Result<u32, ExecuteError>,
+        latency_us: u64,
+    ) {
+        if result.is_err() {
+            metrics.execute_fails.inc();
+            return;
+        }
+        match request_type {
+            RequestType::In => {
+                metrics.read_bytes.add(data_len as usize);
+                metrics.read_count.inc();
+                metrics.read_latency_us.record(latency_us);
+                METRICS.block.read_latency_us.record(latency_us);
+            }
+            RequestType::Out => {
+                metrics.write_bytes.add(data_len as usize);
+                metrics.write_count.inc();
+                metrics.write_latency_us.record(latency_us);
+                METRICS.block.write_latency_us.record(latency_us);
+            }
+            RequestType::Flush => {
+                metrics.flush_count.inc();
+                metrics.flush_latency_us.record(latency_us);
+                METRICS.block.flush_latency_us.record(latency_us);
+            }
+            RequestType::Discard => metrics.discard_count.inc(),
+            RequestType::WriteZeroes => metrics.write_zeroes_count.inc(),
+            _ => (),
+        }
+    }
+
+    // Writes the status of an executed request to `status_addr`. Returns the number of bytes
+    // written to the guest memory, status byte included.
+    fn complete_request(
+        mem: &GuestMemoryMmap,
+        status_addr: GuestAddress,
+        result: result::Result<u32, ExecuteError>,
+    ) -> u32 {
+        let len;
+        let status = match result {
+            Ok(l) => {
+                // Account for the status byte as well.
+                // With a non-faulty driver, we shouldn't get to the point where we
+                // overflow here (since data len must be a multiple of 512 bytes, so
+                // it can't be u32::MAX). In the future, this should be fixed at the
+                // request parsing level, so no data will actually be transferred in
+                // scenarios like this one.
+                if let Some(l) = l.checked_add(1) {
+                    len = l;
+                    VIRTIO_BLK_S_OK
+                } else {
+                    len = l;
+                    VIRTIO_BLK_S_IOERR
+                }
+            }
+            Err(e) => {
+                METRICS.block.invalid_reqs_count.inc();
+                match e {
+                    ExecuteError::Read(GuestMemoryError::PartialBuffer {
+                        completed,
+                        expected,
+                    }) => {
+                        error!(
+                            "Failed to execute virtio block read request: can only \
+                            write {} of {} bytes.",
+                            completed, expected
+                        );
+                        METRICS.block.read_bytes.add(completed);
+                        // This can not overflow since `completed` < data len which is
+                        // an u32.
+                        len = completed as u32 + 1;
+                    }
+                    _ => {
+                        error!("Failed to execute virtio block request: {:?}", e);
+                        // Status byte only.
+                        len = 1;
+                    }
+                };
+                e.status()
+            }
+        };
+
+        if let Err(e) = mem.write_obj(status, status_addr) {
+            error!("Failed to write virtio block status: {:?}", e)
+        }
+        len
+    }
+
+    /// Completes the requests in flight, so that the device state and the guest memory can
+    /// be saved consistently.
+    pub fn prepare_save(&mut self) {
+        if let Some(async_io) = self.async_io.as_mut() {
+            if let Err(e) = async_io.drain() {
+                error!("Failed to drain block requests: {:?}", e);
+                METRICS.block.event_fails.inc();
+            }
+            if self.process_async_completions() {
+                let _ = self.signal_used_queue();
+            }
+        }
+        // The data written to an NBD export is only saved by the server.
+        if self.disk.nbd().is_some() {
+            if let Err(e) = self.disk.sync() {
+                error!("Failed to flush the NBD export: {:?}", e);
+                METRICS.block.execute_fails.inc();
+            }
+        }
+    }
+
     pub(crate) fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -347,19 +994,63 @@ impl Block {
 
     /// Update the backing file and the config space of the block device.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> io::Result<()> {
-        let disk_properties = DiskProperties::new(disk_image_path, self.is_read_only())?;
+        // The overlay only makes sense on top of the base image it was created with.
+        if self.disk.overlay().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The backing file of a drive with an overlay cannot be updated.",
+            ));
+        }
+        if self.disk.nbd().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The backing file of a drive served by an NBD server cannot be updated.",
+            ));
+        }
+        if self.disk.verity().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The backing file of a drive verified against a hash tree cannot be updated.",
+            ));
+        }
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            self.is_read_only(),
+            self.cache_type(),
+            self.image_format(),
+            None,
+        )?;
+        // The requests in flight have to complete on the previous backing file.
+        self.prepare_save();
         self.disk = disk_properties;
-        self.config_space = self.disk.virtio_block_config_space();
+        self.update_config_space();
 
-        // Kick the driver to pick up the changes.
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).unwrap();
+        METRICS.block.update_count.inc();
+        Ok(())
+    }
+
+    /// Resizes the disk, either to the current size of the backing file or by growing the
+    /// backing file to `size` bytes, and notifies the driver of the new capacity.
+    pub fn resize(&mut self, size: Option<u64>) -> io::Result<()> {
+        self.disk.resize(size)?;
+        self.update_config_space();
 
         METRICS.block.update_count.inc();
         Ok(())
     }
 
+    // Rebuilds the config space from the disk properties, and kicks the driver to pick up
+    // the changes.
+    fn update_config_space(&mut self) {
+        self.config_space = self
+            .disk
+            .virtio_block_config_space(self.queues.len() as u16);
+
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).unwrap();
+    }
+
     /// Updates the parameters for the rate limiter
     pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
         self.rate_limiter.update_buckets(bytes, ops);
@@ -384,6 +1075,24 @@ impl Block {
     pub fn is_root_device(&self) -> bool {
         self.root_device
     }
+
+    /// Provides the I/O engine of this block device.
+    pub fn file_engine_type(&self) -> FileEngineType {
+        match self.async_io {
+            Some(_) => FileEngineType::Async,
+            None => FileEngineType::Sync,
+        }
+    }
+
+    /// Provides the cache type of this block device.
+    pub fn cache_type(&self) -> CacheType {
+        self.disk.cache_type()
+    }
+
+    /// Provides the format of the disk image of this block device.
+    pub fn image_format(&self) -> ImageFormat {
+        self.disk.image_format()
+    }
 }
 
 impl VirtioDevice for Block {
@@ -470,6 +1179,8 @@ impl VirtioDevice for Block {
 #[cfg(test)]
 pub(crate) mod tests {
     use std::fs::metadata;
+    use std::mem;
+    use std::os::unix::fs::FileExt;
     use std::os::unix::io::AsRawFd;
     use std::thread;
     use std::time::Duration;
@@ -480,12 +1191,17 @@ pub(crate) mod tests {
     use polly::event_manager::{EventManager, Subscriber};
     use utils::epoll::{EpollEvent, EventSet};
     use utils::tempfile::TempFile;
-    use vm_memory::GuestAddress;
+    use vm_memory::{GuestAddress, GuestMemory};
 
     use crate::check_metric_after_block;
+    use crate::virtio::block::nbd::tests::{spawn_server, TEST_FLAGS};
+    use crate::virtio::block::nbd::NBD_FLAG_READ_ONLY;
+    use crate::virtio::block::qcow::tests::create_image;
     use crate::virtio::block::test_utils::{
-        default_block, invoke_handler_for_queue_event, set_queue, set_rate_limiter,
+        default_async_block, default_block, default_block_with_path,
+        invoke_handler_for_queue_event, send_request, set_queue, set_rate_limiter,
     };
+    use crate::virtio::block::verity::tests::hash_tree_config;
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
 
     #[test]
@@ -495,20 +1211,63 @@ pub(crate) mod tests {
         let size = SECTOR_SIZE * num_sectors;
         f.as_file().set_len(size).unwrap();
 
-        let disk_properties =
-            DiskProperties::new(String::from(f.as_path().to_str().unwrap()), true).unwrap();
+        let disk_properties = DiskProperties::new(
+            String::from(f.as_path().to_str().unwrap()),
+            true,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
 
         assert_eq!(size, SECTOR_SIZE * num_sectors);
         assert_eq!(disk_properties.nsectors, num_sectors);
-        let cfg = disk_properties.virtio_block_config_space();
+        let cfg = disk_properties.virtio_block_config_space(4);
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
+        for (i, byte) in cfg[..8].iter().enumerate() {
             assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
         }
+        let config_u32 = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&cfg[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        assert_eq!(
+            config_u32(CONFIG_MAX_DISCARD_SECTORS_OFFSET),
+            MAX_DISCARD_SECTORS
+        );
+        assert_eq!(
+            config_u32(CONFIG_MAX_DISCARD_SEG_OFFSET),
+            MAX_DISCARD_SEGMENTS
+        );
+        assert_eq!(
+            config_u32(CONFIG_DISCARD_SECTOR_ALIGNMENT_OFFSET),
+            DISCARD_SECTOR_ALIGNMENT
+        );
+        assert_eq!(
+            config_u32(CONFIG_MAX_WRITE_ZEROES_SECTORS_OFFSET),
+            MAX_DISCARD_SECTORS
+        );
+        assert_eq!(
+            config_u32(CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET),
+            MAX_DISCARD_SEGMENTS
+        );
+        assert_eq!(cfg[CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET], 1);
+        assert_eq!(
+            &cfg[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2],
+            &[4, 0]
+        );
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
-        assert!(DiskProperties::new("invalid-disk-path".to_string(), true).is_err());
+        assert!(DiskProperties::new(
+            "invalid-disk-path".to_string(),
+            true,
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            None
+        )
+        .is_err());
     }
 
     #[test]
@@ -517,7 +1276,10 @@ pub(crate) mod tests {
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -532,22 +1294,86 @@ pub(crate) mod tests {
         assert_eq!(block.acked_features, features);
     }
 
+    #[test]
+    fn test_multiple_queues() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                CacheType::Writeback,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                None,
+                num_queues,
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(new_block(0).is_err());
+        assert!(new_block(MAX_NUM_QUEUES + 1).is_err());
+        assert_eq!(
+            new_block(1).unwrap().avail_features() & (1u64 << VIRTIO_BLK_F_MQ),
+            0
+        );
+
+        let mut block = new_block(2).unwrap();
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        let mut num_queues = [0u8; 2];
+        block.read_config(CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 1, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+            .unwrap();
+
+        // The request is only processed once the event of its queue is received.
+        block.queue_evts[0].write(1).unwrap();
+        block.process(
+            &EpollEvent::new(EventSet::IN, block.queue_evts[0].as_raw_fd() as u64),
+            &mut EventManager::new().unwrap(),
+        );
+        assert_eq!(vq.used.idx.get(), 0);
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process(
+            &EpollEvent::new(EventSet::IN, block.queue_evts[1].as_raw_fd() as u64),
+            &mut EventManager::new().unwrap(),
+        );
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+    }
+
     #[test]
     fn test_virtio_read_config() {
         let block = default_block();
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
-        // This will read the number of sectors.
+        // This will read the number of sectors, in the first field of the config space.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -559,11 +1385,10 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block();
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -576,11 +1401,14 @@ pub(crate) mod tests {
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid write.
+        let mut config_space = vec![0u8; CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 5, &new_config_space);
         // Make sure nothing got written.
+        let mut actual_config_space = vec![0u8; CONFIG_SPACE_SIZE];
         block.read_config(0, &mut actual_config_space);
-        assert_eq!(actual_config_space, expected_config_space);
+        assert_eq!(actual_config_space, config_space);
     }
 
     #[test]
@@ -831,8 +1659,8 @@ pub(crate) mod tests {
                 .flags
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
-            let size = block.disk.file.seek(SeekFrom::End(0)).unwrap();
-            block.disk.file.set_len(size / 2).unwrap();
+            let size = block.disk.file().unwrap().seek(SeekFrom::End(0)).unwrap();
+            block.disk.file().unwrap().set_len(size / 2).unwrap();
             mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
@@ -861,8 +1689,8 @@ pub(crate) mod tests {
                 .flags
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
-            let size = block.disk.file.seek(SeekFrom::End(0)).unwrap();
-            block.disk.file.set_len(size / 2).unwrap();
+            let size = block.disk.file().unwrap().seek(SeekFrom::End(0)).unwrap();
+            block.disk.file().unwrap().set_len(size / 2).unwrap();
             // Update sector number: stored at `request_type_addr.0 + 8`
             mem.write_obj(5, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
@@ -956,8 +1784,11 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn test_get_device_id() {
+    fn test_drive_metrics() {
         let mut block = default_block();
+        // Report into metrics which no other test updates.
+        block.metrics = METRICS.block_drives.get_or_insert("metrics_drive");
+        let metrics = block.metrics.clone();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -965,19 +1796,751 @@ pub(crate) mod tests {
         initialize_virtqueue(&vq);
 
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
-        let data_addr = GuestAddress(vq.dtable[1].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
-        let blk_metadata = block.disk.file.metadata();
-
-        // Test that the driver receives the correct device id.
-        {
-            vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES);
 
-            mem.write_obj::<u32>(VIRTIO_BLK_T_GET_ID, request_type_addr)
+        for &request_type in [VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_FLUSH].iter() {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(request_type, request_type_addr)
                 .unwrap();
-
+            vq.dtable[1].len.set(8);
+            if request_type == VIRTIO_BLK_T_IN {
+                vq.dtable[1]
+                    .flags
+                    .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            } else {
+                vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            }
             invoke_handler_for_queue_event(&mut block);
-            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+        assert_eq!(metrics.write_bytes.count(), 8);
+        assert_eq!(metrics.write_count.count(), 1);
+        assert_eq!(metrics.write_latency_us.count(), 1);
+        assert_eq!(metrics.read_bytes.count(), 8);
+        assert_eq!(metrics.read_count.count(), 1);
+        assert_eq!(metrics.read_latency_us.count(), 1);
+        assert_eq!(metrics.flush_count.count(), 1);
+        assert_eq!(metrics.flush_latency_us.count(), 1);
+        assert_eq!(metrics.execute_fails.count(), 0);
+
+        // Failed requests are not accounted as operations.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
+            .unwrap();
+        invoke_handler_for_queue_event(&mut block);
+        assert_eq!(
+            mem.read_obj::<u32>(status_addr).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(metrics.read_count.count(), 1);
+        assert_eq!(metrics.execute_fails.count(), 1);
+    }
+
+    #[test]
+    fn test_cache_types() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let path = f.as_path().to_str().unwrap().to_string();
+        let new_block = |cache_type| {
+            Block::new(
+                "test".to_string(),
+                None,
+                path.clone(),
+                false,
+                false,
+                RateLimiter::default(),
+                cache_type,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                None,
+                1,
+                None,
+                None,
+                None,
+            )
+        };
+
+        // The flush feature is only offered when flushes sync the data.
+        let block = new_block(CacheType::Unsafe).unwrap();
+        assert_eq!(block.cache_type(), CacheType::Unsafe);
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+        let block = new_block(CacheType::Writeback).unwrap();
+        assert_eq!(block.cache_type(), CacheType::Writeback);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+
+        // The host filesystem may not support `O_DIRECT`.
+        let mut block = match new_block(CacheType::Direct) {
+            Ok(block) => block,
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("{:?}", e),
+        };
+        assert_eq!(block.cache_type(), CacheType::Direct);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Unaligned write, through a bounce buffer.
+        {
+            mem.write_obj::<RequestHeader>(
+                RequestHeader::new(VIRTIO_BLK_T_OUT, 1),
+                request_type_addr,
+            )
+            .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(8);
+            mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Read back.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<RequestHeader>(
+                RequestHeader::new(VIRTIO_BLK_T_IN, 1),
+                request_type_addr,
+            )
+            .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            vq.dtable[1].len.set(SECTOR_SIZE as u32);
+            mem.write_obj::<u64>(0, data_addr).unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, SECTOR_SIZE as u32 + 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+        }
+
+        // Flush.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(2);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        // The device reads the segments from the data descriptor.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(mem::size_of::<DiscardSegment>() as u32);
+
+        // The backing file is 0x1000 bytes long.
+        let disk_data = |block: &Block| {
+            let mut data = vec![0u8; 0x1000];
+            block
+                .disk
+                .file()
+                .unwrap()
+                .read_exact_at(&mut data, 0)
+                .unwrap();
+            data
+        };
+        block
+            .disk
+            .file()
+            .unwrap()
+            .write_all_at(&[0xaa; 0x1000], 0)
+            .unwrap();
+
+        let send_discard = |block: &mut Block, request_type, segment| {
+            vq.used.idx.set(0);
+            set_queue(block, 0, vq.create_queue());
+            mem.write_obj::<RequestHeader>(RequestHeader::new(request_type, 0), request_type_addr)
+                .unwrap();
+            mem.write_obj::<DiscardSegment>(segment, data_addr).unwrap();
+            invoke_handler_for_queue_event(block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            mem.read_obj::<u32>(status_addr).unwrap()
+        };
+
+        // Discarded sectors read as zeroes.
+        check_metric_after_block!(
+            &METRICS.block.discard_bytes,
+            0x400,
+            assert_eq!(
+                send_discard(
+                    &mut block,
+                    VIRTIO_BLK_T_DISCARD,
+                    DiscardSegment::new(0, 2, 0)
+                ),
+                VIRTIO_BLK_S_OK
+            )
+        );
+        let data = disk_data(&block);
+        assert!(data[..0x400].iter().all(|&b| b == 0));
+        assert!(data[0x400..].iter().all(|&b| b == 0xaa));
+
+        // Zeroed sectors read as zeroes, whether they are deallocated or not.
+        for &flags in &[0, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP] {
+            block
+                .disk
+                .file()
+                .unwrap()
+                .write_all_at(&[0xaa; 0x1000], 0)
+                .unwrap();
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_bytes,
+                0x400,
+                assert_eq!(
+                    send_discard(
+                        &mut block,
+                        VIRTIO_BLK_T_WRITE_ZEROES,
+                        DiscardSegment::new(6, 2, flags)
+                    ),
+                    VIRTIO_BLK_S_OK
+                )
+            );
+            let data = disk_data(&block);
+            assert!(data[..0xc00].iter().all(|&b| b == 0xaa));
+            assert!(data[0xc00..].iter().all(|&b| b == 0));
+        }
+
+        // Discard requests cannot have flags.
+        assert_eq!(
+            send_discard(
+                &mut block,
+                VIRTIO_BLK_T_DISCARD,
+                DiscardSegment::new(0, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)
+            ),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        // Segments beyond the end of the disk.
+        assert_eq!(
+            send_discard(
+                &mut block,
+                VIRTIO_BLK_T_DISCARD,
+                DiscardSegment::new(7, 2, 0)
+            ),
+            VIRTIO_BLK_S_IOERR
+        );
+        // Segments larger than the advertised limit.
+        assert_eq!(
+            send_discard(
+                &mut block,
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                DiscardSegment::new(0, MAX_DISCARD_SECTORS + 1, 0)
+            ),
+            VIRTIO_BLK_S_IOERR
+        );
+        // Data which is not made of whole segments.
+        vq.dtable[1]
+            .len
+            .set(mem::size_of::<DiscardSegment>() as u32 - 1);
+        assert_eq!(
+            send_discard(
+                &mut block,
+                VIRTIO_BLK_T_DISCARD,
+                DiscardSegment::new(0, 2, 0)
+            ),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_qcow2_image() {
+        let f = TempFile::new().unwrap();
+        create_image(f.as_file(), 0x10000, 16, None);
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Qcow2,
+            None,
+            1,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(block.image_format(), ImageFormat::Qcow2);
+        // The disk has the virtual size of the image.
+        assert_eq!(block.disk.nsectors(), 0x10000 >> SECTOR_SHIFT);
+        assert_eq!(
+            block.avail_features()
+                & ((1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES)),
+            0
+        );
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+
+        // Write and read back past the end of the backing file.
+        vq.dtable[1].len.set(8);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_OUT, 100, 0),
+            VIRTIO_BLK_S_OK
+        );
+        mem.write_obj::<u64>(0, data_addr).unwrap();
+        assert_eq!(
+            send_request(
+                &mut block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                100,
+                VIRTQ_DESC_F_WRITE
+            ),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+        assert_eq!(
+            send_request(
+                &mut block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                99,
+                VIRTQ_DESC_F_WRITE
+            ),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 0);
+
+        // The flushed data is found when reopening the image.
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_FLUSH, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+        let mut image =
+            QcowFile::open(File::open(f.as_path()).unwrap(), f.as_path(), true).unwrap();
+        let mut data = [0u8; 8];
+        image.seek(SeekFrom::Start(100 << SECTOR_SHIFT)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert_eq!(u64::from_le_bytes(data), 123_456_789);
+
+        // Discard and write zeroes requests are not supported.
+        vq.dtable[1]
+            .len
+            .set(mem::size_of::<DiscardSegment>() as u32);
+        mem.write_obj::<DiscardSegment>(DiscardSegment::new(0, 8, 0), data_addr)
+            .unwrap();
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_DISCARD, 0, 0),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_WRITE_ZEROES, 0, 0),
+            VIRTIO_BLK_S_UNSUPP
+        );
+
+        // The image format is kept when the backing file is updated.
+        let other_file = TempFile::new().unwrap();
+        create_image(other_file.as_file(), 0x20000, 16, None);
+        block
+            .update_disk_image(other_file.as_path().to_str().unwrap().to_string())
+            .unwrap();
+        assert_eq!(block.disk.nsectors(), 0x20000 >> SECTOR_SHIFT);
+        assert!(block
+            .update_disk_image(f.as_path().to_str().unwrap().to_string())
+            .is_ok());
+        let raw_file = TempFile::new().unwrap();
+        raw_file.as_file().set_len(0x1000).unwrap();
+        assert!(block
+            .update_disk_image(raw_file.as_path().to_str().unwrap().to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all_at(&[0xaa; 0x2000], 0).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            Some(OverlayConfig {
+                path: overlay.as_path().to_str().unwrap().to_string(),
+                bitmap: None,
+            }),
+            1,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(!block.is_read_only());
+        assert_eq!(block.disk.nsectors(), 0x2000 >> SECTOR_SHIFT);
+        assert_eq!(
+            block.avail_features()
+                & ((1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES)),
+            0
+        );
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+
+        // The writes land in the overlay, and the reads fall through to the base image.
+        vq.dtable[1].len.set(8);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_OUT, 9, 0),
+            VIRTIO_BLK_S_OK
+        );
+        mem.write_obj::<u64>(0, data_addr).unwrap();
+        assert_eq!(
+            send_request(
+                &mut block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                9,
+                VIRTQ_DESC_F_WRITE
+            ),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+        assert_eq!(
+            send_request(
+                &mut block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                8,
+                VIRTQ_DESC_F_WRITE
+            ),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(
+            mem.read_obj::<u64>(data_addr).unwrap(),
+            0xaaaa_aaaa_aaaa_aaaa
+        );
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_FLUSH, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+
+        let mut data = vec![0u8; 0x2000];
+        base.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data.iter().all(|&b| b == 0xaa));
+        assert_eq!(block.disk.overlay().unwrap().bitmap(), &[0b10]);
+
+        // The base image of a drive with an overlay cannot be changed.
+        assert!(block
+            .update_disk_image(base.as_path().to_str().unwrap().to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn test_nbd() {
+        let nbd_block = |nbd: NbdConfig, read_only, cache_type, engine, format, overlay| {
+            Block::new(
+                "test".to_string(),
+                None,
+                String::new(),
+                read_only,
+                false,
+                RateLimiter::default(),
+                cache_type,
+                engine,
+                format,
+                overlay,
+                1,
+                Some(nbd),
+                None,
+                None,
+            )
+        };
+
+        // Only raw exports accessed synchronously through the host page cache are supported,
+        // and read only exports can only back read only drives.
+        let server = spawn_server(0x2000, TEST_FLAGS | NBD_FLAG_READ_ONLY, true, None);
+        let overlay = OverlayConfig {
+            path: String::from("overlay"),
+            bitmap: None,
+        };
+        for &(read_only, cache_type, engine, format, ref overlay) in &[
+            (
+                false,
+                CacheType::Writeback,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                None,
+            ),
+            (
+                true,
+                CacheType::Direct,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                None,
+            ),
+            (
+                true,
+                CacheType::Writeback,
+                FileEngineType::Async,
+                ImageFormat::Raw,
+                None,
+            ),
+            (
+                true,
+                CacheType::Writeback,
+                FileEngineType::Sync,
+                ImageFormat::Qcow2,
+                None,
+            ),
+            (
+                true,
+                CacheType::Writeback,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                Some(overlay.clone()),
+            ),
+        ] {
+            assert!(nbd_block(
+                server.config(),
+                read_only,
+                cache_type,
+                engine,
+                format,
+                overlay.clone()
+            )
+            .is_err());
+        }
+        let block = nbd_block(
+            server.config(),
+            true,
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+        assert!(block.is_read_only());
+        drop(block);
+
+        let server = spawn_server(0x2000, TEST_FLAGS, true, None);
+        let mut block = nbd_block(
+            server.config(),
+            false,
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+        assert_eq!(block.disk.nsectors(), 0x2000 >> SECTOR_SHIFT);
+        assert!(block.disk.file().is_none());
+        assert!(block.disk.image_id().starts_with(b"nbd:"));
+        let features = (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        assert_eq!(block.avail_features() & features, features);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+
+        // The requests are served by the export.
+        vq.dtable[1].len.set(8);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_OUT, 1, 0),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(
+            server.export.lock().unwrap()[0x200..0x208],
+            123_456_789u64.to_le_bytes()
+        );
+        mem.write_obj::<u64>(0, data_addr).unwrap();
+        assert_eq!(
+            send_request(
+                &mut block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                1,
+                VIRTQ_DESC_F_WRITE
+            ),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_FLUSH, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(*server.flush_count.lock().unwrap(), 1);
+
+        // Discard requests trim the export.
+        vq.dtable[1]
+            .len
+            .set(mem::size_of::<DiscardSegment>() as u32);
+        mem.write_obj::<DiscardSegment>(DiscardSegment::new(8, 8, 0), data_addr)
+            .unwrap();
+        assert_eq!(
+            send_request(&mut block, &vq, &mem, VIRTIO_BLK_T_DISCARD, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+        assert!(server.export.lock().unwrap()[0x1000..]
+            .iter()
+            .all(|&b| b == 0xff));
+
+        // The export cannot be replaced or resized.
+        assert!(block.update_disk_image(String::from("disk")).is_err());
+        assert!(block.resize(None).is_err());
+    }
+
+    #[test]
+    fn test_verity() {
+        let data: Vec<u8> = (0..0x4000).map(|i| (i / 0x1000) as u8).collect();
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all_at(&data, 0).unwrap();
+        let (_tree_file, config) = hash_tree_config(&data, 0x1000, b"salt");
+        let verity_block = |read_only, file_engine_type| {
+            Block::new(
+                "test".to_string(),
+                None,
+                f.as_path().to_str().unwrap().to_string(),
+                read_only,
+                false,
+                RateLimiter::default(),
+                CacheType::Unsafe,
+                file_engine_type,
+                ImageFormat::Raw,
+                None,
+                1,
+                None,
+                Some(config.clone()),
+                None,
+            )
+        };
+
+        // Only the synchronous reads of read only drives can be verified.
+        assert!(verity_block(false, FileEngineType::Sync).is_err());
+        assert!(verity_block(true, FileEngineType::Async).is_err());
+        let mut block = verity_block(true, FileEngineType::Sync).unwrap();
+        assert!(block.disk.verity().is_some());
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let send_read = |block: &mut Block, sector| {
+            send_request(
+                block,
+                &vq,
+                &mem,
+                VIRTIO_BLK_T_IN,
+                sector,
+                VIRTQ_DESC_F_WRITE,
+            )
+        };
+
+        // Reads spanning several data blocks, not aligned to them.
+        vq.dtable[1].len.set(0x1400);
+        assert_eq!(send_read(&mut block, 6), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x1400];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf[..], data[0xc00..0x2000]);
+
+        // Tampered blocks cannot be read, while the other ones still can.
+        f.as_file().write_all_at(&[0xff], 0x2000).unwrap();
+        check_metric_after_block!(
+            &METRICS.block.verity_fails,
+            1,
+            assert_eq!(send_read(&mut block, 0x10), VIRTIO_BLK_S_IOERR)
+        );
+        assert_eq!(send_read(&mut block, 0), VIRTIO_BLK_S_OK);
+
+        // The disk has to stay the one the hash tree was built for.
+        assert!(block
+            .update_disk_image(f.as_path().to_str().unwrap().to_string())
+            .is_err());
+        assert!(block.resize(None).is_err());
+    }
+
+    #[test]
+    fn test_get_device_id() {
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let blk_metadata = block.disk.file().unwrap().metadata();
+
+        // Test that the driver receives the correct device id.
+        {
+            vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES);
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_GET_ID, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
             assert_eq!(vq.used.ring[0].get().id, 0);
             assert_eq!(vq.used.ring[0].get().len, 21);
             assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
@@ -1186,6 +2749,157 @@ pub(crate) mod tests {
         }
     }
 
+    // Waits for the asynchronous I/O engine to complete a request and processes its completion.
+    fn wait_async_completion(block: &mut Block) {
+        let completion_fd = block
+            .async_io
+            .as_ref()
+            .unwrap()
+            .completion_evt()
+            .as_raw_fd();
+        let mut pollfd = libc::pollfd {
+            fd: completion_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because `pollfd` is valid for the duration of the call.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+        block.process(
+            &EpollEvent::new(EventSet::IN, completion_fd as u64),
+            &mut EventManager::new().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_async_io() {
+        let mut block = match default_async_block() {
+            Some(block) => block,
+            // io_uring may be unavailable on the host.
+            None => return,
+        };
+        assert_eq!(block.file_engine_type(), FileEngineType::Async);
+        let mem =
+            GuestMemoryMmap::from_ranges_with_tracking(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+        // The completion event is only watched once the device is activated.
+        let completion_fd = block
+            .async_io
+            .as_ref()
+            .unwrap()
+            .completion_evt()
+            .as_raw_fd();
+        assert!(block
+            .interest_list()
+            .iter()
+            .any(|event| event.fd() == completion_fd));
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let queue_evt = EpollEvent::new(EventSet::IN, block.queue_evts[0].as_raw_fd() as u64);
+        let mut event_manager = EventManager::new().unwrap();
+
+        // Write.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(512);
+            mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process(&queue_evt, &mut event_manager);
+            // The request is only returned to the guest once it completes.
+            assert_eq!(vq.used.idx.get(), 0);
+            assert!(block.interrupt_evt.read().is_err());
+
+            check_metric_after_block!(
+                &METRICS.block.write_count,
+                1,
+                wait_async_completion(&mut block)
+            );
+            assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut data = [0u8; 8];
+            block.disk.file().unwrap().seek(SeekFrom::Start(0)).unwrap();
+            std::io::Read::read_exact(&mut block.disk.file().unwrap(), &mut data).unwrap();
+            assert_eq!(u64::from_le_bytes(data), 123_456_789);
+        }
+
+        // Read, completed when preparing the device to be saved.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            mem.write_obj::<u64>(0, data_addr).unwrap();
+            let region = mem.find_region(data_addr).unwrap();
+            region.dirty_bitmap().unwrap().reset();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process(&queue_evt, &mut event_manager);
+            block.prepare_save();
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 513);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+            // The pages written by the kernel are reported as dirty.
+            assert!(region
+                .dirty_bitmap()
+                .unwrap()
+                .is_addr_set(data_addr.0 as usize));
+        }
+
+        // Flush.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(2);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process(&queue_evt, &mut event_manager);
+            check_metric_after_block!(
+                &METRICS.block.flush_count,
+                1,
+                wait_async_completion(&mut block)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Requests beyond the end of the disk fail without being submitted.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(1);
+            let request_header = RequestHeader::new(VIRTIO_BLK_T_IN, 8);
+            mem.write_obj::<RequestHeader>(request_header, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
     #[test]
     fn test_update_disk_image() {
         let mut block = default_block();
@@ -1202,7 +2916,43 @@ pub(crate) mod tests {
             .update_disk_image(String::from(path.to_str().unwrap()))
             .unwrap();
 
-        assert_eq!(block.disk.file.metadata().unwrap().st_ino(), mdata.st_ino());
+        assert_eq!(
+            block.disk.file().unwrap().metadata().unwrap().st_ino(),
+            mdata.st_ino()
+        );
         assert_eq!(block.disk.image_id, id);
     }
+
+    #[test]
+    fn test_resize() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut block = default_block_with_path(f.as_path().to_str().unwrap().to_string());
+        let capacity = |block: &Block| {
+            let mut capacity = [0u8; 8];
+            block.read_config(CONFIG_CAPACITY_OFFSET as u64, &mut capacity);
+            u64::from_le_bytes(capacity)
+        };
+        assert_eq!(capacity(&block), 0x1000 >> SECTOR_SHIFT);
+
+        // The size of a backing file grown on the host is read again.
+        f.as_file().set_len(0x2000).unwrap();
+        block.resize(None).unwrap();
+        assert_eq!(capacity(&block), 0x2000 >> SECTOR_SHIFT);
+        assert_ne!(
+            block.interrupt_status().load(Ordering::SeqCst) & VIRTIO_MMIO_INT_CONFIG as usize,
+            0
+        );
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+
+        // The backing file is grown to the requested size.
+        block.resize(Some(0x3000)).unwrap();
+        assert_eq!(f.as_file().metadata().unwrap().len(), 0x3000);
+        assert_eq!(capacity(&block), 0x3000 >> SECTOR_SHIFT);
+
+        // The backing file cannot be shrunk.
+        assert!(block.resize(Some(0x1000)).is_err());
+        assert_eq!(f.as_file().metadata().unwrap().len(), 0x3000);
+        assert_eq!(capacity(&block), 0x3000 >> SECTOR_SHIFT);
+    }
 }
@@ -0,0 +1,189 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads and writes of backing files opened with `O_DIRECT`, through aligned bounce buffers.
+//!
+//! `O_DIRECT` requires the memory buffers, the file offsets and the lengths of the transfers
+//! to be aligned. The offsets of the block requests are always sector aligned, but the guest
+//! buffers carry no alignment guarantee, so the data is copied through a bounce buffer.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::slice;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::SECTOR_SIZE;
+
+/// Alignment of the bounce buffers, which satisfies the memory alignment constraint of
+/// `O_DIRECT` on any host storage.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// Size of the buffer used to write zeroes.
+const ZEROES_CHUNK_SIZE: usize = 64 << 10;
+
+/// A zeroed heap buffer aligned to `DIRECT_IO_ALIGNMENT`.
+pub(crate) struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    // `len` must not be 0.
+    pub(crate) fn new(len: usize) -> Self {
+        // Safe to unwrap because the alignment is a power of two.
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap();
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the buffer was allocated with this size and is initialized.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the buffer was allocated with this layout.
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+// Rounds `len` up to a whole number of sectors.
+fn sectors_len(len: u32) -> usize {
+    let sector_size = SECTOR_SIZE as usize;
+    (len as usize + sector_size - 1) / sector_size * sector_size
+}
+
+/// Reads `len` bytes from the current position of `file` to the guest memory at `addr`.
+pub(crate) fn read_to_guest<F: Read + ?Sized>(
+    file: &mut F,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: u32,
+) -> Result<(), GuestMemoryError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let mut buffer = AlignedBuffer::new(sectors_len(len));
+    file.read_exact(buffer.as_mut_slice())
+        .map_err(GuestMemoryError::IOError)?;
+    mem.write_slice(&buffer.as_mut_slice()[..len as usize], addr)
+}
+
+/// Writes `len` bytes from the guest memory at `addr` at the offset `offset` of `file`,
+/// which is the current position of `file`.
+pub(crate) fn write_from_guest<F: Read + Write + Seek + ?Sized>(
+    file: &mut F,
+    offset: u64,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: u32,
+) -> Result<(), GuestMemoryError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let aligned_len = sectors_len(len);
+    let mut buffer = AlignedBuffer::new(aligned_len);
+    if aligned_len != len as usize {
+        // The guest data ends within the last sector, whose tail has to be preserved.
+        let tail_offset = aligned_len - SECTOR_SIZE as usize;
+        file.seek(SeekFrom::Start(offset + tail_offset as u64))
+            .and_then(|_| file.read_exact(&mut buffer.as_mut_slice()[tail_offset..]))
+            .and_then(|_| file.seek(SeekFrom::Start(offset)))
+            .map_err(GuestMemoryError::IOError)?;
+    }
+    mem.read_slice(&mut buffer.as_mut_slice()[..len as usize], addr)?;
+    file.write_all(buffer.as_mut_slice())
+        .map_err(GuestMemoryError::IOError)
+}
+
+/// Writes `len` zero bytes from the current position of `file`, where `len` is a whole number
+/// of sectors. This also works for files opened without `O_DIRECT`.
+pub(crate) fn write_zeroes(file: &mut File, mut len: u64) -> io::Result<()> {
+    let mut buffer = AlignedBuffer::new(ZEROES_CHUNK_SIZE);
+    while len > 0 {
+        let chunk_len = cmp::min(len, ZEROES_CHUNK_SIZE as u64) as usize;
+        file.write_all(&buffer.as_mut_slice()[..chunk_len])?;
+        len -= chunk_len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_aligned_buffer() {
+        let mut buffer = AlignedBuffer::new(3 * SECTOR_SIZE as usize);
+        assert_eq!(buffer.ptr as usize % DIRECT_IO_ALIGNMENT, 0);
+        assert_eq!(buffer.as_mut_slice().len(), 3 * SECTOR_SIZE as usize);
+        assert!(buffer.as_mut_slice().iter().all(|&b| b == 0));
+
+        assert_eq!(sectors_len(1), SECTOR_SIZE as usize);
+        assert_eq!(sectors_len(SECTOR_SIZE as u32), SECTOR_SIZE as usize);
+        assert_eq!(
+            sectors_len(SECTOR_SIZE as u32 + 1),
+            2 * SECTOR_SIZE as usize
+        );
+    }
+
+    #[test]
+    fn test_read_write() {
+        let f = TempFile::new().unwrap();
+        let mut file = f.into_file();
+        file.write_all(&[0xaa; 2 * SECTOR_SIZE as usize]).unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        mem.write_slice(&[0x55; 0x100], GuestAddress(0x100))
+            .unwrap();
+
+        // A write ending within a sector preserves the rest of the sector.
+        let offset = SECTOR_SIZE;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        write_from_guest(&mut file, offset, &mem, GuestAddress(0x100), 0x100).unwrap();
+        // Nothing is written past the sector.
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 2 * SECTOR_SIZE);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        read_to_guest(&mut file, &mem, GuestAddress(0x400), 2 * SECTOR_SIZE as u32).unwrap();
+        let mut data = [0u8; 2 * SECTOR_SIZE as usize];
+        mem.read_slice(&mut data, GuestAddress(0x400)).unwrap();
+        assert!(data[..SECTOR_SIZE as usize].iter().all(|&b| b == 0xaa));
+        assert!(data[SECTOR_SIZE as usize..SECTOR_SIZE as usize + 0x100]
+            .iter()
+            .all(|&b| b == 0x55));
+        assert!(data[SECTOR_SIZE as usize + 0x100..]
+            .iter()
+            .all(|&b| b == 0xaa));
+
+        // Reading past the end of the file fails.
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        assert!(read_to_guest(&mut file, &mem, GuestAddress(0), 2 * SECTOR_SIZE as u32).is_err());
+
+        // Zeroes are written in whole sectors.
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        write_zeroes(&mut file, SECTOR_SIZE).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        read_to_guest(&mut file, &mem, GuestAddress(0x400), 2 * SECTOR_SIZE as u32).unwrap();
+        mem.read_slice(&mut data, GuestAddress(0x400)).unwrap();
+        assert!(data[..SECTOR_SIZE as usize].iter().all(|&b| b == 0xaa));
+        assert!(data[SECTOR_SIZE as usize..].iter().all(|&b| b == 0));
+
+        // Empty transfers are no-ops.
+        read_to_guest(&mut file, &mem, GuestAddress(0), 0).unwrap();
+        write_from_guest(&mut file, offset, &mem, GuestAddress(0), 0).unwrap();
+    }
+}
@@ -40,6 +40,26 @@ impl Block {
             error!("Failed to unregister block activate evt: {:?}", e);
         });
     }
+
+    /// Unregisters the events of the device from the `event_manager`, so that the device can
+    /// be unplugged.
+    pub fn unregister_events(&self, event_manager: &mut EventManager) {
+        // Only the activate event is registered until it gets processed, which might not have
+        // happened yet even though the device is activated.
+        if event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .is_ok()
+        {
+            return;
+        }
+        for event in self.interest_list() {
+            event_manager
+                .unregister(event.data() as i32)
+                .unwrap_or_else(|e| {
+                    error!("Failed to unregister block events: {:?}", e);
+                });
+        }
+    }
 }
 
 impl Subscriber for Block {
@@ -60,14 +80,26 @@ impl Subscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
+            // Each request queue has its own event.
+            if let Some(queue_index) = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source)
+            {
+                self.process_queue_event(queue_index);
+                return;
+            }
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let completion_evt = self
+                .async_io
+                .as_ref()
+                .map(|async_io| async_io.completion_evt().as_raw_fd());
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
+                _ if completion_evt == Some(source) => self.process_async_completion_event(),
                 _ if activate_fd == source => self.process_activate_event(evmgr),
                 _ => warn!("Block: Spurious event received: {:?}", source),
             }
@@ -85,10 +117,22 @@ impl Subscriber for Block {
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
         if self.is_activated() {
-            vec![
-                EpollEvent::new(EventSet::IN, self.queue_evts[0].as_raw_fd() as u64),
-                EpollEvent::new(EventSet::IN, self.rate_limiter.as_raw_fd() as u64),
-            ]
+            let mut events: Vec<EpollEvent> = self
+                .queue_evts
+                .iter()
+                .map(|queue_evt| EpollEvent::new(EventSet::IN, queue_evt.as_raw_fd() as u64))
+                .collect();
+            events.push(EpollEvent::new(
+                EventSet::IN,
+                self.rate_limiter.as_raw_fd() as u64,
+            ));
+            if let Some(async_io) = self.async_io.as_ref() {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    async_io.completion_evt().as_raw_fd() as u64,
+                ));
+            }
+            events
         } else {
             vec![EpollEvent::new(
                 EventSet::IN,
@@ -178,4 +222,31 @@ pub mod tests {
         assert_eq!(vq.used.ring[0].get().len, 1);
         assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
     }
+
+    #[test]
+    fn test_unregister_events() {
+        let mut event_manager = EventManager::new().unwrap();
+        let mut block = default_block();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        initialize_virtqueue(&vq);
+
+        let block = Arc::new(Mutex::new(block));
+        event_manager.add_subscriber(block.clone()).unwrap();
+        let activate_fd = block.lock().unwrap().activate_evt.as_raw_fd();
+        let queue_fd = block.lock().unwrap().queue_evts[0].as_raw_fd();
+
+        // The activate event is still registered right after the activation.
+        block.lock().unwrap().activate(mem).unwrap();
+        assert!(event_manager.subscriber(activate_fd).is_ok());
+        block.lock().unwrap().unregister_events(&mut event_manager);
+        assert!(event_manager.subscriber(activate_fd).is_err());
+
+        // Once activated, the device has its queue events registered instead.
+        event_manager.add_subscriber(block.clone()).unwrap();
+        assert!(event_manager.subscriber(queue_fd).is_ok());
+        block.lock().unwrap().unregister_events(&mut event_manager);
+        assert!(event_manager.subscriber(queue_fd).is_err());
+    }
 }
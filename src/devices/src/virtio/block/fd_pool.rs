@@ -0,0 +1,170 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! File descriptors of the block devices hot-plugged after the seccomp filter is installed,
+//! which doesn't allow creating eventfds or io_urings.
+
+use std::io;
+
+use utils::eventfd::EventFd;
+
+use super::async_io::{AsyncIo, FileEngineType};
+use super::device::Block;
+use super::MAX_NUM_QUEUES;
+
+/// The file descriptors used by a block device.
+pub(crate) struct BlockFds {
+    pub queue_evts: Vec<EventFd>,
+    pub interrupt_evt: EventFd,
+    pub activate_evt: EventFd,
+    pub async_io: Option<AsyncIo>,
+}
+
+impl BlockFds {
+    /// Creates the file descriptors of a device with `num_queues` request queues.
+    pub fn new(num_queues: usize, file_engine_type: FileEngineType) -> io::Result<Self> {
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK)?);
+        }
+        let async_io = match file_engine_type {
+            FileEngineType::Sync => None,
+            FileEngineType::Async => Some(AsyncIo::new(num_queues)?),
+        };
+
+        Ok(BlockFds {
+            queue_evts,
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            async_io,
+        })
+    }
+}
+
+/// Eventfds and io_urings created ahead of time, enough for a device with the maximum number
+/// of queues in each hot-plug slot. The file descriptors of the unplugged devices are put
+/// back in the pool.
+#[derive(Default)]
+pub struct BlockFdPool {
+    event_fds: Vec<EventFd>,
+    async_ios: Vec<AsyncIo>,
+}
+
+impl BlockFdPool {
+    /// Creates the file descriptors of `num_devices` devices.
+    ///
+    /// The io_urings only have room for the requests of a single queue, and are shared by all
+    /// the queues of the device. They are not created if the host doesn't support io_uring,
+    /// in which case the devices can only use the Sync I/O engine.
+    pub fn new(num_devices: usize) -> io::Result<Self> {
+        let num_event_fds = num_devices * (MAX_NUM_QUEUES + 2);
+        let mut event_fds = Vec::with_capacity(num_event_fds);
+        for _ in 0..num_event_fds {
+            event_fds.push(EventFd::new(libc::EFD_NONBLOCK)?);
+        }
+        let async_ios = (0..num_devices)
+            .map(|_| AsyncIo::new(1))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap_or_default();
+
+        Ok(BlockFdPool {
+            event_fds,
+            async_ios,
+        })
+    }
+
+    /// Takes the file descriptors of a device with `num_queues` request queues out of the pool.
+    pub(crate) fn take(
+        &mut self,
+        num_queues: usize,
+        file_engine_type: FileEngineType,
+    ) -> io::Result<BlockFds> {
+        if self.event_fds.len() < num_queues + 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "No eventfd left for the hot-plugged block device.",
+            ));
+        }
+        if file_engine_type == FileEngineType::Async && self.async_ios.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "No io_uring left for the hot-plugged block device.",
+            ));
+        }
+
+        let queue_evts = self.event_fds.split_off(self.event_fds.len() - num_queues);
+        Ok(BlockFds {
+            queue_evts,
+            // Checked above.
+            interrupt_evt: self.event_fds.pop().unwrap(),
+            activate_evt: self.event_fds.pop().unwrap(),
+            async_io: match file_engine_type {
+                FileEngineType::Sync => None,
+                FileEngineType::Async => self.async_ios.pop(),
+            },
+        })
+    }
+
+    /// Puts the file descriptors of the unplugged `block` device back in the pool.
+    pub fn reclaim(&mut self, block: Block) {
+        let Block {
+            queue_evts,
+            interrupt_evt,
+            activate_evt,
+            async_io,
+            ..
+        } = block;
+
+        for evt in queue_evts
+            .into_iter()
+            .chain(vec![interrupt_evt, activate_evt])
+        {
+            // Clear the counter, so that the next device doesn't see stale events.
+            let _ = evt.read();
+            self.event_fds.push(evt);
+        }
+        if let Some(async_io) = async_io {
+            // Requests still in flight would complete on behalf of the next device.
+            if async_io.is_idle() {
+                let _ = async_io.completion_evt().read();
+                self.async_ios.push(async_io);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::block::test_utils::default_block;
+
+    #[test]
+    fn test_block_fd_pool() {
+        let mut pool = BlockFdPool::new(1).unwrap();
+        assert_eq!(pool.event_fds.len(), MAX_NUM_QUEUES + 2);
+        let io_uring_supported = !pool.async_ios.is_empty();
+
+        let fds = pool.take(MAX_NUM_QUEUES, FileEngineType::Sync).unwrap();
+        assert_eq!(fds.queue_evts.len(), MAX_NUM_QUEUES);
+        assert!(fds.async_io.is_none());
+        assert!(pool.event_fds.is_empty());
+        assert!(pool.take(1, FileEngineType::Sync).is_err());
+
+        // The file descriptors of the unplugged device can be used by the next one.
+        let mut block = default_block();
+        fds.queue_evts[0].write(1).unwrap();
+        block.queue_evts = fds.queue_evts;
+        block.interrupt_evt = fds.interrupt_evt;
+        block.activate_evt = fds.activate_evt;
+        pool.reclaim(block);
+        assert_eq!(pool.event_fds.len(), MAX_NUM_QUEUES + 2);
+        assert!(pool.event_fds.iter().all(|evt| evt.read().is_err()));
+
+        if io_uring_supported {
+            let fds = pool.take(4, FileEngineType::Async).unwrap();
+            assert!(fds.async_io.is_some());
+        }
+        // The only io_uring is taken, or was never created.
+        assert!(pool.take(1, FileEngineType::Async).is_err());
+    }
+}
@@ -1,24 +1,44 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod async_io;
 pub mod device;
+mod direct_io;
 pub mod event_handler;
+pub mod fd_pool;
+pub mod nbd;
+pub mod overlay;
 pub mod persist;
+mod qcow;
 pub mod request;
 pub mod test_utils;
+pub mod verity;
 
-pub use self::device::Block;
+pub use self::async_io::FileEngineType;
+pub use self::device::{Block, CacheType, ImageFormat};
 pub use self::event_handler::*;
+pub use self::fd_pool::BlockFdPool;
+pub use self::nbd::NbdConfig;
+pub use self::overlay::OverlayConfig;
 pub use self::request::*;
+pub use self::verity::VerityConfig;
 
 use vm_memory::GuestMemoryError;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = 60;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+/// Maximum number of sectors of a discard or write zeroes segment.
+pub const MAX_DISCARD_SECTORS: u32 = (1 << 22) - 1;
+/// Maximum number of segments of a discard or write zeroes request.
+pub const MAX_DISCARD_SEGMENTS: u32 = 16;
+/// Discard granularity, in sectors. Holes can only be punched in whole host pages.
+pub const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+/// Default number of request queues of a block device.
+pub const DEFAULT_NUM_QUEUES: usize = 1;
+/// Maximum number of request queues of a block device.
+pub const MAX_NUM_QUEUES: usize = 16;
 
 #[derive(Debug)]
 pub enum Error {
@@ -30,6 +50,8 @@ pub enum Error {
     GetFileMetadata(std::io::Error),
     /// Guest gave us bad memory addresses.
     GuestMemory(GuestMemoryError),
+    /// Guest gave us discard or write zeroes segments exceeding the advertised limits.
+    InvalidDiscardSegments,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -37,3 +59,12 @@ pub enum Error {
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
 }
+
+// Offsets `base` by the signed `offset`, as seeking does.
+fn checked_add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
@@ -0,0 +1,882 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client of the network block device (NBD) protocol, serving drives from an export of a
+//! remote server instead of a local file.
+//!
+//! Only the fixed newstyle handshake and simple replies are supported, and requests are sent
+//! one at a time. A request failing because the connection broke is retried once on a new
+//! connection; if the server cannot be reached, the request fails and the next one tries to
+//! reconnect again.
+
+use std::cmp;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use logger::{warn, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+
+use super::checked_add_signed;
+
+// Magic numbers of the handshake and of the transmission messages.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_OPT_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags of the server, and of the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options, and their replies.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+const NBD_INFO_EXPORT: u16 = 0;
+
+// Transmission flags of the export.
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub(crate) const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Commands, and their flags.
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+/// Largest range covered by a single request, which servers commonly accept.
+const MAX_REQUEST_LEN: usize = 32 << 20;
+/// Size of the buffer of zeroes written to servers not supporting the write zeroes command.
+const ZEROES_BUF_LEN: usize = 1 << 20;
+/// Time after which a request without reply is considered lost, and the connection broken.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Upper bound for the size of the replies to the options.
+const MAX_OPTION_REPLY_LEN: u32 = 4096;
+/// Size of the request header.
+const REQUEST_HEADER_LEN: usize = 28;
+/// Length of the zeroes padding the reply to `NBD_OPT_EXPORT_NAME`.
+const EXPORT_NAME_PADDING_LEN: usize = 124;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server rejected the export, with the given error reply.
+    ExportRejected(u32),
+    /// Exactly one of the Unix domain socket path and the TCP address has to be set.
+    InvalidAddress,
+    /// The server sent a message which does not follow the protocol.
+    InvalidReply,
+    /// The size of the export changed while reconnecting.
+    SizeChanged(u64),
+    /// The server does not speak the fixed newstyle protocol.
+    UnsupportedServer,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ExportRejected(reply) => write!(f, "The NBD server rejected the export: {:#x}.", reply),
+            InvalidAddress => write!(
+                f,
+                "Either the Unix domain socket path or the IP address and port of the NBD \
+                 server must be set."
+            ),
+            InvalidReply => write!(f, "Invalid reply from the NBD server."),
+            SizeChanged(size) => write!(
+                f,
+                "The size of the NBD export changed while reconnecting: {}.",
+                size
+            ),
+            UnsupportedServer => write!(
+                f,
+                "The NBD server does not support the fixed newstyle negotiation."
+            ),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/// The NBD server and export a drive is served from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdConfig {
+    /// Path of the Unix domain socket the server listens on.
+    #[serde(default)]
+    pub socket_path: Option<String>,
+    /// IP address and port the server listens on, e.g. `192.168.0.2:10809`.
+    #[serde(default)]
+    pub tcp_address: Option<String>,
+    /// Name of the export, the default export of the server when empty.
+    #[serde(default)]
+    pub export_name: String,
+}
+
+impl NbdConfig {
+    /// Describes the server and the export, e.g. to identify the drive to the guest.
+    pub fn describe(&self) -> String {
+        let address = self
+            .socket_path
+            .as_ref()
+            .or_else(|| self.tcp_address.as_ref())
+            .map(String::as_str)
+            .unwrap_or_default();
+        format!("nbd:{}:{}", address, self.export_name)
+    }
+}
+
+// The connection to the server.
+enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn open(config: &NbdConfig) -> io::Result<Connection> {
+        let connection = match (config.socket_path.as_ref(), config.tcp_address.as_ref()) {
+            (Some(socket_path), None) => Connection::Unix(UnixStream::connect(socket_path)?),
+            (None, Some(tcp_address)) => {
+                // Host names are not resolved, which would take the whole VMM down if the
+                // resolver hangs.
+                let address: SocketAddr = tcp_address
+                    .parse()
+                    .map_err(|_| io::Error::from(Error::InvalidAddress))?;
+                let stream = TcpStream::connect(address)?;
+                // Requests are sent one at a time, and wait for their reply.
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+            _ => return Err(Error::InvalidAddress.into()),
+        };
+        match connection {
+            Connection::Unix(ref stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+            }
+            Connection::Tcp(ref stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+            }
+        }
+        Ok(connection)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The protocol is big endian.
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn send_option(connection: &mut Connection, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(16 + data.len());
+    message.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    message.extend_from_slice(&option.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    connection.write_all(&message)
+}
+
+// Receives a reply to `option`, and returns its type and data.
+fn recv_option_reply(connection: &mut Connection, option: u32) -> io::Result<(u32, Vec<u8>)> {
+    if read_u64(connection)? != NBD_OPT_REPLY_MAGIC || read_u32(connection)? != option {
+        return Err(Error::InvalidReply.into());
+    }
+    let reply_type = read_u32(connection)?;
+    let len = read_u32(connection)?;
+    if len > MAX_OPTION_REPLY_LEN {
+        return Err(Error::InvalidReply.into());
+    }
+    let mut data = vec![0u8; len as usize];
+    connection.read_exact(&mut data)?;
+    Ok((reply_type, data))
+}
+
+/// Negotiates the export named `export_name`, and returns its size and transmission flags.
+fn handshake(connection: &mut Connection, export_name: &str) -> io::Result<(u64, u16)> {
+    if read_u64(connection)? != NBD_MAGIC || read_u64(connection)? != NBD_IHAVEOPT {
+        return Err(Error::UnsupportedServer.into());
+    }
+    let handshake_flags = read_u16(connection)?;
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::UnsupportedServer.into());
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    connection.write_all(&client_flags.to_be_bytes())?;
+
+    // The export name, followed by no information request.
+    let mut data = Vec::with_capacity(6 + export_name.len());
+    data.extend_from_slice(&(export_name.len() as u32).to_be_bytes());
+    data.extend_from_slice(export_name.as_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    send_option(connection, NBD_OPT_GO, &data)?;
+
+    let mut export = None;
+    loop {
+        let (reply_type, data) = recv_option_reply(connection, NBD_OPT_GO)?;
+        match reply_type {
+            NBD_REP_INFO => {
+                let mut info = data.as_slice();
+                if read_u16(&mut info)? == NBD_INFO_EXPORT {
+                    export = Some((read_u64(&mut info)?, read_u16(&mut info)?));
+                }
+            }
+            NBD_REP_ACK => break,
+            // Older servers only know how to pick the export without any negotiation.
+            NBD_REP_ERR_UNSUP => {
+                send_option(connection, NBD_OPT_EXPORT_NAME, export_name.as_bytes())?;
+                let size = read_u64(connection)?;
+                let flags = read_u16(connection)?;
+                if !no_zeroes {
+                    connection.read_exact(&mut [0u8; EXPORT_NAME_PADDING_LEN])?;
+                }
+                return Ok((size, flags));
+            }
+            reply_type if reply_type & NBD_REP_FLAG_ERROR != 0 => {
+                return Err(Error::ExportRejected(reply_type).into())
+            }
+            // Other informational replies are not needed.
+            _ => (),
+        }
+    }
+    export.ok_or_else(|| Error::InvalidReply.into())
+}
+
+fn request_header(command: u16, flags: u16, handle: u64, offset: u64, len: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(REQUEST_HEADER_LEN);
+    header.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+    header.extend_from_slice(&flags.to_be_bytes());
+    header.extend_from_slice(&command.to_be_bytes());
+    header.extend_from_slice(&handle.to_be_bytes());
+    header.extend_from_slice(&offset.to_be_bytes());
+    header.extend_from_slice(&len.to_be_bytes());
+    header
+}
+
+/// An NBD export, accessed as a single file.
+pub struct NbdClient {
+    config: NbdConfig,
+    // Absent once the connection broke, until the next request reconnects.
+    connection: Option<Connection>,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+    position: u64,
+}
+
+impl NbdClient {
+    /// Connects to the server described by `config`, and negotiates the export.
+    pub fn connect(config: NbdConfig) -> io::Result<NbdClient> {
+        let mut connection = Connection::open(&config)?;
+        let (size, flags) = handshake(&mut connection, &config.export_name)?;
+        Ok(NbdClient {
+            config,
+            connection: Some(connection),
+            size,
+            flags: Self::transmission_flags(flags),
+            next_handle: 0,
+            position: 0,
+        })
+    }
+
+    // The flags other than `NBD_FLAG_HAS_FLAGS` are only meaningful when it is set.
+    fn transmission_flags(flags: u16) -> u16 {
+        if flags & NBD_FLAG_HAS_FLAGS != 0 {
+            flags
+        } else {
+            0
+        }
+    }
+
+    pub fn config(&self) -> &NbdConfig {
+        &self.config
+    }
+
+    /// Size of the export, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Specifies if the server only allows reading the export.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & NBD_FLAG_READ_ONLY != 0
+    }
+
+    /// Specifies if ranges of the export can be deallocated.
+    pub fn supports_trim(&self) -> bool {
+        self.flags & NBD_FLAG_SEND_TRIM != 0
+    }
+
+    fn reconnect(&mut self) -> io::Result<Connection> {
+        let result = Connection::open(&self.config).and_then(|mut connection| {
+            let (size, flags) = handshake(&mut connection, &self.config.export_name)?;
+            // The guest would see another disk.
+            if size != self.size {
+                return Err(Error::SizeChanged(size).into());
+            }
+            self.flags = Self::transmission_flags(flags);
+            Ok(connection)
+        });
+        match result {
+            Ok(_) => METRICS.block.nbd_reconnect_count.inc(),
+            Err(_) => METRICS.block.nbd_reconnect_fails.inc(),
+        }
+        result
+    }
+
+    /// Sends a request covering `len` bytes from `offset`, along with `data` for writes, and
+    /// receives the data of reads in `buf`. A request failing because the connection broke
+    /// is retried once on a new connection.
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let mut retry = true;
+        loop {
+            let mut connection = match self.connection.take() {
+                Some(connection) => connection,
+                None => self.reconnect()?,
+            };
+            let handle = self.next_handle;
+            self.next_handle = handle.wrapping_add(1);
+
+            let header = request_header(command, flags, handle, offset, len);
+            let result = connection
+                .write_all(&header)
+                .and_then(|_| connection.write_all(data))
+                .and_then(|_| {
+                    if read_u32(&mut connection)? != NBD_SIMPLE_REPLY_MAGIC {
+                        return Err(Error::InvalidReply.into());
+                    }
+                    let error = read_u32(&mut connection)?;
+                    if read_u64(&mut connection)? != handle {
+                        return Err(Error::InvalidReply.into());
+                    }
+                    if error == 0 {
+                        connection.read_exact(buf)?;
+                    }
+                    Ok(error)
+                });
+
+            match result {
+                Ok(error) => {
+                    self.connection = Some(connection);
+                    // The errors of the protocol are errno values.
+                    return match error {
+                        0 => Ok(()),
+                        error => Err(io::Error::from_raw_os_error(error as i32)),
+                    };
+                }
+                // The connection is dropped, and the request retried on a new one.
+                Err(e) if retry => {
+                    warn!("The connection to the NBD server broke: {}", e);
+                    retry = false;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sends a `command` request for each chunk of the range `[offset, offset + len)`.
+    fn request_range(&mut self, command: u16, flags: u16, offset: u64, len: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, MAX_REQUEST_LEN as u64);
+            self.request(command, flags, offset + done, chunk as u32, &[], &mut [])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Makes the data written to the export durable, if the server supports it.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(NBD_CMD_FLUSH, 0, 0, 0, &[], &mut [])
+    }
+
+    /// Deallocates the byte range `[offset, offset + len)` of the export.
+    pub fn trim(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if !self.supports_trim() {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        self.request_range(NBD_CMD_TRIM, 0, offset, len)
+    }
+
+    /// Zeroes the byte range `[offset, offset + len)` of the export, allowing the server to
+    /// deallocate it if `unmap` is set.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES != 0 {
+            let flags = if unmap { 0 } else { NBD_CMD_FLAG_NO_HOLE };
+            return self.request_range(NBD_CMD_WRITE_ZEROES, flags, offset, len);
+        }
+
+        let zeroes = vec![0u8; cmp::min(len, ZEROES_BUF_LEN as u64) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, zeroes.len() as u64) as usize;
+            self.request(
+                NBD_CMD_WRITE,
+                0,
+                offset + done,
+                chunk as u32,
+                &zeroes[..chunk],
+                &mut [],
+            )?;
+            done += chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Read for NbdClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            cmp::min(buf.len(), MAX_REQUEST_LEN) as u64,
+            self.size.saturating_sub(self.position),
+        ) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.request(
+            NBD_CMD_READ,
+            0,
+            self.position,
+            len as u32,
+            &[],
+            &mut buf[..len],
+        )?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for NbdClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), MAX_REQUEST_LEN);
+        if len == 0 {
+            return Ok(0);
+        }
+        self.request(
+            NBD_CMD_WRITE,
+            0,
+            self.position,
+            len as u32,
+            &buf[..len],
+            &mut [],
+        )?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    // The data is sent to the server right away; `sync()` makes it durable.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for NbdClient {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_add_signed(self.size, offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.position, offset),
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+impl Drop for NbdClient {
+    fn drop(&mut self) {
+        // Lets the server know the connection is closed on purpose.
+        if let Some(mut connection) = self.connection.take() {
+            let _ = connection.write_all(&request_header(NBD_CMD_DISC, 0, self.next_handle, 0, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+
+    use crate::check_metric_after_block;
+
+    pub(crate) const TEST_FLAGS: u16 =
+        NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+
+    /// An in-memory export, served on a Unix domain socket.
+    pub(crate) struct TestServer {
+        pub(crate) export: Arc<Mutex<Vec<u8>>>,
+        pub(crate) flush_count: Arc<Mutex<usize>>,
+        // Keeps the socket until the end of the test.
+        dir: TempDir,
+    }
+
+    impl TestServer {
+        pub(crate) fn config(&self) -> NbdConfig {
+            NbdConfig {
+                socket_path: Some(
+                    self.dir
+                        .as_path()
+                        .join("nbd.sock")
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                ),
+                tcp_address: None,
+                export_name: String::from("test"),
+            }
+        }
+    }
+
+    /// Serves an export of `size` bytes with the transmission `flags`. Servers without `go`
+    /// only support `NBD_OPT_EXPORT_NAME`, and each connection is closed after
+    /// `max_requests` requests if set.
+    pub(crate) fn spawn_server(
+        size: usize,
+        flags: u16,
+        go: bool,
+        max_requests: Option<usize>,
+    ) -> TestServer {
+        let dir = TempDir::new().unwrap();
+        let listener = UnixListener::bind(dir.as_path().join("nbd.sock")).unwrap();
+        let export = Arc::new(Mutex::new(vec![0u8; size]));
+        let flush_count = Arc::new(Mutex::new(0));
+
+        let server_export = export.clone();
+        let server_flush_count = flush_count.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let _ = serve(
+                    &mut stream,
+                    &server_export,
+                    &server_flush_count,
+                    flags,
+                    go,
+                    max_requests,
+                );
+            }
+        });
+
+        TestServer {
+            export,
+            flush_count,
+            dir,
+        }
+    }
+
+    fn option_reply(stream: &mut UnixStream, option: u32, reply_type: u32, data: &[u8]) {
+        let mut reply = NBD_OPT_REPLY_MAGIC.to_be_bytes().to_vec();
+        reply.extend_from_slice(&option.to_be_bytes());
+        reply.extend_from_slice(&reply_type.to_be_bytes());
+        reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        reply.extend_from_slice(data);
+        stream.write_all(&reply).unwrap();
+    }
+
+    fn serve(
+        stream: &mut UnixStream,
+        export: &Mutex<Vec<u8>>,
+        flush_count: &Mutex<usize>,
+        flags: u16,
+        go: bool,
+        max_requests: Option<usize>,
+    ) -> io::Result<()> {
+        let size = export.lock().unwrap().len() as u64;
+        stream.write_all(&NBD_MAGIC.to_be_bytes())?;
+        stream.write_all(&NBD_IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+        assert_eq!(
+            read_u32(stream)?,
+            NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+        );
+
+        loop {
+            assert_eq!(read_u64(stream)?, NBD_IHAVEOPT);
+            let option = read_u32(stream)?;
+            let mut data = vec![0u8; read_u32(stream)? as usize];
+            stream.read_exact(&mut data)?;
+            match option {
+                NBD_OPT_GO if go => {
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    option_reply(stream, option, NBD_REP_INFO, &info);
+                    option_reply(stream, option, NBD_REP_ACK, &[]);
+                    break;
+                }
+                NBD_OPT_EXPORT_NAME => {
+                    stream.write_all(&size.to_be_bytes())?;
+                    stream.write_all(&flags.to_be_bytes())?;
+                    break;
+                }
+                _ => option_reply(stream, option, NBD_REP_ERR_UNSUP, &[]),
+            }
+        }
+
+        let mut num_requests = 0;
+        loop {
+            if Some(num_requests) == max_requests {
+                return Ok(());
+            }
+            num_requests += 1;
+
+            let mut header = [0u8; REQUEST_HEADER_LEN];
+            stream.read_exact(&mut header)?;
+            let mut header = &header[..];
+            assert_eq!(read_u32(&mut header)?, NBD_REQUEST_MAGIC);
+            let command_flags = read_u16(&mut header)?;
+            let command = read_u16(&mut header)?;
+            let handle = read_u64(&mut header)?;
+            let offset = read_u64(&mut header)? as usize;
+            let len = read_u32(&mut header)? as usize;
+
+            let mut export = export.lock().unwrap();
+            let mut error = 0u32;
+            let mut data = Vec::new();
+            if offset + len > export.len() {
+                error = libc::EINVAL as u32;
+            }
+            match command {
+                NBD_CMD_READ if error == 0 => data = export[offset..offset + len].to_vec(),
+                NBD_CMD_WRITE => {
+                    let mut buf = vec![0u8; len];
+                    stream.read_exact(&mut buf)?;
+                    if error == 0 {
+                        export[offset..offset + len].copy_from_slice(&buf);
+                    }
+                }
+                NBD_CMD_DISC => return Ok(()),
+                NBD_CMD_FLUSH => *flush_count.lock().unwrap() += 1,
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES if error == 0 => {
+                    // Holes are only punched when allowed, and are marked with ones.
+                    let fill =
+                        if command == NBD_CMD_TRIM || command_flags & NBD_CMD_FLAG_NO_HOLE == 0 {
+                            0xff
+                        } else {
+                            0
+                        };
+                    for byte in export[offset..offset + len].iter_mut() {
+                        *byte = fill;
+                    }
+                }
+                _ => (),
+            }
+
+            stream.write_all(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&error.to_be_bytes())?;
+            stream.write_all(&handle.to_be_bytes())?;
+            stream.write_all(&data)?;
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = spawn_server(0x1000, TEST_FLAGS | NBD_FLAG_READ_ONLY, true, None);
+        let client = NbdClient::connect(server.config()).unwrap();
+        assert_eq!(client.size(), 0x1000);
+        assert!(client.is_read_only());
+        assert!(client.supports_trim());
+        assert_eq!(client.config(), &server.config());
+
+        // Servers without `NBD_OPT_GO` get the export picked by name.
+        let server = spawn_server(0x2000, 0, false, None);
+        let client = NbdClient::connect(server.config()).unwrap();
+        assert_eq!(client.size(), 0x2000);
+        assert!(!client.is_read_only());
+        assert!(!client.supports_trim());
+
+        // Exactly one address has to be set, and host names are not resolved.
+        let mut config = server.config();
+        config.tcp_address = Some(String::from("127.0.0.1:10809"));
+        assert!(NbdClient::connect(config.clone()).is_err());
+        config.socket_path = None;
+        config.tcp_address = Some(String::from("localhost:10809"));
+        assert!(NbdClient::connect(config.clone()).is_err());
+        config.tcp_address = None;
+        assert!(NbdClient::connect(config).is_err());
+
+        // Errors.
+        let errors = vec![
+            Error::ExportRejected(NBD_REP_ERR_UNSUP),
+            Error::InvalidAddress,
+            Error::InvalidReply,
+            Error::SizeChanged(0),
+            Error::UnsupportedServer,
+        ];
+        for error in errors {
+            let _ = format!("{}{:?}", error, error);
+            let _ = io::Error::from(error);
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let config = NbdConfig {
+            socket_path: None,
+            tcp_address: Some(String::from("10.0.0.1:10809")),
+            export_name: String::from("disk"),
+        };
+        assert_eq!(config.describe(), "nbd:10.0.0.1:10809:disk");
+    }
+
+    #[test]
+    fn test_read_write() {
+        let server = spawn_server(0x3000, TEST_FLAGS, true, None);
+        let mut client = NbdClient::connect(server.config()).unwrap();
+
+        client.seek(SeekFrom::Start(0x800)).unwrap();
+        client.write_all(&[0xaa; 0x1000]).unwrap();
+        assert_eq!(client.seek(SeekFrom::Current(0)).unwrap(), 0x1800);
+        assert!(server.export.lock().unwrap()[0x800..0x1800]
+            .iter()
+            .all(|&byte| byte == 0xaa));
+
+        let mut buf = [0u8; 0x1000];
+        client.seek(SeekFrom::Start(0x400)).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x400].iter().all(|&byte| byte == 0));
+        assert!(buf[0x400..].iter().all(|&byte| byte == 0xaa));
+
+        // Reads stop at the end of the export, writes past it fail.
+        client.seek(SeekFrom::End(-0x10)).unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 0x10);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            client.write(&buf).unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        assert!(client.seek(SeekFrom::Current(-0x4000)).is_err());
+
+        client.sync().unwrap();
+        assert_eq!(*server.flush_count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_trim_write_zeroes() {
+        let server = spawn_server(0x3000, TEST_FLAGS, true, None);
+        let mut client = NbdClient::connect(server.config()).unwrap();
+
+        client.trim(0, 0x1000).unwrap();
+        client.write_zeroes(0x1000, 0x1000, true).unwrap();
+        client.write_zeroes(0x2000, 0x1000, false).unwrap();
+        let export = server.export.lock().unwrap().clone();
+        assert!(export[..0x2000].iter().all(|&byte| byte == 0xff));
+        assert!(export[0x2000..].iter().all(|&byte| byte == 0));
+
+        // The zeroes are written out to servers not supporting the commands, which cannot
+        // deallocate ranges either. Syncing is a no-op for servers which cannot flush.
+        let server = spawn_server(0x3000, NBD_FLAG_HAS_FLAGS, true, None);
+        server.export.lock().unwrap()[0x1000..0x2000].copy_from_slice(&[0xaa; 0x1000]);
+        let mut client = NbdClient::connect(server.config()).unwrap();
+        client.write_zeroes(0x1000, 0x1000, true).unwrap();
+        assert!(server.export.lock().unwrap().iter().all(|&byte| byte == 0));
+        assert_eq!(
+            client.trim(0, 0x1000).unwrap_err().raw_os_error(),
+            Some(libc::EOPNOTSUPP)
+        );
+        client.sync().unwrap();
+        assert_eq!(*server.flush_count.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        // The server closes each connection after a single request.
+        let server = spawn_server(0x1000, TEST_FLAGS, true, Some(1));
+        let mut client = NbdClient::connect(server.config()).unwrap();
+        client.write_all(&[0xaa; 0x100]).unwrap();
+
+        // The next request goes through a new connection.
+        let mut buf = [0u8; 0x100];
+        check_metric_after_block!(&METRICS.block.nbd_reconnect_count, 1, {
+            client.seek(SeekFrom::Start(0)).unwrap();
+            client.read_exact(&mut buf).unwrap();
+        });
+        assert!(buf.iter().all(|&byte| byte == 0xaa));
+
+        // The export cannot change size behind the guest's back.
+        client.size = 0x2000;
+        check_metric_after_block!(
+            &METRICS.block.nbd_reconnect_fails,
+            1,
+            assert!(client.read(&mut buf).is_err())
+        );
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy-on-write overlays of read only base images.
+//!
+//! The overlay file has the size of the base image and is sparse: it only holds the blocks
+//! written by the guest, which are tracked by an allocation bitmap. The other blocks are read
+//! from the base image, which can be shared by many drives.
+
+use std::cmp;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::checked_add_signed;
+
+/// Granularity of the copy-on-write overlays.
+pub const COW_BLOCK_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The allocation bitmap does not match the size of the base image.
+    InvalidBitmapLen(usize),
+    /// The size of the overlay file does not match the size of the base image.
+    InvalidOverlaySize(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidBitmapLen(len) => write!(
+                f,
+                "The overlay allocation bitmap length does not match the base image: {}.",
+                len
+            ),
+            InvalidOverlaySize(size) => write!(
+                f,
+                "The overlay size does not match the base image: {}.",
+                size
+            ),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/// The overlay of a drive, along with the blocks it holds when resuming from a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayConfig {
+    /// Path of the overlay file.
+    pub path: String,
+    /// The allocation bitmap of an existing overlay. A new, empty overlay is created when
+    /// there is none.
+    pub bitmap: Option<Vec<u64>>,
+}
+
+/// A base image and its copy-on-write overlay, accessed as a single file.
+pub struct CowOverlay {
+    base: File,
+    overlay: File,
+    path: String,
+    size: u64,
+    // One bit per block, set when the block is held by the overlay.
+    bitmap: Vec<u64>,
+    position: u64,
+}
+
+impl CowOverlay {
+    /// Pairs the `base` image with the overlay described by `config`.
+    pub fn open(base: File, config: OverlayConfig) -> io::Result<CowOverlay> {
+        let size = base.metadata()?.len();
+        let bitmap_len = bitmap_len(size);
+        let (overlay, bitmap) = match config.bitmap {
+            Some(bitmap) => {
+                if bitmap.len() != bitmap_len {
+                    return Err(Error::InvalidBitmapLen(bitmap.len()).into());
+                }
+                let overlay = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(Path::new(&config.path))?;
+                let overlay_size = overlay.metadata()?.len();
+                if overlay_size != size {
+                    return Err(Error::InvalidOverlaySize(overlay_size).into());
+                }
+                (overlay, bitmap)
+            }
+            None => {
+                // The content of a previous overlay is meaningless without its bitmap.
+                let overlay = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(Path::new(&config.path))?;
+                overlay.set_len(size)?;
+                (overlay, vec![0; bitmap_len])
+            }
+        };
+
+        Ok(CowOverlay {
+            base,
+            overlay,
+            path: config.path,
+            size,
+            bitmap,
+            position: 0,
+        })
+    }
+
+    /// Provides the overlay file, which receives all the writes.
+    pub fn overlay_file(&self) -> &File {
+        &self.overlay
+    }
+
+    /// Provides the path of the overlay file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Provides the allocation bitmap, with one bit per block held by the overlay.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    fn is_allocated(&self, block: u64) -> bool {
+        self.bitmap[(block / 64) as usize] & (1 << (block % 64)) != 0
+    }
+
+    fn set_allocated(&mut self, block: u64) {
+        self.bitmap[(block / 64) as usize] |= 1 << (block % 64);
+    }
+
+    fn read_at(&self, buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = cmp::min(
+                buf.len() - done,
+                (COW_BLOCK_SIZE - offset % COW_BLOCK_SIZE) as usize,
+            );
+            let file = if self.is_allocated(offset / COW_BLOCK_SIZE) {
+                &self.overlay
+            } else {
+                &self.base
+            };
+            file.read_exact_at(&mut buf[done..done + len], offset)?;
+            done += len;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], mut offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let block = offset / COW_BLOCK_SIZE;
+            let block_start = block * COW_BLOCK_SIZE;
+            let block_len = cmp::min(COW_BLOCK_SIZE, self.size - block_start);
+            let len = cmp::min(
+                buf.len() - done,
+                (block_start + block_len - offset) as usize,
+            );
+
+            // The rest of a block only partially written comes from the base image.
+            if !self.is_allocated(block) && len as u64 != block_len {
+                let mut data = vec![0u8; block_len as usize];
+                self.base.read_exact_at(&mut data, block_start)?;
+                self.overlay.write_all_at(&data, block_start)?;
+            }
+            self.overlay.write_all_at(&buf[done..done + len], offset)?;
+            self.set_allocated(block);
+
+            done += len;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Read for CowOverlay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        self.read_at(&mut buf[..len], self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for CowOverlay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        self.write_at(&buf[..len], self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for CowOverlay {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_add_signed(self.size, offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.position, offset),
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+// Number of 64 bit words of the bitmap of a base image of `size` bytes.
+fn bitmap_len(size: u64) -> usize {
+    let blocks = (size + COW_BLOCK_SIZE - 1) / COW_BLOCK_SIZE;
+    ((blocks + 63) / 64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    fn read_vec(cow: &mut CowOverlay, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        cow.seek(SeekFrom::Start(offset)).unwrap();
+        cow.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let base = TempFile::new().unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+        // The last block of the base image is partial.
+        let size = 3 * COW_BLOCK_SIZE + 512;
+        base.as_file()
+            .write_all_at(&vec![0xaa; size as usize], 0)
+            .unwrap();
+        overlay.as_file().write_all_at(&[0xff; 16], 0).unwrap();
+        let base_file = || File::open(base.as_path()).unwrap();
+
+        // A new overlay starts empty, whatever the content of the file.
+        let mut cow = CowOverlay::open(
+            base_file(),
+            OverlayConfig {
+                path: overlay_path.clone(),
+                bitmap: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(cow.overlay_file().metadata().unwrap().len(), size);
+        assert_eq!(cow.seek(SeekFrom::End(0)).unwrap(), size);
+        assert_eq!(
+            read_vec(&mut cow, 0, size as usize),
+            vec![0xaa; size as usize]
+        );
+
+        // A write within a block copies the rest of the block from the base image.
+        cow.seek(SeekFrom::Start(COW_BLOCK_SIZE + 100)).unwrap();
+        cow.write_all(&[0x55; 200]).unwrap();
+        // Writes to whole blocks, and to the partial last block.
+        cow.seek(SeekFrom::Start(2 * COW_BLOCK_SIZE)).unwrap();
+        cow.write_all(&vec![0x66; COW_BLOCK_SIZE as usize + 512])
+            .unwrap();
+        // Nothing is written past the end of the base image.
+        assert_eq!(cow.write(&[0x77; 16]).unwrap(), 0);
+        assert_eq!(cow.bitmap(), &[0b1110]);
+
+        let check = |cow: &mut CowOverlay| {
+            let data = read_vec(cow, 0, size as usize);
+            let block = COW_BLOCK_SIZE as usize;
+            assert!(data[..block + 100].iter().all(|&b| b == 0xaa));
+            assert!(data[block + 100..block + 300].iter().all(|&b| b == 0x55));
+            assert!(data[block + 300..2 * block].iter().all(|&b| b == 0xaa));
+            assert!(data[2 * block..].iter().all(|&b| b == 0x66));
+        };
+        check(&mut cow);
+
+        // The base image is left untouched.
+        let mut base_data = vec![0u8; size as usize];
+        base_file().read_exact_at(&mut base_data, 0).unwrap();
+        assert!(base_data.iter().all(|&b| b == 0xaa));
+
+        // The overlay is resumed from its bitmap.
+        let config = OverlayConfig {
+            path: cow.path().to_string(),
+            bitmap: Some(cow.bitmap().to_vec()),
+        };
+        drop(cow);
+        let mut cow = CowOverlay::open(base_file(), config.clone()).unwrap();
+        check(&mut cow);
+
+        // The bitmap and the overlay have to match the base image.
+        let other_base = TempFile::new().unwrap();
+        other_base
+            .as_file()
+            .set_len(size + 64 * COW_BLOCK_SIZE)
+            .unwrap();
+        assert!(
+            CowOverlay::open(File::open(other_base.as_path()).unwrap(), config.clone()).is_err()
+        );
+        other_base.as_file().set_len(size + 1).unwrap();
+        assert!(CowOverlay::open(File::open(other_base.as_path()).unwrap(), config).is_err());
+    }
+}
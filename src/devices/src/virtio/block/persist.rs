@@ -9,18 +9,196 @@ use std::sync::Arc;
 
 use logger::error;
 use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
 use vm_memory::GuestMemoryMmap;
 
+use super::nbd::NbdConfig;
+use super::overlay::{CowOverlay, OverlayConfig};
+use super::verity::VerityConfig;
 use super::*;
 
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
-#[derive(Clone, Versionize)]
+/// The I/O engine of a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FileEngineTypeState {
+    Sync,
+    Async,
+}
+
+impl From<FileEngineType> for FileEngineTypeState {
+    fn from(file_engine_type: FileEngineType) -> Self {
+        match file_engine_type {
+            FileEngineType::Sync => FileEngineTypeState::Sync,
+            FileEngineType::Async => FileEngineTypeState::Async,
+        }
+    }
+}
+
+impl From<FileEngineTypeState> for FileEngineType {
+    fn from(file_engine_type: FileEngineTypeState) -> Self {
+        match file_engine_type {
+            FileEngineTypeState::Sync => FileEngineType::Sync,
+            FileEngineTypeState::Async => FileEngineType::Async,
+        }
+    }
+}
+
+/// The cache type of a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum CacheTypeState {
+    Unsafe,
+    Writeback,
+    Direct,
+}
+
+impl From<CacheType> for CacheTypeState {
+    fn from(cache_type: CacheType) -> Self {
+        match cache_type {
+            CacheType::Unsafe => CacheTypeState::Unsafe,
+            CacheType::Writeback => CacheTypeState::Writeback,
+            CacheType::Direct => CacheTypeState::Direct,
+        }
+    }
+}
+
+impl From<CacheTypeState> for CacheType {
+    fn from(cache_type: CacheTypeState) -> Self {
+        match cache_type {
+            CacheTypeState::Unsafe => CacheType::Unsafe,
+            CacheTypeState::Writeback => CacheType::Writeback,
+            CacheTypeState::Direct => CacheType::Direct,
+        }
+    }
+}
+
+/// The image format of a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ImageFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<ImageFormat> for ImageFormatState {
+    fn from(image_format: ImageFormat) -> Self {
+        match image_format {
+            ImageFormat::Raw => ImageFormatState::Raw,
+            ImageFormat::Qcow2 => ImageFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<ImageFormatState> for ImageFormat {
+    fn from(image_format: ImageFormatState) -> Self {
+        match image_format {
+            ImageFormatState::Raw => ImageFormat::Raw,
+            ImageFormatState::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+/// The copy-on-write overlay of a block device.
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct OverlayState {
+    path: String,
+    bitmap: Vec<u64>,
+}
+
+impl From<&CowOverlay> for OverlayState {
+    fn from(overlay: &CowOverlay) -> Self {
+        OverlayState {
+            path: overlay.path().to_string(),
+            bitmap: overlay.bitmap().to_vec(),
+        }
+    }
+}
+
+impl From<&OverlayState> for OverlayConfig {
+    fn from(overlay: &OverlayState) -> Self {
+        OverlayConfig {
+            path: overlay.path.clone(),
+            bitmap: Some(overlay.bitmap.clone()),
+        }
+    }
+}
+
+/// The NBD server and export a block device is served from.
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct NbdConfigState {
+    socket_path: Option<String>,
+    tcp_address: Option<String>,
+    export_name: String,
+}
+
+impl From<&NbdConfig> for NbdConfigState {
+    fn from(config: &NbdConfig) -> Self {
+        NbdConfigState {
+            socket_path: config.socket_path.clone(),
+            tcp_address: config.tcp_address.clone(),
+            export_name: config.export_name.clone(),
+        }
+    }
+}
+
+impl From<&NbdConfigState> for NbdConfig {
+    fn from(config: &NbdConfigState) -> Self {
+        NbdConfig {
+            socket_path: config.socket_path.clone(),
+            tcp_address: config.tcp_address.clone(),
+            export_name: config.export_name.clone(),
+        }
+    }
+}
+
+/// The hash tree a block device is verified against.
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VerityConfigState {
+    hash_tree_path: String,
+    hash_offset: u64,
+    root_hash: String,
+    salt: String,
+    data_block_size: u32,
+    hash_block_size: u32,
+}
+
+impl From<&VerityConfig> for VerityConfigState {
+    fn from(config: &VerityConfig) -> Self {
+        VerityConfigState {
+            hash_tree_path: config.hash_tree_path.clone(),
+            hash_offset: config.hash_offset,
+            root_hash: config.root_hash.clone(),
+            salt: config.salt.clone(),
+            data_block_size: config.data_block_size,
+            hash_block_size: config.hash_block_size,
+        }
+    }
+}
+
+impl From<&VerityConfigState> for VerityConfig {
+    fn from(config: &VerityConfigState) -> Self {
+        VerityConfig {
+            hash_tree_path: config.hash_tree_path.clone(),
+            hash_offset: config.hash_offset,
+            root_hash: config.root_hash.clone(),
+            salt: config.salt.clone(),
+            data_block_size: config.data_block_size,
+            hash_block_size: config.hash_block_size,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
     id: String,
@@ -29,10 +207,50 @@ pub struct BlockState {
     disk_path: String,
     virtio_state: VirtioDeviceState,
     rate_limiter_state: RateLimiterState,
+    #[version(start = 2, default_fn = "default_file_engine_type")]
+    file_engine_type: FileEngineTypeState,
+    #[version(start = 2, default_fn = "default_cache_type")]
+    cache_type: CacheTypeState,
+    #[version(start = 2, default_fn = "default_image_format")]
+    image_format: ImageFormatState,
+    #[version(start = 2, default_fn = "default_overlay")]
+    overlay: Option<OverlayState>,
+    #[version(start = 2, default_fn = "default_nbd")]
+    nbd: Option<NbdConfigState>,
+    #[version(start = 2, default_fn = "default_verity")]
+    verity: Option<VerityConfigState>,
+}
+
+impl BlockState {
+    fn default_file_engine_type(_: u16) -> FileEngineTypeState {
+        FileEngineTypeState::Sync
+    }
+
+    fn default_cache_type(_: u16) -> CacheTypeState {
+        CacheTypeState::Unsafe
+    }
+
+    fn default_image_format(_: u16) -> ImageFormatState {
+        ImageFormatState::Raw
+    }
+
+    fn default_overlay(_: u16) -> Option<OverlayState> {
+        None
+    }
+
+    fn default_nbd(_: u16) -> Option<NbdConfigState> {
+        None
+    }
+
+    fn default_verity(_: u16) -> Option<VerityConfigState> {
+        None
+    }
 }
 
 pub struct BlockConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// Backing file to restore the device with, instead of the saved one or NBD export.
+    pub disk_path: Option<String>,
 }
 
 impl Persist<'_> for Block {
@@ -41,12 +259,15 @@ impl Persist<'_> for Block {
     type Error = io::Error;
 
     fn save(&self) -> Self::State {
-        if let Err(e) = self.disk.file().flush() {
-            error!("Failed to flush block data on serialization. Error: {}", e);
-        }
-        // Sync data out to backing file on host.
-        if let Err(e) = self.disk.file().sync_all() {
-            error!("Failed to sync block data on serialization. Error: {}", e);
+        // The NBD exports are flushed when preparing the save.
+        if let Some(mut data_file) = self.disk.data_file() {
+            if let Err(e) = data_file.flush() {
+                error!("Failed to flush block data on serialization. Error: {}", e);
+            }
+            // Sync data out to backing file on host.
+            if let Err(e) = data_file.sync_all() {
+                error!("Failed to sync block data on serialization. Error: {}", e);
+            }
         }
         // Save device state.
         BlockState {
@@ -56,6 +277,18 @@ impl Persist<'_> for Block {
             disk_path: self.disk.file_path().clone(),
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
+            file_engine_type: self.file_engine_type().into(),
+            cache_type: self.cache_type().into(),
+            image_format: self.image_format().into(),
+            overlay: self.disk.overlay().map(OverlayState::from),
+            nbd: self
+                .disk
+                .nbd()
+                .map(|nbd| NbdConfigState::from(nbd.config())),
+            verity: self
+                .disk
+                .verity()
+                .map(|tree| VerityConfigState::from(tree.config())),
         }
     }
 
@@ -65,19 +298,41 @@ impl Persist<'_> for Block {
     ) -> Result<Self, Self::Error> {
         let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
         let rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)?;
+        // A local backing file given on restore replaces the NBD export.
+        let nbd = match constructor_args.disk_path {
+            Some(_) => None,
+            None => state.nbd.as_ref().map(NbdConfig::from),
+        };
+        let disk_path = constructor_args
+            .disk_path
+            .unwrap_or_else(|| state.disk_path.clone());
 
         let mut block = Block::new(
             state.id.clone(),
             state.partuuid.clone(),
-            state.disk_path.clone(),
+            disk_path,
             is_disk_read_only,
             state.root_device,
             rate_limiter,
+            state.cache_type.into(),
+            state.file_engine_type.into(),
+            state.image_format.into(),
+            state.overlay.as_ref().map(OverlayConfig::from),
+            // The request queues are all saved in the virtio state.
+            state.virtio_state.queues.len(),
+            nbd,
+            state.verity.as_ref().map(VerityConfig::from),
+            None,
         )?;
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                block.queues.len(),
+                QUEUE_SIZE,
+            )
             .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
         block.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         block.avail_features = state.virtio_state.avail_features;
@@ -97,7 +352,11 @@ mod tests {
     use crate::virtio::device::VirtioDevice;
     use utils::tempfile::TempFile;
 
+    use crate::virtio::block::nbd::tests::{spawn_server, TEST_FLAGS};
+    use crate::virtio::block::test_utils::default_async_block;
+    use crate::virtio::block::verity::tests::hash_tree_config;
     use crate::virtio::test_utils::default_mem;
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::sync::atomic::Ordering;
 
     #[test]
@@ -114,6 +373,14 @@ mod tests {
             false,
             false,
             RateLimiter::default(),
+            CacheType::Unsafe,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+            1,
+            None,
+            None,
+            None,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -128,7 +395,10 @@ mod tests {
 
         // Restore the block device.
         let restored_block = Block::restore(
-            BlockConstructorArgs { mem: guest_mem },
+            BlockConstructorArgs {
+                mem: guest_mem,
+                disk_path: None,
+            },
             &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
         )
         .unwrap();
@@ -146,5 +416,278 @@ mod tests {
 
         // Test that block specific fields are the same.
         assert_eq!(restored_block.disk.file_path(), block.disk.file_path());
+        assert_eq!(restored_block.file_engine_type(), FileEngineType::Sync);
+        assert_eq!(restored_block.cache_type(), CacheType::Unsafe);
+        assert_eq!(restored_block.image_format(), ImageFormat::Raw);
+
+        // Restore the block device with another backing file.
+        let other_file = TempFile::new().unwrap();
+        other_file.as_file().set_len(0x2000).unwrap();
+        let other_path = other_file.as_path().to_str().unwrap().to_string();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: Some(other_path.clone()),
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.disk.file_path(), &other_path);
+        assert_eq!(restored_block.disk.nsectors(), 0x2000 >> SECTOR_SHIFT);
+    }
+
+    #[test]
+    fn test_block_options_persistence() {
+        let block = match default_async_block() {
+            Some(block) => block,
+            // io_uring may be unavailable on the host.
+            None => return,
+        };
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+
+        // The I/O engine and the cache type are saved starting with version 2, older
+        // versions restore the default ones.
+        for (version, file_engine_type, cache_type) in &[
+            (2, FileEngineType::Async, CacheType::Writeback),
+            (1, FileEngineType::Sync, CacheType::Unsafe),
+        ] {
+            let mut mem = vec![0; 4096];
+            <Block as Persist>::save(&block)
+                .serialize(&mut mem.as_mut_slice(), &version_map, *version)
+                .unwrap();
+            let restored_block = Block::restore(
+                BlockConstructorArgs {
+                    mem: default_mem(),
+                    disk_path: Some(f.as_path().to_str().unwrap().to_string()),
+                },
+                &BlockState::deserialize(&mut mem.as_slice(), &version_map, *version).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(restored_block.file_engine_type(), *file_engine_type);
+            assert_eq!(restored_block.cache_type(), *cache_type);
+        }
+    }
+
+    #[test]
+    fn test_overlay_persistence() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x2000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            Some(OverlayConfig {
+                path: overlay_path.clone(),
+                bitmap: None,
+            }),
+            1,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let image = block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.write_all(&[0x55; 16]).unwrap();
+
+        // The overlay is saved starting with version 2.
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        // The overlay is resumed with its allocation bitmap, instead of being recreated.
+        let mut restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        let restored_overlay = restored_block.disk.overlay().unwrap();
+        assert_eq!(restored_overlay.path(), overlay_path);
+        assert_eq!(restored_overlay.bitmap(), &[0b10]);
+        let mut data = [0u8; 16];
+        let image = restored_block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert_eq!(data, [0x55; 16]);
+    }
+
+    #[test]
+    fn test_multiple_queues_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Unsafe,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+            4,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        // All the request queues are restored, each with its own event.
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_nbd_persistence() {
+        let server = spawn_server(0x2000, TEST_FLAGS, true, None);
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            String::new(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+            1,
+            Some(server.config()),
+            None,
+            None,
+        )
+        .unwrap();
+        let image = block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.write_all(&[0x55; 16]).unwrap();
+        // The export is flushed before saving.
+        block.prepare_save();
+        assert_eq!(*server.flush_count.lock().unwrap(), 1);
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        // The server handles a single connection at a time.
+        drop(block);
+
+        // The device reconnects to the export.
+        let mut restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            restored_block.disk.nbd().unwrap().config(),
+            &server.config()
+        );
+        let mut data = [0u8; 16];
+        let image = restored_block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert_eq!(data, [0x55; 16]);
+        drop(restored_block);
+
+        // A local backing file replaces the export.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: Some(f.as_path().to_str().unwrap().to_string()),
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert!(restored_block.disk.nbd().is_none());
+        assert_eq!(restored_block.disk.nsectors(), 0x1000 >> SECTOR_SHIFT);
+    }
+
+    #[test]
+    fn test_verity_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&[0xaa; 0x2000]).unwrap();
+        let (_tree_file, config) = hash_tree_config(&[0xaa; 0x2000], 0x1000, b"salt");
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            f.as_path().to_str().unwrap().to_string(),
+            true,
+            false,
+            RateLimiter::default(),
+            CacheType::Unsafe,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+            1,
+            None,
+            Some(config.clone()),
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        // The device is verified against the same hash tree.
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.disk.verity().unwrap().config(), &config);
     }
 }
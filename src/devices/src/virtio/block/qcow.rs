@@ -0,0 +1,1037 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read and write access to qcow2 disk images, including chains of backing files.
+//!
+//! The features used by the images produced by `qemu-img` are supported, except for
+//! encryption, compressed clusters, external data files and extended L2 entries. Images with
+//! internal snapshots can only be opened read only. New clusters are allocated at the end of
+//! the image file, and the metadata is written through to the image file.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::checked_add_signed;
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Only 16 bit refcounts, the default of `qemu-img`, are supported.
+const REFCOUNT_ORDER: u32 = 4;
+const MAX_BACKING_FILE_NAME_LEN: u32 = 1023;
+// Bounds the length of a backing chain, which also stops reference loops.
+const MAX_BACKING_CHAIN_LEN: u32 = 16;
+// Bounds the size of the metadata tables loaded in memory, against corrupted headers.
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+// Number of L2 tables cached in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+// Offsets of the header fields.
+const VERSION_OFFSET: usize = 4;
+const BACKING_FILE_OFFSET_OFFSET: usize = 8;
+const BACKING_FILE_SIZE_OFFSET: usize = 16;
+const CLUSTER_BITS_OFFSET: usize = 20;
+const SIZE_OFFSET: usize = 24;
+const CRYPT_METHOD_OFFSET: usize = 32;
+const L1_SIZE_OFFSET: usize = 36;
+const L1_TABLE_OFFSET_OFFSET: usize = 40;
+const REFCOUNT_TABLE_OFFSET_OFFSET: usize = 48;
+const REFCOUNT_TABLE_CLUSTERS_OFFSET: usize = 56;
+const NB_SNAPSHOTS_OFFSET: usize = 60;
+const INCOMPATIBLE_FEATURES_OFFSET: usize = 72;
+const AUTOCLEAR_FEATURES_OFFSET: usize = 88;
+const REFCOUNT_ORDER_OFFSET: usize = 96;
+const HEADER_LENGTH_OFFSET: usize = 100;
+
+const INCOMPATIBLE_DIRTY: u64 = 1;
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Fields of the L1 and L2 table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+const ZERO_FLAG: u64 = 1;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = !0x1ff;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The backing chain is longer than `MAX_BACKING_CHAIN_LEN` images.
+    BackingChainTooLong,
+    /// A compressed cluster was accessed.
+    CompressedCluster,
+    /// The backing file name is too long or is not valid UTF-8.
+    InvalidBackingFileName,
+    /// The cluster size is not supported.
+    InvalidClusterBits(u32),
+    /// The length of the header is invalid.
+    InvalidHeaderLength(u32),
+    /// The L1 table does not fit the virtual size, or is misplaced.
+    InvalidL1Table,
+    /// The file does not start with the qcow magic.
+    InvalidMagic,
+    /// The refcount table is too large or is misplaced.
+    InvalidRefcountTable,
+    /// The refcount table has no room for the refcounts of a new cluster.
+    RefcountTableFull,
+    /// The format of the backing file is neither qcow2 nor raw.
+    UnsupportedBackingFormat(String),
+    /// The image is encrypted.
+    UnsupportedEncryption,
+    /// The image uses incompatible features.
+    UnsupportedFeatures(u64),
+    /// The refcounts are not 16 bits wide.
+    UnsupportedRefcountOrder(u32),
+    /// The image has internal snapshots and is not opened read only.
+    UnsupportedSnapshots,
+    /// The image is neither a qcow version 2 nor a qcow version 3 image.
+    UnsupportedVersion(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BackingChainTooLong => write!(
+                f,
+                "The qcow2 backing chain is longer than {} images.",
+                MAX_BACKING_CHAIN_LEN
+            ),
+            CompressedCluster => write!(f, "Compressed qcow2 clusters are not supported."),
+            InvalidBackingFileName => write!(f, "Invalid qcow2 backing file name."),
+            InvalidClusterBits(bits) => write!(f, "Invalid qcow2 cluster bits: {}.", bits),
+            InvalidHeaderLength(len) => write!(f, "Invalid qcow2 header length: {}.", len),
+            InvalidL1Table => write!(f, "Invalid qcow2 L1 table."),
+            InvalidMagic => write!(f, "The file is not a qcow2 image."),
+            InvalidRefcountTable => write!(f, "Invalid qcow2 refcount table."),
+            RefcountTableFull => write!(f, "The qcow2 refcount table is full."),
+            UnsupportedBackingFormat(format) => {
+                write!(f, "Unsupported qcow2 backing file format: {}.", format)
+            }
+            UnsupportedEncryption => write!(f, "Encrypted qcow2 images are not supported."),
+            UnsupportedFeatures(features) => write!(
+                f,
+                "Unsupported qcow2 incompatible features: {:#x}.",
+                features
+            ),
+            UnsupportedRefcountOrder(order) => {
+                write!(f, "Unsupported qcow2 refcount order: {}.", order)
+            }
+            UnsupportedSnapshots => write!(
+                f,
+                "Writable qcow2 images with internal snapshots are not supported."
+            ),
+            UnsupportedVersion(version) => write!(f, "Unsupported qcow version: {}.", version),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+// Where the data of a guest cluster is.
+enum ClusterMapping {
+    // At this offset of the image file.
+    Allocated(u64),
+    // Nowhere, the cluster reads as zeroes.
+    Zero,
+    // In the backing file, or nowhere if there is none.
+    Unallocated,
+}
+
+// The image below a qcow2 image in a backing chain.
+enum BackingFile {
+    Raw { file: File, size: u64 },
+    Qcow(QcowFile),
+}
+
+impl BackingFile {
+    fn open(path: &Path, format: Option<&str>, depth: u32) -> io::Result<BackingFile> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let is_qcow = match format {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => return Err(Error::UnsupportedBackingFormat(format.to_string()).into()),
+            // Images created without a backing format are probed.
+            None => {
+                let mut magic = [0u8; 4];
+                file.read_exact_at(&mut magic, 0).is_ok() && u32::from_be_bytes(magic) == QCOW_MAGIC
+            }
+        };
+
+        if is_qcow {
+            Ok(BackingFile::Qcow(QcowFile::open_chain(
+                file, path, true, depth,
+            )?))
+        } else {
+            let size = file.metadata()?.len();
+            Ok(BackingFile::Raw { file, size })
+        }
+    }
+
+    // Reads the data at `offset`. The data past the end of the backing file reads as zeroes.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            BackingFile::Raw { file, size } => {
+                let available = cmp::min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+                file.read_exact_at(&mut buf[..available], offset)?;
+                zero(&mut buf[available..]);
+                Ok(())
+            }
+            BackingFile::Qcow(qcow) => qcow.read_at(buf, offset),
+        }
+    }
+}
+
+/// A qcow2 image, accessed as a file of the size of its virtual disk.
+pub struct QcowFile {
+    file: File,
+    read_only: bool,
+    cluster_bits: u32,
+    virtual_size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    // L2 tables, by offset in the image file.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    // Offset of the next cluster to allocate, at the end of the image file.
+    next_cluster: u64,
+    backing_file: Option<Box<BackingFile>>,
+    position: u64,
+}
+
+impl QcowFile {
+    /// Opens the qcow2 image `file`, found at `path`, along with its backing chain.
+    ///
+    /// The backing files are opened read only. Their relative paths are relative to the
+    /// directory of the image referencing them.
+    pub fn open(file: File, path: &Path, read_only: bool) -> io::Result<QcowFile> {
+        Self::open_chain(file, path, read_only, 0)
+    }
+
+    fn open_chain(file: File, path: &Path, read_only: bool, depth: u32) -> io::Result<QcowFile> {
+        let mut header = [0u8; V3_HEADER_LEN];
+        file.read_exact_at(&mut header[..V2_HEADER_LEN], 0)?;
+        if be_u32(&header, 0) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic.into());
+        }
+
+        let version = be_u32(&header, VERSION_OFFSET);
+        if version != 2 && version != 3 {
+            return Err(Error::UnsupportedVersion(version).into());
+        }
+        let cluster_bits = be_u32(&header, CLUSTER_BITS_OFFSET);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Error::InvalidClusterBits(cluster_bits).into());
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        if be_u32(&header, CRYPT_METHOD_OFFSET) != 0 {
+            return Err(Error::UnsupportedEncryption.into());
+        }
+
+        // Version 2 images have no feature bits and 16 bit refcounts.
+        let mut header_len = V2_HEADER_LEN as u32;
+        let mut refcount_order = REFCOUNT_ORDER;
+        let mut incompatible_features = 0;
+        let mut autoclear_features = 0;
+        if version == 3 {
+            file.read_exact_at(&mut header[V2_HEADER_LEN..], V2_HEADER_LEN as u64)?;
+            header_len = be_u32(&header, HEADER_LENGTH_OFFSET);
+            if header_len < V3_HEADER_LEN as u32 || u64::from(header_len) > cluster_size {
+                return Err(Error::InvalidHeaderLength(header_len).into());
+            }
+            refcount_order = be_u32(&header, REFCOUNT_ORDER_OFFSET);
+            incompatible_features = be_u64(&header, INCOMPATIBLE_FEATURES_OFFSET);
+            autoclear_features = be_u64(&header, AUTOCLEAR_FEATURES_OFFSET);
+        }
+        if refcount_order != REFCOUNT_ORDER {
+            return Err(Error::UnsupportedRefcountOrder(refcount_order).into());
+        }
+        // The refcounts of a dirty image may be stale, which does not matter for reading.
+        let allowed_features = if read_only { INCOMPATIBLE_DIRTY } else { 0 };
+        if incompatible_features & !allowed_features != 0 {
+            return Err(Error::UnsupportedFeatures(incompatible_features).into());
+        }
+        // Clusters shared with snapshots would have to be copied on write.
+        if !read_only && be_u32(&header, NB_SNAPSHOTS_OFFSET) != 0 {
+            return Err(Error::UnsupportedSnapshots.into());
+        }
+
+        let virtual_size = be_u64(&header, SIZE_OFFSET);
+        let l1_size = u64::from(be_u32(&header, L1_SIZE_OFFSET));
+        let l1_table_offset = be_u64(&header, L1_TABLE_OFFSET_OFFSET);
+        // Each L1 entry maps an L2 table, which maps a cluster worth of 8 byte entries.
+        let l1_entry_span = cluster_size << (cluster_bits - 3);
+        let min_l1_size = virtual_size / l1_entry_span
+            + if virtual_size % l1_entry_span != 0 {
+                1
+            } else {
+                0
+            };
+        if l1_size < min_l1_size
+            || l1_size * 8 > MAX_TABLE_SIZE
+            || l1_table_offset % cluster_size != 0
+        {
+            return Err(Error::InvalidL1Table.into());
+        }
+        let l1_table = read_table(&file, l1_table_offset, l1_size as usize)?;
+
+        let refcount_table_offset = be_u64(&header, REFCOUNT_TABLE_OFFSET_OFFSET);
+        let refcount_table_size =
+            u64::from(be_u32(&header, REFCOUNT_TABLE_CLUSTERS_OFFSET)) << cluster_bits;
+        if refcount_table_size > MAX_TABLE_SIZE || refcount_table_offset % cluster_size != 0 {
+            return Err(Error::InvalidRefcountTable.into());
+        }
+        let refcount_table = read_table(
+            &file,
+            refcount_table_offset,
+            (refcount_table_size / 8) as usize,
+        )?;
+
+        let backing_file_offset = be_u64(&header, BACKING_FILE_OFFSET_OFFSET);
+        let backing_file = if backing_file_offset != 0 {
+            if depth + 1 >= MAX_BACKING_CHAIN_LEN {
+                return Err(Error::BackingChainTooLong.into());
+            }
+            let name_len = be_u32(&header, BACKING_FILE_SIZE_OFFSET);
+            if name_len > MAX_BACKING_FILE_NAME_LEN {
+                return Err(Error::InvalidBackingFileName.into());
+            }
+            let mut name = vec![0u8; name_len as usize];
+            file.read_exact_at(&mut name, backing_file_offset)?;
+            let name = String::from_utf8(name).map_err(|_| Error::InvalidBackingFileName)?;
+            let format = read_backing_format(&file, u64::from(header_len), cluster_size)?;
+
+            let mut backing_path = path.parent().map(PathBuf::from).unwrap_or_default();
+            backing_path.push(name);
+            Some(Box::new(BackingFile::open(
+                &backing_path,
+                format.as_deref(),
+                depth + 1,
+            )?))
+        } else {
+            None
+        };
+
+        // The autoclear features describe metadata which is not updated on writes, such as
+        // dirty bitmaps, and which has to be invalidated.
+        if !read_only && autoclear_features != 0 {
+            file.write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET as u64)?;
+        }
+
+        let file_len = file.metadata()?.len();
+        Ok(QcowFile {
+            file,
+            read_only,
+            cluster_bits,
+            virtual_size,
+            l1_table_offset,
+            l1_table,
+            refcount_table_offset,
+            refcount_table,
+            l2_cache: HashMap::new(),
+            next_cluster: (file_len + cluster_size - 1) & !(cluster_size - 1),
+            backing_file,
+            position: 0,
+        })
+    }
+
+    /// Provides the size of the virtual disk.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    /// Writes back the metadata of the image, then makes both the guest data and the
+    /// metadata durable in the image file.
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync_all()
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    // Returns the indexes of the L1 and L2 entries mapping the guest `offset`.
+    fn table_indexes(&self, offset: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        let cluster = offset >> self.cluster_bits;
+        (
+            (cluster >> l2_bits) as usize,
+            (cluster & ((1 << l2_bits) - 1)) as usize,
+        )
+    }
+
+    fn l2_table(&mut self, l2_offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            let table = read_table(&self.file, l2_offset, (self.cluster_size() / 8) as usize)?;
+            self.cache_l2_table(l2_offset, table);
+        }
+        // Safe to unwrap because the table was just cached.
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    fn cache_l2_table(&mut self, l2_offset: u64, table: Vec<u64>) {
+        if self.l2_cache.len() >= L2_CACHE_SIZE {
+            // The tables are written through, so any of them can be evicted.
+            if let Some(&evicted) = self.l2_cache.keys().next() {
+                self.l2_cache.remove(&evicted);
+            }
+        }
+        self.l2_cache.insert(l2_offset, table);
+    }
+
+    fn cluster_mapping(&mut self, offset: u64) -> io::Result<ClusterMapping> {
+        let (l1_index, l2_index) = self.table_indexes(offset);
+        let l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(ClusterMapping::Unallocated);
+        }
+        l2_entry_mapping(self.l2_table(l2_offset)?[l2_index])
+    }
+
+    // Reads the guest data at `offset`. The data past the virtual size reads as zeroes, which
+    // is how backing files smaller than their overlays behave.
+    fn read_at(&mut self, buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster_offset = offset & (self.cluster_size() - 1);
+            let len = cmp::min(
+                buf.len() - done,
+                (self.cluster_size() - cluster_offset) as usize,
+            );
+            let chunk = &mut buf[done..done + len];
+
+            if offset >= self.virtual_size {
+                zero(chunk);
+            } else {
+                match self.cluster_mapping(offset)? {
+                    ClusterMapping::Allocated(host_offset) => self
+                        .file
+                        .read_exact_at(chunk, host_offset + cluster_offset)?,
+                    ClusterMapping::Zero => zero(chunk),
+                    ClusterMapping::Unallocated => match self.backing_file {
+                        Some(ref mut backing_file) => backing_file.read_at(chunk, offset)?,
+                        None => zero(chunk),
+                    },
+                }
+            }
+
+            done += len;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    // Writes the guest data at `offset`, allocating the clusters it lands in.
+    fn write_at(&mut self, buf: &[u8], mut offset: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster_offset = offset & (self.cluster_size() - 1);
+            let len = cmp::min(
+                buf.len() - done,
+                (self.cluster_size() - cluster_offset) as usize,
+            );
+
+            let host_offset =
+                self.cluster_for_write(offset - cluster_offset, len as u64 == self.cluster_size())?;
+            self.file
+                .write_all_at(&buf[done..done + len], host_offset + cluster_offset)?;
+
+            done += len;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    // Returns the offset in the image file of the guest cluster at `offset`, allocating it if
+    // needed. The content of a new cluster is initialized, unless it is about to be
+    // `overwritten` entirely.
+    fn cluster_for_write(&mut self, offset: u64, overwritten: bool) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.table_indexes(offset);
+        let mut l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            let table = vec![0u64; (self.cluster_size() / 8) as usize];
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], l2_offset)?;
+            self.cache_l2_table(l2_offset, table);
+            self.set_table_entry(self.l1_table_offset, l1_index, l2_offset | COPIED_FLAG)?;
+            self.l1_table[l1_index] = l2_offset | COPIED_FLAG;
+        }
+
+        let l2_entry = self.l2_table(l2_offset)?[l2_index];
+        let mapping = l2_entry_mapping(l2_entry)?;
+        if let ClusterMapping::Allocated(host_offset) = mapping {
+            return Ok(host_offset);
+        }
+
+        // Zero clusters may keep their preallocated host cluster.
+        let host_offset = match l2_entry & OFFSET_MASK {
+            0 => self.allocate_cluster()?,
+            host_offset => host_offset,
+        };
+        if !overwritten {
+            let mut data = vec![0u8; self.cluster_size() as usize];
+            if let ClusterMapping::Unallocated = mapping {
+                if let Some(ref mut backing_file) = self.backing_file {
+                    backing_file.read_at(&mut data, offset)?;
+                }
+            }
+            self.file.write_all_at(&data, host_offset)?;
+        }
+
+        self.set_table_entry(l2_offset, l2_index, host_offset | COPIED_FLAG)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[l2_index] = host_offset | COPIED_FLAG;
+        }
+        Ok(host_offset)
+    }
+
+    // Allocates a cluster at the end of the image file, and returns its offset.
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn set_refcount(&mut self, offset: u64, refcount: u16) -> io::Result<()> {
+        // Each refcount block holds a cluster worth of 2 byte refcounts.
+        let block_entries = self.cluster_size() / 2;
+        let cluster = offset >> self.cluster_bits;
+        let table_index = (cluster / block_entries) as usize;
+        let block_index = cluster % block_entries;
+        if table_index >= self.refcount_table.len() {
+            return Err(Error::RefcountTableFull.into());
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.next_cluster;
+            self.next_cluster += self.cluster_size();
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], block_offset)?;
+            self.set_table_entry(self.refcount_table_offset, table_index, block_offset)?;
+            self.refcount_table[table_index] = block_offset;
+            // The new refcount block is counted either by itself, or by a block following it.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        self.file
+            .write_all_at(&refcount.to_be_bytes(), block_offset + block_index * 2)
+    }
+
+    fn set_table_entry(&self, table_offset: u64, index: usize, entry: u64) -> io::Result<()> {
+        self.file
+            .write_all_at(&entry.to_be_bytes(), table_offset + index as u64 * 8)
+    }
+}
+
+impl Read for QcowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.virtual_size.saturating_sub(self.position),
+        ) as usize;
+        self.read_at(&mut buf[..len], self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for QcowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.virtual_size.saturating_sub(self.position),
+        ) as usize;
+        self.write_at(&buf[..len], self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The data and the metadata are written through to the image file.
+        Ok(())
+    }
+}
+
+impl Seek for QcowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_add_signed(self.virtual_size, offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.position, offset),
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+fn l2_entry_mapping(entry: u64) -> io::Result<ClusterMapping> {
+    if entry & COMPRESSED_FLAG != 0 {
+        return Err(Error::CompressedCluster.into());
+    }
+    if entry & ZERO_FLAG != 0 {
+        return Ok(ClusterMapping::Zero);
+    }
+    match entry & OFFSET_MASK {
+        0 => Ok(ClusterMapping::Unallocated),
+        host_offset => Ok(ClusterMapping::Allocated(host_offset)),
+    }
+}
+
+// Looks for the format of the backing file in the header extensions, which follow the header
+// in the first cluster.
+fn read_backing_format(
+    file: &File,
+    mut offset: u64,
+    cluster_size: u64,
+) -> io::Result<Option<String>> {
+    let mut ext_header = [0u8; 8];
+    while offset + 8 <= cluster_size {
+        file.read_exact_at(&mut ext_header, offset)?;
+        let ext_type = be_u32(&ext_header, 0);
+        let ext_len = u64::from(be_u32(&ext_header, 4));
+        offset += 8;
+        if ext_type == HEADER_EXT_END || offset + ext_len > cluster_size {
+            break;
+        }
+        if ext_type == HEADER_EXT_BACKING_FORMAT {
+            let mut format = vec![0u8; ext_len as usize];
+            file.read_exact_at(&mut format, offset)?;
+            return String::from_utf8(format)
+                .map(Some)
+                .map_err(|_| Error::UnsupportedBackingFormat(String::new()).into());
+        }
+        // The extension data is padded to a multiple of 8 bytes.
+        offset += (ext_len + 7) & !7;
+    }
+    Ok(None)
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut bytes = vec![0u8; entries * 8];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|entry| be_u64(entry, 0))
+        .collect())
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn zero(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = 0;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    // Creates an empty version 3 image, with the refcount table in cluster 1, its only
+    // refcount block in cluster 2, and the L1 table from cluster 3.
+    pub(crate) fn create_image(
+        file: &File,
+        virtual_size: u64,
+        cluster_bits: u32,
+        backing_file: Option<(&str, Option<&str>)>,
+    ) {
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_entry_span = cluster_size << (cluster_bits - 3);
+        let l1_size = (virtual_size + l1_entry_span - 1) / l1_entry_span;
+        let l1_clusters = (l1_size * 8 + cluster_size - 1) / cluster_size;
+
+        let mut header = vec![0u8; cluster_size as usize];
+        let put_u32 = |header: &mut Vec<u8>, offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_be_bytes())
+        };
+        put_u32(&mut header, 0, QCOW_MAGIC);
+        put_u32(&mut header, VERSION_OFFSET, 3);
+        put_u32(&mut header, CLUSTER_BITS_OFFSET, cluster_bits);
+        put_u32(&mut header, L1_SIZE_OFFSET, l1_size as u32);
+        put_u32(&mut header, REFCOUNT_TABLE_CLUSTERS_OFFSET, 1);
+        put_u32(&mut header, REFCOUNT_ORDER_OFFSET, REFCOUNT_ORDER);
+        put_u32(&mut header, HEADER_LENGTH_OFFSET, V3_HEADER_LEN as u32);
+        header[SIZE_OFFSET..SIZE_OFFSET + 8].copy_from_slice(&virtual_size.to_be_bytes());
+        header[L1_TABLE_OFFSET_OFFSET..L1_TABLE_OFFSET_OFFSET + 8]
+            .copy_from_slice(&(3 * cluster_size).to_be_bytes());
+        header[REFCOUNT_TABLE_OFFSET_OFFSET..REFCOUNT_TABLE_OFFSET_OFFSET + 8]
+            .copy_from_slice(&cluster_size.to_be_bytes());
+
+        if let Some((name, format)) = backing_file {
+            let mut ext_offset = V3_HEADER_LEN;
+            if let Some(format) = format {
+                put_u32(&mut header, ext_offset, HEADER_EXT_BACKING_FORMAT);
+                put_u32(&mut header, ext_offset + 4, format.len() as u32);
+                header[ext_offset + 8..ext_offset + 8 + format.len()]
+                    .copy_from_slice(format.as_bytes());
+                ext_offset += 8 + (format.len() + 7) / 8 * 8;
+            }
+            put_u32(&mut header, ext_offset, HEADER_EXT_END);
+            let name_offset = ext_offset + 8;
+            header[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+            header[BACKING_FILE_OFFSET_OFFSET..BACKING_FILE_OFFSET_OFFSET + 8]
+                .copy_from_slice(&(name_offset as u64).to_be_bytes());
+            put_u32(&mut header, BACKING_FILE_SIZE_OFFSET, name.len() as u32);
+        }
+        file.write_all_at(&header, 0).unwrap();
+
+        // Refcount table and refcount block.
+        file.write_all_at(&(2 * cluster_size).to_be_bytes(), cluster_size)
+            .unwrap();
+        for cluster in 0..3 + l1_clusters {
+            file.write_all_at(&1u16.to_be_bytes(), 2 * cluster_size + cluster * 2)
+                .unwrap();
+        }
+        file.set_len((3 + l1_clusters) * cluster_size).unwrap();
+    }
+
+    fn open(path: &Path, read_only: bool) -> io::Result<QcowFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .unwrap();
+        QcowFile::open(file, path, read_only)
+    }
+
+    fn read_vec(qcow: &mut QcowFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0xffu8; len];
+        qcow.seek(SeekFrom::Start(offset)).unwrap();
+        qcow.read_exact(&mut data).unwrap();
+        data
+    }
+
+    // Checks that every cluster of the image file, except for the header, the refcount
+    // table and the L1 table, is counted once and referenced once.
+    fn check_refcounts(path: &Path) {
+        let qcow = open(path, true).unwrap();
+        let file_len = qcow.file.metadata().unwrap().len();
+        let clusters = (file_len >> qcow.cluster_bits) as usize;
+        let block_entries = qcow.cluster_size() / 2;
+
+        let mut references = vec![0u16; clusters];
+        let l1_clusters =
+            (qcow.l1_table.len() as u64 * 8 + qcow.cluster_size() - 1) >> qcow.cluster_bits;
+        let first_l1_cluster = (qcow.l1_table_offset >> qcow.cluster_bits) as usize;
+        references[0] = 1;
+        references[1] = 1;
+        for count in references
+            .iter_mut()
+            .skip(first_l1_cluster)
+            .take(l1_clusters as usize)
+        {
+            *count += 1;
+        }
+        for &block in qcow.refcount_table.iter().filter(|&&block| block != 0) {
+            references[(block >> qcow.cluster_bits) as usize] += 1;
+        }
+        for &l1_entry in qcow.l1_table.iter() {
+            let l2_offset = l1_entry & OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            references[(l2_offset >> qcow.cluster_bits) as usize] += 1;
+            let table = read_table(&qcow.file, l2_offset, block_entries as usize / 4).unwrap();
+            for &l2_entry in table.iter().filter(|&&entry| entry & OFFSET_MASK != 0) {
+                references[((l2_entry & OFFSET_MASK) >> qcow.cluster_bits) as usize] += 1;
+            }
+        }
+
+        for (cluster, &count) in references.iter().enumerate() {
+            let block = qcow.refcount_table[cluster / block_entries as usize];
+            let mut refcount = [0u8; 2];
+            if block != 0 {
+                qcow.file
+                    .read_exact_at(&mut refcount, block + (cluster as u64 % block_entries) * 2)
+                    .unwrap();
+            }
+            assert_eq!(u16::from_be_bytes(refcount), count, "cluster {}", cluster);
+        }
+    }
+
+    #[test]
+    fn test_invalid_images() {
+        let f = TempFile::new().unwrap();
+        let path = f.as_path().to_path_buf();
+        let file = f.as_file();
+
+        // Too short.
+        assert_eq!(
+            open(&path, false).err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let patch_and_open = |offset: usize, value: &[u8], read_only: bool| {
+            create_image(file, 1 << 20, 16, None);
+            file.write_all_at(value, offset as u64).unwrap();
+            open(&path, read_only).err().map(|e| e.to_string())
+        };
+
+        assert!(patch_and_open(0, &[0; 4], true).is_some());
+        assert!(patch_and_open(VERSION_OFFSET, &1u32.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(CLUSTER_BITS_OFFSET, &8u32.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(CLUSTER_BITS_OFFSET, &22u32.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(CRYPT_METHOD_OFFSET, &1u32.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(HEADER_LENGTH_OFFSET, &72u32.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(REFCOUNT_ORDER_OFFSET, &5u32.to_be_bytes(), true).is_some());
+        // The L1 table is too small for the virtual size.
+        assert!(patch_and_open(SIZE_OFFSET, &(1u64 << 40).to_be_bytes(), true).is_some());
+        assert!(patch_and_open(L1_TABLE_OFFSET_OFFSET, &1u64.to_be_bytes(), true).is_some());
+        assert!(patch_and_open(
+            REFCOUNT_TABLE_CLUSTERS_OFFSET,
+            &u32::MAX.to_be_bytes(),
+            true
+        )
+        .is_some());
+
+        // External data files are not supported.
+        assert!(patch_and_open(INCOMPATIBLE_FEATURES_OFFSET, &4u64.to_be_bytes(), true).is_some());
+        // Dirty images and images with internal snapshots can only be read.
+        assert!(patch_and_open(INCOMPATIBLE_FEATURES_OFFSET, &1u64.to_be_bytes(), true).is_none());
+        assert!(patch_and_open(INCOMPATIBLE_FEATURES_OFFSET, &1u64.to_be_bytes(), false).is_some());
+        assert!(patch_and_open(NB_SNAPSHOTS_OFFSET, &1u32.to_be_bytes(), true).is_none());
+        assert!(patch_and_open(NB_SNAPSHOTS_OFFSET, &1u32.to_be_bytes(), false).is_some());
+
+        // The autoclear features are cleared when the image is opened for writing.
+        assert!(patch_and_open(AUTOCLEAR_FEATURES_OFFSET, &1u64.to_be_bytes(), false).is_none());
+        let mut autoclear_features = [0u8; 8];
+        file.read_exact_at(&mut autoclear_features, AUTOCLEAR_FEATURES_OFFSET as u64)
+            .unwrap();
+        assert_eq!(u64::from_be_bytes(autoclear_features), 0);
+
+        // Version 2 images are supported.
+        create_image(file, 1 << 20, 16, None);
+        file.write_all_at(&2u32.to_be_bytes(), VERSION_OFFSET as u64)
+            .unwrap();
+        assert_eq!(open(&path, false).unwrap().virtual_size(), 1 << 20);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let f = TempFile::new().unwrap();
+        let path = f.as_path().to_path_buf();
+        // With 512 byte clusters, an L2 table maps 32 KiB and a refcount block counts 128 KiB
+        // worth of clusters.
+        let virtual_size = 1 << 20;
+        create_image(f.as_file(), virtual_size, 9, None);
+
+        let mut qcow = open(&path, false).unwrap();
+        assert_eq!(qcow.virtual_size(), virtual_size);
+        assert_eq!(qcow.seek(SeekFrom::End(0)).unwrap(), virtual_size);
+        assert_eq!(
+            qcow.seek(SeekFrom::Current(-512)).unwrap(),
+            virtual_size - 512
+        );
+        assert!(qcow
+            .seek(SeekFrom::Current(-(virtual_size as i64) - 1))
+            .is_err());
+        assert_eq!(read_vec(&mut qcow, 0, 4096), vec![0u8; 4096]);
+
+        // Writes spanning clusters, L2 tables and refcount blocks.
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        qcow.seek(SeekFrom::Start(1000)).unwrap();
+        qcow.write_all(&data).unwrap();
+        qcow.seek(SeekFrom::Start(500_000)).unwrap();
+        qcow.write_all(&data[..100]).unwrap();
+        assert_eq!(read_vec(&mut qcow, 1000, data.len()), data);
+        assert_eq!(read_vec(&mut qcow, 0, 1000), vec![0u8; 1000]);
+        assert_eq!(read_vec(&mut qcow, 500_000, 100), &data[..100]);
+        let mut tail = read_vec(&mut qcow, 499_712, 1024);
+        assert_eq!(&tail[288..388], &data[..100]);
+        for byte in &mut tail[288..388] {
+            *byte = 0;
+        }
+        assert_eq!(tail, vec![0u8; 1024]);
+
+        // Nothing is read or written past the virtual size.
+        qcow.seek(SeekFrom::Start(virtual_size - 10)).unwrap();
+        let mut buf = [0u8; 20];
+        assert_eq!(qcow.read(&mut buf).unwrap(), 10);
+        assert_eq!(qcow.read(&mut buf).unwrap(), 0);
+        assert_eq!(qcow.write(&buf).unwrap(), 0);
+
+        // The data and the metadata are persisted.
+        drop(qcow);
+        let mut qcow = open(&path, false).unwrap();
+        assert_eq!(read_vec(&mut qcow, 1000, data.len()), data);
+        check_refcounts(&path);
+
+        // Rewriting allocated clusters does not allocate any more.
+        let file_len = qcow.file.metadata().unwrap().len();
+        qcow.seek(SeekFrom::Start(1000)).unwrap();
+        qcow.write_all(&data[..10_000]).unwrap();
+        assert_eq!(qcow.file.metadata().unwrap().len(), file_len);
+
+        // Read only images cannot be written.
+        let mut qcow = open(&path, true).unwrap();
+        assert_eq!(
+            qcow.write(&buf).err().unwrap().raw_os_error(),
+            Some(libc::EROFS)
+        );
+        assert_eq!(read_vec(&mut qcow, 1000, data.len()), data);
+    }
+
+    #[test]
+    fn test_refcount_table_full() {
+        let f = TempFile::new().unwrap();
+        let path = f.as_path().to_path_buf();
+        // A single refcount table cluster counts 64 refcount blocks of 256 clusters each,
+        // which is 8 MiB worth of 512 byte clusters.
+        create_image(f.as_file(), 16 << 20, 9, None);
+
+        let mut qcow = open(&path, false).unwrap();
+        let data = vec![0xaau8; 1 << 20];
+        for i in 0..8 {
+            qcow.seek(SeekFrom::Start(i << 20)).unwrap();
+            if i < 7 {
+                qcow.write_all(&data).unwrap();
+            } else {
+                assert!(qcow.write_all(&data).is_err());
+            }
+        }
+        assert_eq!(read_vec(&mut qcow, 6 << 20, 1 << 20), data);
+    }
+
+    #[test]
+    fn test_zero_clusters() {
+        let f = TempFile::new().unwrap();
+        let path = f.as_path().to_path_buf();
+        create_image(f.as_file(), 1 << 20, 16, None);
+
+        let mut qcow = open(&path, false).unwrap();
+        qcow.write_all(&[0xaa; 1 << 16]).unwrap();
+        // Turn the first cluster into a preallocated zero cluster.
+        let l2_offset = qcow.l1_table[0] & OFFSET_MASK;
+        let host_offset = qcow.l2_table(l2_offset).unwrap()[0] & OFFSET_MASK;
+        qcow.file
+            .write_all_at(&(host_offset | ZERO_FLAG).to_be_bytes(), l2_offset)
+            .unwrap();
+
+        let mut qcow = open(&path, false).unwrap();
+        assert_eq!(read_vec(&mut qcow, 0, 1 << 16), vec![0u8; 1 << 16]);
+        // Writing to the cluster zeroes the rest of it, and reuses its host cluster.
+        let file_len = qcow.file.metadata().unwrap().len();
+        qcow.seek(SeekFrom::Start(512)).unwrap();
+        qcow.write_all(&[0x55; 512]).unwrap();
+        assert_eq!(qcow.file.metadata().unwrap().len(), file_len);
+        let data = read_vec(&mut qcow, 0, 1 << 16);
+        assert!(data[..512].iter().all(|&b| b == 0));
+        assert!(data[512..1024].iter().all(|&b| b == 0x55));
+        assert!(data[1024..].iter().all(|&b| b == 0));
+
+        // Compressed clusters are not supported.
+        qcow.file
+            .write_all_at(&(host_offset | COMPRESSED_FLAG).to_be_bytes(), l2_offset)
+            .unwrap();
+        let mut qcow = open(&path, false).unwrap();
+        let mut buf = [0u8; 512];
+        assert!(qcow.read(&mut buf).is_err());
+        assert!(qcow.write(&buf).is_err());
+    }
+
+    #[test]
+    fn test_backing_chain() {
+        let dir = TempDir::new().unwrap();
+        let base = TempFile::new_in(dir.as_path()).unwrap();
+        let middle = TempFile::new_in(dir.as_path()).unwrap();
+        let top = TempFile::new_in(dir.as_path()).unwrap();
+        let name = |f: &TempFile| {
+            f.as_path()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        // The raw base is smaller than the images above it.
+        base.as_file().write_all_at(&[0x11; 3 << 16], 0).unwrap();
+        // The format of the base is probed, while the one of the middle image is recorded.
+        create_image(middle.as_file(), 4 << 16, 16, Some((&name(&base), None)));
+        create_image(
+            top.as_file(),
+            4 << 16,
+            16,
+            Some((&name(&middle), Some("qcow2"))),
+        );
+
+        let mut qcow = open(middle.as_path(), false).unwrap();
+        qcow.seek(SeekFrom::Start(2 << 16)).unwrap();
+        qcow.write_all(&[0x22; 512]).unwrap();
+
+        let mut qcow = open(top.as_path(), false).unwrap();
+        qcow.seek(SeekFrom::Start((2 << 16) + 1024)).unwrap();
+        qcow.write_all(&[0x33; 512]).unwrap();
+
+        let data = read_vec(&mut qcow, 0, 4 << 16);
+        assert!(data[..2 << 16].iter().all(|&b| b == 0x11));
+        let cluster = &data[2 << 16..3 << 16];
+        assert!(cluster[..512].iter().all(|&b| b == 0x22));
+        assert!(cluster[512..1024].iter().all(|&b| b == 0x11));
+        assert!(cluster[1024..1536].iter().all(|&b| b == 0x33));
+        assert!(cluster[1536..].iter().all(|&b| b == 0x11));
+        assert!(data[3 << 16..].iter().all(|&b| b == 0));
+
+        // The backing files are left untouched.
+        let mut qcow = open(middle.as_path(), true).unwrap();
+        assert!(read_vec(&mut qcow, 2 << 16, 1024)[512..]
+            .iter()
+            .all(|&b| b == 0x11));
+        let mut base_data = vec![0u8; 3 << 16];
+        base.as_file().read_exact_at(&mut base_data, 0).unwrap();
+        assert!(base_data.iter().all(|&b| b == 0x11));
+        check_refcounts(top.as_path());
+
+        // Backing files which are missing, in an unknown format or referencing themselves
+        // cannot be opened.
+        create_image(top.as_file(), 4 << 16, 16, Some(("missing", None)));
+        assert_eq!(
+            open(top.as_path(), false).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        create_image(
+            top.as_file(),
+            4 << 16,
+            16,
+            Some((&name(&base), Some("vmdk"))),
+        );
+        assert!(open(top.as_path(), false).is_err());
+        create_image(top.as_file(), 4 << 16, 16, Some((&name(&top), None)));
+        assert!(open(top.as_path(), false).is_err());
+    }
+}
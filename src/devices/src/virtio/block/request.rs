@@ -6,7 +6,8 @@
 // found in the THIRD-PARTY file.
 
 use std::convert::From;
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, Seek, SeekFrom};
+use std::mem;
 use std::result;
 
 use logger::{IncMetric, METRICS};
@@ -14,28 +15,38 @@ use virtio_gen::virtio_blk::*;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
-use super::device::DiskProperties;
-use super::{Error, SECTOR_SHIFT, SECTOR_SIZE};
+use super::device::{CacheType, DiskProperties};
+use super::direct_io;
+use super::verity::Error as VerityError;
+use super::{Error, MAX_DISCARD_SECTORS, MAX_DISCARD_SEGMENTS, SECTOR_SHIFT, SECTOR_SIZE};
 
 #[derive(Debug)]
 pub enum ExecuteError {
     BadRequest(Error),
+    Discard(io::Error),
     Flush(io::Error),
     Read(GuestMemoryError),
     Seek(io::Error),
     Write(GuestMemoryError),
     Unsupported(u32),
+    Verity(VerityError),
 }
 
 impl ExecuteError {
     pub fn status(&self) -> u32 {
         match *self {
             ExecuteError::BadRequest(_) => VIRTIO_BLK_S_IOERR,
+            // The backing file does not support deallocating or zeroing ranges.
+            ExecuteError::Discard(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                VIRTIO_BLK_S_UNSUPP
+            }
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::Verity(_) => VIRTIO_BLK_S_IOERR,
         }
     }
 }
@@ -46,6 +57,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -56,6 +69,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -65,8 +80,8 @@ pub struct Request {
     pub request_type: RequestType,
     pub data_len: u32,
     pub status_addr: GuestAddress,
-    sector: u64,
-    data_addr: GuestAddress,
+    pub(crate) sector: u64,
+    pub(crate) data_addr: GuestAddress,
 }
 
 /// The request header represents the mandatory fields of each block device request.
@@ -89,6 +104,29 @@ pub struct RequestHeader {
 // Safe because RequestHeader only contains plain data.
 unsafe impl ByteValued for RequestHeader {}
 
+/// A range of sectors to discard or to write zeroes to, in the data of a discard or write
+/// zeroes request.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardSegment only contains plain data.
+unsafe impl ByteValued for DiscardSegment {}
+
+impl DiscardSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardSegment {
+        DiscardSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 impl RequestHeader {
     pub fn new(request_type: u32, sector: u64) -> RequestHeader {
         RequestHeader {
@@ -148,7 +186,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -176,11 +218,8 @@ impl Request {
         Ok(req)
     }
 
-    pub(crate) fn execute(
-        &self,
-        disk: &mut DiskProperties,
-        mem: &GuestMemoryMmap,
-    ) -> result::Result<u32, ExecuteError> {
+    /// Checks that the data of the request fits within the disk.
+    pub(crate) fn check_bounds(&self, disk: &DiskProperties) -> result::Result<(), ExecuteError> {
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -191,31 +230,73 @@ impl Request {
         if top > disk.nsectors() {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
+        Ok(())
+    }
 
-        let diskfile = disk.file_mut();
+    /// Specifies if the request is submitted to the io_uring of a device using the
+    /// asynchronous I/O engine.
+    pub(crate) fn is_async(&self, disk: &DiskProperties) -> bool {
+        match self.request_type {
+            RequestType::In | RequestType::Out => true,
+            // There is nothing to wait for when the data is not synced.
+            RequestType::Flush => disk.cache_type() != CacheType::Unsafe,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn execute(
+        &self,
+        disk: &mut DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        self.check_bounds(disk)?;
+
+        let is_direct = disk.cache_type() == CacheType::Direct;
+        let is_verified = disk.verity().is_some();
+        let offset = self.sector << SECTOR_SHIFT;
+        let mut diskfile = disk.image_mut();
         diskfile
-            .seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
+            .seek(SeekFrom::Start(offset))
             .map_err(ExecuteError::Seek)?;
 
         match self.request_type {
+            RequestType::In if is_verified => self.execute_verified_read(disk, mem),
+            RequestType::In if is_direct => {
+                direct_io::read_to_guest(diskfile, mem, self.data_addr, self.data_len)
+                    .map(|_| {
+                        METRICS.block.read_bytes.add(self.data_len as usize);
+                        METRICS.block.read_count.inc();
+                        self.data_len
+                    })
+                    .map_err(ExecuteError::Read)
+            }
             RequestType::In => mem
-                .read_exact_from(self.data_addr, diskfile, self.data_len as usize)
+                .read_exact_from(self.data_addr, &mut diskfile, self.data_len as usize)
                 .map(|_| {
                     METRICS.block.read_bytes.add(self.data_len as usize);
                     METRICS.block.read_count.inc();
                     self.data_len
                 })
                 .map_err(ExecuteError::Read),
+            RequestType::Out if is_direct => {
+                direct_io::write_from_guest(diskfile, offset, mem, self.data_addr, self.data_len)
+                    .map(|_| {
+                        METRICS.block.write_bytes.add(self.data_len as usize);
+                        METRICS.block.write_count.inc();
+                        0
+                    })
+                    .map_err(ExecuteError::Write)
+            }
             RequestType::Out => mem
-                .write_all_to(self.data_addr, diskfile, self.data_len as usize)
+                .write_all_to(self.data_addr, &mut diskfile, self.data_len as usize)
                 .map(|_| {
                     METRICS.block.write_bytes.add(self.data_len as usize);
                     METRICS.block.write_count.inc();
                     0
                 })
                 .map_err(ExecuteError::Write),
-            RequestType::Flush => diskfile
-                .flush()
+            RequestType::Flush => disk
+                .sync()
                 .map(|_| {
                     METRICS.block.flush_count.inc();
                     0
@@ -230,9 +311,106 @@ impl Request {
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(ExecuteError::Write)
             }
+            RequestType::Discard | RequestType::WriteZeroes => self.execute_discard(disk, mem),
             RequestType::Unsupported(t) => Err(ExecuteError::Unsupported(t)),
         }
     }
+
+    // Reads the data of a read request, once checked against the hash tree of the disk.
+    fn execute_verified_read(
+        &self,
+        disk: &mut DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        let mut data = vec![0u8; self.data_len as usize];
+        disk.read_verified(self.sector << SECTOR_SHIFT, &mut data)
+            .map_err(ExecuteError::Verity)?;
+        mem.write_slice(&data, self.data_addr)
+            .map_err(ExecuteError::Read)?;
+        METRICS.block.read_bytes.add(self.data_len as usize);
+        METRICS.block.read_count.inc();
+        Ok(self.data_len)
+    }
+
+    // Deallocates or zeroes the sector ranges held by the data of a discard or write zeroes
+    // request.
+    fn execute_discard(
+        &self,
+        disk: &mut DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        let segment_size = mem::size_of::<DiscardSegment>() as u32;
+        let num_segments = self.data_len / segment_size;
+        if self.data_len % segment_size != 0
+            || num_segments == 0
+            || num_segments > MAX_DISCARD_SEGMENTS
+        {
+            return Err(ExecuteError::BadRequest(Error::InvalidDiscardSegments));
+        }
+        // Only write zeroes requests may allow deallocating their ranges.
+        let (raw_request_type, allowed_flags) = match self.request_type {
+            RequestType::WriteZeroes => (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            ),
+            _ => (VIRTIO_BLK_T_DISCARD, 0),
+        };
+        // The features are not offered for qcow2 images and overlays.
+        if !disk.supports_discard() {
+            return Err(ExecuteError::Unsupported(raw_request_type));
+        }
+
+        let mut segments = Vec::with_capacity(num_segments as usize);
+        for i in 0..num_segments {
+            let addr = self
+                .data_addr
+                .checked_add(u64::from(i * segment_size))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            let segment: DiscardSegment = mem.read_obj(addr).map_err(ExecuteError::Read)?;
+            if segment.flags & !allowed_flags != 0 {
+                return Err(ExecuteError::Unsupported(raw_request_type));
+            }
+            if segment.num_sectors > MAX_DISCARD_SECTORS {
+                return Err(ExecuteError::BadRequest(Error::InvalidDiscardSegments));
+            }
+            let end = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if end > disk.nsectors() {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+            segments.push(segment);
+        }
+
+        let mut len = 0;
+        for segment in segments {
+            let offset = segment.sector << SECTOR_SHIFT;
+            let seg_len = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+            match self.request_type {
+                RequestType::Discard => disk.punch_hole(offset, seg_len),
+                _ => disk.write_zeroes(
+                    offset,
+                    seg_len,
+                    segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+                ),
+            }
+            .map_err(ExecuteError::Discard)?;
+            len += seg_len;
+        }
+
+        match self.request_type {
+            RequestType::Discard => {
+                METRICS.block.discard_bytes.add(len as usize);
+                METRICS.block.discard_count.inc();
+            }
+            _ => {
+                METRICS.block.write_zeroes_bytes.add(len as usize);
+                METRICS.block.write_zeroes_count.inc();
+            }
+        }
+        Ok(0)
+    }
 }
 
 #[cfg(test)]
@@ -281,6 +459,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -290,6 +476,14 @@ mod tests {
             ExecuteError::BadRequest(Error::InvalidOffset).status(),
             VIRTIO_BLK_S_IOERR
         );
+        assert_eq!(
+            ExecuteError::Discard(io::Error::from_raw_os_error(42)).status(),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(
+            ExecuteError::Discard(io::Error::from_raw_os_error(libc::EOPNOTSUPP)).status(),
+            VIRTIO_BLK_S_UNSUPP
+        );
         assert_eq!(
             ExecuteError::Flush(io::Error::from_raw_os_error(42)).status(),
             VIRTIO_BLK_S_IOERR
@@ -307,6 +501,10 @@ mod tests {
             VIRTIO_BLK_S_IOERR
         );
         assert_eq!(ExecuteError::Unsupported(42).status(), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(
+            ExecuteError::Verity(VerityError::DataBlockMismatch(0)).status(),
+            VIRTIO_BLK_S_IOERR
+        );
     }
 
     #[test]
@@ -373,6 +571,17 @@ mod tests {
             ));
         }
 
+        // Write only data for DISCARD and WRITE_ZEROES.
+        for &request_type in &[VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let mut q = vq.create_queue();
+            m.write_obj::<u32>(request_type, GuestAddress(0x1000))
+                .unwrap();
+            assert!(matches!(
+                Request::parse(&q.pop(m).unwrap(), m),
+                Err(Error::UnexpectedWriteOnlyDescriptor)
+            ));
+        }
+
         {
             let mut q = vq.create_queue();
             // Read only data for GetDeviceID.
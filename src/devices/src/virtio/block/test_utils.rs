@@ -3,11 +3,14 @@
 
 use std::os::unix::io::AsRawFd;
 
-use crate::virtio::{Block, Queue};
+use crate::virtio::block::request::RequestHeader;
+use crate::virtio::test_utils::VirtQueue;
+use crate::virtio::{Block, CacheType, FileEngineType, ImageFormat, Queue, VIRTQ_DESC_F_NEXT};
 use polly::event_manager::{EventManager, Subscriber};
 use rate_limiter::RateLimiter;
 use utils::epoll::{EpollEvent, EventSet};
 use utils::tempfile::TempFile;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 
 /// Create a default Block instance to be used in tests.
 pub fn default_block() -> Block {
@@ -25,7 +28,48 @@ pub fn default_block_with_path(path: String) -> Block {
 
     let id = "test".to_string();
     // The default block device is read-write and non-root.
-    Block::new(id, None, path, false, false, rate_limiter).unwrap()
+    Block::new(
+        id,
+        None,
+        path,
+        false,
+        false,
+        rate_limiter,
+        CacheType::Writeback,
+        FileEngineType::Sync,
+        ImageFormat::Raw,
+        None,
+        1,
+        None,
+        None,
+        None,
+    )
+    .unwrap()
+}
+
+/// Create a Block instance using the asynchronous I/O engine to be used in tests, if the host
+/// implements io_uring.
+pub fn default_async_block() -> Option<Block> {
+    let f = TempFile::new().unwrap();
+    f.as_file().set_len(0x1000).unwrap();
+
+    Block::new(
+        "test".to_string(),
+        None,
+        f.as_path().to_str().unwrap().to_string(),
+        false,
+        false,
+        RateLimiter::default(),
+        CacheType::Writeback,
+        FileEngineType::Async,
+        ImageFormat::Raw,
+        None,
+        1,
+        None,
+        None,
+        None,
+    )
+    .ok()
 }
 
 pub fn invoke_handler_for_queue_event(b: &mut Block) {
@@ -40,6 +84,30 @@ pub fn invoke_handler_for_queue_event(b: &mut Block) {
     assert_eq!(b.interrupt_evt.read().unwrap(), 1);
 }
 
+/// Send a request for `sector` through the first three descriptors of `vq`, with `flags` set on
+/// the data descriptor, and return the status of the request.
+pub fn send_request(
+    blk: &mut Block,
+    vq: &VirtQueue,
+    mem: &GuestMemoryMmap,
+    request_type: u32,
+    sector: u64,
+    flags: u16,
+) -> u32 {
+    vq.used.idx.set(0);
+    set_queue(blk, 0, vq.create_queue());
+    mem.write_obj::<RequestHeader>(
+        RequestHeader::new(request_type, sector),
+        GuestAddress(vq.dtable[0].addr.get()),
+    )
+    .unwrap();
+    vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT | flags);
+    invoke_handler_for_queue_event(blk);
+    assert_eq!(vq.used.idx.get(), 1);
+    mem.read_obj::<u32>(GuestAddress(vq.dtable[2].addr.get()))
+        .unwrap()
+}
+
 pub fn set_queue(blk: &mut Block, idx: usize, q: Queue) {
     blk.queues[idx] = q;
 }
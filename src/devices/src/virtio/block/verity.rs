@@ -0,0 +1,481 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the data read from read only drives against a dm-verity hash tree.
+//!
+//! The leaf level of the tree holds the SHA-256 digests of the data blocks of the disk, and
+//! each level above holds the digests of the hash blocks of the level below, up to a single
+//! hash block whose digest is the root hash. The blocks are salted as in version 1 of the
+//! dm-verity format, and the levels are stored from the top one down, as produced by
+//! `veritysetup format`.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use logger::{IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::SECTOR_SIZE;
+
+/// Size of the SHA-256 digests, which is a power of two and needs no padding in the hash
+/// blocks.
+const DIGEST_SIZE: usize = 32;
+/// Maximum size of the data and hash blocks, which is the page size.
+const MAX_BLOCK_SIZE: u32 = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A data block does not match the hash tree.
+    DataBlockMismatch(u64),
+    /// A block of the hash tree does not match the level above it, or the root hash.
+    HashBlockMismatch(usize, u64),
+    /// The hash tree file is smaller than the given size, which the tree of the disk takes.
+    HashTreeTooSmall(u64),
+    /// The data or hash block size is not a power of two between 512 and 4096 bytes.
+    InvalidBlockSize(u32),
+    /// The disk size is not a multiple of the data block size.
+    InvalidDiskSize(u64),
+    /// The root hash is not a hexadecimal SHA-256 digest.
+    InvalidRootHash,
+    /// The salt is not a hexadecimal string.
+    InvalidSalt,
+    /// Cannot open the hash tree file.
+    OpenHashTree(io::Error),
+    /// Cannot read the disk.
+    ReadData(io::Error),
+    /// Cannot read the hash tree file.
+    ReadHashTree(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DataBlockMismatch(index) => {
+                write!(f, "The data block {} does not match the hash tree.", index)
+            }
+            HashBlockMismatch(level, index) => write!(
+                f,
+                "The hash block {} of level {} does not match the hash tree.",
+                index, level
+            ),
+            HashTreeTooSmall(size) => write!(
+                f,
+                "The hash tree file is too small, the tree of the disk ends at offset {}.",
+                size
+            ),
+            InvalidBlockSize(size) => write!(
+                f,
+                "Invalid block size: {}. The block sizes must be powers of two between {} \
+                 and {} bytes.",
+                size, SECTOR_SIZE, MAX_BLOCK_SIZE
+            ),
+            InvalidDiskSize(size) => write!(
+                f,
+                "The disk size {} is not a multiple of the data block size.",
+                size
+            ),
+            InvalidRootHash => write!(f, "The root hash is not a hexadecimal SHA-256 digest."),
+            InvalidSalt => write!(f, "The salt is not a hexadecimal string."),
+            OpenHashTree(e) => write!(f, "Cannot open the hash tree file: {}", e),
+            ReadData(e) => write!(f, "Cannot read the disk: {}", e),
+            ReadHashTree(e) => write!(f, "Cannot read the hash tree file: {}", e),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The hash tree and the root hash the data of a drive is verified against.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerityConfig {
+    /// Path of the file holding the hash tree.
+    pub hash_tree_path: String,
+    /// Offset of the hash tree in the file, e.g. past the superblock written by
+    /// `veritysetup`.
+    #[serde(default)]
+    pub hash_offset: u64,
+    /// The hexadecimal SHA-256 digest of the top hash block.
+    pub root_hash: String,
+    /// The hexadecimal salt the blocks are hashed with.
+    #[serde(default)]
+    pub salt: String,
+    /// Size of the data blocks, in bytes.
+    #[serde(default = "default_block_size")]
+    pub data_block_size: u32,
+    /// Size of the hash blocks, in bytes.
+    #[serde(default = "default_block_size")]
+    pub hash_block_size: u32,
+}
+
+fn default_block_size() -> u32 {
+    MAX_BLOCK_SIZE
+}
+
+// Decodes a hexadecimal string.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn check_block_size(size: u32) -> Result<u64> {
+    if !size.is_power_of_two() || u64::from(size) < SECTOR_SIZE || size > MAX_BLOCK_SIZE {
+        return Err(Error::InvalidBlockSize(size));
+    }
+    Ok(u64::from(size))
+}
+
+/// A hash tree verifying the data blocks of a disk.
+pub struct HashTree {
+    config: VerityConfig,
+    file: File,
+    root_hash: Vec<u8>,
+    salt: Vec<u8>,
+    data_block_size: u64,
+    hash_block_size: u64,
+    // Number of digests held by a hash block, as a power of two.
+    hash_per_block_bits: u32,
+    // Offsets of the first hash block of each level in the file, from the leaf level up.
+    level_offsets: Vec<u64>,
+    // The hash blocks verified so far, by level and index. They are kept in memory, so that
+    // changes to the file cannot go unnoticed.
+    verified: HashMap<(usize, u64), Vec<u8>>,
+}
+
+impl HashTree {
+    /// Opens the hash tree described by `config`, for a disk of `disk_size` bytes.
+    pub fn open(config: VerityConfig, disk_size: u64) -> Result<HashTree> {
+        let data_block_size = check_block_size(config.data_block_size)?;
+        let hash_block_size = check_block_size(config.hash_block_size)?;
+        if disk_size % data_block_size != 0 {
+            return Err(Error::InvalidDiskSize(disk_size));
+        }
+        let root_hash = parse_hex(&config.root_hash)
+            .filter(|hash| hash.len() == DIGEST_SIZE)
+            .ok_or(Error::InvalidRootHash)?;
+        let salt = parse_hex(&config.salt).ok_or(Error::InvalidSalt)?;
+
+        // The number of levels is computed as by dm-verity: a disk of a single data block
+        // has no hash block at all, its digest being the root hash.
+        let data_blocks = disk_size / data_block_size;
+        let hash_per_block_bits = (hash_block_size as usize / DIGEST_SIZE).trailing_zeros();
+        let mut num_levels = 0;
+        while hash_per_block_bits * num_levels < 64
+            && data_blocks.saturating_sub(1) >> (hash_per_block_bits * num_levels) != 0
+        {
+            num_levels += 1;
+        }
+
+        let mut level_offsets = vec![0; num_levels as usize];
+        let mut offset = config.hash_offset;
+        for level in (0..num_levels).rev() {
+            level_offsets[level as usize] = offset;
+            let num_blocks = data_blocks
+                .saturating_sub(1)
+                .checked_shr(hash_per_block_bits * (level + 1))
+                .unwrap_or(0)
+                + 1;
+            offset += num_blocks * hash_block_size;
+        }
+
+        let file = File::open(&config.hash_tree_path).map_err(Error::OpenHashTree)?;
+        if file.metadata().map_err(Error::ReadHashTree)?.len() < offset {
+            return Err(Error::HashTreeTooSmall(offset));
+        }
+
+        Ok(HashTree {
+            config,
+            file,
+            root_hash,
+            salt,
+            data_block_size,
+            hash_block_size,
+            hash_per_block_bits,
+            level_offsets,
+            verified: HashMap::new(),
+        })
+    }
+
+    /// Provides the description of the hash tree.
+    pub fn config(&self) -> &VerityConfig {
+        &self.config
+    }
+
+    /// Provides the size of the data blocks, in bytes.
+    pub fn data_block_size(&self) -> u64 {
+        self.data_block_size
+    }
+
+    fn digest(&self, block: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(block);
+        hasher.finalize().to_vec()
+    }
+
+    // Provides the hash block `index` of `level`, reading it from the file and checking it
+    // against the `expected` digest if it was not verified yet.
+    fn hash_block(&mut self, level: usize, index: u64, expected: &[u8]) -> Result<&[u8]> {
+        let key = (level, index);
+        if !self.verified.contains_key(&key) {
+            let mut block = vec![0u8; self.hash_block_size as usize];
+            self.file
+                .read_exact_at(
+                    &mut block,
+                    self.level_offsets[level] + index * self.hash_block_size,
+                )
+                .map_err(Error::ReadHashTree)?;
+            if self.digest(&block) != expected {
+                METRICS.block.verity_fails.inc();
+                return Err(Error::HashBlockMismatch(level, index));
+            }
+            self.verified.insert(key, block);
+        }
+        Ok(&self.verified[&key])
+    }
+
+    /// Checks the data block `index` against the hash tree.
+    pub fn verify(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        let mut expected = self.root_hash.clone();
+        for level in (0..self.level_offsets.len()).rev() {
+            let shift = self.hash_per_block_bits * level as u32;
+            let block_index = index
+                .checked_shr(shift + self.hash_per_block_bits)
+                .unwrap_or(0);
+            let position =
+                ((index >> shift) as usize & ((1 << self.hash_per_block_bits) - 1)) * DIGEST_SIZE;
+            let hash_block = self.hash_block(level, block_index, &expected)?;
+            expected = hash_block[position..position + DIGEST_SIZE].to_vec();
+        }
+
+        if self.digest(data) != expected {
+            METRICS.block.verity_fails.inc();
+            return Err(Error::DataBlockMismatch(index));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    use crate::check_metric_after_block;
+
+    /// Builds the hash tree of `data`, and returns it along with its root hash.
+    pub(crate) fn build_hash_tree(
+        data: &[u8],
+        data_block_size: usize,
+        hash_block_size: usize,
+        salt: &[u8],
+    ) -> (Vec<u8>, Vec<u8>) {
+        let digest = |block: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(salt);
+            hasher.update(block);
+            hasher.finalize().to_vec()
+        };
+
+        let mut digests: Vec<Vec<u8>> = data.chunks(data_block_size).map(digest).collect();
+        let mut levels = Vec::new();
+        while digests.len() > 1 {
+            let mut level = Vec::new();
+            for chunk in digests.chunks(hash_block_size / DIGEST_SIZE) {
+                let mut block = chunk.concat();
+                block.resize(hash_block_size, 0);
+                level.push(block);
+            }
+            digests = level.iter().map(|block| digest(block)).collect();
+            levels.push(level.concat());
+        }
+        levels.reverse();
+        (levels.concat(), digests.remove(0))
+    }
+
+    /// Writes the hash tree of `data` to a temporary file, and describes it.
+    pub(crate) fn hash_tree_config(
+        data: &[u8],
+        block_size: u32,
+        salt: &[u8],
+    ) -> (TempFile, VerityConfig) {
+        let (tree, root_hash) =
+            build_hash_tree(data, block_size as usize, block_size as usize, salt);
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&tree).unwrap();
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        let config = VerityConfig {
+            hash_tree_path: file.as_path().to_str().unwrap().to_string(),
+            hash_offset: 0,
+            root_hash: hex(&root_hash),
+            salt: hex(salt),
+            data_block_size: block_size,
+            hash_block_size: block_size,
+        };
+        (file, config)
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex(""), Some(vec![]));
+        assert_eq!(parse_hex("00aBff"), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("+a"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_open() {
+        let data = vec![0xaa; 0x4000];
+        let (_file, config) = hash_tree_config(&data, 512, b"salt");
+        assert!(HashTree::open(config.clone(), 0x4000).is_ok());
+
+        let errors = vec![
+            (
+                VerityConfig {
+                    data_block_size: 1000,
+                    ..config.clone()
+                },
+                0x4000,
+                Error::InvalidBlockSize(1000),
+            ),
+            (
+                VerityConfig {
+                    hash_block_size: 8192,
+                    ..config.clone()
+                },
+                0x4000,
+                Error::InvalidBlockSize(8192),
+            ),
+            (config.clone(), 0x4100, Error::InvalidDiskSize(0x4100)),
+            (
+                VerityConfig {
+                    root_hash: String::from("abcd"),
+                    ..config.clone()
+                },
+                0x4000,
+                Error::InvalidRootHash,
+            ),
+            (
+                VerityConfig {
+                    salt: String::from("salt"),
+                    ..config.clone()
+                },
+                0x4000,
+                Error::InvalidSalt,
+            ),
+            (
+                VerityConfig {
+                    hash_tree_path: String::from("/invalid/path"),
+                    ..config.clone()
+                },
+                0x4000,
+                Error::OpenHashTree(io::Error::from_raw_os_error(libc::ENOENT)),
+            ),
+            // The tree of a larger disk takes more hash blocks.
+            (config, 0x40000, Error::HashTreeTooSmall(0x4600)),
+        ];
+        for (config, disk_size, error) in errors {
+            match HashTree::open(config, disk_size) {
+                Err(e) => assert_eq!(e.to_string(), error.to_string()),
+                Ok(_) => panic!("Unexpected success."),
+            }
+        }
+
+        // Errors.
+        let errors = vec![
+            Error::HashBlockMismatch(0, 0),
+            Error::DataBlockMismatch(0),
+            Error::ReadData(io::Error::from_raw_os_error(libc::EIO)),
+            Error::ReadHashTree(io::Error::from_raw_os_error(libc::EIO)),
+        ];
+        for error in errors {
+            let _ = format!("{}{:?}", error, error);
+            let _ = io::Error::from(error);
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        // 300 blocks of 512 bytes take 3 levels of hash blocks holding 16 digests each.
+        let data: Vec<u8> = (0..300 * 512).map(|i| (i / 512) as u8).collect();
+        let (file, config) = hash_tree_config(&data, 512, b"salt");
+        let mut tree = HashTree::open(config.clone(), data.len() as u64).unwrap();
+        assert_eq!(tree.level_offsets, vec![3 * 512, 512, 0]);
+        for (index, block) in data.chunks(512).enumerate() {
+            tree.verify(index as u64, block).unwrap();
+        }
+        assert_eq!(tree.verified.len(), 19 + 2 + 1);
+
+        // Tampered data blocks are detected.
+        let mut block = data[0x200..0x400].to_vec();
+        block[0x10] ^= 1;
+        check_metric_after_block!(
+            &METRICS.block.verity_fails,
+            1,
+            match tree.verify(1, &block) {
+                Err(Error::DataBlockMismatch(1)) => (),
+                _ => panic!("Unexpected result."),
+            }
+        );
+
+        // So are tampered hash blocks, when they are first read.
+        file.as_file().write_all_at(&[0xff; 16], 3 * 512).unwrap();
+        let mut tree = HashTree::open(config.clone(), data.len() as u64).unwrap();
+        match tree.verify(0, &data[..0x200]) {
+            Err(Error::HashBlockMismatch(0, 0)) => (),
+            _ => panic!("Unexpected result."),
+        }
+        tree.verify(16, &data[16 * 0x200..17 * 0x200]).unwrap();
+
+        // And a wrong root hash.
+        let mut tree = HashTree::open(
+            VerityConfig {
+                root_hash: "00".repeat(DIGEST_SIZE),
+                ..config
+            },
+            data.len() as u64,
+        )
+        .unwrap();
+        match tree.verify(16, &data[16 * 0x200..17 * 0x200]) {
+            Err(Error::HashBlockMismatch(2, 0)) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn test_single_block() {
+        // The digest of the only data block is the root hash.
+        let data = vec![0x55; 0x1000];
+        let (_file, config) = hash_tree_config(&data, 0x1000, &[]);
+        let mut tree = HashTree::open(config, 0x1000).unwrap();
+        assert!(tree.level_offsets.is_empty());
+        tree.verify(0, &data).unwrap();
+        assert!(tree.verify(0, &[0; 0x1000]).is_err());
+    }
+}
@@ -331,6 +331,89 @@ impl BusDevice for MmioTransport {
     }
 }
 
+/// A MMIO slot reserved at boot time, in which a virtio device can be plugged while the
+/// guest is running.
+///
+/// The vCPUs route MMIO accesses through their own copy of the bus, so the slot stays on the
+/// bus for the whole lifetime of the microVM and only the transport behind it changes.
+/// An empty slot reports a device ID of 0, which the guest virtio-mmio driver treats as
+/// "no device present", leaving the slot unbound until a device is plugged in.
+///
+/// The guest is notified of hot-plug and unplug through a configuration change interrupt on
+/// the slot interrupt line. Once the slot is empty, the interrupt is reported through its
+/// InterruptStatus register until the guest acknowledges it.
+#[derive(Debug, Default)]
+pub struct MmioHotplugSlot {
+    transport: Option<MmioTransport>,
+    // Interrupts raised while the slot is empty.
+    interrupt_status: u32,
+}
+
+impl MmioHotplugSlot {
+    /// Constructs an empty hot-plug slot.
+    pub fn new() -> MmioHotplugSlot {
+        MmioHotplugSlot {
+            transport: None,
+            interrupt_status: 0,
+        }
+    }
+
+    /// Gets the transport of the device plugged in this slot, if any.
+    pub fn transport(&self) -> Option<&MmioTransport> {
+        self.transport.as_ref()
+    }
+
+    /// Plugs the `transport` in this slot, returning the transport previously plugged, if any.
+    pub fn plug(&mut self, transport: MmioTransport) -> Option<MmioTransport> {
+        self.transport.replace(transport)
+    }
+
+    /// Empties this slot, returning the transport that was plugged in it, if any. The slot
+    /// reports a configuration change interrupt from then on, which the caller signals through
+    /// the interrupt eventfd of the unplugged device.
+    pub fn unplug(&mut self) -> Option<MmioTransport> {
+        let transport = self.transport.take()?;
+        self.interrupt_status |= VIRTIO_MMIO_INT_CONFIG;
+        Some(transport)
+    }
+}
+
+impl BusDevice for MmioHotplugSlot {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match self.transport.as_mut() {
+            Some(transport) => transport.read(offset, data),
+            None if data.len() == 4 => {
+                let v = match offset {
+                    0x0 => MMIO_MAGIC_VALUE,
+                    0x04 => MMIO_VERSION,
+                    0x60 => self.interrupt_status,
+                    // Device ID 0 marks an empty slot.
+                    _ => 0,
+                };
+                byte_order::write_le_u32(data, v);
+            }
+            None => (),
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match self.transport.as_mut() {
+            Some(transport) => transport.write(offset, data),
+            None if offset == 0x64 && data.len() == 4 => {
+                self.interrupt_status &= !byte_order::read_le_u32(data);
+            }
+            None => (),
+        }
+    }
+
+    fn interrupt(&self, irq_mask: u32) -> std::io::Result<()> {
+        match self.transport.as_ref() {
+            Some(transport) => transport.interrupt(irq_mask),
+            None => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use utils::byte_order::{read_le_u32, write_le_u32};
@@ -845,6 +928,50 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_hotplug_slot() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut slot = MmioHotplugSlot::new();
+        let mut buf = vec![0xff; 4];
+
+        // An empty slot only exposes the virtio-mmio header, with a null device ID.
+        assert!(slot.transport().is_none());
+        slot.read(0, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_MAGIC_VALUE);
+        slot.read(0x04, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_VERSION);
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+        slot.write(0x70, &buf[..]);
+        assert!(slot.interrupt(0x1).is_ok());
+        assert!(slot.unplug().is_none());
+
+        // Accesses are forwarded to the plugged transport.
+        let d = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+        let device_type = d.locked_device().device_type();
+        assert!(slot.plug(d).is_none());
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), device_type);
+        write_le_u32(&mut buf[..], device_status::ACKNOWLEDGE);
+        slot.write(0x70, &buf[..]);
+        assert_eq!(
+            slot.transport().unwrap().device_status,
+            device_status::ACKNOWLEDGE
+        );
+
+        // The slot is empty again after unplugging the transport, and reports a configuration
+        // change until the guest acknowledges it.
+        assert!(slot.unplug().is_some());
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+        slot.read(0x60, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), VIRTIO_MMIO_INT_CONFIG);
+        write_le_u32(&mut buf[..], VIRTIO_MMIO_INT_CONFIG);
+        slot.write(0x64, &buf[..]);
+        slot.read(0x60, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
@@ -17,6 +17,8 @@ pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_user;
+pub mod vhost_user_blk;
 pub mod vsock;
 
 pub use self::balloon::*;
@@ -26,6 +28,7 @@ pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_user_blk::VhostUserBlock;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
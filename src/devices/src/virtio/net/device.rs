@@ -5,12 +5,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use crate::virtio::net::pcap::{Direction, PacketCapture};
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::vhost::VhostNet;
 use crate::virtio::net::Error;
 use crate::virtio::net::Result;
-use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX};
+use crate::virtio::net::{rx_queue_index, tx_queue_index, MAX_BUFFER_SIZE, QUEUE_SIZE};
 use crate::virtio::{
     ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
 };
@@ -18,12 +20,12 @@ use crate::{report_net_event_fail, Error as DeviceError};
 
 use dumbo::pdu::ethernet::EthernetFrame;
 use libc::EAGAIN;
-use logger::{error, warn, IncMetric, METRICS};
+use logger::{error, warn, IncMetric, NetIfaceMetrics, METRICS};
 use mmds::ns::MmdsNetworkStack;
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
-#[cfg(not(test))]
 use std::io;
 use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -31,12 +33,17 @@ use std::{cmp, mem, result};
 use utils::eventfd::EventFd;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
     VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_OK,
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
+// The only control commands handled by the device are the multi-queue ones, made of a
+// 2 bytes header (class and command) followed by the little endian number of queue pairs.
+const CTRL_MQ_REQUEST_LEN: usize = 4;
+
 enum FrontendError {
     AddUsed,
     DescriptorChainTooSmall,
@@ -77,24 +84,53 @@ fn init_vnet_hdr(buf: &mut [u8]) {
 }
 
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: [u8; MAC_ADDR_LEN],
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
 }
 
 impl Default for ConfigSpace {
     fn default() -> ConfigSpace {
         ConfigSpace {
             guest_mac: [0; MAC_ADDR_LEN],
+            status: 0,
+            max_virtqueue_pairs: 1,
         }
     }
 }
 
 unsafe impl ByteValued for ConfigSpace {}
 
+/// A receive queue and a transmit queue, backed by their own queue of the tap interface.
+pub(crate) struct QueuePair {
+    pub(crate) tap: Tap,
+
+    pub(crate) rx_rate_limiter: RateLimiter,
+    pub(crate) tx_rate_limiter: RateLimiter,
+
+    pub(crate) rx_deferred_frame: bool,
+
+    rx_bytes_read: usize,
+    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
+
+    // Whether the frame in `rx_frame_buf` was written by the MMDS, and whether the RX and TX
+    // frames were held back by the rate limiters, in order to annotate the captured frames.
+    rx_frame_from_mmds: bool,
+    rx_throttled: bool,
+    tx_throttled: bool,
+
+    // The kernel driver processing the queues of the pair instead of the VMM, if any.
+    pub(crate) vhost: Option<VhostNet>,
+}
+
 pub struct Net {
     pub(crate) id: String,
 
-    pub(crate) tap: Tap,
+    pub(crate) pairs: Vec<QueuePair>,
+    // The number of queue pairs the driver enabled through the control queue.
+    pub(crate) active_pairs: usize,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -102,15 +138,8 @@ pub struct Net {
     pub(crate) queues: Vec<Queue>,
     pub(crate) queue_evts: Vec<EventFd>,
 
-    pub(crate) rx_rate_limiter: RateLimiter,
-    pub(crate) tx_rate_limiter: RateLimiter,
-
-    pub(crate) rx_deferred_frame: bool,
     rx_deferred_irqs: bool,
 
-    rx_bytes_read: usize,
-    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
-
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
 
@@ -125,31 +154,52 @@ pub struct Net {
 
     pub(crate) mmds_ns: Option<MmdsNetworkStack>,
 
+    pub(crate) metrics: Arc<NetIfaceMetrics>,
+
+    capture: Option<PacketCapture>,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    ///
+    /// The device gets a queue pair for each element of `rate_limiters`, which holds the RX
+    /// and TX rate limiters of the pair. A device with more than one queue pair requires the
+    /// TAP interface to be created with the `multi_queue` flag.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
         guest_mac: Option<&MacAddr>,
-        rx_rate_limiter: RateLimiter,
-        tx_rate_limiter: RateLimiter,
+        rate_limiters: Vec<(RateLimiter, RateLimiter)>,
         allow_mmds_requests: bool,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
+        let num_pairs = rate_limiters.len();
+        let taps = if num_pairs > 1 {
+            Tap::open_named_queues(&tap_if_name, num_pairs).map_err(Error::TapOpen)?
+        } else {
+            vec![Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?]
+        };
 
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        for tap in &taps {
+            // Set offload flags to match the virtio features below.
+            tap.set_offload(
+                net_gen::TUN_F_CSUM
+                    | net_gen::TUN_F_UFO
+                    | net_gen::TUN_F_TSO4
+                    | net_gen::TUN_F_TSO6,
+            )
+            .map_err(Error::TapSetOffload)?;
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+            let vnet_hdr_size = vnet_hdr_len() as i32;
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
+        // Only the first queue pair is used until the driver enables more of them.
+        for tap in taps.iter().skip(1) {
+            tap.set_queue_enabled(false).map_err(Error::TapSetQueue)?;
+        }
 
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
@@ -167,12 +217,37 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
+        // The queue pairs come first, followed by the control queue of multi-queue devices.
+        let mut num_queues = 2 * num_pairs;
+        if num_pairs > 1 {
+            config_space.max_virtqueue_pairs = num_pairs as u16;
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            num_queues += 1;
+        }
+
         let mut queue_evts = Vec::new();
-        for _ in QUEUE_SIZES.iter() {
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
         }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
+        let pairs = taps
+            .into_iter()
+            .zip(rate_limiters.into_iter())
+            .map(|(tap, (rx_rate_limiter, tx_rate_limiter))| QueuePair {
+                tap,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                rx_deferred_frame: false,
+                rx_bytes_read: 0,
+                rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+                rx_frame_from_mmds: false,
+                rx_throttled: false,
+                tx_throttled: false,
+                vhost: None,
+            })
+            .collect();
 
         let mmds_ns = if allow_mmds_requests {
             Some(MmdsNetworkStack::new_with_defaults(None))
@@ -180,18 +255,15 @@ impl Net {
             None
         };
         Ok(Net {
+            metrics: METRICS.net_ifaces.get_or_insert(&id),
             id,
-            tap,
+            pairs,
+            active_pairs: 1,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            rx_deferred_frame: false,
             rx_deferred_irqs: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
             interrupt_status: Arc::new(AtomicUsize::new(0)),
@@ -201,6 +273,7 @@ impl Net {
             config_space,
             mmds_ns,
             guest_mac: guest_mac.copied(),
+            capture: None,
 
             #[cfg(test)]
             mocks: Mocks::default(),
@@ -217,11 +290,128 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
+    /// Provides the number of queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> usize {
+        self.pairs.len()
+    }
+
     /// Provides a mutable reference to the `MmdsNetworkStack`.
     pub fn mmds_ns_mut(&mut self) -> Option<&mut MmdsNetworkStack> {
         self.mmds_ns.as_mut()
     }
 
+    /// Hands the queue pairs over to the `vhost-net` kernel driver once the device gets
+    /// activated, so that the frames no longer go through the VMM.
+    ///
+    /// The frames are then neither detoured to the MMDS nor accounted by the rate limiters,
+    /// which have to be left unconfigured.
+    pub fn enable_vhost(&mut self) -> Result<()> {
+        for pair in self.pairs.iter_mut() {
+            pair.vhost = Some(VhostNet::new().map_err(Error::VhostNet)?);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the queue pairs of this net device are processed by `vhost-net`.
+    pub fn is_vhost(&self) -> bool {
+        self.pairs.iter().any(|pair| pair.vhost.is_some())
+    }
+
+    /// Hands the queues set up by the driver over to the `vhost-net` instances of the pairs.
+    /// On failure, the instances are dropped and the device processes the queues itself.
+    pub(crate) fn start_vhost(&mut self) -> Result<()> {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // The queues are only handed over once the device is activated.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut result = Ok(());
+        for (index, pair) in self.pairs.iter().enumerate() {
+            if let Some(ref vhost) = pair.vhost {
+                let queues = rx_queue_index(index)..=tx_queue_index(index);
+                result = vhost.start(
+                    self.acked_features,
+                    mem,
+                    &self.queues[queues.clone()],
+                    &self.queue_evts[queues],
+                    &pair.tap,
+                );
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+
+        if result.is_err() {
+            for pair in self.pairs.iter_mut() {
+                pair.vhost = None;
+            }
+        }
+        result.map_err(Error::VhostNet)
+    }
+
+    /// Stops the `vhost-net` instances and saves the position they reached in the queues, so
+    /// that the device state and the guest memory can be saved consistently. They resume
+    /// processing the queues when the device queues are processed again.
+    pub fn prepare_save(&mut self) {
+        if !self.is_activated() {
+            return;
+        }
+        for (index, pair) in self.pairs.iter().enumerate() {
+            if let Some(ref vhost) = pair.vhost {
+                let queues = &mut self.queues[rx_queue_index(index)..=tx_queue_index(index)];
+                if let Err(e) = vhost.stop(queues) {
+                    error!("Failed to stop vhost-net: {:?}", e);
+                    METRICS.net.event_fails.inc();
+                }
+            }
+        }
+    }
+
+    /// Starts capturing the frames received and transmitted by the device in the pcapng file
+    /// at `path`, replacing the capture in progress, if any.
+    pub fn start_capture(&mut self, path: &Path) -> Result<()> {
+        // The frames moved by vhost-net never go through the device.
+        if self.is_vhost() {
+            return Err(Error::CaptureVhost);
+        }
+        self.capture = Some(PacketCapture::new(path, &self.id).map_err(Error::CaptureCreate)?);
+        Ok(())
+    }
+
+    /// Stops the packet capture in progress.
+    pub fn stop_capture(&mut self) -> Result<()> {
+        self.capture
+            .take()
+            .map(|_| ())
+            .ok_or(Error::CaptureNotStarted)
+    }
+
+    /// Returns whether the frames of the device are being captured.
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    // Writes the frame following the VNET header in `frame_buf` to the packet capture in
+    // progress, if any. The capture is stopped if the frame cannot be written.
+    fn capture_frame(
+        capture: &mut Option<PacketCapture>,
+        direction: Direction,
+        frame_buf: &[u8],
+        comments: &[&str],
+    ) {
+        if let Some(pcap) = capture.as_mut() {
+            // Frames too short to hold a VNET header are captured as they are.
+            let frame = frame_bytes_from_buf(frame_buf).unwrap_or(frame_buf);
+            if let Err(e) = pcap.write_frame(direction, frame, comments) {
+                error!("Failed to write the packet capture, stopping it: {:?}", e);
+                METRICS.net.capture_write_fails.inc();
+                *capture = None;
+            }
+        }
+    }
+
     fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -246,41 +436,52 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, pair: usize) -> bool {
+        let queue_pair = &mut self.pairs[pair];
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+        if !queue_pair.rx_rate_limiter.consume(1, TokenType::Ops) {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.rx_rate_limiter_throttled.inc();
+            queue_pair.rx_throttled = true;
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
+        if !queue_pair
             .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
+            .consume(queue_pair.rx_bytes_read as u64, TokenType::Bytes)
         {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            queue_pair
+                .rx_rate_limiter
+                .manual_replenish(1, TokenType::Ops);
             METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.rx_rate_limiter_throttled.inc();
+            queue_pair.rx_throttled = true;
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
+            let queue_pair = &mut self.pairs[pair];
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            queue_pair
+                .rx_rate_limiter
+                .manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+            queue_pair
+                .rx_rate_limiter
+                .manual_replenish(queue_pair.rx_bytes_read as u64, TokenType::Bytes);
         }
         success
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of the queue pair into the guest.
+    fn do_write_frame_to_guest(&mut self, pair: usize) -> std::result::Result<(), FrontendError> {
         let mut result: std::result::Result<(), FrontendError> = Ok(());
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
@@ -288,14 +489,15 @@ impl Net {
             DeviceState::Inactive => unreachable!(),
         };
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue = &mut self.queues[rx_queue_index(pair)];
         let head_descriptor = queue.pop(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
         })?;
         let head_index = head_descriptor.index;
 
-        let mut frame_slice = &self.rx_frame_buf[..self.rx_bytes_read];
+        let queue_pair = &self.pairs[pair];
+        let mut frame_slice = &queue_pair.rx_frame_buf[..queue_pair.rx_bytes_read];
         let frame_len = frame_slice.len();
         let mut maybe_next_descriptor = Some(head_descriptor);
         while let Some(descriptor) = &maybe_next_descriptor {
@@ -321,6 +523,7 @@ impl Net {
                         _ => &METRICS.net.rx_fails,
                     }
                     .inc();
+                    self.metrics.rx_fails.inc();
                     result = Err(FrontendError::GuestMemory(e));
                     break;
                 }
@@ -331,6 +534,7 @@ impl Net {
         if result.is_ok() && !frame_slice.is_empty() {
             warn!("Receiving buffer is too small to hold frame of current size");
             METRICS.net.rx_fails.inc();
+            self.metrics.rx_fails.inc();
             result = Err(FrontendError::DescriptorChainTooSmall);
         }
 
@@ -345,16 +549,35 @@ impl Net {
         if result.is_ok() {
             METRICS.net.rx_bytes_count.add(frame_len);
             METRICS.net.rx_packets_count.inc();
+            self.metrics.rx_bytes_count.add(frame_len);
+            self.metrics.rx_packets_count.inc();
+
+            let queue_pair = &mut self.pairs[pair];
+            let mut comments = Vec::new();
+            if queue_pair.rx_frame_from_mmds {
+                comments.push("Sent by MMDS");
+            }
+            if queue_pair.rx_throttled {
+                comments.push("Delayed by the RX rate limiter");
+                queue_pair.rx_throttled = false;
+            }
+            Self::capture_frame(
+                &mut self.capture,
+                Direction::Rx,
+                &queue_pair.rx_frame_buf[..queue_pair.rx_bytes_read],
+                &comments,
+            );
         }
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of the queue pair into the guest. In case
+    // of an error retries the operation if possible. Returns true if the operation was
+    // successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -372,29 +595,46 @@ impl Net {
     // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
-    // Returns whether MMDS consumed the frame.
+    // Returns whether MMDS consumed the frame. The frame is also written to the packet capture,
+    // if any, noting whether it was `throttled` by the rate limiter.
+    #[allow(clippy::too_many_arguments)]
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
         tap: &mut Tap,
         guest_mac: Option<MacAddr>,
+        metrics: &NetIfaceMetrics,
+        capture: &mut Option<PacketCapture>,
+        throttled: bool,
     ) -> Result<bool> {
-        let checked_frame = |frame_buf| {
+        let checked_frame = |frame_buf, capture: &mut Option<PacketCapture>| {
             frame_bytes_from_buf(frame_buf).map_err(|e| {
                 error!("VNET header missing in the TX frame.");
                 METRICS.net.tx_malformed_frames.inc();
+                Self::capture_frame(
+                    capture,
+                    Direction::Tx,
+                    frame_buf,
+                    &["Dropped: the VNET header is missing"],
+                );
                 e
             })
         };
+        let mut comments = Vec::new();
+        if throttled {
+            comments.push("Delayed by the TX rate limiter");
+        }
         if let Some(ns) = mmds_ns {
-            if ns.detour_frame(checked_frame(frame_buf)?) {
+            if ns.detour_frame(checked_frame(frame_buf, capture)?) {
                 METRICS.mmds.rx_accepted.inc();
 
                 // MMDS frames are not accounted by the rate limiter.
                 rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
                 rate_limiter.manual_replenish(1, TokenType::Ops);
 
+                comments.push("Detoured to MMDS");
+                Self::capture_frame(capture, Direction::Tx, frame_buf, &comments);
                 // MMDS consumed the frame.
                 return Ok(true);
             }
@@ -404,11 +644,13 @@ impl Net {
 
         // Check for guest MAC spoofing.
         if let Some(mac) = guest_mac {
-            let _ = EthernetFrame::from_bytes(checked_frame(frame_buf)?).map(|eth_frame| {
-                if mac != eth_frame.src_mac() {
-                    METRICS.net.tx_spoofed_mac_count.inc();
-                }
-            });
+            let _ =
+                EthernetFrame::from_bytes(checked_frame(frame_buf, capture)?).map(|eth_frame| {
+                    if mac != eth_frame.src_mac() {
+                        METRICS.net.tx_spoofed_mac_count.inc();
+                        comments.push("Spoofed source MAC address");
+                    }
+                });
         }
 
         match tap.write(frame_buf) {
@@ -416,41 +658,48 @@ impl Net {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
                 METRICS.net.tx_packets_count.inc();
                 METRICS.net.tx_count.inc();
+                metrics.tx_bytes_count.add(frame_buf.len());
+                metrics.tx_packets_count.inc();
             }
             Err(e) => {
                 error!("Failed to write to tap: {:?}", e);
                 METRICS.net.tap_write_fails.inc();
+                metrics.tap_write_fails.inc();
+                comments.push("Dropped: failed to write to the TAP device");
             }
         };
+        Self::capture_frame(capture, Direction::Tx, frame_buf, &comments);
         Ok(false)
     }
 
     // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
-        if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
-            {
+    // The MMDS frames are always received on the first queue pair.
+    fn read_from_mmds_or_tap(&mut self, pair: usize) -> Result<usize> {
+        if let (0, Some(ns)) = (pair, self.mmds_ns.as_mut()) {
+            let rx_frame_buf = &mut self.pairs[pair].rx_frame_buf[..];
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                init_vnet_hdr(rx_frame_buf);
+                self.pairs[pair].rx_frame_from_mmds = true;
                 return Ok(vnet_hdr_len() + len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        self.pairs[pair].rx_frame_from_mmds = false;
+        self.read_tap(pair).map_err(Error::IO)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
+                    self.pairs[pair].rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    if !self.rate_limited_rx_single_frame(pair) {
+                        self.pairs[pair].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -462,6 +711,7 @@ impl Net {
                         _ => {
                             error!("Failed to read tap: {:?}", e);
                             METRICS.net.tap_read_fails.inc();
+                            self.metrics.tap_read_fails.inc();
                             return Err(DeviceError::FailedReadTap);
                         }
                     };
@@ -479,26 +729,26 @@ impl Net {
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair) {
+            self.pairs[pair].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair);
         }
 
         self.signal_rx_used_queue()
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.pairs[pair].rx_deferred_frame {
+            self.handle_deferred_frame(pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
@@ -511,16 +761,19 @@ impl Net {
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
         let mut raise_irq = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(pair)];
+        let queue_pair = &mut self.pairs[pair];
 
         while let Some(head) = tx_queue.pop(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+            if !queue_pair.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                self.metrics.tx_rate_limiter_throttled.inc();
+                queue_pair.tx_throttled = true;
                 break;
             }
 
@@ -541,16 +794,20 @@ impl Net {
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
+            if !queue_pair
                 .tx_rate_limiter
                 .consume(read_count as u64, TokenType::Bytes)
             {
                 // revert the OPS consume()
-                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                queue_pair
+                    .tx_rate_limiter
+                    .manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                self.metrics.tx_rate_limiter_throttled.inc();
+                queue_pair.tx_throttled = true;
                 break;
             }
 
@@ -577,6 +834,7 @@ impl Net {
                             _ => &METRICS.net.tx_fails,
                         }
                         .inc();
+                        self.metrics.tx_fails.inc();
                         read_count = 0;
                         break;
                     }
@@ -585,13 +843,17 @@ impl Net {
 
             let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
+                &mut queue_pair.tx_rate_limiter,
                 &self.tx_frame_buf[..read_count],
-                &mut self.tap,
+                &mut queue_pair.tap,
                 self.guest_mac,
+                &self.metrics,
+                &mut self.capture,
+                queue_pair.tx_throttled,
             )
             .unwrap_or_else(|_| false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
+            queue_pair.tx_throttled = false;
+            if frame_consumed_by_mmds {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
             }
@@ -608,49 +870,142 @@ impl Net {
             METRICS.net.no_tx_avail_buffer.inc();
         }
 
-        // An incoming frame for the MMDS may trigger the transmission of a new message.
-        if process_rx_for_mmds {
-            self.process_rx()
+        // An incoming frame for the MMDS may trigger the transmission of a new message,
+        // which is received on the first queue pair.
+        if process_rx_for_mmds && !self.pairs[0].rx_deferred_frame {
+            self.process_rx(0)
         } else {
             Ok(())
         }
     }
 
-    /// Updates the parameters for the rate limiters
+    // Handles a control command, returning the acknowledgement sent back to the driver.
+    fn handle_ctrl_command(&mut self, request: &[u8]) -> u8 {
+        if request.len() == CTRL_MQ_REQUEST_LEN
+            && u32::from(request[0]) == VIRTIO_NET_CTRL_MQ
+            && u32::from(request[1]) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+        {
+            let active_pairs = usize::from(u16::from_le_bytes([request[2], request[3]]));
+            if active_pairs >= 1 && active_pairs <= self.pairs.len() {
+                match self.set_active_pairs(active_pairs) {
+                    Ok(()) => return VIRTIO_NET_OK as u8,
+                    Err(e) => error!("Failed to set the active queue pairs: {:?}", e),
+                }
+            }
+        }
+
+        METRICS.net.ctrl_fails.inc();
+        VIRTIO_NET_ERR as u8
+    }
+
+    fn process_ctrl_queue(&mut self) -> result::Result<(), DeviceError> {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+        let ctrl_index = match self.ctrl_queue_index() {
+            Some(ctrl_index) => ctrl_index,
+            None => return Ok(()),
+        };
+
+        let mut raise_irq = false;
+        while let Some(head) = self.queues[ctrl_index].pop(&mem) {
+            let head_index = head.index;
+            let mut request = [0u8; CTRL_MQ_REQUEST_LEN + 1];
+            let mut request_len = 0;
+            let mut ack_addr = None;
+            let mut next_desc = Some(head);
+
+            // The command is in the device readable descriptors, and the acknowledgement goes
+            // in the last byte written by the device. Longer commands are not supported, so
+            // only one extra byte is read in order to reject them.
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    ack_addr = Some(desc.addr);
+                } else {
+                    let len = cmp::min(desc.len as usize, request.len() - request_len);
+                    if let Err(e) =
+                        mem.read_slice(&mut request[request_len..request_len + len], desc.addr)
+                    {
+                        error!("Failed to read control command: {:?}", e);
+                    }
+                    request_len += len;
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            let ack = self.handle_ctrl_command(&request[..request_len]);
+            let used_len = match ack_addr.map(|addr| mem.write_obj(ack, addr)) {
+                Some(Ok(())) => 1,
+                _ => {
+                    error!("Failed to acknowledge control command");
+                    METRICS.net.ctrl_fails.inc();
+                    0
+                }
+            };
+
+            self.queues[ctrl_index]
+                .add_used(&mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+            raise_irq = true;
+        }
+
+        if raise_irq {
+            self.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    /// Updates the parameters for the rate limiters of all the queue pairs.
     pub fn patch_rate_limiters(
         &mut self,
         rx_bytes: BucketUpdate,
         rx_ops: BucketUpdate,
         tx_bytes: BucketUpdate,
         tx_ops: BucketUpdate,
-    ) {
-        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+    ) -> io::Result<()> {
+        // The frames moved by vhost-net never go through the rate limiters.
+        if self.is_vhost() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The rate limiters of a network interface using vhost-net cannot be updated.",
+            ));
+        }
+        for pair in self.pairs.iter_mut() {
+            pair.rx_rate_limiter
+                .update_buckets(rx_bytes.clone(), rx_ops.clone());
+            pair.tx_rate_limiter
+                .update_buckets(tx_bytes.clone(), tx_ops.clone());
+        }
+        Ok(())
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+        let queue_pair = &mut self.pairs[pair];
+        queue_pair.tap.read(&mut queue_pair.rx_frame_buf)
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    pub fn process_rx_queue_event(&mut self, pair: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(e) = self.queue_evts[RX_INDEX].read() {
+        if let Err(e) = self.queue_evts[rx_queue_index(pair)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", e);
             METRICS.net.event_fails.inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            if !self.rx_rate_limiter.is_blocked() {
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+            if !self.pairs[pair].rx_rate_limiter.is_blocked() {
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             } else {
                 METRICS.net.rx_rate_limiter_throttled.inc();
+                self.metrics.rx_rate_limiter_throttled.inc();
             }
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair: usize) {
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
@@ -662,51 +1017,80 @@ impl Net {
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        if self.queues[rx_queue_index(pair)].is_empty(mem) && self.pairs[pair].rx_deferred_frame {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
-        if self.rx_rate_limiter.is_blocked() {
+        if self.pairs[pair].rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            self.metrics.rx_rate_limiter_throttled.inc();
             return;
         }
 
-        if self.rx_deferred_frame
+        if self.pairs[pair].rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, pair: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(e) = self.queue_evts[TX_INDEX].read() {
+        if let Err(e) = self.queue_evts[tx_queue_index(pair)].read() {
             error!("Failed to get tx queue event: {:?}", e);
             METRICS.net.event_fails.inc();
-        } else if !self.tx_rate_limiter.is_blocked()
+        } else if !self.pairs[pair].tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair).unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
+            self.metrics.tx_rate_limiter_throttled.inc();
+        }
+    }
+
+    pub fn process_vhost_call_event(&mut self, pair: usize, queue: usize) {
+        METRICS.net.vhost_call_event_count.inc();
+        let call_evt = match self.pairs[pair].vhost {
+            Some(ref vhost) => &vhost.call_evts[queue],
+            // This should never happen, it's been already validated in the event handler.
+            None => unreachable!(),
+        };
+        if let Err(e) = call_evt.read() {
+            error!("Failed to get vhost-net call event: {:?}", e);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.signal_used_queue()
+                .unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_rx_rate_limiter_event(&mut self) {
+    pub fn process_ctrl_queue_event(&mut self, ctrl_index: usize) {
+        METRICS.net.ctrl_queue_event_count.inc();
+        if let Err(e) = self.queue_evts[ctrl_index].read() {
+            error!("Failed to get ctrl queue event: {:?}", e);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl_queue()
+                .unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    pub fn process_rx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
-        match self.rx_rate_limiter.event_handler() {
+        match self.pairs[pair].rx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(e) => {
                 error!("Failed to get rx rate-limiter event: {:?}", e);
@@ -715,14 +1099,14 @@ impl Net {
         }
     }
 
-    pub fn process_tx_rate_limiter_event(&mut self) {
+    pub fn process_tx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.tx_rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
-        match self.tx_rate_limiter.event_handler() {
+        match self.pairs[pair].tx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                self.process_tx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(e) => {
                 error!("Failed to get tx rate-limiter event: {:?}", e);
@@ -733,8 +1117,24 @@ impl Net {
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        if self.is_vhost() {
+            // The queues of the pairs served by vhost-net are processed by the kernel, which
+            // may have been stopped to save the device state.
+            for pair in self.pairs.iter() {
+                if let Some(ref vhost) = pair.vhost {
+                    if let Err(e) = vhost.resume(&pair.tap) {
+                        error!("Failed to resume vhost-net: {:?}", e);
+                        METRICS.net.event_fails.inc();
+                    }
+                }
+            }
+        } else {
+            for pair in 0..self.pairs.len() {
+                let _ = self.resume_rx(pair);
+                let _ = self.process_tx(pair);
+            }
+        }
+        let _ = self.process_ctrl_queue();
     }
 }
 
@@ -776,7 +1176,7 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_space_bytes = self.config_space.as_slice();
+        let config_space_bytes = &self.config_space.as_slice()[..self.config_space_len()];
         let config_len = config_space_bytes.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
@@ -794,8 +1194,8 @@ impl VirtioDevice for Net {
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
+        let config_len = self.config_space_len() as u64;
         let config_space_bytes = self.config_space.as_mut_slice();
-        let config_len = config_space_bytes.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
             METRICS.net.cfg_fails.inc();
@@ -837,41 +1237,46 @@ pub mod tests {
     use std::sync::atomic::Ordering;
     use std::time::Duration;
     use std::{io, mem, thread};
+    use utils::byte_order::{read_le_u16, read_le_u32};
+    use utils::tempfile::TempFile;
 
     use crate::check_metric_after_block;
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        check_used_queue_signal, default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent,
-        NetQueue, ReadTapMock, TapTrafficSimulator,
+        check_used_queue_signal, default_guest_memory, default_multi_queue_net, default_net,
+        if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
+        TapTrafficSimulator,
     };
     use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::test_utils::VirtQueue;
     use crate::virtio::{
         Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTIO_MMIO_INT_VRING,
-        VIRTQ_DESC_F_WRITE,
+        VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
     };
     use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
     use dumbo::pdu::ethernet::ETHERTYPE_ARP;
     use logger::{IncMetric, METRICS};
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
     use virtio_gen::virtio_net::{
-        virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-        VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
-        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+        virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MAC, VIRTIO_NET_F_CSUM,
+        VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+        VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
     };
     use vm_memory::{Address, GuestMemory};
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+            let queue_pair = &mut self.pairs[pair];
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
+                    queue_pair.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => queue_pair.tap.read(&mut queue_pair.rx_frame_buf),
             }
         }
     }
@@ -991,6 +1396,72 @@ pub mod tests {
         assert_eq!(new_config, new_config_read);
     }
 
+    #[test]
+    fn test_multi_queue() {
+        let mut net = default_multi_queue_net(2);
+
+        // The control queue comes after the 2 queue pairs.
+        assert_eq!(net.num_queue_pairs(), 2);
+        assert_eq!(net.queues().len(), 5);
+        assert_eq!(net.queue_events().len(), 5);
+        assert_eq!(net.ctrl_queue_index(), Some(4));
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+
+        // The number of queue pairs follows the MAC and the status in the config space.
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 2);
+
+        // Only the first queue pair is used before the driver enables the others.
+        assert_eq!(net.active_pairs, 1);
+
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[4] = ctrlq.create_queue();
+        net.activate(mem.clone()).unwrap();
+
+        let request_addr = ctrlq.end().unchecked_align_up(0x100);
+        let ack_addr = request_addr.unchecked_add(0x100);
+        let send_command = |net: &mut Net, request: &[u8]| -> u8 {
+            mem.write_slice(request, request_addr).unwrap();
+            ctrlq.dtable[0].set(
+                request_addr.raw_value(),
+                request.len() as u32,
+                VIRTQ_DESC_F_NEXT,
+                1,
+            );
+            ctrlq.dtable[1].set(ack_addr.raw_value(), 1, VIRTQ_DESC_F_WRITE, 0);
+            let ring_index = ctrlq.avail.idx.get();
+            ctrlq.avail.ring[ring_index as usize].set(0);
+            ctrlq.avail.idx.set(ring_index + 1);
+
+            net.process_ctrl_queue().unwrap();
+            ctrlq.check_used_elem(ring_index, 0, 1);
+            mem.read_obj(ack_addr).unwrap()
+        };
+
+        // Enable both queue pairs.
+        let ack = send_command(&mut net, &[VIRTIO_NET_CTRL_MQ as u8, 0, 2, 0]);
+        assert_eq!(ack, VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_pairs, 2);
+
+        // The device doesn't have 3 queue pairs.
+        let ack = send_command(&mut net, &[VIRTIO_NET_CTRL_MQ as u8, 0, 3, 0]);
+        assert_eq!(ack, VIRTIO_NET_ERR as u8);
+        assert_eq!(net.active_pairs, 2);
+
+        // Other commands aren't supported.
+        let ack = send_command(&mut net, &[VIRTIO_NET_CTRL_MAC as u8, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ack, VIRTIO_NET_ERR as u8);
+
+        // Go back to a single queue pair.
+        let ack = send_command(&mut net, &[VIRTIO_NET_CTRL_MQ as u8, 0, 1, 0]);
+        assert_eq!(ack, VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_pairs, 1);
+        assert_eq!(ctrlq.used.idx.get(), 4);
+    }
+
     #[test]
     fn test_rx_missing_queue_signal() {
         let mut th = TestHelper::default();
@@ -1107,7 +1578,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1140,7 +1611,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         check_used_queue_signal(&th.net(), 1);
@@ -1179,7 +1650,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         check_used_queue_signal(&th.net(), 1);
@@ -1197,7 +1668,7 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1217,7 +1688,7 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1236,7 +1707,7 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1258,7 +1729,7 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1286,7 +1757,7 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1326,7 +1797,7 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1354,7 +1825,7 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().pairs[0].tap));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1428,6 +1899,7 @@ pub mod tests {
         let dst_ip = Ipv4Addr::new(169, 254, 169, 254);
 
         let (frame_buf, frame_len) = create_arp_request(src_mac, src_ip, dst_mac, dst_ip);
+        let queue_pair = &mut net.pairs[0];
 
         // Call the code which sends the packet to the host or MMDS.
         // Validate the frame was consumed by MMDS and that the metrics reflect that.
@@ -1436,10 +1908,13 @@ pub mod tests {
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut queue_pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut queue_pair.tap,
                 Some(src_mac),
+                &net.metrics,
+                &mut net.capture,
+                false,
             )
             .unwrap())
         );
@@ -1448,7 +1923,7 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
@@ -1463,6 +1938,7 @@ pub mod tests {
         let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
 
         let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        let queue_pair = &mut net.pairs[0];
 
         // Check that a legit MAC doesn't affect the spoofed MAC metric.
         check_metric_after_block!(
@@ -1470,10 +1946,13 @@ pub mod tests {
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut queue_pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut queue_pair.tap,
                 Some(guest_mac),
+                &net.metrics,
+                &mut net.capture,
+                false,
             )
         );
 
@@ -1483,14 +1962,166 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut queue_pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut queue_pair.tap,
                 Some(not_guest_mac),
+                &net.metrics,
+                &mut net.capture,
+                false,
             )
         );
     }
 
+    #[test]
+    fn test_iface_metrics() {
+        let mut net = default_net();
+        // Report into metrics which no other test updates.
+        net.metrics = METRICS.net_ifaces.get_or_insert("metrics_iface");
+
+        let src_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let src_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+        let (frame_buf, frame_len) = create_arp_request(src_mac, src_ip, dst_mac, dst_ip);
+        let queue_pair = &mut net.pairs[0];
+
+        assert!(!Net::write_to_mmds_or_tap(
+            None,
+            &mut queue_pair.tx_rate_limiter,
+            &frame_buf[..frame_len],
+            &mut queue_pair.tap,
+            Some(src_mac),
+            &net.metrics,
+            &mut net.capture,
+            false,
+        )
+        .unwrap());
+        assert_eq!(net.metrics.tx_packets_count.count(), 1);
+        assert_eq!(net.metrics.tx_bytes_count.count(), frame_len);
+        assert_eq!(net.metrics.tap_write_fails.count(), 0);
+    }
+
+    // Returns the direction flags, the bytes and the comments of the frames of a capture file.
+    fn read_captured_frames(path: &Path) -> Vec<(u32, Vec<u8>, Vec<String>)> {
+        let bytes = std::fs::read(path).unwrap();
+        let mut frames = Vec::new();
+        let mut block = &bytes[..];
+        while !block.is_empty() {
+            let block_len = read_le_u32(&block[4..]) as usize;
+            // Enhanced packet blocks.
+            if read_le_u32(block) == 6 {
+                let frame_len = read_le_u32(&block[20..]) as usize;
+                let mut options = &block[28 + (frame_len + 3) / 4 * 4..block_len - 4];
+                let mut flags = 0;
+                let mut comments = Vec::new();
+                loop {
+                    let code = read_le_u16(options);
+                    let len = read_le_u16(&options[2..]) as usize;
+                    let value = &options[4..4 + len];
+                    match code {
+                        0 => break,
+                        1 => comments.push(String::from_utf8(value.to_vec()).unwrap()),
+                        2 => flags = read_le_u32(value),
+                        _ => (),
+                    }
+                    options = &options[4 + (len + 3) / 4 * 4..];
+                }
+                frames.push((flags, block[28..28 + frame_len].to_vec(), comments));
+            }
+            block = &block[block_len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let capture_file = TempFile::new().unwrap();
+        let mut net = default_net();
+        assert!(matches!(net.stop_capture(), Err(Error::CaptureNotStarted)));
+        net.start_capture(capture_file.as_path()).unwrap();
+        assert!(net.is_capturing());
+
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let not_guest_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let mmds_ip = Ipv4Addr::new(169, 254, 169, 254);
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+        let (mmds_frame_buf, mmds_frame_len) =
+            create_arp_request(guest_mac, guest_ip, dst_mac, mmds_ip);
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        let queue_pair = &mut net.pairs[0];
+
+        // A frame held back by the rate limiter, then detoured to MMDS.
+        assert!(Net::write_to_mmds_or_tap(
+            net.mmds_ns.as_mut(),
+            &mut queue_pair.tx_rate_limiter,
+            &mmds_frame_buf[..mmds_frame_len],
+            &mut queue_pair.tap,
+            Some(guest_mac),
+            &net.metrics,
+            &mut net.capture,
+            true,
+        )
+        .unwrap());
+        // A frame with a spoofed MAC address, sent on the tap.
+        assert!(!Net::write_to_mmds_or_tap(
+            net.mmds_ns.as_mut(),
+            &mut queue_pair.tx_rate_limiter,
+            &frame_buf[..frame_len],
+            &mut queue_pair.tap,
+            Some(not_guest_mac),
+            &net.metrics,
+            &mut net.capture,
+            false,
+        )
+        .unwrap());
+        // A frame dropped for being too short.
+        assert!(Net::write_to_mmds_or_tap(
+            net.mmds_ns.as_mut(),
+            &mut queue_pair.tx_rate_limiter,
+            &frame_buf[..vnet_hdr_len() - 1],
+            &mut queue_pair.tap,
+            Some(guest_mac),
+            &net.metrics,
+            &mut net.capture,
+            false,
+        )
+        .is_err());
+
+        net.stop_capture().unwrap();
+        assert!(!net.is_capturing());
+        let frames = read_captured_frames(capture_file.as_path());
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0, 2);
+        assert_eq!(frames[0].1, &mmds_frame_buf[vnet_hdr_len()..mmds_frame_len]);
+        assert_eq!(
+            frames[0].2,
+            vec!["Delayed by the TX rate limiter", "Detoured to MMDS"]
+        );
+        assert_eq!(frames[1].1, &frame_buf[vnet_hdr_len()..frame_len]);
+        assert_eq!(frames[1].2, vec!["Spoofed source MAC address"]);
+        assert_eq!(frames[2].1, &frame_buf[..vnet_hdr_len() - 1]);
+        assert_eq!(frames[2].2, vec!["Dropped: the VNET header is missing"]);
+
+        // The frames received by the guest are captured as inbound.
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        th.net().start_capture(capture_file.as_path()).unwrap();
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, 1000, VIRTQ_DESC_F_WRITE)]);
+        let frame = inject_tap_tx_frame(&th.net(), 200);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.rxq.used.idx.get(), 1);
+
+        let frames = read_captured_frames(capture_file.as_path());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, 1);
+        assert_eq!(frames[0].1, &frame[vnet_hdr_len()..]);
+        assert!(frames[0].2.is_empty());
+    }
+
     #[test]
     fn test_process_error_cases() {
         let mut th = TestHelper::default();
@@ -1523,7 +2154,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1544,7 +2175,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().pairs[0].rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
             &METRICS.net.event_fails,
@@ -1558,7 +2189,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         th.simulate_event(NetEvent::TxRateLimiter);
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
@@ -1581,7 +2212,7 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of bandwidth rate limiting
@@ -1591,7 +2222,7 @@ pub mod tests {
                 th.simulate_event(NetEvent::TxQueue);
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().pairs[0].tx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.tx_rate_limiter_throttled.count(), 1);
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
@@ -1610,7 +2241,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1624,10 +2255,10 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1636,9 +2267,9 @@ pub mod tests {
                 th.simulate_event(NetEvent::Tap);
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().pairs[0].rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 check_used_queue_signal(&th.net(), 1);
                 // make sure the data is still queued for processing
@@ -1659,7 +2290,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::RxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().rx_rate_limiter.is_blocked());
+                assert!(!th.net().pairs[0].rx_rate_limiter.is_blocked());
                 // make sure the virtio queue operation completed this time
                 check_used_queue_signal(&th.net(), 1);
                 // make sure the data queue advanced
@@ -1683,7 +2314,7 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of ops rate limiting
@@ -1697,7 +2328,7 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
             }
@@ -1715,7 +2346,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1729,10 +2360,10 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1745,9 +2376,9 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().pairs[0].rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 check_used_queue_signal(&th.net(), 1);
                 // make sure the data is still queued for processing
@@ -1784,40 +2415,105 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
-        th.net().tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().pairs[0].rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().pairs[0].tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
 
         let rx_bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
         let rx_ops = TokenBucket::new(1003, 1004, 1005).unwrap();
         let tx_bytes = TokenBucket::new(1006, 1007, 1008).unwrap();
         let tx_ops = TokenBucket::new(1009, 1010, 1011).unwrap();
 
-        th.net().patch_rate_limiters(
-            BucketUpdate::Update(rx_bytes.clone()),
-            BucketUpdate::Update(rx_ops.clone()),
-            BucketUpdate::Update(tx_bytes.clone()),
-            BucketUpdate::Update(tx_ops.clone()),
-        );
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Update(rx_bytes.clone()),
+                BucketUpdate::Update(rx_ops.clone()),
+                BucketUpdate::Update(tx_bytes.clone()),
+                BucketUpdate::Update(tx_ops.clone()),
+            )
+            .unwrap();
         let compare_buckets = |a: &TokenBucket, b: &TokenBucket| {
             assert_eq!(a.capacity(), b.capacity());
             assert_eq!(a.one_time_burst(), b.one_time_burst());
             assert_eq!(a.refill_time_ms(), b.refill_time_ms());
         };
-        compare_buckets(th.net().rx_rate_limiter.bandwidth().unwrap(), &rx_bytes);
-        compare_buckets(th.net().rx_rate_limiter.ops().unwrap(), &rx_ops);
-        compare_buckets(th.net().tx_rate_limiter.bandwidth().unwrap(), &tx_bytes);
-        compare_buckets(th.net().tx_rate_limiter.ops().unwrap(), &tx_ops);
-
-        th.net().patch_rate_limiters(
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
+        compare_buckets(
+            th.net().pairs[0].rx_rate_limiter.bandwidth().unwrap(),
+            &rx_bytes,
         );
-        assert!(th.net().rx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().rx_rate_limiter.ops().is_none());
-        assert!(th.net().tx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().tx_rate_limiter.ops().is_none());
+        compare_buckets(th.net().pairs[0].rx_rate_limiter.ops().unwrap(), &rx_ops);
+        compare_buckets(
+            th.net().pairs[0].tx_rate_limiter.bandwidth().unwrap(),
+            &tx_bytes,
+        );
+        compare_buckets(th.net().pairs[0].tx_rate_limiter.ops().unwrap(), &tx_ops);
+
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+            )
+            .unwrap();
+        assert!(th.net().pairs[0].rx_rate_limiter.bandwidth().is_none());
+        assert!(th.net().pairs[0].rx_rate_limiter.ops().is_none());
+        assert!(th.net().pairs[0].tx_rate_limiter.bandwidth().is_none());
+        assert!(th.net().pairs[0].tx_rate_limiter.ops().is_none());
+    }
+
+    #[test]
+    fn test_vhost() {
+        let mut th = TestHelper::default();
+        th.net().enable_vhost().unwrap();
+        assert!(th.net().is_vhost());
+        // The frames moved by the kernel never go through the rate limiters.
+        assert!(th
+            .net()
+            .patch_rate_limiters(
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+            )
+            .is_err());
+        // Neither can they be captured.
+        assert!(matches!(
+            th.net().start_capture(Path::new("capture.pcapng")),
+            Err(Error::CaptureVhost)
+        ));
+
+        let features = th.net().avail_features();
+        th.net().set_acked_features(features);
+        th.activate_net();
+        assert!(th.net().is_vhost());
+
+        // The kernel is directly kicked, and signals the used descriptors through the call
+        // events of the device.
+        th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1000, 0)]);
+        check_metric_after_block!(
+            &METRICS.net.vhost_call_event_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.txq.used.idx.get(), 1);
+        check_used_queue_signal(&th.net(), 1);
+        // The device itself did not process the queue.
+        assert_eq!(th.net().queues[TX_INDEX].next_avail(), 0);
+
+        // The position the kernel reached in the queues is saved with the device.
+        th.net().prepare_save();
+        assert_eq!(th.net().queues[RX_INDEX].next_avail(), 0);
+        assert_eq!(th.net().queues[TX_INDEX].next_avail(), 1);
+
+        // The stopped kernel driver resumes once the queues are processed again.
+        th.net().process_virtio_queues();
+        th.add_desc_chain(NetQueue::Tx, 0, &[(1, 1000, 0)]);
+        check_metric_after_block!(
+            &METRICS.net.vhost_call_event_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.txq.used.idx.get(), 2);
     }
 
     #[test]
@@ -8,14 +8,23 @@ use polly::event_manager::{EventManager, Subscriber};
 use utils::epoll::{EpollEvent, EventSet};
 
 use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::{VirtioDevice, RX_INDEX};
 
 impl Net {
-    fn process_activate_event(&self, event_manager: &mut EventManager) {
+    fn process_activate_event(&mut self, event_manager: &mut EventManager) {
         debug!("net: activate event");
         if let Err(e) = self.activate_evt.read() {
             error!("Failed to consume net activate event: {:?}", e);
         }
+        if self.is_vhost() {
+            if let Err(e) = self.start_vhost() {
+                error!(
+                    "Failed to set up vhost-net, falling back to the userspace path: {:?}",
+                    e
+                );
+                METRICS.net.activate_fails.inc();
+            }
+        }
         let activate_fd = self.activate_evt.as_raw_fd();
         // The subscriber must exist as we previously registered activate_evt via
         // `interest_list()`.
@@ -60,25 +69,60 @@ impl Subscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
-            let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
+            // The pairs served by vhost-net signal the used descriptors through their own
+            // call events.
+            if let Some((pair, queue)) = self.pairs.iter().enumerate().find_map(|(index, pair)| {
+                pair.vhost
+                    .as_ref()?
+                    .call_evts
+                    .iter()
+                    .position(|call_evt| call_evt.as_raw_fd() == source)
+                    .map(|queue| (index, queue))
+            }) {
+                self.process_vhost_call_event(pair, queue);
+                return;
+            }
+            // Each queue has its own event.
+            if let Some(queue_index) = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source)
+            {
+                if Some(queue_index) == self.ctrl_queue_index() {
+                    self.process_ctrl_queue_event(queue_index);
+                } else if queue_index % 2 == RX_INDEX {
+                    self.process_rx_queue_event(queue_index / 2);
+                } else {
+                    self.process_tx_queue_event(queue_index / 2);
+                }
+                return;
+            }
             let activate_fd = self.activate_evt.as_raw_fd();
 
-            // Looks better than C style if/else if/else.
-            match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
-                _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
-                _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
-                _ if activate_fd == source => self.process_activate_event(evmgr),
-                _ => {
-                    warn!("Net: Spurious event received: {:?}", source);
-                    METRICS.net.event_fails.inc();
-                }
+            // Each queue pair has its own tap queue and rate limiters.
+            if let Some(pair) = self
+                .pairs
+                .iter()
+                .position(|pair| pair.tap.as_raw_fd() == source)
+            {
+                self.process_tap_rx_event(pair);
+            } else if let Some(pair) = self
+                .pairs
+                .iter()
+                .position(|pair| pair.rx_rate_limiter.as_raw_fd() == source)
+            {
+                self.process_rx_rate_limiter_event(pair);
+            } else if let Some(pair) = self
+                .pairs
+                .iter()
+                .position(|pair| pair.tx_rate_limiter.as_raw_fd() == source)
+            {
+                self.process_tx_rate_limiter_event(pair);
+            } else if activate_fd == source {
+                self.process_activate_event(evmgr);
+            } else {
+                warn!("Net: Spurious event received: {:?}", source);
+                METRICS.net.event_fails.inc();
             }
         } else {
             warn!(
@@ -93,17 +137,44 @@ impl Subscriber for Net {
         //  - shortly after device creation,
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
-        if self.is_activated() {
-            vec![
-                EpollEvent::new(EventSet::IN, self.queue_evts[RX_INDEX].as_raw_fd() as u64),
-                EpollEvent::new(EventSet::IN, self.queue_evts[TX_INDEX].as_raw_fd() as u64),
-                EpollEvent::new(EventSet::IN, self.rx_rate_limiter.as_raw_fd() as u64),
-                EpollEvent::new(EventSet::IN, self.tx_rate_limiter.as_raw_fd() as u64),
-                EpollEvent::new(
+        if self.is_vhost() && self.is_activated() {
+            // Only the control queue is left to the device, the kernel being directly kicked
+            // through the events of the other queues, and reading from the taps.
+            let mut events: Vec<EpollEvent> = self
+                .pairs
+                .iter()
+                .filter_map(|pair| pair.vhost.as_ref())
+                .flat_map(|vhost| vhost.call_evts.iter())
+                .map(|call_evt| EpollEvent::new(EventSet::IN, call_evt.as_raw_fd() as u64))
+                .collect();
+            if let Some(ctrl_index) = self.ctrl_queue_index() {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    self.queue_evts[ctrl_index].as_raw_fd() as u64,
+                ));
+            }
+            events
+        } else if self.is_activated() {
+            let mut events: Vec<EpollEvent> = self
+                .queue_evts
+                .iter()
+                .map(|queue_evt| EpollEvent::new(EventSet::IN, queue_evt.as_raw_fd() as u64))
+                .collect();
+            for pair in &self.pairs {
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    pair.rx_rate_limiter.as_raw_fd() as u64,
+                ));
+                events.push(EpollEvent::new(
+                    EventSet::IN,
+                    pair.tx_rate_limiter.as_raw_fd() as u64,
+                ));
+                events.push(EpollEvent::new(
                     EventSet::IN | EventSet::EDGE_TRIGGERED,
-                    self.tap.as_raw_fd() as u64,
-                ),
-            ]
+                    pair.tap.as_raw_fd() as u64,
+                ));
+            }
+            events
         } else {
             vec![EpollEvent::new(
                 EventSet::IN,
@@ -11,16 +11,33 @@ pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 pub const RX_INDEX: usize = 0;
 // The index of the tx queue from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
+/// Default number of queue pairs of a network device.
+pub const DEFAULT_NUM_QUEUE_PAIRS: usize = 1;
+/// Maximum number of queue pairs of a network device.
+pub const MAX_NUM_QUEUE_PAIRS: usize = 16;
+
+// The index of the rx queue of a queue pair, in the Net device queues/queues_evts vector.
+pub(crate) fn rx_queue_index(pair: usize) -> usize {
+    2 * pair + RX_INDEX
+}
+
+// The index of the tx queue of a queue pair, in the Net device queues/queues_evts vector.
+pub(crate) fn tx_queue_index(pair: usize) -> usize {
+    2 * pair + TX_INDEX
+}
 
 pub mod device;
 pub mod event_handler;
+mod pcap;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+mod vhost;
 
 pub use self::device::Net;
 pub use self::event_handler::*;
 pub use tap::Error as TapError;
+pub use vhost::Error as VhostNetError;
 
 #[derive(Debug)]
 pub enum Error {
@@ -30,6 +47,8 @@ pub enum Error {
     TapSetOffload(TapError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
+    /// Attaching or detaching a queue of the tap interface failed.
+    TapSetQueue(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
     /// EventFd error.
@@ -38,6 +57,14 @@ pub enum Error {
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// Setting up the vhost-net kernel driver failed.
+    VhostNet(VhostNetError),
+    /// Creating the packet capture file failed.
+    CaptureCreate(io::Error),
+    /// The frames moved by the vhost-net kernel driver cannot be captured.
+    CaptureVhost,
+    /// No packet capture is in progress.
+    CaptureNotStarted,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,217 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Records the frames received and transmitted by a network device in a pcapng file, which
+//! can be read by tools like Wireshark or tcpdump.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use utils::time::{get_time_us, ClockType};
+
+// As defined in the pcapng specification:
+// https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-03.html
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 1;
+const EPB_FLAGS_OUTBOUND: u32 = 2;
+
+/// The direction of a captured frame, from the point of view of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// A frame received by the guest.
+    Rx,
+    /// A frame transmitted by the guest.
+    Tx,
+}
+
+/// Writes the frames of a network device to a pcapng file.
+pub struct PacketCapture {
+    file: File,
+    // The block being written, reused across frames.
+    block: Vec<u8>,
+}
+
+impl PacketCapture {
+    /// Creates the capture file at `path`, replacing any existing file, and describes the
+    /// captured interface as `iface_id`.
+    pub fn new(path: &Path, iface_id: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut capture = PacketCapture {
+            file,
+            block: Vec::new(),
+        };
+
+        capture.start_block(SECTION_HEADER_BLOCK);
+        capture.push_u32(BYTE_ORDER_MAGIC);
+        // Version 1.0 of the format.
+        capture.push_u16(1);
+        capture.push_u16(0);
+        // The length of the section is not specified.
+        capture.block.extend_from_slice(&(-1i64).to_le_bytes());
+        capture.write_block()?;
+
+        capture.start_block(INTERFACE_DESCRIPTION_BLOCK);
+        capture.push_u16(LINKTYPE_ETHERNET);
+        // Reserved.
+        capture.push_u16(0);
+        // The frames are not truncated.
+        capture.push_u32(0);
+        capture.push_option(IF_NAME, iface_id.as_bytes());
+        capture.push_option(OPT_ENDOFOPT, &[]);
+        capture.write_block()?;
+
+        Ok(capture)
+    }
+
+    /// Writes a frame, without its VNET header, annotated with the given comments.
+    pub fn write_frame(
+        &mut self,
+        direction: Direction,
+        frame: &[u8],
+        comments: &[&str],
+    ) -> io::Result<()> {
+        // The timestamps default to a resolution of a microsecond.
+        let timestamp = get_time_us(ClockType::Real);
+
+        self.start_block(ENHANCED_PACKET_BLOCK);
+        // The only interface of the section.
+        self.push_u32(0);
+        self.push_u32((timestamp >> 32) as u32);
+        self.push_u32(timestamp as u32);
+        self.push_u32(frame.len() as u32);
+        self.push_u32(frame.len() as u32);
+        self.push_padded(frame);
+
+        let flags = match direction {
+            Direction::Rx => EPB_FLAGS_INBOUND,
+            Direction::Tx => EPB_FLAGS_OUTBOUND,
+        };
+        self.push_option(EPB_FLAGS, &flags.to_le_bytes());
+        for comment in comments {
+            self.push_option(OPT_COMMENT, comment.as_bytes());
+        }
+        self.push_option(OPT_ENDOFOPT, &[]);
+        self.write_block()
+    }
+
+    fn start_block(&mut self, block_type: u32) {
+        self.block.clear();
+        self.push_u32(block_type);
+        // The total length of the block is filled in by `write_block()`.
+        self.push_u32(0);
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        // The total length is repeated at the end of the block.
+        let len = (self.block.len() + 4) as u32;
+        self.block[4..8].copy_from_slice(&len.to_le_bytes());
+        self.push_u32(len);
+        self.file.write_all(&self.block)
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.block.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.block.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Pushes the bytes, padded to a multiple of 4 bytes.
+    fn push_padded(&mut self, bytes: &[u8]) {
+        self.block.extend_from_slice(bytes);
+        let padding = (4 - bytes.len() % 4) % 4;
+        self.block.extend_from_slice(&[0u8; 3][..padding]);
+    }
+
+    fn push_option(&mut self, code: u16, value: &[u8]) {
+        self.push_u16(code);
+        self.push_u16(value.len() as u16);
+        self.push_padded(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::byte_order::{read_le_u16, read_le_u32};
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_packet_capture() {
+        let file = TempFile::new().unwrap();
+        let mut capture = PacketCapture::new(file.as_path(), "eth0").unwrap();
+        capture
+            .write_frame(Direction::Rx, &[0xAA; 14], &["Sent by MMDS"])
+            .unwrap();
+        capture
+            .write_frame(Direction::Tx, &[0xBB; 16], &[])
+            .unwrap();
+
+        let bytes = fs::read(file.as_path()).unwrap();
+
+        // Section header block.
+        assert_eq!(read_le_u32(&bytes[0..]), SECTION_HEADER_BLOCK);
+        assert_eq!(read_le_u32(&bytes[4..]), 28);
+        assert_eq!(read_le_u32(&bytes[8..]), BYTE_ORDER_MAGIC);
+        assert_eq!(read_le_u32(&bytes[24..]), 28);
+        let bytes = &bytes[28..];
+
+        // Interface description block, naming the interface.
+        assert_eq!(read_le_u32(&bytes[0..]), INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(read_le_u32(&bytes[4..]), 32);
+        assert_eq!(read_le_u16(&bytes[8..]), LINKTYPE_ETHERNET);
+        assert_eq!(read_le_u16(&bytes[16..]), IF_NAME);
+        assert_eq!(read_le_u16(&bytes[18..]), 4);
+        assert_eq!(&bytes[20..24], b"eth0");
+        assert_eq!(read_le_u32(&bytes[24..]), 0);
+        assert_eq!(read_le_u32(&bytes[28..]), 32);
+        let bytes = &bytes[32..];
+
+        // Inbound frame, padded to 16 bytes, with its comment.
+        let len = 28 + 16 + 8 + 4 + 12 + 4 + 4;
+        assert_eq!(read_le_u32(&bytes[0..]), ENHANCED_PACKET_BLOCK);
+        assert_eq!(read_le_u32(&bytes[4..]) as usize, len);
+        assert_eq!(read_le_u32(&bytes[20..]), 14);
+        assert_eq!(read_le_u32(&bytes[24..]), 14);
+        assert_eq!(&bytes[28..42], &[0xAA; 14]);
+        assert_eq!(&bytes[42..44], &[0; 2]);
+        assert_eq!(read_le_u16(&bytes[44..]), EPB_FLAGS);
+        assert_eq!(read_le_u32(&bytes[48..]), EPB_FLAGS_INBOUND);
+        assert_eq!(read_le_u16(&bytes[52..]), OPT_COMMENT);
+        assert_eq!(read_le_u16(&bytes[54..]), 12);
+        assert_eq!(&bytes[56..68], b"Sent by MMDS");
+        assert_eq!(read_le_u32(&bytes[68..]), 0);
+        assert_eq!(read_le_u32(&bytes[len - 4..]) as usize, len);
+        let bytes = &bytes[len..];
+
+        // Outbound frame, without comments.
+        let len = 28 + 16 + 8 + 4 + 4;
+        assert_eq!(read_le_u32(&bytes[0..]), ENHANCED_PACKET_BLOCK);
+        assert_eq!(read_le_u32(&bytes[4..]) as usize, len);
+        assert_eq!(&bytes[28..44], &[0xBB; 16]);
+        assert_eq!(read_le_u32(&bytes[48..]), EPB_FLAGS_OUTBOUND);
+        assert_eq!(bytes.len(), len);
+
+        // The capture file is replaced.
+        PacketCapture::new(file.as_path(), "eth0").unwrap();
+        assert_eq!(fs::metadata(file.as_path()).unwrap().len(), 28 + 32);
+    }
+}
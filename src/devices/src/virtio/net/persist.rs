@@ -7,27 +7,36 @@ use std::io;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
+use logger::error;
 use mmds::{ns::MmdsNetworkStack, persist::MmdsNetworkStackState};
 use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use serde::Serialize;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
-use super::device::{ConfigSpace, Net};
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::Net;
+use super::QUEUE_SIZE;
 
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct QueuePairState {
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+}
+
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
     id: String,
@@ -37,16 +46,40 @@ pub struct NetState {
     mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    // The rate limiters of the first queue pair are saved above.
+    #[version(start = 2, default_fn = "default_extra_queue_pairs")]
+    extra_queue_pairs: Vec<QueuePairState>,
+    #[version(start = 2, default_fn = "default_active_pairs")]
+    active_pairs: usize,
+    #[version(start = 2, default_fn = "default_vhost")]
+    vhost: bool,
+}
+
+impl NetState {
+    fn default_extra_queue_pairs(_: u16) -> Vec<QueuePairState> {
+        Vec::new()
+    }
+
+    fn default_active_pairs(_: u16) -> usize {
+        1
+    }
+
+    fn default_vhost(_: u16) -> bool {
+        false
+    }
 }
 
 pub struct NetConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// Tap device to restore the device with, instead of the saved one.
+    pub tap_if_name: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum Error {
     CreateNet(super::Error),
     CreateRateLimiter(io::Error),
+    InvalidActivePairs(usize),
     VirtioState(VirtioStateError),
 }
 
@@ -58,14 +91,23 @@ impl Persist<'_> for Net {
     fn save(&self) -> Self::State {
         NetState {
             id: self.id().clone(),
-            tap_if_name: self.tap.if_name_as_str().to_string(),
-            rx_rate_limiter_state: self.rx_rate_limiter.save(),
-            tx_rate_limiter_state: self.tx_rate_limiter.save(),
+            tap_if_name: self.pairs[0].tap.if_name_as_str().to_string(),
+            rx_rate_limiter_state: self.pairs[0].rx_rate_limiter.save(),
+            tx_rate_limiter_state: self.pairs[0].tx_rate_limiter.save(),
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac: self.config_space.guest_mac,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            extra_queue_pairs: self.pairs[1..]
+                .iter()
+                .map(|pair| QueuePairState {
+                    rx_rate_limiter_state: pair.rx_rate_limiter.save(),
+                    tx_rate_limiter_state: pair.tx_rate_limiter.save(),
+                })
+                .collect(),
+            active_pairs: self.active_pairs,
+            vhost: self.is_vhost(),
         }
     }
 
@@ -74,16 +116,33 @@ impl Persist<'_> for Net {
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
         // RateLimiter::restore() can fail at creating a timerfd.
-        let rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)
-            .map_err(Error::CreateRateLimiter)?;
-        let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)
-            .map_err(Error::CreateRateLimiter)?;
+        let restore_rate_limiters =
+            |rx_state: &RateLimiterState,
+             tx_state: &RateLimiterState|
+             -> std::result::Result<(RateLimiter, RateLimiter), Error> {
+                Ok((
+                    RateLimiter::restore((), rx_state).map_err(Error::CreateRateLimiter)?,
+                    RateLimiter::restore((), tx_state).map_err(Error::CreateRateLimiter)?,
+                ))
+            };
+        let mut rate_limiters = vec![restore_rate_limiters(
+            &state.rx_rate_limiter_state,
+            &state.tx_rate_limiter_state,
+        )?];
+        for pair_state in &state.extra_queue_pairs {
+            rate_limiters.push(restore_rate_limiters(
+                &pair_state.rx_rate_limiter_state,
+                &pair_state.tx_rate_limiter_state,
+            )?);
+        }
+        let tap_if_name = constructor_args
+            .tap_if_name
+            .unwrap_or_else(|| state.tap_if_name.clone());
         let mut net = Net::new_with_tap(
             state.id.clone(),
-            state.tap_if_name.clone(),
+            tap_if_name,
             None,
-            rx_rate_limiter,
-            tx_rate_limiter,
+            rate_limiters,
             state.mmds_ns.is_some(),
         )
         .map_err(Error::CreateNet)?;
@@ -96,21 +155,41 @@ impl Persist<'_> for Net {
 
         net.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_NET, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_NET,
+                net.queues.len(),
+                QUEUE_SIZE,
+            )
             .map_err(Error::VirtioState)?;
         net.interrupt_status = Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         net.avail_features = state.virtio_state.avail_features;
         net.acked_features = state.virtio_state.acked_features;
-        net.config_space = ConfigSpace {
-            guest_mac: state.config_space.guest_mac,
-        };
+        net.config_space.guest_mac = state.config_space.guest_mac;
 
         net.guest_mac = Some(MacAddr::from_bytes_unchecked(
             &state.config_space.guest_mac[..MAC_ADDR_LEN],
         ));
 
+        if state.active_pairs == 0 || state.active_pairs > net.pairs.len() {
+            return Err(Error::InvalidActivePairs(state.active_pairs));
+        }
+        net.set_active_pairs(state.active_pairs)
+            .map_err(Error::CreateNet)?;
+
+        if state.vhost {
+            net.enable_vhost().map_err(Error::CreateNet)?;
+        }
         if state.virtio_state.activated {
             net.device_state = DeviceState::Activated(constructor_args.mem);
+            if net.is_vhost() {
+                if let Err(e) = net.start_vhost() {
+                    error!(
+                        "Failed to set up vhost-net, falling back to the userspace path: {:?}",
+                        e
+                    );
+                }
+            }
         }
 
         Ok(net)
@@ -122,7 +201,9 @@ mod tests {
     use super::*;
     use crate::virtio::device::VirtioDevice;
 
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net};
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_multi_queue_net, default_net,
+    };
     use std::sync::atomic::Ordering;
 
     #[test]
@@ -146,7 +227,7 @@ mod tests {
 
             // Save some fields that we want to check later.
             id = net.id.clone();
-            tap_if_name = net.tap.if_name_as_str().to_string();
+            tap_if_name = net.pairs[0].tap.if_name_as_str().to_string();
             allow_mmds_requests = net.mmds_ns.is_some();
             virtio_state = VirtioDeviceState::from_device(&net);
         }
@@ -154,7 +235,10 @@ mod tests {
         // Deserialize and restore the net device.
         {
             let restored_net = Net::restore(
-                NetConstructorArgs { mem: guest_mem },
+                NetConstructorArgs {
+                    mem: guest_mem,
+                    tap_if_name: None,
+                },
                 &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
             )
             .unwrap();
@@ -171,10 +255,60 @@ mod tests {
 
             // Test that net specific fields are the same.
             assert_eq!(&restored_net.id, &id);
-            assert_eq!(&restored_net.tap.if_name_as_str(), &tap_if_name);
+            assert_eq!(&restored_net.pairs[0].tap.if_name_as_str(), &tap_if_name);
             assert_eq!(restored_net.mmds_ns.is_some(), allow_mmds_requests);
-            assert_eq!(restored_net.rx_rate_limiter, RateLimiter::default());
-            assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
+            assert_eq!(
+                restored_net.pairs[0].rx_rate_limiter,
+                RateLimiter::default()
+            );
+            assert_eq!(
+                restored_net.pairs[0].tx_rate_limiter,
+                RateLimiter::default()
+            );
+            assert_eq!(restored_net.num_queue_pairs(), 1);
+            assert_eq!(restored_net.active_pairs, 1);
+        }
+    }
+
+    #[test]
+    fn test_multi_queue_persistence() {
+        let guest_mem = default_guest_memory();
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // Create and save a net device with 2 active queue pairs.
+        {
+            let mut net = default_multi_queue_net(2);
+            net.set_active_pairs(2).unwrap();
+            net.pairs[1].tx_rate_limiter = RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap();
+
+            <Net as Persist>::save(&net)
+                .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+                .unwrap();
         }
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: guest_mem,
+                tap_if_name: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_net.num_queue_pairs(), 2);
+        assert_eq!(restored_net.queues().len(), 5);
+        assert_eq!(restored_net.active_pairs, 2);
+        assert_eq!(restored_net.config_space.max_virtqueue_pairs, 2);
+        assert_eq!(
+            restored_net.pairs[1].rx_rate_limiter,
+            RateLimiter::default()
+        );
+        let ops = restored_net.pairs[1].tx_rate_limiter.ops().unwrap();
+        assert_eq!(ops.capacity(), 10);
+        assert_eq!(ops.refill_time_ms(), 100);
     }
 }
@@ -36,6 +36,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -105,6 +106,33 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Self::open(
+            if_name,
+            net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR,
+        )
+    }
+
+    /// Open `num_queues` queues of a multi-queue TUN/TAP device given the interface name.
+    /// The device must have been created with the `multi_queue` flag.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_named_queues(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        (0..num_queues)
+            .map(|_| {
+                Self::open(
+                    if_name,
+                    net_gen::IFF_TAP
+                        | net_gen::IFF_NO_PI
+                        | net_gen::IFF_VNET_HDR
+                        | net_gen::IFF_MULTI_QUEUE,
+                )
+            })
+            .collect()
+    }
+
+    fn open(if_name: &str, flags: c_uint) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -123,7 +151,7 @@ impl Tap {
 
         let ifreq = IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .flags(flags as i16)
             .execute(&tuntap, TUNSETIFF())?;
 
         // Safe since only the name is accessed, and it's cloned out.
@@ -163,6 +191,21 @@ impl Tap {
 
         Ok(())
     }
+
+    /// Attach or detach this queue of a multi-queue tap interface. The kernel only
+    /// delivers received frames to the attached queues.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
 }
 
 impl Read for Tap {
@@ -237,6 +280,22 @@ pub mod tests {
         Tap::open_named("exclusivetap").unwrap_err();
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let taps = Tap::open_named_queues("multiqtap", 2).unwrap();
+        assert_eq!(taps.len(), 2);
+        assert_eq!(taps[0].if_name_as_str(), "multiqtap");
+        assert_eq!(taps[1].if_name_as_str(), "multiqtap");
+        assert_ne!(taps[0].as_raw_fd(), taps[1].as_raw_fd());
+
+        taps[1].set_queue_enabled(false).unwrap();
+        taps[1].set_queue_enabled(true).unwrap();
+
+        // A single queue tap cannot be attached or detached.
+        let tap = Tap::open_named("").unwrap();
+        assert!(tap.set_queue_enabled(false).is_err());
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
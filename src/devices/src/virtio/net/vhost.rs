@@ -0,0 +1,273 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hands the queues of a queue pair over to the `vhost-net` kernel driver, which moves the
+//! frames between the guest memory and a queue of the tap interface without going through
+//! the VMM.
+
+use std::fs::{File, OpenOptions};
+use std::io::Error as IoError;
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use utils::eventfd::EventFd;
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+use virtio_gen::virtio_net::VIRTIO_F_VERSION_1;
+use vm_memory::{
+    ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress,
+};
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::net::{NUM_QUEUES, RX_INDEX, TX_INDEX};
+use crate::virtio::vhost_user::{MemoryRegion, MemoryTableHeader, VringAddr, VringState};
+use crate::virtio::Queue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+// As defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v4.14/source/include/uapi/linux/vhost.h
+const VHOST_VIRTIO: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, MemoryTableHeader);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST_VIRTIO, 0x12, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, VringFile);
+
+/// List of errors the vhost-net implementation can throw.
+#[derive(Debug)]
+pub enum Error {
+    /// Unable to create an event fd.
+    EventFd(IoError),
+    /// A guest memory region is not mapped in this process.
+    InvalidMemoryRegion(u64),
+    /// A ring of a queue lies outside the guest memory.
+    InvalidRingAddress(u64),
+    /// ioctl failed.
+    IoctlError(IoError),
+    /// The kernel driver lacks a feature the device requires.
+    MissingFeature(&'static str),
+    /// Couldn't open /dev/vhost-net.
+    OpenVhostNet(IoError),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Payload of `VHOST_SET_VRING_KICK`, `VHOST_SET_VRING_CALL` and `VHOST_NET_SET_BACKEND`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VringFile {
+    index: u32,
+    fd: RawFd,
+}
+
+fn ioctl_result(ret: c_int) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::IoctlError(IoError::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Handle for a `vhost-net` instance, serving the RX and TX queues of a queue pair.
+///
+/// The queues are numbered as in a single queue pair device within the instance. The kernel
+/// driver is directly kicked through the queue events, and signals the descriptors it used
+/// through the call events, which the device turns into interrupts.
+pub struct VhostNet {
+    file: File,
+    features: u64,
+    pub(crate) call_evts: Vec<EventFd>,
+}
+
+impl VhostNet {
+    /// Opens a new `vhost-net` instance and claims it for this process.
+    pub fn new() -> Result<VhostNet> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::OpenVhostNet)?;
+
+        // Safe because we know that our file is a vhost fd and we verify the return result.
+        ioctl_result(unsafe { ioctl(&file, VHOST_SET_OWNER()) })?;
+        let mut features = 0u64;
+        // Safe because we know that our file is a vhost fd, the kernel only writes the
+        // features, and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_mut_ref(&file, VHOST_GET_FEATURES(), &mut features) })?;
+        // The frames carry the virtio 1.0 header, which the tap interface handles.
+        if features & (1u64 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::MissingFeature("VIRTIO_F_VERSION_1"));
+        }
+
+        let mut call_evts = Vec::with_capacity(NUM_QUEUES);
+        for _ in 0..NUM_QUEUES {
+            call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+
+        Ok(VhostNet {
+            file,
+            features,
+            call_evts,
+        })
+    }
+
+    /// Hands the RX and TX `queues` set up by the driver over to the kernel driver, which
+    /// starts moving frames between them and `tap`. Only the features of `acked_features`
+    /// the kernel driver knows about are passed on, the others being handled by `tap`.
+    pub fn start(
+        &self,
+        acked_features: u64,
+        mem: &GuestMemoryMmap,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        tap: &Tap,
+    ) -> Result<()> {
+        let features = acked_features & self.features;
+        // Safe because we know that our file is a vhost fd and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })?;
+        self.set_mem_table(mem)?;
+
+        for (index, (queue, queue_evt)) in queues.iter().zip(queue_evts.iter()).enumerate() {
+            self.set_vring_state(VHOST_SET_VRING_NUM(), index, u32::from(queue.actual_size()))?;
+            self.set_vring_addr(index, queue, mem)?;
+            self.set_vring_state(VHOST_SET_VRING_BASE(), index, u32::from(queue.next_avail()))?;
+            self.set_vring_file(VHOST_SET_VRING_KICK(), index, queue_evt.as_raw_fd())?;
+            self.set_vring_file(
+                VHOST_SET_VRING_CALL(),
+                index,
+                self.call_evts[index].as_raw_fd(),
+            )?;
+        }
+        self.set_backend(Some(tap))
+    }
+
+    /// Stops the kernel driver and saves the position it reached in each of the `queues`.
+    ///
+    /// The kernel driver uses the descriptor chains right after popping them, so none is
+    /// in flight once it is stopped.
+    pub fn stop(&self, queues: &mut [Queue]) -> Result<()> {
+        self.set_backend(None)?;
+        for (index, queue) in queues.iter_mut().enumerate() {
+            let mut state = VringState {
+                index: index as u32,
+                num: 0,
+            };
+            // Safe because we know that our file is a vhost fd, the kernel only writes the
+            // state, and we verify the return result.
+            ioctl_result(unsafe {
+                ioctl_with_mut_ref(&self.file, VHOST_GET_VRING_BASE(), &mut state)
+            })?;
+            queue.set_next_avail(state.num as u16);
+        }
+        Ok(())
+    }
+
+    /// Lets the kernel driver resume moving frames between the queues and `tap`. Does nothing
+    /// if it was not stopped.
+    pub fn resume(&self, tap: &Tap) -> Result<()> {
+        self.set_backend(Some(tap))
+    }
+
+    // Attaches the queues to `tap`, or detaches them when `tap` is `None`, which waits for
+    // the kernel driver to stop processing them.
+    fn set_backend(&self, tap: Option<&Tap>) -> Result<()> {
+        let fd = tap.map_or(-1, AsRawFd::as_raw_fd);
+        for index in &[RX_INDEX, TX_INDEX] {
+            self.set_vring_file(VHOST_NET_SET_BACKEND(), *index, fd)?;
+        }
+        Ok(())
+    }
+
+    // Unlike the vhost-user backends, the kernel driver accesses the guest memory through
+    // the mappings of this process.
+    fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let header = MemoryTableHeader {
+            num_regions: mem.num_regions() as u32,
+            padding: 0,
+        };
+        let mut table = header.as_slice().to_vec();
+        mem.with_regions_mut(|_, region| {
+            let userspace_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(|_| Error::InvalidMemoryRegion(region.start_addr().0))?;
+            let table_region = MemoryRegion {
+                guest_phys_addr: region.start_addr().0,
+                memory_size: region.len(),
+                userspace_addr: userspace_addr as u64,
+                // The kernel driver ignores the last field of the regions.
+                mmap_offset: 0,
+            };
+            table.extend_from_slice(table_region.as_slice());
+            Ok(())
+        })?;
+
+        // Safe because we know that our file is a vhost fd, the kernel only reads the
+        // `num_regions` regions following the header, and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_ptr(&self.file, VHOST_SET_MEM_TABLE(), table.as_ptr()) })
+    }
+
+    fn set_vring_addr(&self, index: usize, queue: &Queue, mem: &GuestMemoryMmap) -> Result<()> {
+        let host_address = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|addr| addr as u64)
+                .map_err(|_| Error::InvalidRingAddress(addr.0))
+        };
+        let addr = VringAddr {
+            index: index as u32,
+            flags: 0,
+            descriptor: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            available: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+        // Safe because we know that our file is a vhost fd and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) })
+    }
+
+    fn set_vring_state(&self, request: c_ulong, index: usize, num: u32) -> Result<()> {
+        let state = VringState {
+            index: index as u32,
+            num,
+        };
+        // Safe because we know that our file is a vhost fd and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, request, &state) })
+    }
+
+    fn set_vring_file(&self, request: c_ulong, index: usize, fd: RawFd) -> Result<()> {
+        let file = VringFile {
+            index: index as u32,
+            fd,
+        };
+        // Safe because we know that our file is a vhost fd and we verify the return result.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, request, &file) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // As computed by the C macros of the Linux UAPI.
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_af10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(VHOST_GET_VRING_BASE(), 0xc008_af12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_af20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_af21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+}
@@ -344,6 +344,18 @@ impl Queue {
         self.next_avail -= Wrapping(1);
     }
 
+    /// Returns the position, in the avail ring, of the next descriptor chain to pop.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Sets the position of the next descriptor chain to pop, and of the next used ring entry.
+    /// Used when a queue is handed over while no descriptor chain is in flight.
+    pub fn set_next_avail(&mut self, next_avail: u16) {
+        self.next_avail = Wrapping(next_avail);
+        self.next_used = Wrapping(next_avail);
+    }
+
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(
         &mut self,
@@ -0,0 +1,408 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use utils::eventfd::EventFd;
+use vm_memory::{
+    ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress,
+};
+
+use super::{
+    recv_reply, request, send_message, ConfigHeader, Error, MemoryRegion, MemoryTableHeader,
+    Result, VringAddr, VringState, MAX_MEMORY_REGIONS,
+};
+use crate::virtio::Queue;
+
+/// The frontend end of a vhost-user connection, which sends requests to the backend.
+pub struct Frontend {
+    stream: UnixStream,
+}
+
+impl Frontend {
+    /// Connects to the backend listening on `socket_path`.
+    pub fn connect(socket_path: &str) -> Result<Self> {
+        let stream = UnixStream::connect(socket_path).map_err(Error::Connect)?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Creates a frontend from a connected stream.
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Frontend { stream }
+    }
+
+    fn send_request(&self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        send_message(&self.stream, request, 0, payload, fds)
+    }
+
+    fn get_u64(&self, request: u32) -> Result<u64> {
+        self.send_request(request, &[], &[])?;
+        let reply = recv_reply(&self.stream, request)?;
+        u64::from_slice(&reply)
+            .copied()
+            .ok_or(Error::InvalidMessage)
+    }
+
+    fn set_u64(&self, request: u32, value: u64) -> Result<()> {
+        self.send_request(request, value.as_slice(), &[])
+    }
+
+    fn set_vring_state(&self, request: u32, index: usize, num: u32) -> Result<()> {
+        let state = VringState {
+            index: index as u32,
+            num,
+        };
+        self.send_request(request, state.as_slice(), &[])
+    }
+
+    /// Claims the backend for this frontend.
+    pub fn set_owner(&self) -> Result<()> {
+        self.send_request(request::SET_OWNER, &[], &[])
+    }
+
+    /// Returns the virtio features offered by the backend.
+    pub fn get_features(&self) -> Result<u64> {
+        self.get_u64(request::GET_FEATURES)
+    }
+
+    /// Sets the virtio features acknowledged by the driver.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        self.set_u64(request::SET_FEATURES, features)
+    }
+
+    /// Returns the protocol features offered by the backend.
+    pub fn get_protocol_features(&self) -> Result<u64> {
+        self.get_u64(request::GET_PROTOCOL_FEATURES)
+    }
+
+    /// Sets the protocol features used by the frontend.
+    pub fn set_protocol_features(&self, features: u64) -> Result<()> {
+        self.set_u64(request::SET_PROTOCOL_FEATURES, features)
+    }
+
+    /// Returns the maximum number of queues of the backend.
+    pub fn get_queue_num(&self) -> Result<u64> {
+        self.get_u64(request::GET_QUEUE_NUM)
+    }
+
+    /// Returns `size` bytes of the configuration space of the backend, starting at `offset`.
+    pub fn get_config(&self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        let header = ConfigHeader {
+            offset,
+            size,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.resize(payload.len() + size as usize, 0);
+        self.send_request(request::GET_CONFIG, &payload, &[])?;
+
+        let reply = recv_reply(&self.stream, request::GET_CONFIG)?;
+        if reply.len() != payload.len() {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(reply[std::mem::size_of::<ConfigHeader>()..].to_vec())
+    }
+
+    /// Writes `data` to the configuration space of the backend, at `offset`.
+    pub fn set_config(&self, offset: u32, data: &[u8]) -> Result<()> {
+        let header = ConfigHeader {
+            offset,
+            size: data.len() as u32,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.extend_from_slice(data);
+        self.send_request(request::SET_CONFIG, &payload, &[])
+    }
+
+    /// Shares the guest memory with the backend. Every region must be mapped from a file.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(num_regions));
+        }
+
+        let mut regions = Vec::with_capacity(num_regions);
+        let mut fds = Vec::with_capacity(num_regions);
+        mem.with_regions_mut(|_, region| {
+            let file_offset = region.file_offset().ok_or(Error::GuestMemoryNotShared)?;
+            let userspace_addr = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(|_| Error::GuestMemoryNotShared)?;
+            regions.push(MemoryRegion {
+                guest_phys_addr: region.start_addr().0,
+                memory_size: region.len(),
+                userspace_addr: userspace_addr as u64,
+                mmap_offset: file_offset.start(),
+            });
+            fds.push(file_offset.file().as_raw_fd());
+            Ok(())
+        })?;
+
+        let header = MemoryTableHeader {
+            num_regions: num_regions as u32,
+            padding: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        for region in regions.iter() {
+            payload.extend_from_slice(region.as_slice());
+        }
+        self.send_request(request::SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Sets the size of the queue `index`.
+    pub fn set_vring_num(&self, index: usize, num: u16) -> Result<()> {
+        self.set_vring_state(request::SET_VRING_NUM, index, u32::from(num))
+    }
+
+    /// Sets the addresses of the rings of the queue `index`, as mapped in this process.
+    pub fn set_vring_addr(&self, index: usize, queue: &Queue, mem: &GuestMemoryMmap) -> Result<()> {
+        let host_address = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|addr| addr as u64)
+                .map_err(|_| Error::InvalidRingAddress(addr.0))
+        };
+        let addr = VringAddr {
+            index: index as u32,
+            flags: 0,
+            descriptor: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            available: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+        self.send_request(request::SET_VRING_ADDR, addr.as_slice(), &[])
+    }
+
+    /// Sets the position of the next descriptor chain the backend pops from the queue `index`.
+    pub fn set_vring_base(&self, index: usize, base: u16) -> Result<()> {
+        self.set_vring_state(request::SET_VRING_BASE, index, u32::from(base))
+    }
+
+    /// Sets the event the backend waits on for the queue `index` to be kicked.
+    pub fn set_vring_kick(&self, index: usize, kick_evt: &EventFd) -> Result<()> {
+        self.send_request(
+            request::SET_VRING_KICK,
+            (index as u64).as_slice(),
+            &[kick_evt.as_raw_fd()],
+        )
+    }
+
+    /// Sets the event the backend writes to when it used descriptors of the queue `index`.
+    pub fn set_vring_call(&self, index: usize, call_evt: &EventFd) -> Result<()> {
+        self.send_request(
+            request::SET_VRING_CALL,
+            (index as u64).as_slice(),
+            &[call_evt.as_raw_fd()],
+        )
+    }
+
+    /// Enables or disables the queue `index`.
+    pub fn set_vring_enable(&self, index: usize, enable: bool) -> Result<()> {
+        self.set_vring_state(request::SET_VRING_ENABLE, index, enable as u32)
+    }
+}
+
+impl AsRawFd for Frontend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::virtio::vhost_user::{
+        recv_message, Message, VHOST_USER_REPLY_MASK, VHOST_USER_VRING_IDX_MASK,
+    };
+    use vm_memory::FileOffset;
+
+    fn reply(stream: &UnixStream, msg: &Message, payload: &[u8]) {
+        send_message(
+            stream,
+            msg.header.request,
+            VHOST_USER_REPLY_MASK,
+            payload,
+            &[],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_get_set_u64() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let frontend = Frontend::from_stream(frontend);
+
+        let handle = std::thread::spawn(move || {
+            for value in [3u64, 5, 7].iter() {
+                let msg = recv_message(&backend).unwrap();
+                assert!(msg.payload.is_empty());
+                reply(&backend, &msg, value.as_slice());
+            }
+            let msg = recv_message(&backend).unwrap();
+            assert_eq!(msg.header.request, request::SET_FEATURES);
+            assert_eq!(msg.payload_u64().unwrap(), 0xf0);
+            let msg = recv_message(&backend).unwrap();
+            assert_eq!(msg.header.request, request::SET_OWNER);
+            // A malformed reply.
+            let msg = recv_message(&backend).unwrap();
+            reply(&backend, &msg, &[1, 2]);
+        });
+
+        assert_eq!(frontend.get_features().unwrap(), 3);
+        assert_eq!(frontend.get_protocol_features().unwrap(), 5);
+        assert_eq!(frontend.get_queue_num().unwrap(), 7);
+        frontend.set_features(0xf0).unwrap();
+        frontend.set_owner().unwrap();
+        assert!(matches!(
+            frontend.get_features(),
+            Err(Error::InvalidMessage)
+        ));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_config() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let frontend = Frontend::from_stream(frontend);
+
+        let handle = std::thread::spawn(move || {
+            let msg = recv_message(&backend).unwrap();
+            let header = msg.payload_as::<ConfigHeader>().unwrap();
+            assert_eq!(header.offset, 4);
+            assert_eq!(header.size, 2);
+            let mut payload = header.as_slice().to_vec();
+            payload.extend_from_slice(&[8, 9]);
+            reply(&backend, &msg, &payload);
+
+            let msg = recv_message(&backend).unwrap();
+            assert_eq!(msg.header.request, request::SET_CONFIG);
+            assert_eq!(
+                &msg.payload[std::mem::size_of::<ConfigHeader>()..],
+                &[1, 2, 3]
+            );
+        });
+
+        assert_eq!(frontend.get_config(4, 2).unwrap(), vec![8, 9]);
+        frontend.set_config(0, &[1, 2, 3]).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_set_mem_table() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let frontend = Frontend::from_stream(frontend);
+
+        // Anonymous memory cannot be shared.
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        assert!(matches!(
+            frontend.set_mem_table(&mem),
+            Err(Error::GuestMemoryNotShared)
+        ));
+
+        let file = utils::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x3000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(
+            vec![
+                (
+                    GuestAddress(0),
+                    0x1000,
+                    Some(FileOffset::new(file.try_clone().unwrap(), 0)),
+                ),
+                (
+                    GuestAddress(0x10000),
+                    0x2000,
+                    Some(FileOffset::new(file, 0x1000)),
+                ),
+            ]
+            .iter()
+            .cloned(),
+            false,
+        )
+        .unwrap();
+        frontend.set_mem_table(&mem).unwrap();
+
+        let mut msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_MEM_TABLE);
+        assert_eq!(
+            msg.payload_as::<MemoryTableHeader>().unwrap().num_regions,
+            2
+        );
+        let header_len = std::mem::size_of::<MemoryTableHeader>();
+        let regions = msg.payload[header_len..]
+            .chunks(std::mem::size_of::<MemoryRegion>())
+            .map(|chunk| *MemoryRegion::from_slice(chunk).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(regions[0].guest_phys_addr, 0);
+        assert_eq!(regions[0].memory_size, 0x1000);
+        assert_eq!(regions[0].mmap_offset, 0);
+        assert_eq!(
+            regions[0].userspace_addr,
+            mem.get_host_address(GuestAddress(0)).unwrap() as u64
+        );
+        assert_eq!(regions[1].guest_phys_addr, 0x10000);
+        assert_eq!(regions[1].memory_size, 0x2000);
+        assert_eq!(regions[1].mmap_offset, 0x1000);
+
+        // Both files are the same one, written through the guest memory.
+        assert_eq!(msg.files.len(), 2);
+        msg.files[1].write_all(&[0xaa]).unwrap();
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0)).unwrap(), 0xaa);
+    }
+
+    #[test]
+    fn test_set_vring() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let frontend = Frontend::from_stream(frontend);
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let mut queue = Queue::new(16);
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+        frontend.set_vring_num(1, 16).unwrap();
+        frontend.set_vring_addr(1, &queue, &mem).unwrap();
+        frontend.set_vring_base(1, 0).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        frontend.set_vring_kick(1, &evt).unwrap();
+        frontend.set_vring_call(1, &evt).unwrap();
+        frontend.set_vring_enable(1, true).unwrap();
+
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_VRING_NUM);
+        assert_eq!(
+            msg.payload_as::<VringState>().unwrap(),
+            VringState { index: 1, num: 16 }
+        );
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_VRING_ADDR);
+        let addr = msg.payload_as::<VringAddr>().unwrap();
+        let base = mem.get_host_address(GuestAddress(0)).unwrap() as u64;
+        assert_eq!(addr.index, 1);
+        assert_eq!(addr.descriptor, base + 0x1000);
+        assert_eq!(addr.available, base + 0x2000);
+        assert_eq!(addr.used, base + 0x3000);
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_VRING_BASE);
+        assert_eq!(msg.payload_as::<VringState>().unwrap().num, 0);
+        for code in [request::SET_VRING_KICK, request::SET_VRING_CALL].iter() {
+            let msg = recv_message(&backend).unwrap();
+            assert_eq!(msg.header.request, *code);
+            assert_eq!(msg.payload_u64().unwrap() & VHOST_USER_VRING_IDX_MASK, 1);
+            assert_eq!(msg.files.len(), 1);
+        }
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_VRING_ENABLE);
+        assert_eq!(
+            msg.payload_as::<VringState>().unwrap(),
+            VringState { index: 1, num: 1 }
+        );
+
+        // The rings must lie in the guest memory.
+        queue.used_ring = GuestAddress(0x20000);
+        assert!(frontend.set_vring_addr(1, &queue, &mem).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the subset of the vhost-user protocol needed to hand the virtqueues of a device
+//! over to a backend running in another process, connected through a Unix domain socket.
+//!
+//! The backend-initiated channel, dirty page logging and inflight I/O tracking are not supported.
+
+mod frontend;
+
+pub use self::frontend::Frontend;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::result;
+
+use utils::sock_ctrl_msg::ScmSocket;
+use vm_memory::ByteValued;
+
+/// Request codes, as defined by the vhost-user specification.
+pub mod request {
+    pub const GET_FEATURES: u32 = 1;
+    pub const SET_FEATURES: u32 = 2;
+    pub const SET_OWNER: u32 = 3;
+    pub const RESET_OWNER: u32 = 4;
+    pub const SET_MEM_TABLE: u32 = 5;
+    pub const SET_VRING_NUM: u32 = 8;
+    pub const SET_VRING_ADDR: u32 = 9;
+    pub const SET_VRING_BASE: u32 = 10;
+    pub const GET_VRING_BASE: u32 = 11;
+    pub const SET_VRING_KICK: u32 = 12;
+    pub const SET_VRING_CALL: u32 = 13;
+    pub const SET_VRING_ERR: u32 = 14;
+    pub const GET_PROTOCOL_FEATURES: u32 = 15;
+    pub const SET_PROTOCOL_FEATURES: u32 = 16;
+    pub const GET_QUEUE_NUM: u32 = 17;
+    pub const SET_VRING_ENABLE: u32 = 18;
+    pub const GET_CONFIG: u32 = 24;
+    pub const SET_CONFIG: u32 = 25;
+}
+
+/// Version of the protocol, carried in the flags of every message.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+/// Set in the flags of the replies.
+pub const VHOST_USER_REPLY_MASK: u32 = 0x4;
+/// Feature bit signaling the support of `GET_PROTOCOL_FEATURES`.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 30;
+/// Protocol feature bit signaling the support of `GET_QUEUE_NUM`.
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 0;
+/// Protocol feature bit signaling the support of `GET_CONFIG` and `SET_CONFIG`.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 9;
+/// Mask of the queue index in the payload of `SET_VRING_KICK`, `SET_VRING_CALL` and
+/// `SET_VRING_ERR`.
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+/// Set in the payload of `SET_VRING_KICK`, `SET_VRING_CALL` and `SET_VRING_ERR` when no file
+/// descriptor is attached.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x100;
+/// Maximum number of memory regions in a memory table.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+// Upper bound for the size of a message payload.
+const MAX_PAYLOAD_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the backend.
+    Connect(io::Error),
+    /// The peer closed the connection.
+    Disconnected,
+    /// The guest memory is not backed by files which can be shared with the backend.
+    GuestMemoryNotShared,
+    /// The message received is malformed or does not match the request.
+    InvalidMessage,
+    /// A ring of a queue lies outside the guest memory.
+    InvalidRingAddress(u64),
+    /// The backend lacks a feature the frontend requires.
+    MissingFeature(&'static str),
+    /// Failed to receive a message.
+    Receive(io::Error),
+    /// Failed to send a message.
+    Send(io::Error),
+    /// The guest memory has more regions than a memory table can describe.
+    TooManyMemoryRegions(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Connect(err) => write!(f, "Failed to connect to the vhost-user backend: {}", err),
+            Disconnected => write!(f, "The vhost-user peer closed the connection"),
+            GuestMemoryNotShared => write!(
+                f,
+                "The guest memory cannot be shared with the vhost-user backend"
+            ),
+            InvalidMessage => write!(f, "Invalid vhost-user message"),
+            InvalidRingAddress(addr) => write!(f, "Invalid virtio ring address: {:#x}", addr),
+            MissingFeature(feature) => {
+                write!(f, "The vhost-user backend does not support {}", feature)
+            }
+            Receive(err) => write!(f, "Failed to receive a vhost-user message: {}", err),
+            Send(err) => write!(f, "Failed to send a vhost-user message: {}", err),
+            TooManyMemoryRegions(num) => write!(
+                f,
+                "Too many guest memory regions for a vhost-user memory table: {}",
+                num
+            ),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Header of every message.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MessageHeader {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+unsafe impl ByteValued for MessageHeader {}
+
+/// Payload of `SET_VRING_NUM`, `SET_VRING_BASE`, `GET_VRING_BASE` and `SET_VRING_ENABLE`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+unsafe impl ByteValued for VringState {}
+
+/// Payload of `SET_VRING_ADDR`. The addresses are virtual addresses of the frontend.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub descriptor: u64,
+    pub used: u64,
+    pub available: u64,
+    pub log: u64,
+}
+
+unsafe impl ByteValued for VringAddr {}
+
+/// Header of the payload of `SET_MEM_TABLE`, followed by `num_regions` memory regions.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryTableHeader {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+unsafe impl ByteValued for MemoryTableHeader {}
+
+/// Guest memory region of a memory table, whose file descriptor is attached to the message.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+unsafe impl ByteValued for MemoryRegion {}
+
+/// Header of the payload of `GET_CONFIG` and `SET_CONFIG`, followed by `size` bytes of the
+/// configuration space.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConfigHeader {
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+unsafe impl ByteValued for ConfigHeader {}
+
+/// A message received with the file descriptors attached to it.
+#[derive(Debug)]
+pub struct Message {
+    pub header: MessageHeader,
+    pub payload: Vec<u8>,
+    pub files: Vec<File>,
+}
+
+impl Message {
+    /// Returns the object at the start of the payload.
+    pub fn payload_as<T: ByteValued>(&self) -> Result<T> {
+        self.payload
+            .get(..size_of::<T>())
+            .and_then(T::from_slice)
+            .copied()
+            .ok_or(Error::InvalidMessage)
+    }
+
+    /// Returns the `u64` payload of the messages carrying one, e.g. `SET_FEATURES`.
+    pub fn payload_u64(&self) -> Result<u64> {
+        self.payload_as::<u64>()
+    }
+}
+
+/// Sends a `request` message carrying `payload`, with the file descriptors `fds` attached.
+pub fn send_message(
+    stream: &UnixStream,
+    request: u32,
+    flags: u32,
+    payload: &[u8],
+    fds: &[RawFd],
+) -> Result<()> {
+    let header = MessageHeader {
+        request,
+        flags: VHOST_USER_VERSION | flags,
+        size: payload.len() as u32,
+    };
+    let mut buf = header.as_slice().to_vec();
+    buf.extend_from_slice(payload);
+
+    let len = stream
+        .send_with_fds(&[&buf[..]], fds)
+        .map_err(|e| Error::Send(io::Error::from_raw_os_error(e.errno())))?;
+    if len != buf.len() {
+        return Err(Error::Send(io::Error::from(io::ErrorKind::WriteZero)));
+    }
+    Ok(())
+}
+
+/// Reads the payload following `header` from `stream`.
+fn read_payload(mut stream: &UnixStream, header: &MessageHeader) -> Result<Vec<u8>> {
+    let size = header.size as usize;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+    let mut payload = vec![0u8; size];
+    stream
+        .read_exact(&mut payload)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Disconnected,
+            _ => Error::Receive(e),
+        })?;
+    Ok(payload)
+}
+
+/// Receives a message, along with the file descriptors attached to it.
+pub fn recv_message(mut stream: &UnixStream) -> Result<Message> {
+    let mut header = MessageHeader::default();
+    let mut raw_fds = [-1 as RawFd; MAX_MEMORY_REGIONS];
+    let mut iovecs = [libc::iovec {
+        iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+        iov_len: size_of::<MessageHeader>(),
+    }];
+    // Safe because the iovec points to the header, which outlives the call.
+    let (len, num_fds) = unsafe { stream.recv_with_fds(&mut iovecs, &mut raw_fds) }
+        .map_err(|e| Error::Receive(io::Error::from_raw_os_error(e.errno())))?;
+    // Safe because the file descriptors were just received, and are owned by this process.
+    let files = raw_fds[..num_fds]
+        .iter()
+        .map(|fd| unsafe { File::from_raw_fd(*fd) })
+        .collect();
+    if len == 0 {
+        return Err(Error::Disconnected);
+    }
+    if len < size_of::<MessageHeader>() {
+        stream
+            .read_exact(&mut header.as_mut_slice()[len..])
+            .map_err(|_| Error::Disconnected)?;
+    }
+    let payload = read_payload(stream, &header)?;
+
+    Ok(Message {
+        header,
+        payload,
+        files,
+    })
+}
+
+/// Receives the reply to `request`, without any file descriptor attached.
+pub fn recv_reply(mut stream: &UnixStream, request: u32) -> Result<Vec<u8>> {
+    let mut header = MessageHeader::default();
+    stream
+        .read_exact(header.as_mut_slice())
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Disconnected,
+            _ => Error::Receive(e),
+        })?;
+    let payload = read_payload(stream, &header)?;
+    if header.request != request || header.flags & VHOST_USER_REPLY_MASK == 0 {
+        return Err(Error::InvalidMessage);
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    use utils::eventfd::EventFd;
+
+    #[test]
+    fn test_message_layout() {
+        assert_eq!(size_of::<MessageHeader>(), 12);
+        assert_eq!(size_of::<VringState>(), 8);
+        assert_eq!(size_of::<VringAddr>(), 40);
+        assert_eq!(size_of::<MemoryTableHeader>(), 8);
+        assert_eq!(size_of::<MemoryRegion>(), 32);
+        assert_eq!(size_of::<ConfigHeader>(), 12);
+    }
+
+    #[test]
+    fn test_send_recv_message() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+
+        let state = VringState { index: 1, num: 256 };
+        send_message(&frontend, request::SET_VRING_NUM, 0, state.as_slice(), &[]).unwrap();
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_VRING_NUM);
+        assert_eq!(msg.header.flags, VHOST_USER_VERSION);
+        assert_eq!(msg.header.size, 8);
+        assert_eq!(msg.payload_as::<VringState>().unwrap(), state);
+        assert!(msg.files.is_empty());
+        assert!(msg.payload_as::<VringAddr>().is_err());
+
+        // The file descriptors travel along with the message.
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        send_message(
+            &frontend,
+            request::SET_VRING_KICK,
+            0,
+            1u64.as_slice(),
+            &[evt.as_raw_fd()],
+        )
+        .unwrap();
+        let mut msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.payload_u64().unwrap(), 1);
+        assert_eq!(msg.files.len(), 1);
+        msg.files[0].write_all(&1u64.to_ne_bytes()).unwrap();
+        assert_eq!(evt.read().unwrap(), 1);
+
+        drop(frontend);
+        assert!(matches!(recv_message(&backend), Err(Error::Disconnected)));
+    }
+
+    #[test]
+    fn test_recv_reply() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+
+        send_message(
+            &backend,
+            request::GET_FEATURES,
+            VHOST_USER_REPLY_MASK,
+            7u64.as_slice(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            recv_reply(&frontend, request::GET_FEATURES).unwrap(),
+            7u64.to_ne_bytes().to_vec()
+        );
+
+        // Replies to another request, or messages not flagged as replies, are rejected.
+        send_message(
+            &backend,
+            request::GET_QUEUE_NUM,
+            VHOST_USER_REPLY_MASK,
+            7u64.as_slice(),
+            &[],
+        )
+        .unwrap();
+        assert!(matches!(
+            recv_reply(&frontend, request::GET_FEATURES),
+            Err(Error::InvalidMessage)
+        ));
+        send_message(&backend, request::GET_FEATURES, 0, &[], &[]).unwrap();
+        assert!(matches!(
+            recv_reply(&frontend, request::GET_FEATURES),
+            Err(Error::InvalidMessage)
+        ));
+
+        drop(backend);
+        assert!(matches!(
+            recv_reply(&frontend, request::GET_FEATURES),
+            Err(Error::Disconnected)
+        ));
+    }
+}
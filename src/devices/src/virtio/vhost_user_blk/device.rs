@@ -0,0 +1,581 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::*;
+use vm_memory::GuestMemoryMmap;
+
+use super::{Error, Result};
+use crate::virtio::block::{CONFIG_SPACE_SIZE, MAX_NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::vhost_user::{
+    self, Frontend, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ,
+};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK,
+    VIRTIO_MMIO_INT_VRING,
+};
+
+// Offset of the `num_queues` field of `struct virtio_blk_config` in the configuration space.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+
+// The virtio features of the backend which are exposed to the driver.
+const BACKEND_FEATURES: u64 = (1u64 << VIRTIO_F_VERSION_1)
+    | (1u64 << VIRTIO_BLK_F_SIZE_MAX)
+    | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+    | (1u64 << VIRTIO_BLK_F_RO)
+    | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
+    | (1u64 << VIRTIO_BLK_F_FLUSH)
+    | (1u64 << VIRTIO_BLK_F_TOPOLOGY)
+    | (1u64 << VIRTIO_BLK_F_DISCARD)
+    | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+
+/// Virtio block device whose queues are processed by a vhost-user backend.
+///
+/// The guest memory is shared with the backend, which is directly kicked through the queue
+/// events. The backend signals used descriptors through the call events, turned into
+/// interrupts by the device.
+pub struct VhostUserBlock {
+    // Connection to the backend, and the virtio features it offers.
+    pub(crate) frontend: Frontend,
+    pub(crate) backend_features: u64,
+
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    config_space: Vec<u8>,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) interrupt_status: Arc<AtomicUsize>,
+    pub(crate) interrupt_evt: EventFd,
+    pub(crate) queue_evts: Vec<EventFd>,
+    pub(crate) call_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    pub(crate) partuuid: Option<String>,
+    pub(crate) socket_path: String,
+}
+
+impl VhostUserBlock {
+    /// Creates a new block device served by the vhost-user backend listening on `socket_path`,
+    /// with `num_queues` request queues.
+    pub fn new(
+        id: String,
+        partuuid: Option<String>,
+        socket_path: String,
+        is_disk_read_only: bool,
+        num_queues: usize,
+    ) -> Result<VhostUserBlock> {
+        let frontend = Frontend::connect(&socket_path).map_err(Error::VhostUser)?;
+        Self::with_frontend(
+            id,
+            partuuid,
+            socket_path,
+            frontend,
+            is_disk_read_only,
+            num_queues,
+        )
+    }
+
+    fn with_frontend(
+        id: String,
+        partuuid: Option<String>,
+        socket_path: String,
+        frontend: Frontend,
+        is_disk_read_only: bool,
+        num_queues: usize,
+    ) -> Result<VhostUserBlock> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        frontend.set_owner().map_err(Error::VhostUser)?;
+        let backend_features = frontend.get_features().map_err(Error::VhostUser)?;
+        if backend_features & (1u64 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            return Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "protocol features",
+            )));
+        }
+
+        let protocol_features = frontend.get_protocol_features().map_err(Error::VhostUser)?
+            & ((1u64 << VHOST_USER_PROTOCOL_F_MQ) | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG));
+        if protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            return Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "the configuration space",
+            )));
+        }
+        frontend
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUser)?;
+
+        let mut avail_features = backend_features & BACKEND_FEATURES;
+        if is_disk_read_only {
+            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        }
+        if num_queues > 1 {
+            if protocol_features & (1u64 << VHOST_USER_PROTOCOL_F_MQ) == 0
+                || backend_features & (1u64 << VIRTIO_BLK_F_MQ) == 0
+            {
+                return Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                    "multiple queues",
+                )));
+            }
+            let max_queues = frontend.get_queue_num().map_err(Error::VhostUser)?;
+            if num_queues as u64 > max_queues {
+                return Err(Error::InvalidNumQueues(num_queues));
+            }
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let mut config_space = frontend
+            .get_config(0, CONFIG_SPACE_SIZE as u32)
+            .map_err(Error::VhostUser)?;
+        config_space[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2]
+            .copy_from_slice(&(num_queues as u16).to_le_bytes());
+
+        let new_evts = || -> Result<Vec<EventFd>> {
+            (0..num_queues)
+                .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+                .collect()
+        };
+
+        Ok(VhostUserBlock {
+            frontend,
+            backend_features,
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queues: (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queue_evts: new_evts()?,
+            call_evts: new_evts()?,
+            device_state: DeviceState::Inactive,
+            id,
+            partuuid,
+            socket_path,
+        })
+    }
+
+    /// Provides the ID of this block device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the PARTUUID of this block device.
+    pub fn partuuid(&self) -> Option<&String> {
+        self.partuuid.as_ref()
+    }
+
+    /// Provides the path of the socket of the backend.
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    /// Hands the memory and the queues set up by the driver over to the backend.
+    pub(crate) fn setup_backend(&self) -> vhost_user::Result<()> {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // The backend is only set up once the device is activated.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        // The read-only feature may be offered to the driver on behalf of the backend.
+        self.frontend.set_features(
+            (self.acked_features & self.backend_features)
+                | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES),
+        )?;
+        self.frontend.set_mem_table(mem)?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            self.frontend.set_vring_num(index, queue.actual_size())?;
+            self.frontend.set_vring_addr(index, queue, mem)?;
+            self.frontend.set_vring_base(index, queue.next_avail())?;
+            self.frontend
+                .set_vring_kick(index, &self.queue_evts[index])?;
+            self.frontend
+                .set_vring_call(index, &self.call_evts[index])?;
+            self.frontend.set_vring_enable(index, true)?;
+        }
+        Ok(())
+    }
+
+    /// Raises an interrupt after the backend used descriptors of the queue `queue_index`.
+    pub(crate) fn process_call_event(&self, queue_index: usize) {
+        METRICS.block.queue_event_count.inc();
+        if let Err(e) = self.call_evts[queue_index].read() {
+            error!("Failed to get vhost-user call event: {:?}", e);
+            METRICS.block.event_fails.inc();
+            return;
+        }
+
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        if let Err(e) = self.interrupt_evt.write(1) {
+            error!("Failed to signal used queue: {:?}", e);
+            METRICS.block.event_fails.inc();
+        }
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        // The configuration space was fetched from the backend when the device was created.
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        let config_len = self.config_space.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+
+        if let Err(e) = self.frontend.set_config(offset as u32, data) {
+            error!("Failed to write the vhost-user config space: {}", e);
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        self.config_space[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
+    }
+
+    fn is_activated(&self) -> bool {
+        match self.device_state {
+            DeviceState::Inactive => false,
+            DeviceState::Activated(_) => true,
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        // The backend is set up by the activate event handler, off the vCPU thread.
+        if self.activate_evt.write(1).is_err() {
+            error!("VhostUserBlock: Cannot write to activate_evt");
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread::{self, JoinHandle};
+
+    use crate::check_metric_after_block;
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
+    use crate::virtio::vhost_user::{
+        recv_message, request, send_message, ConfigHeader, Message, VringState,
+        VHOST_USER_REPLY_MASK,
+    };
+    use vm_memory::{ByteValued, FileOffset, GuestAddress};
+
+    pub(crate) const TEST_CAPACITY: u64 = 0x1234;
+
+    fn reply(stream: &UnixStream, msg: &Message, payload: &[u8]) {
+        send_message(
+            stream,
+            msg.header.request,
+            VHOST_USER_REPLY_MASK,
+            payload,
+            &[],
+        )
+        .unwrap();
+    }
+
+    // Answers the requests sent while creating a device, and returns the backend end of the
+    // connection once the configuration space is fetched.
+    pub(crate) fn spawn_backend(
+        backend: UnixStream,
+        features: u64,
+        protocol_features: u64,
+    ) -> JoinHandle<UnixStream> {
+        thread::spawn(move || loop {
+            let msg = match recv_message(&backend) {
+                Ok(msg) => msg,
+                Err(_) => return backend,
+            };
+            match msg.header.request {
+                request::GET_FEATURES => reply(&backend, &msg, features.as_slice()),
+                request::GET_PROTOCOL_FEATURES => {
+                    reply(&backend, &msg, protocol_features.as_slice())
+                }
+                request::GET_QUEUE_NUM => reply(&backend, &msg, 4u64.as_slice()),
+                request::GET_CONFIG => {
+                    let header = msg.payload_as::<ConfigHeader>().unwrap();
+                    let mut payload = header.as_slice().to_vec();
+                    payload.extend_from_slice(TEST_CAPACITY.as_slice());
+                    payload.resize(payload.len() + header.size as usize - 8, 0);
+                    reply(&backend, &msg, &payload);
+                    return backend;
+                }
+                _ => (),
+            }
+        })
+    }
+
+    pub(crate) fn default_vhost_user_block(num_queues: usize) -> (VhostUserBlock, UnixStream) {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(
+            backend,
+            u64::MAX,
+            (1u64 << VHOST_USER_PROTOCOL_F_MQ) | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG),
+        );
+        let block = VhostUserBlock::with_frontend(
+            "vhost-user".to_string(),
+            None,
+            "/foo/bar".to_string(),
+            Frontend::from_stream(frontend),
+            false,
+            num_queues,
+        )
+        .unwrap();
+        (block, handle.join().unwrap())
+    }
+
+    fn new_block(
+        features: u64,
+        protocol_features: u64,
+        read_only: bool,
+        num_queues: usize,
+    ) -> Result<VhostUserBlock> {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let handle = spawn_backend(backend, features, protocol_features);
+        let block = VhostUserBlock::with_frontend(
+            "vhost-user".to_string(),
+            None,
+            "/foo/bar".to_string(),
+            Frontend::from_stream(frontend),
+            read_only,
+            num_queues,
+        );
+        drop(handle);
+        block
+    }
+
+    #[test]
+    fn test_new() {
+        let (block, _backend) = default_vhost_user_block(2);
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        assert_eq!(block.id(), "vhost-user");
+        assert_eq!(block.socket_path(), "/foo/bar");
+        assert_eq!(
+            block.avail_features(),
+            BACKEND_FEATURES | (1u64 << VIRTIO_BLK_F_MQ)
+        );
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+
+        // The capacity comes from the backend, the number of queues from the configuration.
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), TEST_CAPACITY);
+        let mut num_queues = [0u8; 2];
+        block.read_config(CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        // Read-only drives are exposed as such, whatever the backend offers.
+        let protocol_features = 1u64 << VHOST_USER_PROTOCOL_F_CONFIG;
+        let block = new_block(
+            (1u64 << VHOST_USER_F_PROTOCOL_FEATURES) | (1u64 << VIRTIO_F_VERSION_1),
+            protocol_features,
+            true,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            block.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_RO)
+        );
+    }
+
+    #[test]
+    fn test_new_errors() {
+        let protocol_features = 1u64 << VHOST_USER_PROTOCOL_F_CONFIG;
+        assert!(matches!(
+            new_block(u64::MAX, protocol_features, false, 0),
+            Err(Error::InvalidNumQueues(0))
+        ));
+        assert!(matches!(
+            new_block(u64::MAX, protocol_features, false, MAX_NUM_QUEUES + 1),
+            Err(Error::InvalidNumQueues(_))
+        ));
+        assert!(matches!(
+            new_block(
+                !(1u64 << VHOST_USER_F_PROTOCOL_FEATURES),
+                protocol_features,
+                false,
+                1
+            ),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(_)))
+        ));
+        assert!(matches!(
+            new_block(u64::MAX, 0, false, 1),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(_)))
+        ));
+        // Multiple queues require the MQ protocol feature, and are bounded by the backend.
+        assert!(matches!(
+            new_block(u64::MAX, protocol_features, false, 2),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(_)))
+        ));
+        assert!(matches!(
+            new_block(
+                u64::MAX,
+                protocol_features | (1u64 << VHOST_USER_PROTOCOL_F_MQ),
+                false,
+                8
+            ),
+            Err(Error::InvalidNumQueues(8))
+        ));
+    }
+
+    #[test]
+    fn test_write_config() {
+        let (mut block, backend) = default_vhost_user_block(1);
+
+        block.write_config(8, &[1, 2]);
+        let msg = recv_message(&backend).unwrap();
+        assert_eq!(msg.header.request, request::SET_CONFIG);
+        let header = msg.payload_as::<ConfigHeader>().unwrap();
+        assert_eq!((header.offset, header.size), (8, 2));
+        let mut data = [0u8; 2];
+        block.read_config(8, &mut data);
+        assert_eq!(data, [1, 2]);
+
+        // Out of bounds writes are not forwarded.
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 1, &[1, 2]);
+        drop(block);
+        assert!(recv_message(&backend).is_err());
+    }
+
+    #[test]
+    fn test_setup_backend() {
+        let (mut block, backend) = default_vhost_user_block(1);
+
+        // Anonymous memory cannot be shared with the backend.
+        block.activate(default_mem()).unwrap();
+        assert!(matches!(
+            block.setup_backend(),
+            Err(vhost_user::Error::GuestMemoryNotShared)
+        ));
+
+        let file = utils::tempfile::TempFile::new().unwrap().into_file();
+        file.set_len(0x10000).unwrap();
+        let mem = GuestMemoryMmap::from_ranges_with_files(
+            vec![(GuestAddress(0), 0x10000, Some(FileOffset::new(file, 0)))]
+                .iter()
+                .cloned(),
+            false,
+        )
+        .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.set_acked_features(1u64 << VIRTIO_F_VERSION_1);
+        block.activate(mem.clone()).unwrap();
+        block.setup_backend().unwrap();
+
+        // Drop the messages of the first attempt.
+        let mut msg = recv_message(&backend).unwrap();
+        while msg.header.request != request::SET_MEM_TABLE || msg.files.is_empty() {
+            msg = recv_message(&backend).unwrap();
+        }
+        assert_eq!(msg.files.len(), 1);
+        let expected = [
+            request::SET_VRING_NUM,
+            request::SET_VRING_ADDR,
+            request::SET_VRING_BASE,
+            request::SET_VRING_KICK,
+            request::SET_VRING_CALL,
+            request::SET_VRING_ENABLE,
+        ];
+        for code in expected.iter() {
+            let msg = recv_message(&backend).unwrap();
+            assert_eq!(msg.header.request, *code);
+            if *code == request::SET_VRING_NUM {
+                assert_eq!(
+                    msg.payload_as::<VringState>().unwrap(),
+                    VringState { index: 0, num: 16 }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_call_event() {
+        let (block, _backend) = default_vhost_user_block(2);
+
+        block.call_evts[1].write(1).unwrap();
+        block.process_call_event(1);
+        assert_eq!(
+            block.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+
+        // A spurious event does not raise an interrupt.
+        check_metric_after_block!(&METRICS.block.event_fails, 1, block.process_call_event(0));
+        assert!(block.interrupt_evt.read().is_err());
+    }
+}
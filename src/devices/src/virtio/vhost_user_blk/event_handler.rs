@@ -0,0 +1,184 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use std::os::unix::io::AsRawFd;
+
+use logger::{debug, error, warn, IncMetric, METRICS};
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use crate::virtio::vhost_user_blk::device::VhostUserBlock;
+use crate::virtio::VirtioDevice;
+
+impl VhostUserBlock {
+    fn process_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("vhost-user block: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume vhost-user block activate event: {:?}", e);
+        }
+        if let Err(e) = self.setup_backend() {
+            error!("Failed to set up the vhost-user backend: {}", e);
+            METRICS.block.activate_fails.inc();
+        }
+
+        let activate_fd = self.activate_evt.as_raw_fd();
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = match event_manager.subscriber(activate_fd) {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                error!("Failed to process vhost-user block activate evt: {:?}", e);
+                return;
+            }
+        };
+
+        // Interest list changes when the device is activated.
+        let interest_list = self.interest_list();
+        for event in interest_list {
+            event_manager
+                .register(event.data() as i32, event, self_subscriber.clone())
+                .unwrap_or_else(|e| {
+                    error!("Failed to register vhost-user block events: {:?}", e);
+                });
+        }
+
+        event_manager.unregister(activate_fd).unwrap_or_else(|e| {
+            error!(
+                "Failed to unregister vhost-user block activate evt: {:?}",
+                e
+            );
+        });
+    }
+
+    // The backend never sends requests, so the socket only gets readable when the backend
+    // misbehaves or goes away.
+    fn process_socket_event(&self, event_manager: &mut EventManager) {
+        error!(
+            "The vhost-user backend of block device {} disconnected",
+            self.id()
+        );
+        METRICS.block.event_fails.inc();
+        event_manager
+            .unregister(self.frontend.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister vhost-user socket: {:?}", e);
+            });
+    }
+}
+
+impl Subscriber for VhostUserBlock {
+    // Handle an event for a call event or the socket.
+    fn process(&mut self, event: &EpollEvent, evmgr: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let socket_fd = self.frontend.as_raw_fd();
+        let supported_events = if source == socket_fd {
+            EventSet::IN | EventSet::HANG_UP | EventSet::ERROR
+        } else {
+            EventSet::IN
+        };
+        if !supported_events.contains(event_set) {
+            warn!(
+                "VhostUserBlock: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            if let Some(queue_index) = self
+                .call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source)
+            {
+                self.process_call_event(queue_index);
+                return;
+            }
+            let activate_fd = self.activate_evt.as_raw_fd();
+
+            match source {
+                _ if socket_fd == source => self.process_socket_event(evmgr),
+                _ if activate_fd == source => self.process_activate_event(evmgr),
+                _ => warn!("VhostUserBlock: Spurious event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "VhostUserBlock: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        if self.is_activated() {
+            let mut events: Vec<EpollEvent> = self
+                .call_evts
+                .iter()
+                .map(|call_evt| EpollEvent::new(EventSet::IN, call_evt.as_raw_fd() as u64))
+                .collect();
+            events.push(EpollEvent::new(
+                EventSet::IN,
+                self.frontend.as_raw_fd() as u64,
+            ));
+            events
+        } else {
+            vec![EpollEvent::new(
+                EventSet::IN,
+                self.activate_evt.as_raw_fd() as u64,
+            )]
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::check_metric_after_block;
+    use crate::virtio::test_utils::default_mem;
+    use crate::virtio::vhost_user_blk::device::tests::default_vhost_user_block;
+    use crate::virtio::VIRTIO_MMIO_INT_VRING;
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let (block, backend) = default_vhost_user_block(1);
+        let block = Arc::new(Mutex::new(block));
+        event_manager.add_subscriber(block.clone()).unwrap();
+
+        // Call events are ignored before activation.
+        block.lock().unwrap().call_evts[0].write(1).unwrap();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+
+        // The backend cannot use anonymous memory, which is reported but does not prevent
+        // the device events from being handled.
+        check_metric_after_block!(&METRICS.block.activate_fails, 1, {
+            block.lock().unwrap().activate(default_mem()).unwrap();
+            let ev_count = event_manager.run_with_timeout(50).unwrap();
+            assert_eq!(ev_count, 1);
+        });
+
+        // Call events raise interrupts.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert_eq!(
+            block
+                .lock()
+                .unwrap()
+                .interrupt_status
+                .load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        assert_eq!(block.lock().unwrap().interrupt_evt().read().unwrap(), 1);
+
+        // The socket gets unregistered once the backend goes away.
+        drop(backend);
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+    }
+}
@@ -0,0 +1,37 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Block device whose requests are served by a vhost-user backend.
+
+use std::{fmt, io, result};
+
+pub mod device;
+pub mod event_handler;
+
+pub use self::device::VhostUserBlock;
+
+use super::vhost_user::Error as VhostUserError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// EventFd error.
+    EventFd(io::Error),
+    /// The number of queues is out of range, or not supported by the backend.
+    InvalidNumQueues(usize),
+    /// Error while talking to the backend.
+    VhostUser(VhostUserError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            EventFd(err) => write!(f, "Failed to create an event fd: {}", err),
+            InvalidNumQueues(num) => write!(f, "Invalid number of block queues: {}", num),
+            VhostUser(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
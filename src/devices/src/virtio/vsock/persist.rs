@@ -7,6 +7,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use super::*;
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -15,7 +16,7 @@ use vm_memory::GuestMemoryMmap;
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_VSOCK};
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockState {
     pub backend: VsockBackendState,
@@ -23,7 +24,7 @@ pub struct VsockState {
 }
 
 /// The Vsock serializable state.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockFrontendState {
     pub cid: u64,
@@ -31,14 +32,14 @@ pub struct VsockFrontendState {
 }
 
 /// An enum for the serializable backend state types.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum VsockBackendState {
     Uds(VsockUdsState),
 }
 
 /// The Vsock Unix Backend serializable state.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockUdsState {
     /// The path for the UDS socket.
@@ -55,6 +56,8 @@ pub struct VsockConstructorArgs<B> {
 pub struct VsockUdsConstructorArgs {
     // cid available in VsockFrontendState.
     pub cid: u64,
+    /// Unix domain socket to restore the backend with, instead of the saved one.
+    pub uds_path: Option<String>,
 }
 
 impl Persist<'_> for VsockUnixBackend {
@@ -75,7 +78,9 @@ impl Persist<'_> for VsockUnixBackend {
         match state {
             VsockBackendState::Uds(uds_state) => Ok(VsockUnixBackend::new(
                 constructor_args.cid,
-                uds_state.path.clone(),
+                constructor_args
+                    .uds_path
+                    .unwrap_or_else(|| uds_state.path.clone()),
             )?),
         }
     }
@@ -20,13 +20,15 @@ use utils::{
 };
 use vmm::{
     resources::VmResources,
-    rpc_interface::{PrebootApiController, RuntimeApiController, VmmAction},
+    rpc_interface::{ActionResult, PrebootApiController, RuntimeApiController, VmmAction},
     vmm_config::instance_info::InstanceInfo,
     Vmm,
 };
 
 struct ApiServerAdapter {
     api_event_fd: EventFd,
+    // Written to come back to a request the controller left pending.
+    pending_request_evt: EventFd,
     from_api: Receiver<ApiRequest>,
     to_api: Sender<ApiResponse>,
     controller: RuntimeApiController,
@@ -37,6 +39,7 @@ impl ApiServerAdapter {
     /// to a `RuntimeApiController`.
     fn run_microvm(
         api_event_fd: EventFd,
+        pending_request_evt: EventFd,
         from_api: Receiver<ApiRequest>,
         to_api: Sender<ApiResponse>,
         vm_resources: VmResources,
@@ -45,6 +48,7 @@ impl ApiServerAdapter {
     ) {
         let api_adapter = Arc::new(Mutex::new(Self {
             api_event_fd,
+            pending_request_evt,
             from_api,
             to_api,
             controller: RuntimeApiController::new(vm_resources, vmm),
@@ -59,18 +63,31 @@ impl ApiServerAdapter {
         }
     }
 
-    fn handle_request(&mut self, req_action: VmmAction) {
-        let response = self.controller.handle_request(req_action);
-        // Send back the result.
-        self.to_api
-            .send(Box::new(response))
-            .map_err(|_| ())
-            .expect("one-shot channel closed");
+    fn handle_request(&mut self, req_action: VmmAction, event_manager: &mut EventManager) {
+        let response = self.controller.handle_request(req_action, event_manager);
+        self.respond(response);
+    }
+
+    fn respond(&mut self, response: Option<ActionResult>) {
+        match response {
+            // Send back the result.
+            Some(response) => self
+                .to_api
+                .send(Box::new(response))
+                .map_err(|_| ())
+                .expect("one-shot channel closed"),
+            // Continue the request once the event loop has served the other events. The API
+            // thread waits for the response in the meantime, so no other request comes in.
+            None => self
+                .pending_request_evt
+                .write(1)
+                .expect("Failed to write the pending request event"),
+        }
     }
 }
 impl Subscriber for ApiServerAdapter {
     /// Handle a read event (EPOLLIN).
-    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
         let source = event.fd();
         let event_set = event.event_set();
 
@@ -78,7 +95,7 @@ impl Subscriber for ApiServerAdapter {
             match self.from_api.try_recv() {
                 Ok(api_request) => {
                     let request_is_pause = *api_request == VmmAction::Pause;
-                    self.handle_request(*api_request);
+                    self.handle_request(*api_request, event_manager);
 
                     // If the latest req is a pause request, temporarily switch to a mode where we
                     // do blocking `recv`s on the `from_api` receiver in a loop, until we get
@@ -91,7 +108,7 @@ impl Subscriber for ApiServerAdapter {
                         loop {
                             let req = self.from_api.recv().expect("Error receiving API request.");
                             let req_is_resume = *req == VmmAction::Resume;
-                            self.handle_request(*req);
+                            self.handle_request(*req, event_manager);
                             if req_is_resume {
                                 break;
                             }
@@ -106,16 +123,20 @@ impl Subscriber for ApiServerAdapter {
                 }
             };
             let _ = self.api_event_fd.read();
+        } else if source == self.pending_request_evt.as_raw_fd() && event_set == EventSet::IN {
+            let _ = self.pending_request_evt.read();
+            let response = self.controller.continue_request();
+            self.respond(response);
         } else {
             error!("Spurious EventManager event for handler: ApiServerAdapter");
         }
     }
 
     fn interest_list(&self) -> Vec<EpollEvent> {
-        vec![EpollEvent::new(
-            EventSet::IN,
-            self.api_event_fd.as_raw_fd() as u64,
-        )]
+        vec![
+            EpollEvent::new(EventSet::IN, self.api_event_fd.as_raw_fd() as u64),
+            EpollEvent::new(EventSet::IN, self.pending_request_evt.as_raw_fd() as u64),
+        ]
     }
 }
 
@@ -132,6 +153,9 @@ pub(crate) fn run_with_api(
     // It is used in the config/pre-boot loop which is a simple blocking loop
     // which only consumes API events.
     let api_event_fd = EventFd::new(0).expect("Cannot create API Eventfd.");
+    // The seccomp filter installed when starting the microVM doesn't allow creating eventfds.
+    let pending_request_evt =
+        EventFd::new(libc::EFD_NONBLOCK).expect("Cannot create the pending request eventfd.");
     // Channels for both directions between Vmm and Api threads.
     let (to_vmm, from_api) = channel();
     let (to_api, from_vmm) = channel();
@@ -229,6 +253,7 @@ pub(crate) fn run_with_api(
 
     ApiServerAdapter::run_microvm(
         api_event_fd,
+        pending_request_evt,
         from_api,
         to_api,
         vm_resources,
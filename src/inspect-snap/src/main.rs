@@ -0,0 +1,176 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host tool which prints the contents of a snapshot state file as JSON, or the
+//! differences between two snapshot state files.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::{json, Value};
+use snapshot::Snapshot;
+use utils::arg_parser::{ArgParser, Argument};
+use vmm::persist::{
+    diff_state_values, snapshot_state_from_file, snapshot_state_sanity_check, LoadSnapshotError,
+};
+use vmm::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
+
+const INSPECT_SNAP_VERSION: &str = env!("FIRECRACKER_VERSION");
+const SNAPSHOT_PATH: &str = "snapshot-path";
+const DIFF_SNAPSHOT_PATH: &str = "diff-snapshot-path";
+
+#[derive(Debug)]
+enum Error {
+    MissingArgument(&'static str),
+    OpenFile(PathBuf, io::Error),
+    ReadVersion(PathBuf, snapshot::Error),
+    Serialize(serde_json::Error),
+    SnapshotState(PathBuf, LoadSnapshotError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            MissingArgument(arg) => write!(f, "Missing argument: --{}", arg),
+            OpenFile(path, err) => write!(f, "Failed to open {:?}: {}", path, err),
+            ReadVersion(path, err) => write!(
+                f,
+                "Failed to read the snapshot version of {:?}: {:?}",
+                path, err
+            ),
+            Serialize(err) => write!(f, "Failed to serialize the snapshot state: {}", err),
+            SnapshotState(path, err) => {
+                write!(f, "Failed to load the snapshot state {:?}: {}", path, err)
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new(SNAPSHOT_PATH)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the snapshot state file to inspect."),
+        )
+        .arg(Argument::new(DIFF_SNAPSHOT_PATH).takes_value(true).help(
+            "Path to a second snapshot state file. When provided, only the differences \
+             between the two snapshot states are printed.",
+        ))
+        .arg(
+            Argument::new("version")
+                .takes_value(false)
+                .help("Print the binary version number."),
+        )
+}
+
+/// Loads the snapshot state file at `path` and describes it as JSON, together with
+/// the snapshot data version and the result of the restore sanity checks.
+fn inspect(path: &Path) -> Result<Value> {
+    let mut file = File::open(path).map_err(|e| Error::OpenFile(path.to_path_buf(), e))?;
+    let data_version = Snapshot::get_data_version(&mut file, &VERSION_MAP)
+        .map_err(|e| Error::ReadVersion(path.to_path_buf(), e))?;
+    let microvm_state = snapshot_state_from_file(&path.to_path_buf(), VERSION_MAP.clone())
+        .map_err(|e| Error::SnapshotState(path.to_path_buf(), e))?;
+
+    let firecracker_version = FC_VERSION_TO_SNAP_VERSION
+        .iter()
+        .find(|(_, version)| **version == data_version)
+        .map(|(fc_version, _)| fc_version.clone());
+    let sanity_check_error = snapshot_state_sanity_check(&microvm_state)
+        .err()
+        .map(|err| err.to_string());
+    let state = serde_json::to_value(&microvm_state).map_err(Error::Serialize)?;
+
+    Ok(json!({
+        "data_version": data_version,
+        "firecracker_version": firecracker_version,
+        "sanity_check_error": sanity_check_error,
+        "state": state,
+    }))
+}
+
+fn run(arg_parser: &ArgParser) -> Result<()> {
+    let arguments = arg_parser.arguments();
+
+    let snapshot_path = arguments
+        .single_value(SNAPSHOT_PATH)
+        .ok_or(Error::MissingArgument(SNAPSHOT_PATH))?;
+    let mut output = inspect(Path::new(snapshot_path))?;
+
+    if let Some(diff_snapshot_path) = arguments.single_value(DIFF_SNAPSHOT_PATH) {
+        let diffs = diff_state_values(&output, &inspect(Path::new(diff_snapshot_path))?);
+        output = serde_json::to_value(diffs).map_err(Error::Serialize)?;
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&output).map_err(Error::Serialize)?
+    );
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    match arg_parser.parse_from_cmdline() {
+        Err(err) => {
+            println!(
+                "Arguments parsing error: {} \n\n\
+                 For more information try --help.",
+                err
+            );
+            process::exit(1);
+        }
+        _ => {
+            if arg_parser.arguments().flag_present("help") {
+                println!("inspect-snap v{}\n", INSPECT_SNAP_VERSION);
+                println!("{}\n", arg_parser.formatted_help());
+                process::exit(0);
+            }
+
+            if arg_parser.arguments().flag_present("version") {
+                println!("inspect-snap v{}\n", INSPECT_SNAP_VERSION);
+                process::exit(0);
+            }
+        }
+    }
+
+    if let Err(err) = run(&arg_parser) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_inspect_errors() {
+        let missing = PathBuf::from("/foo/bar");
+        match inspect(&missing) {
+            Err(Error::OpenFile(path, _)) => assert_eq!(path, missing),
+            _ => panic!("Expected an OpenFile error"),
+        }
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0u8; 16]).unwrap();
+        match inspect(file.as_path()) {
+            Err(Error::ReadVersion(_, snapshot::Error::InvalidMagic(0))) => (),
+            _ => panic!("Expected a ReadVersion error"),
+        }
+    }
+}
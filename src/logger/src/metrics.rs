@@ -59,14 +59,21 @@
 //!   (this could be a concern, I guess).
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
+//!
+//! Besides the aggregate metrics of all the block and network devices, the metrics of each drive
+//! and network interface are written under `block_drives` and `net_ifaces`, keyed by their ids.
+//! Each device holds its own metrics, so that they are updated without locking, and the map is
+//! only locked when a device is added or removed, and when the metrics are flushed.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::Write;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 
 use lazy_static::lazy_static;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 
 use super::extract_guard;
@@ -269,6 +276,83 @@ impl Serialize for SharedStoreMetric {
     }
 }
 
+/// Upper bounds, in microseconds, of the buckets of a `LatencyHistogram`. A last bucket counts
+/// the larger latencies.
+pub const LATENCY_BUCKETS_US: [u64; 10] = [
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 1_000_000,
+];
+
+/// Distribution of the latencies of an operation. Each bucket counts the latencies up to its
+/// bound, and is reset upon flush like a `SharedIncMetric`.
+#[derive(Default)]
+pub struct LatencyHistogram([SharedIncMetric; 11]);
+
+impl LatencyHistogram {
+    /// Records an operation which took `us` microseconds.
+    pub fn record(&self, us: u64) {
+        let index = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| us <= bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS_US.len());
+        self.0[index].inc();
+    }
+
+    /// Returns the number of recorded operations.
+    pub fn count(&self) -> usize {
+        self.0.iter().map(IncMetric::count).sum()
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(self.0.iter()) {
+            map.serialize_entry(&format!("le_{}", bound), bucket)?;
+        }
+        map.serialize_entry("le_inf", &self.0[LATENCY_BUCKETS_US.len()])?;
+        map.end()
+    }
+}
+
+/// Metrics of the devices of a kind, keyed by the id of each device.
+pub struct DeviceMetrics<T>(RwLock<BTreeMap<String, Arc<T>>>);
+
+impl<T> Default for DeviceMetrics<T> {
+    fn default() -> Self {
+        DeviceMetrics(RwLock::new(BTreeMap::new()))
+    }
+}
+
+impl<T: Default> DeviceMetrics<T> {
+    /// Returns the metrics of the device `id`, which are created along with the device. A device
+    /// replacing another one with the same id keeps updating its metrics.
+    pub fn get_or_insert(&self, id: &str) -> Arc<T> {
+        if let Some(metrics) = extract_guard(self.0.read()).get(id) {
+            return metrics.clone();
+        }
+        extract_guard(self.0.write())
+            .entry(id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Stops writing the metrics of the device `id`, once it is removed.
+    pub fn remove(&self, id: &str) {
+        extract_guard(self.0.write()).remove(id);
+    }
+}
+
+impl<T: Serialize> Serialize for DeviceMetrics<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let devices = extract_guard(self.0.read());
+        let mut map = serializer.serialize_map(Some(devices.len()))?;
+        for (id, metrics) in devices.iter() {
+            map.serialize_entry(id, metrics.as_ref())?;
+        }
+        map.end()
+    }
+}
+
 // The following structs are used to define a certain organization for the set of metrics we
 // are interested in. Whenever the name of a field differs from its ideal textual representation
 // in the serialized form, we can use the #[serde(rename = "name")] attribute to, well, rename it.
@@ -326,6 +410,10 @@ pub struct PutRequestsMetrics {
     pub metrics_count: SharedIncMetric,
     /// Number of failures in initializing the metrics system.
     pub metrics_fails: SharedIncMetric,
+    /// Number of PUTs for starting the packet capture of a network interface.
+    pub network_capture_count: SharedIncMetric,
+    /// Number of failures in starting the packet capture of a network interface.
+    pub network_capture_fails: SharedIncMetric,
     /// Number of PUTs for creating a new network interface.
     pub network_count: SharedIncMetric,
     /// Number of failures in creating a new network interface.
@@ -349,6 +437,19 @@ pub struct PatchRequestsMetrics {
     pub machine_cfg_fails: SharedIncMetric,
 }
 
+/// Metrics specific to DELETE API Requests for counting user triggered actions and/or failures.
+#[derive(Default, Serialize)]
+pub struct DeleteRequestsMetrics {
+    /// Number of tries to DELETE a block device.
+    pub drive_count: SharedIncMetric,
+    /// Number of failures in DELETEing a block device.
+    pub drive_fails: SharedIncMetric,
+    /// Number of tries to DELETE the packet capture of a network interface.
+    pub network_capture_count: SharedIncMetric,
+    /// Number of failures in DELETEing the packet capture of a network interface.
+    pub network_capture_fails: SharedIncMetric,
+}
+
 /// Balloon Device associated metrics.
 #[derive(Default, Serialize)]
 pub struct BalloonDeviceMetrics {
@@ -401,6 +502,55 @@ pub struct BlockDeviceMetrics {
     pub write_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Number of bytes discarded by this block device.
+    pub discard_bytes: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of bytes zeroed by this block device.
+    pub write_zeroes_bytes: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
+    /// Number of successful reconnections to NBD servers.
+    pub nbd_reconnect_count: SharedIncMetric,
+    /// Number of failed reconnections to NBD servers.
+    pub nbd_reconnect_fails: SharedIncMetric,
+    /// Number of data or hash blocks read from drives which do not match their hash tree.
+    pub verity_fails: SharedIncMetric,
+    /// Latencies of the read operations, in microseconds.
+    pub read_latency_us: LatencyHistogram,
+    /// Latencies of the write operations, in microseconds.
+    pub write_latency_us: LatencyHistogram,
+    /// Latencies of the flush operations, in microseconds.
+    pub flush_latency_us: LatencyHistogram,
+}
+
+/// Metrics of a single drive.
+#[derive(Default, Serialize)]
+pub struct BlockDriveMetrics {
+    /// Number of failures in executing a request on this drive.
+    pub execute_fails: SharedIncMetric,
+    /// Number of successful flush operations.
+    pub flush_count: SharedIncMetric,
+    /// Number of bytes read from this drive.
+    pub read_bytes: SharedIncMetric,
+    /// Number of bytes written to this drive.
+    pub write_bytes: SharedIncMetric,
+    /// Number of successful read operations.
+    pub read_count: SharedIncMetric,
+    /// Number of successful write operations.
+    pub write_count: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
+    /// Number of rate limiter throttling events.
+    pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Latencies of the read operations, in microseconds.
+    pub read_latency_us: LatencyHistogram,
+    /// Latencies of the write operations, in microseconds.
+    pub write_latency_us: LatencyHistogram,
+    /// Latencies of the flush operations, in microseconds.
+    pub flush_latency_us: LatencyHistogram,
 }
 
 /// Metrics specific to the i8042 device.
@@ -465,10 +615,16 @@ pub struct MmdsMetrics {
 pub struct NetDeviceMetrics {
     /// Number of times when activate failed on a network device.
     pub activate_fails: SharedIncMetric,
+    /// Number of times writing to a packet capture file failed.
+    pub capture_write_fails: SharedIncMetric,
     /// Number of times when interacting with the space config of a network device failed.
     pub cfg_fails: SharedIncMetric,
     //// Number of times the mac address was updated through the config space.
     pub mac_address_updates: SharedIncMetric,
+    /// Number of events associated with the control queue.
+    pub ctrl_queue_event_count: SharedIncMetric,
+    /// Number of control commands which were rejected or could not be acknowledged.
+    pub ctrl_fails: SharedIncMetric,
     /// No available buffer for the net device rx queue.
     pub no_rx_avail_buffer: SharedIncMetric,
     /// No available buffer for the net device tx queue.
@@ -517,6 +673,33 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of times the vhost-net kernel driver signaled used descriptors.
+    pub vhost_call_event_count: SharedIncMetric,
+}
+
+/// Metrics of a single network interface.
+#[derive(Default, Serialize)]
+pub struct NetIfaceMetrics {
+    /// Number of RX rate limiter throttling events.
+    pub rx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of bytes received.
+    pub rx_bytes_count: SharedIncMetric,
+    /// Number of packets received.
+    pub rx_packets_count: SharedIncMetric,
+    /// Number of errors while receiving data.
+    pub rx_fails: SharedIncMetric,
+    /// Number of times reading from TAP failed.
+    pub tap_read_fails: SharedIncMetric,
+    /// Number of times writing to TAP failed.
+    pub tap_write_fails: SharedIncMetric,
+    /// Number of transmitted bytes.
+    pub tx_bytes_count: SharedIncMetric,
+    /// Number of errors while transmitting data.
+    pub tx_fails: SharedIncMetric,
+    /// Number of transmitted packets.
+    pub tx_packets_count: SharedIncMetric,
+    /// Number of TX rate limiter throttling events.
+    pub tx_rate_limiter_throttled: SharedIncMetric,
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -539,6 +722,10 @@ pub struct PerformanceMetrics {
     pub pause_vm: SharedStoreMetric,
     /// Measures the microVM resuming duration, at the API (user) level, in microseconds.
     pub resume_vm: SharedStoreMetric,
+    /// Measures the microVM migration send time, at the API (user) level, in microseconds.
+    pub send_migration: SharedStoreMetric,
+    /// Measures the microVM migration receive time, at the API (user) level, in microseconds.
+    pub receive_migration: SharedStoreMetric,
     /// Measures the snapshot full create time, at the VMM level, in microseconds.
     pub vmm_full_create_snapshot: SharedStoreMetric,
     /// Measures the snapshot diff create time, at the VMM level, in microseconds.
@@ -549,6 +736,10 @@ pub struct PerformanceMetrics {
     pub vmm_pause_vm: SharedStoreMetric,
     /// Measures the microVM resuming duration, at the VMM level, in microseconds.
     pub vmm_resume_vm: SharedStoreMetric,
+    /// Measures the microVM migration send time, at the VMM level, in microseconds.
+    pub vmm_send_migration: SharedStoreMetric,
+    /// Measures the microVM migration receive time, at the VMM level, in microseconds.
+    pub vmm_receive_migration: SharedStoreMetric,
 }
 
 /// Metrics specific to the RTC device.
@@ -698,6 +889,10 @@ pub struct FirecrackerMetrics {
     pub balloon: BalloonDeviceMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Metrics of each drive, keyed by drive id.
+    pub block_drives: DeviceMetrics<BlockDriveMetrics>,
+    /// Metrics related to API DELETE requests.
+    pub delete_api_requests: DeleteRequestsMetrics,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics related to the i8042 device.
@@ -710,6 +905,8 @@ pub struct FirecrackerMetrics {
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
     pub net: NetDeviceMetrics,
+    /// Metrics of each network interface, keyed by interface id.
+    pub net_ifaces: DeviceMetrics<NetIfaceMetrics>,
     /// Metrics related to API PATCH requests.
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
@@ -802,6 +999,46 @@ mod tests {
         assert_eq!(1, m1.fetch());
     }
 
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(0);
+        histogram.record(10);
+        histogram.record(11);
+        histogram.record(2_000_000);
+        assert_eq!(histogram.count(), 4);
+
+        let s = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(s["le_10"], 2);
+        assert_eq!(s["le_50"], 1);
+        assert_eq!(s["le_1000000"], 0);
+        assert_eq!(s["le_inf"], 1);
+        // The buckets are reset upon flush.
+        let s = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(s["le_10"], 0);
+        assert_eq!(s["le_inf"], 0);
+    }
+
+    #[test]
+    fn test_device_metrics() {
+        let devices = DeviceMetrics::<NetIfaceMetrics>::default();
+        let eth0 = devices.get_or_insert("eth0");
+        eth0.rx_bytes_count.add(10);
+        devices.get_or_insert("eth1").tx_fails.inc();
+        // A device replacing another one keeps its metrics.
+        assert!(Arc::ptr_eq(&eth0, &devices.get_or_insert("eth0")));
+
+        let s = serde_json::to_value(&devices).unwrap();
+        assert_eq!(s["eth0"]["rx_bytes_count"], 10);
+        assert_eq!(s["eth0"]["tx_fails"], 0);
+        assert_eq!(s["eth1"]["tx_fails"], 1);
+
+        devices.remove("eth1");
+        let s = serde_json::to_value(&devices).unwrap();
+        assert!(s.get("eth0").is_some());
+        assert!(s.get("eth1").is_none());
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
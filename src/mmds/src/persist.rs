@@ -5,6 +5,7 @@
 
 use std::net::Ipv4Addr;
 
+use serde::Serialize;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use versionize::{VersionMap, Versionize, VersionizeResult};
@@ -13,7 +14,7 @@ use versionize_derive::Versionize;
 use super::ns::MmdsNetworkStack;
 
 /// State of a MmdsNetworkStack.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MmdsNetworkStackState {
     mac_addr: [u8; MAC_ADDR_LEN],
@@ -102,11 +102,11 @@ impl Snapshot {
         }
     }
 
-    /// Attempts to load an existing snapshot without CRC validation.
-    pub fn unchecked_load<T, O>(mut reader: &mut T, version_map: VersionMap) -> Result<O, Error>
+    /// Reads the data version an existing snapshot was saved with, without loading
+    /// or validating the snapshot contents.
+    pub fn get_data_version<T>(mut reader: &mut T, version_map: &VersionMap) -> Result<u16, Error>
     where
         T: Read,
-        O: Versionize,
     {
         let format_version_map = Self::format_version_map();
         let magic_id =
@@ -125,8 +125,18 @@ impl Snapshot {
             return Err(Error::InvalidDataVersion(hdr.data_version));
         }
 
-        Ok(O::deserialize(&mut reader, &version_map, hdr.data_version)
-            .map_err(Error::Versionize)?)
+        Ok(hdr.data_version)
+    }
+
+    /// Attempts to load an existing snapshot without CRC validation.
+    pub fn unchecked_load<T, O>(mut reader: &mut T, version_map: VersionMap) -> Result<O, Error>
+    where
+        T: Read,
+        O: Versionize,
+    {
+        let data_version = Self::get_data_version(&mut reader, &version_map)?;
+
+        Ok(O::deserialize(&mut reader, &version_map, data_version).map_err(Error::Versionize)?)
     }
 
     /// Attempts to load an existing snapshot and validate CRC.
@@ -512,6 +522,36 @@ mod tests {
         let _: Test1 = Snapshot::load(&mut snapshot_mem.as_slice(), 38, vm).unwrap();
     }
 
+    #[test]
+    fn test_get_data_version() {
+        let mut vm = VersionMap::new();
+        vm.new_version();
+        let state = Test1 {
+            field_x: 0,
+            field0: 0,
+            field1: 1,
+        };
+
+        let mut snapshot_mem = vec![0u8; 1024];
+        let mut snapshot = Snapshot::new(vm.clone(), 2);
+        snapshot
+            .save(&mut snapshot_mem.as_mut_slice(), &state)
+            .unwrap();
+
+        assert_eq!(
+            Snapshot::get_data_version(&mut snapshot_mem.as_slice(), &vm),
+            Ok(2)
+        );
+        assert_eq!(
+            Snapshot::get_data_version(&mut snapshot_mem.as_slice(), &VersionMap::new()),
+            Err(Error::InvalidDataVersion(2))
+        );
+        assert_eq!(
+            Snapshot::get_data_version(&mut vec![0u8; 16].as_slice(), &vm),
+            Err(Error::InvalidMagic(0))
+        );
+    }
+
     #[test]
     fn test_invalid_snapshot_size() {
         let vm = VersionMap::new();
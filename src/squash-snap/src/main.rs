@@ -0,0 +1,354 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host tool which squashes a chain of memory snapshot files (a full memory file
+//! followed by one or more diff memory files) into a single full memory file.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use utils::arg_parser::{ArgParser, Argument};
+use vmm::compressed_memory::{self, CompressedMemoryReader};
+use vmm::memory_snapshot::{self, data_segments, validate_memory_layers, MemoryFileFormatState};
+use vmm::persist::{snapshot_state_from_file, LoadSnapshotError};
+use vmm::version_map::VERSION_MAP;
+
+const SQUASH_SNAP_VERSION: &str = env!("FIRECRACKER_VERSION");
+const BASE_FILE: &str = "base-file";
+const DIFF_FILE: &str = "diff-file";
+const OUTPUT_FILE: &str = "output-file";
+const SNAPSHOT_PATH: &str = "snapshot-path";
+
+#[derive(Debug)]
+enum Error {
+    Copy(PathBuf, io::Error),
+    EncryptedBase(PathBuf),
+    InvalidMemoryLayers(memory_snapshot::Error),
+    MissingArgument(&'static str),
+    OpenFile(PathBuf, io::Error),
+    ReadCompressed(PathBuf, compressed_memory::Error),
+    ReadSegments(PathBuf, io::Error),
+    SetLength(io::Error),
+    SnapshotState(LoadSnapshotError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Copy(path, err) => write!(f, "Failed to copy data from {:?}: {}", path, err),
+            EncryptedBase(path) => write!(f, "Cannot read the encrypted memory file {:?}", path),
+            InvalidMemoryLayers(err) => write!(f, "Invalid memory file chain: {}", err),
+            MissingArgument(arg) => write!(f, "Missing argument: --{}", arg),
+            OpenFile(path, err) => write!(f, "Failed to open {:?}: {}", path, err),
+            ReadCompressed(path, err) => write!(f, "Failed to read {:?}: {}", path, err),
+            ReadSegments(path, err) => {
+                write!(f, "Failed to find the data segments of {:?}: {}", path, err)
+            }
+            SetLength(err) => write!(f, "Failed to set the length of the output file: {}", err),
+            SnapshotState(err) => write!(f, "Failed to load the snapshot state: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new(BASE_FILE)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the full memory file the chain starts from."),
+        )
+        .arg(
+            Argument::new(DIFF_FILE)
+                .required(true)
+                .allow_multiple(true)
+                .help(
+                    "Path to a diff memory file. This argument can be used multiple times; \
+                     the diff files are applied in the order they are provided.",
+                ),
+        )
+        .arg(
+            Argument::new(OUTPUT_FILE)
+                .required(true)
+                .takes_value(true)
+                .help("Path where the squashed full memory file is written."),
+        )
+        .arg(Argument::new(SNAPSHOT_PATH).takes_value(true).help(
+            "Path to the snapshot file created together with the last diff memory file. \
+             When provided, the memory file chain is verified against it before squashing.",
+        ))
+        .arg(
+            Argument::new("version")
+                .takes_value(false)
+                .help("Print the binary version number."),
+        )
+}
+
+/// Copies all the data segments of `src` at the same offsets in `dst`.
+fn copy_data_segments(src_path: &Path, src: &mut File, dst: &mut File) -> Result<()> {
+    let segments =
+        data_segments(src).map_err(|e| Error::ReadSegments(src_path.to_path_buf(), e))?;
+
+    for (offset, len) in segments {
+        src.seek(SeekFrom::Start(offset))
+            .and_then(|_| dst.seek(SeekFrom::Start(offset)))
+            .and_then(|_| io::copy(&mut src.by_ref().take(len), dst))
+            .map_err(|e| Error::Copy(src_path.to_path_buf(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Writes the decompressed chunks of a compressed memory file at their offsets in `dst`.
+fn decompress_chunks(src_path: &Path, src: &CompressedMemoryReader, dst: &File) -> Result<()> {
+    for chunk in src.data_chunks() {
+        let (offset, data) = chunk.map_err(|e| Error::ReadCompressed(src_path.to_path_buf(), e))?;
+        dst.write_all_at(&data, offset)
+            .map_err(|e| Error::Copy(src_path.to_path_buf(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Writes to `output_path` the result of applying the memory files in `layer_paths`
+/// (the base file first) on top of each other.
+fn squash(
+    layer_paths: &[PathBuf],
+    output_path: &Path,
+    snapshot_path: Option<&PathBuf>,
+) -> Result<()> {
+    let mut layers = layer_paths
+        .iter()
+        .map(|path| File::open(path).map_err(|e| Error::OpenFile(path.clone(), e)))
+        .collect::<Result<Vec<File>>>()?;
+
+    // The format of the base memory file is recorded in the snapshot state, if provided.
+    let base_format = match snapshot_path {
+        Some(snapshot_path) => {
+            let microvm_state = snapshot_state_from_file(snapshot_path, VERSION_MAP.clone())
+                .map_err(Error::SnapshotState)?;
+            validate_memory_layers(&microvm_state.memory_state.layers, &layers, true)
+                .map_err(Error::InvalidMemoryLayers)?;
+            Some(microvm_state.memory_state.base_file_format(layers.len()))
+        }
+        None => None,
+    };
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .map_err(|e| Error::OpenFile(output_path.to_path_buf(), e))?;
+
+    // The base memory file may be compressed, while diff memory files are always raw. Without
+    // the snapshot state, a compressed base memory file is recognized by its index.
+    let compressed_base = match base_format {
+        Some(MemoryFileFormatState::Raw) => None,
+        Some(MemoryFileFormatState::Compressed) => Some(
+            CompressedMemoryReader::new(&layers[0])
+                .map_err(|e| Error::ReadCompressed(layer_paths[0].clone(), e))?,
+        ),
+        Some(MemoryFileFormatState::Encrypted) => {
+            return Err(Error::EncryptedBase(layer_paths[0].clone()))
+        }
+        None => match CompressedMemoryReader::new(&layers[0]) {
+            Ok(reader) => Some(reader),
+            Err(compressed_memory::Error::InvalidFile) => None,
+            Err(e) => return Err(Error::ReadCompressed(layer_paths[0].clone(), e)),
+        },
+    };
+
+    let mut len = 0;
+    for (idx, (path, layer)) in layer_paths.iter().zip(layers.iter()).enumerate() {
+        let layer_len = match compressed_base.as_ref() {
+            Some(reader) if idx == 0 => reader.memory_size(),
+            _ => layer
+                .metadata()
+                .map_err(|e| Error::OpenFile(path.clone(), e))?
+                .len(),
+        };
+        len = std::cmp::max(len, layer_len);
+    }
+    output.set_len(len).map_err(Error::SetLength)?;
+
+    for (idx, (path, layer)) in layer_paths.iter().zip(layers.iter_mut()).enumerate() {
+        match compressed_base.as_ref() {
+            Some(reader) if idx == 0 => decompress_chunks(path, reader, &output)?,
+            _ => copy_data_segments(path, layer, &mut output)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn run(arg_parser: &ArgParser) -> Result<()> {
+    let arguments = arg_parser.arguments();
+
+    let base_file = arguments
+        .single_value(BASE_FILE)
+        .ok_or(Error::MissingArgument(BASE_FILE))?;
+    let diff_files = arguments
+        .multiple_values(DIFF_FILE)
+        .ok_or(Error::MissingArgument(DIFF_FILE))?;
+    let output_file = arguments
+        .single_value(OUTPUT_FILE)
+        .ok_or(Error::MissingArgument(OUTPUT_FILE))?;
+    let snapshot_path = arguments.single_value(SNAPSHOT_PATH).map(PathBuf::from);
+
+    let mut layer_paths = vec![PathBuf::from(base_file)];
+    layer_paths.extend(diff_files.iter().map(PathBuf::from));
+
+    squash(&layer_paths, Path::new(output_file), snapshot_path.as_ref())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    match arg_parser.parse_from_cmdline() {
+        Err(err) => {
+            println!(
+                "Arguments parsing error: {} \n\n\
+                 For more information try --help.",
+                err
+            );
+            process::exit(1);
+        }
+        _ => {
+            if arg_parser.arguments().flag_present("help") {
+                println!("squash-snap v{}\n", SQUASH_SNAP_VERSION);
+                println!("{}\n", arg_parser.formatted_help());
+                process::exit(0);
+            }
+
+            if arg_parser.arguments().flag_present("version") {
+                println!("squash-snap v{}\n", SQUASH_SNAP_VERSION);
+                process::exit(0);
+            }
+        }
+    }
+
+    if let Err(err) = run(&arg_parser) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+    use vmm::compressed_memory::CompressedMemoryWriter;
+
+    fn write_pages(file: &File, len: u64, pages: &[(u64, u8)]) {
+        file.set_len(len).unwrap();
+        for (offset, val) in pages {
+            file.write_all_at(&[*val; 4096], *offset).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_squash() {
+        let base = TempFile::new().unwrap();
+        let diff1 = TempFile::new().unwrap();
+        let diff2 = TempFile::new().unwrap();
+        let output = TempFile::new().unwrap();
+
+        write_pages(
+            base.as_file(),
+            4 * 4096,
+            &[(0, 1), (4096, 1), (3 * 4096, 1)],
+        );
+        write_pages(diff1.as_file(), 4 * 4096, &[(4096, 2), (2 * 4096, 2)]);
+        write_pages(diff2.as_file(), 4 * 4096, &[(2 * 4096, 3)]);
+
+        let layer_paths = vec![
+            base.as_path().to_path_buf(),
+            diff1.as_path().to_path_buf(),
+            diff2.as_path().to_path_buf(),
+        ];
+        squash(&layer_paths, output.as_path(), None).unwrap();
+
+        let mut contents = Vec::new();
+        File::open(output.as_path())
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents.len(), 4 * 4096);
+        for (page, expected) in contents.chunks(4096).zip([1u8, 2, 3, 1].iter()) {
+            assert!(page.iter().all(|b| b == expected));
+        }
+
+        // Squashing into an existing file replaces its contents.
+        output.as_file().write_all(&[0xff; 5 * 4096]).unwrap();
+        squash(&layer_paths[..1], output.as_path(), None).unwrap();
+        let mut contents = Vec::new();
+        File::open(output.as_path())
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents.len(), 4 * 4096);
+        for (page, expected) in contents.chunks(4096).zip([1u8, 1, 0, 1].iter()) {
+            assert!(page.iter().all(|b| b == expected));
+        }
+    }
+
+    #[test]
+    fn test_squash_compressed_base() {
+        let base = TempFile::new().unwrap();
+        let diff = TempFile::new().unwrap();
+        let output = TempFile::new().unwrap();
+
+        let mut writer = CompressedMemoryWriter::new(base.as_file(), 4096);
+        for val in [1u8, 1, 0, 1].iter() {
+            writer.write_all(&[*val; 4096]).unwrap();
+        }
+        writer.finish().unwrap();
+        write_pages(diff.as_file(), 4 * 4096, &[(2 * 4096, 2)]);
+
+        let layer_paths = vec![base.as_path().to_path_buf(), diff.as_path().to_path_buf()];
+        squash(&layer_paths, output.as_path(), None).unwrap();
+
+        let mut contents = Vec::new();
+        File::open(output.as_path())
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents.len(), 4 * 4096);
+        for (page, expected) in contents.chunks(4096).zip([1u8, 1, 2, 1].iter()) {
+            assert!(page.iter().all(|b| b == expected));
+        }
+    }
+
+    #[test]
+    fn test_squash_errors() {
+        let output = TempFile::new().unwrap();
+        let missing = PathBuf::from("/foo/bar");
+
+        match squash(&[missing.clone()], output.as_path(), None) {
+            Err(Error::OpenFile(path, _)) => assert_eq!(path, missing),
+            _ => panic!("Expected an OpenFile error"),
+        }
+
+        let base = TempFile::new().unwrap();
+        match squash(
+            &[base.as_path().to_path_buf()],
+            output.as_path(),
+            Some(&missing),
+        ) {
+            Err(Error::SnapshotState(_)) => (),
+            _ => panic!("Expected a SnapshotState error"),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host tool which translates a snapshot state file to the snapshot data version of
+//! another Firecracker version, reporting the saved values which the translation loses.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::{json, Value};
+use snapshot::Snapshot;
+use utils::arg_parser::{ArgParser, Argument};
+use vmm::persist::{
+    diff_state_values, get_state_data_version, snapshot_state_from_file, snapshot_state_to_file,
+    translate_snapshot_state, CreateSnapshotError, LoadSnapshotError,
+};
+use vmm::version_map::VERSION_MAP;
+
+const TRANSLATE_SNAP_VERSION: &str = env!("FIRECRACKER_VERSION");
+const SNAPSHOT_PATH: &str = "snapshot-path";
+const TARGET_VERSION: &str = "target-version";
+const OUTPUT_PATH: &str = "output-path";
+
+#[derive(Debug)]
+enum Error {
+    InvalidTargetVersion(String, CreateSnapshotError),
+    MissingArgument(&'static str),
+    OpenFile(PathBuf, io::Error),
+    ReadVersion(PathBuf, snapshot::Error),
+    Serialize(serde_json::Error),
+    SnapshotState(PathBuf, LoadSnapshotError),
+    Translate(snapshot::Error),
+    WriteSnapshot(PathBuf, CreateSnapshotError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidTargetVersion(version, err) => {
+                write!(f, "Invalid target version {}: {}", version, err)
+            }
+            MissingArgument(arg) => write!(f, "Missing argument: --{}", arg),
+            OpenFile(path, err) => write!(f, "Failed to open {:?}: {}", path, err),
+            ReadVersion(path, err) => write!(
+                f,
+                "Failed to read the snapshot version of {:?}: {:?}",
+                path, err
+            ),
+            Serialize(err) => write!(f, "Failed to serialize the snapshot state: {}", err),
+            SnapshotState(path, err) => {
+                write!(f, "Failed to load the snapshot state {:?}: {}", path, err)
+            }
+            Translate(err) => write!(f, "Failed to translate the snapshot state: {:?}", err),
+            WriteSnapshot(path, err) => {
+                write!(f, "Failed to write the snapshot state {:?}: {}", path, err)
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new(SNAPSHOT_PATH)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the snapshot state file to translate."),
+        )
+        .arg(
+            Argument::new(TARGET_VERSION)
+                .required(true)
+                .takes_value(true)
+                .help("Firecracker version the snapshot state is translated for (e.g. 0.24.0)."),
+        )
+        .arg(Argument::new(OUTPUT_PATH).takes_value(true).help(
+            "Path where the translated snapshot state file is written. When omitted, \
+             only the translation report is printed.",
+        ))
+        .arg(
+            Argument::new("version")
+                .takes_value(false)
+                .help("Print the binary version number."),
+        )
+}
+
+/// Translates the snapshot state file at `snapshot_path` to the snapshot data version of
+/// Firecracker `target_version`, writing it to `output_path` when provided. Returns a
+/// report of the translation, listing the values it loses.
+fn translate(
+    snapshot_path: &Path,
+    target_version: &str,
+    output_path: Option<&PathBuf>,
+) -> Result<Value> {
+    let mut file =
+        File::open(snapshot_path).map_err(|e| Error::OpenFile(snapshot_path.to_path_buf(), e))?;
+    let source_data_version = Snapshot::get_data_version(&mut file, &VERSION_MAP)
+        .map_err(|e| Error::ReadVersion(snapshot_path.to_path_buf(), e))?;
+    let microvm_state = snapshot_state_from_file(&snapshot_path.to_path_buf(), VERSION_MAP.clone())
+        .map_err(|e| Error::SnapshotState(snapshot_path.to_path_buf(), e))?;
+
+    let target_data_version = get_state_data_version(
+        &Some(target_version.to_string()),
+        &VERSION_MAP,
+        &microvm_state,
+    )
+    .map_err(|e| Error::InvalidTargetVersion(target_version.to_string(), e))?;
+
+    let translated_state =
+        translate_snapshot_state(&microvm_state, target_data_version, VERSION_MAP.clone())
+            .map_err(Error::Translate)?;
+    let lost_values = diff_state_values(
+        &serde_json::to_value(&microvm_state).map_err(Error::Serialize)?,
+        &serde_json::to_value(&translated_state).map_err(Error::Serialize)?,
+    );
+
+    if let Some(output_path) = output_path {
+        snapshot_state_to_file(
+            &microvm_state,
+            output_path,
+            target_data_version,
+            VERSION_MAP.clone(),
+        )
+        .map_err(|e| Error::WriteSnapshot(output_path.clone(), e))?;
+    }
+
+    Ok(json!({
+        "source_data_version": source_data_version,
+        "target_data_version": target_data_version,
+        "lost_values": lost_values,
+    }))
+}
+
+fn run(arg_parser: &ArgParser) -> Result<()> {
+    let arguments = arg_parser.arguments();
+
+    let snapshot_path = arguments
+        .single_value(SNAPSHOT_PATH)
+        .ok_or(Error::MissingArgument(SNAPSHOT_PATH))?;
+    let target_version = arguments
+        .single_value(TARGET_VERSION)
+        .ok_or(Error::MissingArgument(TARGET_VERSION))?;
+    let output_path = arguments.single_value(OUTPUT_PATH).map(PathBuf::from);
+
+    let report = translate(
+        Path::new(snapshot_path),
+        target_version,
+        output_path.as_ref(),
+    )?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).map_err(Error::Serialize)?
+    );
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    match arg_parser.parse_from_cmdline() {
+        Err(err) => {
+            println!(
+                "Arguments parsing error: {} \n\n\
+                 For more information try --help.",
+                err
+            );
+            process::exit(1);
+        }
+        _ => {
+            if arg_parser.arguments().flag_present("help") {
+                println!("translate-snap v{}\n", TRANSLATE_SNAP_VERSION);
+                println!("{}\n", arg_parser.formatted_help());
+                process::exit(0);
+            }
+
+            if arg_parser.arguments().flag_present("version") {
+                println!("translate-snap v{}\n", TRANSLATE_SNAP_VERSION);
+                process::exit(0);
+            }
+        }
+    }
+
+    if let Err(err) = run(&arg_parser) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_translate_errors() {
+        let missing = PathBuf::from("/foo/bar");
+        match translate(&missing, "0.24.0", None) {
+            Err(Error::OpenFile(path, _)) => assert_eq!(path, missing),
+            _ => panic!("Expected an OpenFile error"),
+        }
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0u8; 16]).unwrap();
+        match translate(file.as_path(), "0.24.0", None) {
+            Err(Error::ReadVersion(_, snapshot::Error::InvalidMagic(0))) => (),
+            _ => panic!("Expected a ReadVersion error"),
+        }
+    }
+}
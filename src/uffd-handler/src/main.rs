@@ -0,0 +1,429 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Example page fault handler for microVMs restored from a snapshot with a
+//! `uffd_socket_path`. It populates the guest memory on first access with the
+//! contents of the raw or compressed memory file created with the snapshot.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use utils::arg_parser::{ArgParser, Argument};
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::uffd::{Event, Uffd};
+use vmm::compressed_memory::{self, CompressedMemoryReader};
+use vmm::memory_snapshot::MemoryFileFormatState;
+use vmm::persist::{GuestRegionUffdMapping, UffdMemoryLayout};
+
+const UFFD_HANDLER_VERSION: &str = env!("FIRECRACKER_VERSION");
+const SOCKET_PATH: &str = "socket-path";
+// Upper bound for the size of the memory layout message.
+const MAX_LAYOUT_LEN: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum Error {
+    Accept(io::Error),
+    Bind(PathBuf, io::Error),
+    EncryptedMemoryFile(PathBuf),
+    InvalidLayout(serde_json::Error),
+    MissingArgument(&'static str),
+    MissingUffd,
+    OpenMemoryFile(PathBuf, io::Error),
+    Poll(io::Error),
+    ReadCompressed(compressed_memory::Error),
+    ReadEvent(io::Error),
+    ReadMemoryFile(io::Error),
+    Receive(io::Error),
+    ServePage(u64, io::Error),
+    UnknownAddress(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Accept(err) => write!(f, "Failed to accept a connection: {}", err),
+            Bind(path, err) => write!(f, "Failed to bind to {:?}: {}", path, err),
+            EncryptedMemoryFile(path) => {
+                write!(f, "Cannot serve the encrypted memory file {:?}", path)
+            }
+            InvalidLayout(err) => write!(f, "Invalid memory layout: {}", err),
+            MissingArgument(arg) => write!(f, "Missing argument: --{}", arg),
+            MissingUffd => write!(f, "No userfaultfd was received"),
+            OpenMemoryFile(path, err) => write!(f, "Failed to open {:?}: {}", path, err),
+            Poll(err) => write!(f, "Failed to poll for events: {}", err),
+            ReadCompressed(err) => write!(f, "Failed to read the memory file: {}", err),
+            ReadEvent(err) => write!(f, "Failed to read a userfaultfd event: {}", err),
+            ReadMemoryFile(err) => write!(f, "Failed to read the memory file: {}", err),
+            Receive(err) => write!(f, "Failed to receive the memory layout: {}", err),
+            ServePage(addr, err) => write!(f, "Failed to serve the page at {:#x}: {}", addr, err),
+            UnknownAddress(addr) => write!(f, "Page fault outside guest memory: {:#x}", addr),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn page_size() -> usize {
+    // Safe because sysconf has no side effects.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The contents the guest memory is populated with.
+enum MemorySource {
+    Raw(File),
+    Compressed(CompressedMemoryReader),
+}
+
+impl MemorySource {
+    fn open(path: &Path, format: MemoryFileFormatState) -> Result<Self> {
+        let file = File::open(path).map_err(|e| Error::OpenMemoryFile(path.to_path_buf(), e))?;
+        match format {
+            MemoryFileFormatState::Raw => Ok(MemorySource::Raw(file)),
+            MemoryFileFormatState::Compressed => CompressedMemoryReader::new(&file)
+                .map(MemorySource::Compressed)
+                .map_err(Error::ReadCompressed),
+            MemoryFileFormatState::Encrypted => Err(Error::EncryptedMemoryFile(path.to_path_buf())),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            MemorySource::Raw(file) => file
+                .read_exact_at(buf, offset)
+                .map_err(Error::ReadMemoryFile),
+            MemorySource::Compressed(reader) => {
+                reader.read_at(offset, buf).map_err(Error::ReadCompressed)
+            }
+        }
+    }
+}
+
+struct PageFaultHandler {
+    uffd: Uffd,
+    regions: Vec<GuestRegionUffdMapping>,
+    source: MemorySource,
+    page: Vec<u8>,
+}
+
+impl PageFaultHandler {
+    fn new(uffd: Uffd, regions: Vec<GuestRegionUffdMapping>, source: MemorySource) -> Self {
+        PageFaultHandler {
+            uffd,
+            regions,
+            source,
+            page: vec![0u8; page_size()],
+        }
+    }
+
+    /// Populates the page containing `addr` from the memory file.
+    fn serve_pagefault(&mut self, addr: u64) -> Result<()> {
+        let page_addr = addr & !(self.page.len() as u64 - 1);
+        let region = self
+            .regions
+            .iter()
+            .find(|region| {
+                page_addr >= region.base_host_virt_addr
+                    && page_addr - region.base_host_virt_addr < region.size as u64
+            })
+            .ok_or(Error::UnknownAddress(addr))?;
+        let offset = region.offset + (page_addr - region.base_host_virt_addr);
+
+        self.source.read_at(offset, &mut self.page)?;
+        if self.page.iter().all(|&byte| byte == 0) {
+            self.uffd.zeropage(page_addr, self.page.len())
+        } else {
+            // Safe because `self.page` is valid for reading a whole page.
+            unsafe {
+                self.uffd.copy(
+                    self.page.as_ptr() as *const c_void,
+                    page_addr,
+                    self.page.len(),
+                )
+            }
+        }
+        .map_err(|e| Error::ServePage(page_addr, e))
+    }
+
+    /// Serves all the pending page faults.
+    fn handle_events(&mut self) -> Result<()> {
+        while let Some(event) = self.uffd.read_event().map_err(Error::ReadEvent)? {
+            match event {
+                Event::Pagefault { addr } => self.serve_pagefault(addr)?,
+                // Only missing page faults are requested when registering the memory.
+                Event::Other(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves page faults until Firecracker closes its end of `stream`.
+    fn run(&mut self, stream: &UnixStream) -> Result<()> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stream.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            // Safe because `fds` is valid for the duration of the call.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Poll(err));
+            }
+
+            if fds[0].revents & libc::POLLIN != 0 {
+                self.handle_events()?;
+            }
+            // Firecracker never writes to the socket, so any event means it went away.
+            if fds[1].revents != 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Receives the memory layout and the userfaultfd sent by Firecracker over `stream`.
+fn receive_memory(stream: &UnixStream) -> Result<(UffdMemoryLayout, Uffd)> {
+    let mut buf = vec![0u8; MAX_LAYOUT_LEN];
+    let (len, file) = stream
+        .recv_with_fd(&mut buf)
+        .map_err(|e| Error::Receive(io::Error::from_raw_os_error(e.errno())))?;
+    let file = file.ok_or(Error::MissingUffd)?;
+    let layout = serde_json::from_slice(&buf[..len]).map_err(Error::InvalidLayout)?;
+    // Safe because Firecracker sends a userfaultfd, which is now owned by this process.
+    let uffd = unsafe { Uffd::from_raw_fd(file.into_raw_fd()) };
+    Ok((layout, uffd))
+}
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new(SOCKET_PATH)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the Unix domain socket Firecracker connects to."),
+        )
+        .arg(
+            Argument::new("version")
+                .takes_value(false)
+                .help("Print the binary version number."),
+        )
+}
+
+fn run(arg_parser: &ArgParser) -> Result<()> {
+    let socket_path = arg_parser
+        .arguments()
+        .single_value(SOCKET_PATH)
+        .map(PathBuf::from)
+        .ok_or(Error::MissingArgument(SOCKET_PATH))?;
+
+    let listener =
+        UnixListener::bind(&socket_path).map_err(|e| Error::Bind(socket_path.clone(), e))?;
+    let (stream, _) = listener.accept().map_err(Error::Accept)?;
+
+    let (layout, uffd) = receive_memory(&stream)?;
+    let source = MemorySource::open(&layout.mem_file_path, layout.mem_file_format)?;
+    PageFaultHandler::new(uffd, layout.regions, source).run(&stream)
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    match arg_parser.parse_from_cmdline() {
+        Err(err) => {
+            println!(
+                "Arguments parsing error: {} \n\n\
+                 For more information try --help.",
+                err
+            );
+            process::exit(1);
+        }
+        _ => {
+            if arg_parser.arguments().flag_present("help") {
+                println!("uffd-handler v{}\n", UFFD_HANDLER_VERSION);
+                println!("{}\n", arg_parser.formatted_help());
+                process::exit(0);
+            }
+
+            if arg_parser.arguments().flag_present("version") {
+                println!("uffd-handler v{}\n", UFFD_HANDLER_VERSION);
+                process::exit(0);
+            }
+        }
+    }
+
+    if let Err(err) = run(&arg_parser) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use utils::tempfile::TempFile;
+    use vmm::compressed_memory::CompressedMemoryWriter;
+
+    fn new_uffd() -> Option<Uffd> {
+        match Uffd::new() {
+            Ok(uffd) => Some(uffd),
+            // Unprivileged userfaultfd may be disabled on the host.
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => None,
+            Err(err) => panic!("Cannot create userfaultfd: {}", err),
+        }
+    }
+
+    // Maps `len` bytes of anonymous memory registered with `uffd`, serves one read of each
+    // page from `source` and returns the first byte of each page.
+    fn serve_pages(uffd: Uffd, source: MemorySource, len: usize) -> Vec<u8> {
+        // Safe because we check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        uffd.register(addr, len).unwrap();
+
+        let regions = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: addr as u64,
+            size: len,
+            offset: 0,
+        }];
+        let mut handler = PageFaultHandler::new(uffd, regions, source);
+
+        let base = addr as u64;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let contents = (0..len / page_size())
+                // Safe because the memory is mapped and its pages are populated by the handler.
+                .map(|idx| unsafe {
+                    std::ptr::read_volatile((base + (idx * page_size()) as u64) as *const u8)
+                })
+                .collect::<Vec<u8>>();
+            sender.send(contents).unwrap();
+        });
+        let contents = loop {
+            if let Ok(contents) = receiver.try_recv() {
+                break contents;
+            }
+            handler.handle_events().unwrap();
+            thread::yield_now();
+        };
+
+        // Safe because the memory was mapped above.
+        unsafe { libc::munmap(addr, len) };
+        contents
+    }
+
+    #[test]
+    fn test_serve_raw() {
+        let uffd = match new_uffd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let page_size = page_size();
+
+        let mem_file = TempFile::new().unwrap();
+        mem_file.as_file().set_len(3 * page_size as u64).unwrap();
+        mem_file
+            .as_file()
+            .write_all_at(&vec![1u8; page_size], 0)
+            .unwrap();
+        mem_file
+            .as_file()
+            .write_all_at(&vec![3u8; page_size], 2 * page_size as u64)
+            .unwrap();
+
+        let source = MemorySource::open(mem_file.as_path(), MemoryFileFormatState::Raw).unwrap();
+        assert!(matches!(source, MemorySource::Raw(_)));
+        assert_eq!(serve_pages(uffd, source, 3 * page_size), vec![1, 0, 3]);
+    }
+
+    #[test]
+    fn test_serve_compressed() {
+        // The memory file below is laid out for 4K pages.
+        if page_size() != 4096 {
+            return;
+        }
+        let uffd = match new_uffd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+
+        let mem_file = TempFile::new().unwrap();
+        let mut writer = CompressedMemoryWriter::new(mem_file.as_file(), 4096);
+        for val in [0u8, 5, 0, 7].iter() {
+            writer.write_all(&[*val; 4096]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let source =
+            MemorySource::open(mem_file.as_path(), MemoryFileFormatState::Compressed).unwrap();
+        assert!(matches!(source, MemorySource::Compressed(_)));
+        assert_eq!(serve_pages(uffd, source, 4 * 4096), vec![0, 5, 0, 7]);
+    }
+
+    #[test]
+    fn test_receive_memory() {
+        let uffd = match new_uffd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let layout = UffdMemoryLayout {
+            mem_file_path: PathBuf::from("/foo/bar"),
+            mem_file_format: MemoryFileFormatState::Compressed,
+            regions: vec![GuestRegionUffdMapping {
+                base_host_virt_addr: 0x1000,
+                size: 0x2000,
+                offset: 0,
+            }],
+        };
+        let body = serde_json::to_vec(&layout).unwrap();
+        sender.send_with_fd(&body[..], uffd.as_raw_fd()).unwrap();
+        let (received_layout, _) = receive_memory(&receiver).unwrap();
+        assert_eq!(received_layout, layout);
+
+        // A message without a file descriptor is rejected.
+        (&sender).write_all(&body).unwrap();
+        match receive_memory(&receiver) {
+            Err(Error::MissingUffd) => (),
+            _ => panic!("Expected a MissingUffd error"),
+        }
+
+        let err = Error::UnknownAddress(0);
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -0,0 +1,568 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal wrapper over the Linux io_uring interface, covering the file reads, writes
+//! and syncs of the block device.
+
+use std::fs::File;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// As defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v5.10/source/include/uapi/linux/io_uring.h
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_EVENTFD: u32 = 4;
+const IORING_REGISTER_PROBE: u32 = 8;
+const IO_URING_OP_SUPPORTED: u16 = 1;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+// Number of entries of the probe, enough to cover every opcode.
+const PROBE_OPS: usize = 256;
+
+#[repr(C)]
+#[derive(Default)]
+struct IoSqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IoCqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IoUringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: IoSqringOffsets,
+    cq_off: IoCqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IoUringSqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IoUringCqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IoUringProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+#[repr(C)]
+struct IoUringProbe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [IoUringProbeOp; PROBE_OPS],
+}
+
+/// An operation submitted to an io_uring.
+#[derive(Clone, Copy, Debug)]
+pub struct Operation {
+    sqe: IoUringSqe,
+}
+
+impl Operation {
+    /// Reads `len` bytes at `offset` of the file `fd` into the buffer at `addr`.
+    pub fn read(fd: RawFd, addr: *mut u8, len: u32, offset: u64, user_data: u64) -> Self {
+        Self::rw(IORING_OP_READ, fd, addr as u64, len, offset, user_data)
+    }
+
+    /// Writes `len` bytes from the buffer at `addr` at `offset` of the file `fd`.
+    pub fn write(fd: RawFd, addr: *const u8, len: u32, offset: u64, user_data: u64) -> Self {
+        Self::rw(IORING_OP_WRITE, fd, addr as u64, len, offset, user_data)
+    }
+
+    /// Syncs the data and metadata of the file `fd` to the disk.
+    pub fn fsync(fd: RawFd, user_data: u64) -> Self {
+        Self::rw(IORING_OP_FSYNC, fd, 0, 0, 0, user_data)
+    }
+
+    fn rw(opcode: u8, fd: RawFd, addr: u64, len: u32, off: u64, user_data: u64) -> Self {
+        Operation {
+            sqe: IoUringSqe {
+                opcode,
+                fd,
+                off,
+                addr,
+                len,
+                user_data,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns the value identifying the operation in its completion.
+    pub fn user_data(&self) -> u64 {
+        self.sqe.user_data
+    }
+}
+
+/// The outcome of a completed operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Completion {
+    user_data: u64,
+    res: i32,
+}
+
+impl Completion {
+    /// Returns the value identifying the operation, as set when it was submitted.
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// Returns the number of bytes transferred by the operation, or its error.
+    pub fn result(&self) -> io::Result<u32> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+}
+
+// A memory mapping shared with the kernel.
+struct Mmap {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // Safe because we check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    // Returns the `u32` at `offset`, which the kernel may access concurrently.
+    fn atomic_u32(&self, offset: u32) -> &AtomicU32 {
+        // Safe because the kernel provides naturally aligned offsets within the mapping.
+        unsafe { &*(self.addr.add(offset as usize) as *const AtomicU32) }
+    }
+
+    fn u32_at(&self, offset: u32) -> u32 {
+        // Safe because the kernel provides naturally aligned offsets within the mapping.
+        unsafe { ptr::read_volatile(self.addr.add(offset as usize) as *const u32) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // Safe because we mapped the area ourselves and nothing references it anymore.
+        unsafe { libc::munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+/// An io_uring instance, with submission and completion queues shared with the kernel.
+///
+/// The number of operations in flight is capped to the size of the submission queue, so
+/// that the completion queue, twice as large, never overflows.
+pub struct IoUring {
+    file: File,
+    sq_ring: Mmap,
+    // `None` when the kernel maps both queues in `sq_ring`.
+    cq_ring: Option<Mmap>,
+    sqes: Mmap,
+    params: IoUringParams,
+    // Operations pushed but not yet submitted to the kernel.
+    unsubmitted: u32,
+    // Operations pushed and whose completion was not popped yet.
+    in_flight: u32,
+}
+
+impl IoUring {
+    /// Creates an io_uring with room for `entries` operations in flight. Fails if the
+    /// kernel does not implement the file operations of `Operation`.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = IoUringParams::default();
+        // Safe because `params` is valid for writes and we check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut IoUringParams,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the newly created file descriptor.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let sq_ring_len =
+            params.sq_off.array as usize + params.sq_entries as usize * std::mem::size_of::<u32>();
+        let cq_ring_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * std::mem::size_of::<IoUringCqe>();
+        let (sq_ring, cq_ring) = if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let len = std::cmp::max(sq_ring_len, cq_ring_len);
+            (Mmap::new(fd as RawFd, len, IORING_OFF_SQ_RING)?, None)
+        } else {
+            (
+                Mmap::new(fd as RawFd, sq_ring_len, IORING_OFF_SQ_RING)?,
+                Some(Mmap::new(fd as RawFd, cq_ring_len, IORING_OFF_CQ_RING)?),
+            )
+        };
+        let sqes = Mmap::new(
+            fd as RawFd,
+            params.sq_entries as usize * std::mem::size_of::<IoUringSqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        let ring = IoUring {
+            file,
+            sq_ring,
+            cq_ring,
+            sqes,
+            params,
+            unsubmitted: 0,
+            in_flight: 0,
+        };
+        ring.check_supported_ops()?;
+        Ok(ring)
+    }
+
+    fn check_supported_ops(&self) -> io::Result<()> {
+        let mut probe = IoUringProbe {
+            last_op: 0,
+            ops_len: 0,
+            resv: 0,
+            resv2: [0; 3],
+            ops: [IoUringProbeOp::default(); PROBE_OPS],
+        };
+        // Kernels older than 5.6 cannot be probed, and do not implement reads and writes
+        // on plain buffers either.
+        self.register(
+            IORING_REGISTER_PROBE,
+            &mut probe as *mut IoUringProbe as *const c_void,
+            PROBE_OPS as u32,
+        )?;
+        for opcode in &[IORING_OP_FSYNC, IORING_OP_READ, IORING_OP_WRITE] {
+            let op = probe.ops[*opcode as usize];
+            if *opcode > probe.last_op || op.flags & IO_URING_OP_SUPPORTED == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+            }
+        }
+        Ok(())
+    }
+
+    fn register(&self, opcode: u32, arg: *const c_void, nr_args: u32) -> io::Result<()> {
+        // Safe because the caller provides an `arg` valid for `opcode` and we check the
+        // return value.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.file.as_raw_fd(),
+                opcode,
+                arg,
+                nr_args,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Has the kernel signal the eventfd `fd` every time an operation completes.
+    pub fn register_eventfd(&self, fd: RawFd) -> io::Result<()> {
+        self.register(
+            IORING_REGISTER_EVENTFD,
+            &fd as *const RawFd as *const c_void,
+            1,
+        )
+    }
+
+    /// Returns the number of operations pushed whose completion was not popped yet.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight
+    }
+
+    /// Specifies if no more operations can be pushed before popping completions.
+    pub fn is_full(&self) -> bool {
+        self.in_flight >= self.params.sq_entries
+    }
+
+    /// Queues `op` for submission. Returns the operation back if the ring is full.
+    ///
+    /// # Safety
+    ///
+    /// The file and the buffer of `op` must stay valid until its completion is popped.
+    pub unsafe fn push(&mut self, op: Operation) -> Result<(), Operation> {
+        if self.is_full() {
+            return Err(op);
+        }
+        let sq_off = &self.params.sq_off;
+        // Only we write the tail, so there is no need to synchronize when loading it.
+        let tail = self.sq_ring.atomic_u32(sq_off.tail).load(Ordering::Relaxed);
+        let index = tail & self.sq_ring.u32_at(sq_off.ring_mask);
+        // The kernel is done with the entry at `index`, because less than `sq_entries`
+        // operations are in flight.
+        ptr::write_volatile(
+            (self.sqes.addr as *mut IoUringSqe).add(index as usize),
+            op.sqe,
+        );
+        ptr::write_volatile(
+            (self.sq_ring.addr.add(sq_off.array as usize) as *mut u32).add(index as usize),
+            index,
+        );
+        // Publish the entry to the kernel.
+        self.sq_ring
+            .atomic_u32(sq_off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Submits the pushed operations to the kernel.
+    pub fn submit(&mut self) -> io::Result<()> {
+        self.enter(0, 0)
+    }
+
+    /// Submits the pushed operations to the kernel and waits for all the operations in
+    /// flight to complete.
+    pub fn submit_and_wait_all(&mut self) -> io::Result<()> {
+        let pending = self.in_flight - self.completed();
+        self.enter(pending, IORING_ENTER_GETEVENTS)
+    }
+
+    fn enter(&mut self, min_complete: u32, flags: u32) -> io::Result<()> {
+        if self.unsubmitted == 0 && min_complete == 0 {
+            return Ok(());
+        }
+        loop {
+            // Safe because the ring is ours and we check the return value.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.file.as_raw_fd(),
+                    self.unsubmitted,
+                    min_complete,
+                    flags,
+                    ptr::null::<c_void>(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            self.unsubmitted -= ret as u32;
+            return Ok(());
+        }
+    }
+
+    fn cq_ring(&self) -> &Mmap {
+        self.cq_ring.as_ref().unwrap_or(&self.sq_ring)
+    }
+
+    // Returns the number of completions waiting to be popped.
+    fn completed(&self) -> u32 {
+        let cq_off = &self.params.cq_off;
+        let cq_ring = self.cq_ring();
+        let tail = cq_ring.atomic_u32(cq_off.tail).load(Ordering::Acquire);
+        let head = cq_ring.atomic_u32(cq_off.head).load(Ordering::Relaxed);
+        tail.wrapping_sub(head)
+    }
+
+    /// Pops the next completion, if any.
+    pub fn pop(&mut self) -> Option<Completion> {
+        let cq_off = &self.params.cq_off;
+        let cq_ring = self.cq_ring();
+        let head = cq_ring.atomic_u32(cq_off.head).load(Ordering::Relaxed);
+        if cq_ring.atomic_u32(cq_off.tail).load(Ordering::Acquire) == head {
+            return None;
+        }
+        let index = head & cq_ring.u32_at(cq_off.ring_mask);
+        // Safe because the kernel published the entry at `index` before moving the tail.
+        let cqe = unsafe {
+            ptr::read_volatile(
+                (cq_ring.addr.add(cq_off.cqes as usize) as *const IoUringCqe).add(index as usize),
+            )
+        };
+        // Hand the entry back to the kernel.
+        cq_ring
+            .atomic_u32(cq_off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+        self.in_flight -= 1;
+        Some(Completion {
+            user_data: cqe.user_data,
+            res: cqe.res,
+        })
+    }
+}
+
+// Safe because the mappings are owned by the ring and only accessed through it, and the
+// kernel does not care which thread uses the ring.
+unsafe impl Send for IoUring {}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom};
+
+    use vmm_sys_util::eventfd::EventFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_io_uring() {
+        let mut ring = match IoUring::new(4) {
+            Ok(ring) => ring,
+            // io_uring may be unavailable on the host.
+            Err(err) => {
+                eprintln!("Skipping the io_uring test: {}", err);
+                return;
+            }
+        };
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        ring.register_eventfd(evt.as_raw_fd()).unwrap();
+        assert_eq!(ring.pop(), None);
+
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        let data = [0xabu8; 512];
+        let mut buf = [0u8; 512];
+
+        // Safe because the file and the buffers outlive the operations.
+        unsafe {
+            ring.push(Operation::write(fd, data.as_ptr(), 512, 512, 1))
+                .unwrap();
+            ring.push(Operation::fsync(fd, 2)).unwrap();
+        }
+        assert_eq!(ring.in_flight(), 2);
+        ring.submit_and_wait_all().unwrap();
+        let mut completions = vec![ring.pop().unwrap(), ring.pop().unwrap()];
+        completions.sort_by_key(Completion::user_data);
+        assert_eq!(completions[0].user_data(), 1);
+        assert_eq!(completions[0].result().unwrap(), 512);
+        assert_eq!(completions[1].result().unwrap(), 0);
+        assert_eq!(ring.pop(), None);
+        assert_eq!(ring.in_flight(), 0);
+        assert!(evt.read().unwrap() >= 1);
+
+        let mut content = Vec::new();
+        let mut f = file.as_file();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 1024);
+        assert_eq!(&content[512..], &data[..]);
+
+        // Safe because the file and the buffer outlive the operation.
+        unsafe {
+            ring.push(Operation::read(fd, buf.as_mut_ptr(), 512, 512, 3))
+                .unwrap();
+        }
+        ring.submit_and_wait_all().unwrap();
+        assert_eq!(ring.pop().unwrap().result().unwrap(), 512);
+        assert_eq!(&buf[..], &data[..]);
+
+        // The ring only accepts as many operations as it has entries.
+        for i in 0..4 {
+            // Safe because the file outlives the operations.
+            unsafe { ring.push(Operation::fsync(fd, i)).unwrap() };
+        }
+        assert!(ring.is_full());
+        // Safe because the operation is not pushed.
+        assert!(unsafe { ring.push(Operation::fsync(fd, 4)) }.is_err());
+        ring.submit_and_wait_all().unwrap();
+        while ring.pop().is_some() {}
+        assert!(!ring.is_full());
+
+        // Errors are reported in the completion.
+        // Safe because the buffer outlives the operation.
+        unsafe {
+            ring.push(Operation::read(-1, buf.as_mut_ptr(), 512, 0, 5))
+                .unwrap();
+        }
+        ring.submit_and_wait_all().unwrap();
+        let completion = ring.pop().unwrap();
+        assert_eq!(completion.user_data(), 5);
+        assert_eq!(
+            completion.result().unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+}
@@ -6,15 +6,19 @@
 // More specifically, we are re-exporting modules from `vmm_sys_util` as part
 // of the `utils` crate.
 pub use vmm_sys_util::{
-    epoll, errno, eventfd, fam, ioctl, rand, syscall, tempdir, tempfile, terminal,
+    epoll, errno, eventfd, fam, ioctl, rand, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
+};
+pub use vmm_sys_util::{
+    ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr,
 };
-pub use vmm_sys_util::{ioctl_expr, ioctl_ioc_nr, ioctl_iow_nr};
 
 pub mod arg_parser;
 pub mod byte_order;
+pub mod io_uring;
 pub mod net;
 pub mod signal;
 pub mod sm;
 pub mod structs;
 pub mod time;
+pub mod uffd;
 pub mod validators;
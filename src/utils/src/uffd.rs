@@ -0,0 +1,283 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal wrapper over the Linux userfaultfd interface, covering the page fault
+//! handling of anonymous memory.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_expr, ioctl_ioc_nr, ioctl_iowr_nr};
+
+// As defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v4.14/source/include/uapi/linux/userfaultfd.h
+const UFFD_API: u64 = 0xAA;
+const UFFDIO: ::std::os::raw::c_uint = 0xAA;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_MSG_SIZE: usize = 32;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+ioctl_iowr_nr!(UFFDIO_ZEROPAGE, UFFDIO, 0x04, UffdioZeropage);
+
+/// Events read from a userfaultfd.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// The page containing `addr` was accessed while missing.
+    Pagefault {
+        /// The faulting address.
+        addr: u64,
+    },
+    /// Any event the handler didn't ask for.
+    Other(u8),
+}
+
+/// A userfaultfd object.
+pub struct Uffd {
+    file: File,
+}
+
+impl Uffd {
+    /// Creates a new non-blocking userfaultfd object.
+    pub fn new() -> io::Result<Self> {
+        // Safe because we check the return value.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the newly created file descriptor.
+        let uffd = unsafe { Uffd::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because we know that our file is a userfaultfd and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(uffd)
+    }
+
+    /// Registers the `[addr, addr + len)` range, so that accessing its missing pages
+    /// generates page fault events.
+    pub fn register(&self, addr: *mut c_void, len: usize) -> io::Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: addr as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ..Default::default()
+        };
+        // Safe because we know that our file is a userfaultfd and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, UFFDIO_REGISTER(), &mut register) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Atomically copies `len` bytes from `src` to the missing pages at `dst`, and wakes
+    /// up the threads waiting on them.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reading `len` bytes. `dst` and `len` must be page aligned.
+    pub unsafe fn copy(&self, src: *const c_void, dst: u64, len: usize) -> io::Result<()> {
+        let mut copy = UffdioCopy {
+            dst,
+            src: src as u64,
+            len: len as u64,
+            ..Default::default()
+        };
+        if ioctl_with_mut_ref(self, UFFDIO_COPY(), &mut copy) < 0 {
+            let err = io::Error::last_os_error();
+            // The pages were populated in the meantime, e.g. by a concurrent fault.
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the zero page over the missing pages in `[dst, dst + len)`, and wakes up the
+    /// threads waiting on them. `dst` and `len` must be page aligned.
+    pub fn zeropage(&self, dst: u64, len: usize) -> io::Result<()> {
+        let mut zeropage = UffdioZeropage {
+            range: UffdioRange {
+                start: dst,
+                len: len as u64,
+            },
+            ..Default::default()
+        };
+        // Safe because we know that our file is a userfaultfd and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, UFFDIO_ZEROPAGE(), &mut zeropage) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next event. Returns `None` if no event is pending.
+    pub fn read_event(&self) -> io::Result<Option<Event>> {
+        let mut msg = [0u8; UFFD_MSG_SIZE];
+        match (&self.file).read(&mut msg) {
+            Ok(len) if len == UFFD_MSG_SIZE => (),
+            Ok(_) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        // struct uffd_msg: the event type is the first byte and the faulting address of
+        // a page fault is found at offset 16.
+        if msg[0] != UFFD_EVENT_PAGEFAULT {
+            return Ok(Some(Event::Other(msg[0])));
+        }
+        let mut addr = [0u8; 8];
+        addr.copy_from_slice(&msg[16..24]);
+        Ok(Some(Event::Pagefault {
+            addr: u64::from_ne_bytes(addr),
+        }))
+    }
+}
+
+impl AsRawFd for Uffd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl FromRawFd for Uffd {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Uffd {
+            file: File::from_raw_fd(fd),
+        }
+    }
+}
+
+impl IntoRawFd for Uffd {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_uffd() {
+        let uffd = match Uffd::new() {
+            Ok(uffd) => uffd,
+            // Unprivileged userfaultfd may be disabled on the host.
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => return,
+            Err(err) => panic!("Cannot create userfaultfd: {}", err),
+        };
+        assert_eq!(uffd.read_event().unwrap(), None);
+
+        let page_size = 4096;
+        let len = page_size * 2;
+        // Safe because we check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        uffd.register(addr, len).unwrap();
+
+        let base = addr as u64;
+        let reader = thread::spawn(move || {
+            // Safe because the memory is mapped and its pages are populated by the handler.
+            let first = unsafe { std::ptr::read_volatile(base as *const u8) };
+            let second = unsafe { std::ptr::read_volatile((base + 4096) as *const u8) };
+            (first, second)
+        });
+
+        let page = vec![0xabu8; page_size];
+        let mut served = 0;
+        while served < 2 {
+            match uffd.read_event().unwrap() {
+                Some(Event::Pagefault { addr }) => {
+                    let page_addr = addr & !(page_size as u64 - 1);
+                    if page_addr == base {
+                        // Safe because `page` is valid for reading a page.
+                        unsafe {
+                            uffd.copy(page.as_ptr() as *const c_void, page_addr, page_size)
+                                .unwrap()
+                        };
+                    } else {
+                        uffd.zeropage(page_addr, page_size).unwrap();
+                    }
+                    served += 1;
+                }
+                Some(event) => panic!("Unexpected event: {:?}", event),
+                None => thread::yield_now(),
+            }
+        }
+
+        assert_eq!(reader.join().unwrap(), (0xab, 0));
+        // Safe because the memory was mapped above.
+        unsafe { libc::munmap(addr, len) };
+    }
+}
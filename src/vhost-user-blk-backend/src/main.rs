@@ -0,0 +1,613 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Example vhost-user block backend for drives configured with a `vhost_user_socket`.
+//! It serves the requests of a single Firecracker block device from a raw disk image,
+//! reusing the virtio block emulation of the `devices` crate.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process;
+
+use devices::virtio::vhost_user::{
+    self, recv_message, request, send_message, ConfigHeader, MemoryRegion, MemoryTableHeader,
+    Message, VringAddr, VringState, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_REPLY_MASK, VHOST_USER_VRING_IDX_MASK,
+    VHOST_USER_VRING_NOFD_MASK,
+};
+use devices::virtio::{Block, CacheType, FileEngineType, ImageFormat, VirtioDevice};
+use rate_limiter::RateLimiter;
+use utils::arg_parser::{ArgParser, Argument};
+use vm_memory::{ByteValued, FileOffset, GuestAddress, GuestMemoryMmap};
+
+const BACKEND_VERSION: &str = env!("FIRECRACKER_VERSION");
+const SOCKET_PATH: &str = "socket-path";
+const PATH_ON_HOST: &str = "path-on-host";
+const READ_ONLY: &str = "read-only";
+const NUM_QUEUES: &str = "num-queues";
+
+#[derive(Debug)]
+enum Error {
+    Accept(io::Error),
+    ActivateDevice,
+    Bind(PathBuf, io::Error),
+    CreateDevice(io::Error),
+    InvalidArgument(&'static str),
+    InvalidQueue(u32),
+    InvalidRingAddress(u64),
+    MapMemory(vm_memory::mmap::Error),
+    MissingArgument(&'static str),
+    Poll(io::Error),
+    Signal(io::Error),
+    UnsupportedRequest(u32),
+    VhostUser(vhost_user::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Accept(err) => write!(f, "Failed to accept a connection: {}", err),
+            ActivateDevice => write!(f, "Failed to activate the block device"),
+            Bind(path, err) => write!(f, "Failed to bind to {:?}: {}", path, err),
+            CreateDevice(err) => write!(f, "Failed to create the block device: {}", err),
+            InvalidArgument(arg) => write!(f, "Invalid value for argument: --{}", arg),
+            InvalidQueue(index) => write!(f, "Invalid queue: {}", index),
+            InvalidRingAddress(addr) => write!(f, "Invalid virtio ring address: {:#x}", addr),
+            MapMemory(err) => write!(f, "Failed to map the guest memory: {:?}", err),
+            MissingArgument(arg) => write!(f, "Missing argument: --{}", arg),
+            Poll(err) => write!(f, "Failed to poll for events: {}", err),
+            Signal(err) => write!(f, "Failed to signal the frontend: {}", err),
+            UnsupportedRequest(code) => write!(f, "Unsupported vhost-user request: {}", code),
+            VhostUser(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The state of a queue which is not kept by the block device itself.
+#[derive(Default)]
+struct Vring {
+    kick: Option<File>,
+    call: Option<File>,
+    enabled: bool,
+}
+
+struct Backend {
+    stream: UnixStream,
+    block: Block,
+    vrings: Vec<Vring>,
+    // The guest memory regions, along with their address in the frontend.
+    regions: Vec<MemoryRegion>,
+    mem: Option<GuestMemoryMmap>,
+}
+
+impl Backend {
+    fn new(stream: UnixStream, block: Block) -> Self {
+        let vrings = block.queues().iter().map(|_| Vring::default()).collect();
+        Backend {
+            stream,
+            block,
+            vrings,
+            regions: Vec::new(),
+            mem: None,
+        }
+    }
+
+    fn reply(&self, code: u32, payload: &[u8]) -> Result<()> {
+        send_message(&self.stream, code, VHOST_USER_REPLY_MASK, payload, &[])
+            .map_err(Error::VhostUser)
+    }
+
+    fn vring_index(&self, index: u32) -> Result<usize> {
+        if (index as usize) < self.vrings.len() {
+            Ok(index as usize)
+        } else {
+            Err(Error::InvalidQueue(index))
+        }
+    }
+
+    /// Translates an address of the frontend into a guest physical address.
+    fn guest_address(&self, addr: u64) -> Result<GuestAddress> {
+        self.regions
+            .iter()
+            .find(|region| {
+                addr >= region.userspace_addr && addr - region.userspace_addr < region.memory_size
+            })
+            .map(|region| GuestAddress(region.guest_phys_addr + (addr - region.userspace_addr)))
+            .ok_or(Error::InvalidRingAddress(addr))
+    }
+
+    fn set_mem_table(&mut self, msg: Message) -> Result<()> {
+        let header: MemoryTableHeader = msg.payload_as().map_err(Error::VhostUser)?;
+        let num_regions = header.num_regions as usize;
+        let region_size = std::mem::size_of::<MemoryRegion>();
+        let table = &msg.payload[std::mem::size_of::<MemoryTableHeader>()..];
+        if num_regions != msg.files.len() || table.len() != num_regions * region_size {
+            return Err(Error::VhostUser(vhost_user::Error::InvalidMessage));
+        }
+        let regions: Vec<MemoryRegion> = table
+            .chunks(region_size)
+            .filter_map(|chunk| MemoryRegion::from_slice(chunk).copied())
+            .collect();
+
+        let ranges = regions
+            .iter()
+            .zip(msg.files.into_iter())
+            .map(|(region, file)| {
+                (
+                    GuestAddress(region.guest_phys_addr),
+                    region.memory_size as usize,
+                    Some(FileOffset::new(file, region.mmap_offset)),
+                )
+            });
+        self.mem =
+            Some(GuestMemoryMmap::from_ranges_with_files(ranges, false).map_err(Error::MapMemory)?);
+        self.regions = regions;
+        Ok(())
+    }
+
+    fn set_vring_addr(&mut self, addr: VringAddr) -> Result<()> {
+        let index = self.vring_index(addr.index)?;
+        let desc_table = self.guest_address(addr.descriptor)?;
+        let avail_ring = self.guest_address(addr.available)?;
+        let used_ring = self.guest_address(addr.used)?;
+
+        let queue = &mut self.block.queues_mut()[index];
+        queue.desc_table = desc_table;
+        queue.avail_ring = avail_ring;
+        queue.used_ring = used_ring;
+        Ok(())
+    }
+
+    // The payload of `SET_VRING_KICK` and `SET_VRING_CALL` holds the queue index, and a flag
+    // telling whether a file descriptor is attached.
+    fn vring_file(&self, mut msg: Message) -> Result<(usize, Option<File>)> {
+        let payload = msg.payload_u64().map_err(Error::VhostUser)?;
+        let index = self.vring_index((payload & VHOST_USER_VRING_IDX_MASK) as u32)?;
+        if payload & VHOST_USER_VRING_NOFD_MASK != 0 {
+            return Ok((index, None));
+        }
+        match msg.files.pop() {
+            Some(file) => Ok((index, Some(file))),
+            None => Err(Error::VhostUser(vhost_user::Error::InvalidMessage)),
+        }
+    }
+
+    fn set_vring_enable(&mut self, state: VringState) -> Result<()> {
+        let index = self.vring_index(state.index)?;
+        if state.num == 0 {
+            self.vrings[index].enabled = false;
+            return Ok(());
+        }
+
+        let mem = self
+            .mem
+            .clone()
+            .ok_or(Error::VhostUser(vhost_user::Error::InvalidMessage))?;
+        // The device gets activated when the first queue is enabled.
+        if !self.block.is_activated() {
+            self.block
+                .activate(mem.clone())
+                .map_err(|_| Error::ActivateDevice)?;
+        }
+        let queue = &mut self.block.queues_mut()[index];
+        queue.ready = true;
+        if !queue.is_valid(&mem) {
+            return Err(Error::InvalidQueue(state.index));
+        }
+        self.vrings[index].enabled = true;
+        Ok(())
+    }
+
+    /// Handles a message from the frontend.
+    fn handle_message(&mut self, msg: Message) -> Result<()> {
+        match msg.header.request {
+            request::SET_OWNER | request::RESET_OWNER => (),
+            request::GET_FEATURES => {
+                let features =
+                    self.block.avail_features() | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES);
+                self.reply(request::GET_FEATURES, features.as_slice())?;
+            }
+            request::SET_FEATURES => {
+                let features = msg.payload_u64().map_err(Error::VhostUser)?;
+                self.block
+                    .set_acked_features(features & self.block.avail_features());
+            }
+            request::GET_PROTOCOL_FEATURES => {
+                let features =
+                    (1u64 << VHOST_USER_PROTOCOL_F_MQ) | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG);
+                self.reply(request::GET_PROTOCOL_FEATURES, features.as_slice())?;
+            }
+            request::SET_PROTOCOL_FEATURES => (),
+            request::GET_QUEUE_NUM => {
+                let num = self.vrings.len() as u64;
+                self.reply(request::GET_QUEUE_NUM, num.as_slice())?;
+            }
+            request::GET_CONFIG => {
+                let header: ConfigHeader = msg.payload_as().map_err(Error::VhostUser)?;
+                let mut data = vec![0u8; header.size as usize];
+                self.block.read_config(u64::from(header.offset), &mut data);
+                let mut payload = header.as_slice().to_vec();
+                payload.extend_from_slice(&data);
+                self.reply(request::GET_CONFIG, &payload)?;
+            }
+            request::SET_CONFIG => {
+                let header: ConfigHeader = msg.payload_as().map_err(Error::VhostUser)?;
+                let data = &msg.payload[std::mem::size_of::<ConfigHeader>()..];
+                self.block.write_config(u64::from(header.offset), data);
+            }
+            request::SET_MEM_TABLE => self.set_mem_table(msg)?,
+            request::SET_VRING_NUM => {
+                let state: VringState = msg.payload_as().map_err(Error::VhostUser)?;
+                let index = self.vring_index(state.index)?;
+                self.block.queues_mut()[index].size = state.num as u16;
+            }
+            request::SET_VRING_ADDR => {
+                self.set_vring_addr(msg.payload_as().map_err(Error::VhostUser)?)?
+            }
+            request::SET_VRING_BASE => {
+                let state: VringState = msg.payload_as().map_err(Error::VhostUser)?;
+                let index = self.vring_index(state.index)?;
+                self.block.queues_mut()[index].set_next_avail(state.num as u16);
+            }
+            request::GET_VRING_BASE => {
+                let state: VringState = msg.payload_as().map_err(Error::VhostUser)?;
+                let index = self.vring_index(state.index)?;
+                // The queue is stopped until it gets enabled again.
+                self.vrings[index].enabled = false;
+                let reply = VringState {
+                    index: state.index,
+                    num: u32::from(self.block.queues()[index].next_avail()),
+                };
+                self.reply(request::GET_VRING_BASE, reply.as_slice())?;
+            }
+            request::SET_VRING_KICK => {
+                let (index, file) = self.vring_file(msg)?;
+                self.vrings[index].kick = file;
+            }
+            request::SET_VRING_CALL => {
+                let (index, file) = self.vring_file(msg)?;
+                self.vrings[index].call = file;
+            }
+            // Errors are reported through the exit status instead.
+            request::SET_VRING_ERR => (),
+            request::SET_VRING_ENABLE => {
+                self.set_vring_enable(msg.payload_as().map_err(Error::VhostUser)?)?
+            }
+            code => return Err(Error::UnsupportedRequest(code)),
+        }
+        Ok(())
+    }
+
+    /// Processes the requests of the queue `index`, after it was kicked.
+    fn handle_kick(&mut self, index: usize) -> Result<()> {
+        if let Some(mut kick) = self.vrings[index].kick.as_ref() {
+            // Consume the eventfd counter. The kick may have been consumed already, which
+            // is not an error.
+            let _ = kick.read(&mut [0u8; 8]);
+        }
+        if !self.vrings[index].enabled || !self.block.process_queue(index) {
+            return Ok(());
+        }
+        if let Some(mut call) = self.vrings[index].call.as_ref() {
+            call.write_all(&1u64.to_ne_bytes()).map_err(Error::Signal)?;
+        }
+        Ok(())
+    }
+
+    /// Serves the frontend until it closes its end of the socket.
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let mut fds = vec![libc::pollfd {
+                fd: self.stream.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            let kicked_vrings: Vec<(usize, RawFd)> = self
+                .vrings
+                .iter()
+                .enumerate()
+                .filter(|(_, vring)| vring.enabled)
+                .filter_map(|(index, vring)| {
+                    vring.kick.as_ref().map(|kick| (index, kick.as_raw_fd()))
+                })
+                .collect();
+            fds.extend(kicked_vrings.iter().map(|(_, fd)| libc::pollfd {
+                fd: *fd,
+                events: libc::POLLIN,
+                revents: 0,
+            }));
+
+            // Safe because `fds` is valid for the duration of the call.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::Poll(err));
+            }
+
+            for ((index, _), pollfd) in kicked_vrings.iter().zip(fds[1..].iter()) {
+                if pollfd.revents & libc::POLLIN != 0 {
+                    self.handle_kick(*index)?;
+                }
+            }
+            if fds[0].revents != 0 {
+                match recv_message(&self.stream) {
+                    Ok(msg) => self.handle_message(msg)?,
+                    Err(vhost_user::Error::Disconnected) => return Ok(()),
+                    Err(e) => return Err(Error::VhostUser(e)),
+                }
+            }
+        }
+    }
+}
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new(SOCKET_PATH)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the Unix domain socket Firecracker connects to."),
+        )
+        .arg(
+            Argument::new(PATH_ON_HOST)
+                .required(true)
+                .takes_value(true)
+                .help("Path to the raw disk image served to the guest."),
+        )
+        .arg(
+            Argument::new(READ_ONLY)
+                .takes_value(false)
+                .help("Serve the disk image as read-only."),
+        )
+        .arg(
+            Argument::new(NUM_QUEUES)
+                .takes_value(true)
+                .default_value("1")
+                .help("Maximum number of request queues offered to Firecracker."),
+        )
+        .arg(
+            Argument::new("version")
+                .takes_value(false)
+                .help("Print the binary version number."),
+        )
+}
+
+fn run(arg_parser: &ArgParser) -> Result<()> {
+    let arguments = arg_parser.arguments();
+    let socket_path = arguments
+        .single_value(SOCKET_PATH)
+        .map(PathBuf::from)
+        .ok_or(Error::MissingArgument(SOCKET_PATH))?;
+    let path_on_host = arguments
+        .single_value(PATH_ON_HOST)
+        .cloned()
+        .ok_or(Error::MissingArgument(PATH_ON_HOST))?;
+    let num_queues = arguments
+        .single_value(NUM_QUEUES)
+        .map(|num| num.parse::<usize>())
+        .unwrap_or(Ok(1))
+        .map_err(|_| Error::InvalidArgument(NUM_QUEUES))?;
+
+    let block = Block::new(
+        String::from("vhost-user"),
+        None,
+        path_on_host,
+        arguments.flag_present(READ_ONLY),
+        false,
+        RateLimiter::default(),
+        CacheType::Writeback,
+        FileEngineType::Sync,
+        ImageFormat::Raw,
+        None,
+        num_queues,
+        None,
+        None,
+        None,
+    )
+    .map_err(Error::CreateDevice)?;
+
+    let listener =
+        UnixListener::bind(&socket_path).map_err(|e| Error::Bind(socket_path.clone(), e))?;
+    let (stream, _) = listener.accept().map_err(Error::Accept)?;
+    Backend::new(stream, block).run()
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    match arg_parser.parse_from_cmdline() {
+        Err(err) => {
+            println!(
+                "Arguments parsing error: {} \n\n\
+                 For more information try --help.",
+                err
+            );
+            process::exit(1);
+        }
+        _ => {
+            if arg_parser.arguments().flag_present("help") {
+                println!("vhost-user-blk-backend v{}\n", BACKEND_VERSION);
+                println!("{}\n", arg_parser.formatted_help());
+                process::exit(0);
+            }
+
+            if arg_parser.arguments().flag_present("version") {
+                println!("vhost-user-blk-backend v{}\n", BACKEND_VERSION);
+                process::exit(0);
+            }
+        }
+    }
+
+    if let Err(err) = run(&arg_parser) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileExt;
+    use std::thread;
+
+    use devices::virtio::test_utils::{initialize_virtqueue, VirtQueue};
+    use devices::virtio::vhost_user::Frontend;
+    use utils::eventfd::EventFd;
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestMemory};
+
+    const MEM_SIZE: usize = 0x10000;
+
+    fn new_block(disk: &TempFile, num_queues: usize) -> Block {
+        Block::new(
+            String::from("test"),
+            None,
+            disk.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+            num_queues,
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn shared_mem() -> GuestMemoryMmap {
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(MEM_SIZE as u64).unwrap();
+        GuestMemoryMmap::from_ranges_with_files(
+            vec![(GuestAddress(0), MEM_SIZE, Some(FileOffset::new(file, 0)))],
+            false,
+        )
+        .unwrap()
+    }
+
+    // Handles the next message sent by the frontend.
+    fn handle_next(backend: &mut Backend) -> Result<()> {
+        let msg = recv_message(&backend.stream).unwrap();
+        backend.handle_message(msg)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let disk = TempFile::new().unwrap();
+        disk.as_file().set_len(0x1000).unwrap();
+        let (frontend, stream) = UnixStream::pair().unwrap();
+        let mut backend = Backend::new(stream, new_block(&disk, 2));
+        let frontend = Frontend::from_stream(frontend);
+
+        frontend.set_owner().unwrap();
+        handle_next(&mut backend).unwrap();
+        frontend.set_protocol_features(0).unwrap();
+        handle_next(&mut backend).unwrap();
+
+        let thread = thread::spawn(move || {
+            let features = frontend.get_features().unwrap();
+            let protocol_features = frontend.get_protocol_features().unwrap();
+            let queue_num = frontend.get_queue_num().unwrap();
+            let config = frontend.get_config(0, 8).unwrap();
+            (frontend, features, protocol_features, queue_num, config)
+        });
+        for _ in 0..4 {
+            handle_next(&mut backend).unwrap();
+        }
+        let (frontend, features, protocol_features, queue_num, config) = thread.join().unwrap();
+        assert_ne!(features & (1u64 << VHOST_USER_F_PROTOCOL_FEATURES), 0);
+        assert_eq!(
+            features & !(1u64 << VHOST_USER_F_PROTOCOL_FEATURES),
+            backend.block.avail_features()
+        );
+        assert_eq!(
+            protocol_features,
+            (1u64 << VHOST_USER_PROTOCOL_F_MQ) | (1u64 << VHOST_USER_PROTOCOL_F_CONFIG)
+        );
+        assert_eq!(queue_num, 2);
+        // The capacity of the disk, in sectors.
+        assert_eq!(config, 8u64.to_le_bytes().to_vec());
+
+        // Features not offered by the device are not acked.
+        frontend.set_features(u64::max_value()).unwrap();
+        handle_next(&mut backend).unwrap();
+        assert_eq!(
+            backend.block.acked_features(),
+            backend.block.avail_features()
+        );
+
+        // Queues out of range are rejected.
+        frontend.set_vring_num(2, 16).unwrap();
+        assert!(matches!(
+            handle_next(&mut backend),
+            Err(Error::InvalidQueue(2))
+        ));
+
+        // A queue cannot be enabled before the memory table was received.
+        frontend.set_vring_enable(0, true).unwrap();
+        assert!(matches!(
+            handle_next(&mut backend),
+            Err(Error::VhostUser(vhost_user::Error::InvalidMessage))
+        ));
+
+        let err = Error::UnsupportedRequest(0);
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_serve_requests() {
+        let disk = TempFile::new().unwrap();
+        disk.as_file().write_all_at(&[0xab; 0x1000], 0).unwrap();
+        let (frontend, stream) = UnixStream::pair().unwrap();
+        let backend_thread = thread::spawn(move || {
+            let mut backend = Backend::new(stream, new_block(&disk, 1));
+            backend.run()
+        });
+        let frontend = Frontend::from_stream(frontend);
+
+        let mem = shared_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let queue = vq.create_queue();
+        // Reads the first sector of the disk.
+        initialize_virtqueue(&vq);
+        let kick = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let call = EventFd::new(0).unwrap();
+
+        frontend.set_owner().unwrap();
+        frontend.set_mem_table(&mem).unwrap();
+        frontend.set_vring_num(0, queue.actual_size()).unwrap();
+        frontend.set_vring_addr(0, &queue, &mem).unwrap();
+        frontend.set_vring_base(0, 0).unwrap();
+        frontend.set_vring_kick(0, &kick).unwrap();
+        frontend.set_vring_call(0, &call).unwrap();
+        frontend.set_vring_enable(0, true).unwrap();
+
+        kick.write(1).unwrap();
+        assert_eq!(call.read().unwrap(), 1);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x3000)).unwrap(), 0);
+        let mut data = [0u8; 0x1000];
+        mem.read_slice(&mut data, GuestAddress(0x2000)).unwrap();
+        assert!(data.iter().all(|&byte| byte == 0xab));
+        assert_eq!(mem.num_regions(), 1);
+
+        // The backend exits once the frontend goes away.
+        drop(frontend);
+        assert!(backend_thread.join().unwrap().is_ok());
+    }
+}
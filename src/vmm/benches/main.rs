@@ -20,7 +20,7 @@ use vmm::persist::MicrovmState;
 use vmm::utilities::mock_resources::NOISY_KERNEL_IMAGE;
 use vmm::utilities::test_utils::{create_vmm, set_panic_hook, wait_vmm_child_process};
 use vmm::version_map::VERSION_MAP;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemoryFileFormat, SnapshotType};
 
 #[inline]
 pub fn bench_restore_snapshot(
@@ -80,6 +80,9 @@ fn create_microvm_state(is_diff: bool) -> MicrovmState {
                 snapshot_type,
                 snapshot_path: snapshot_file.as_path().to_path_buf(),
                 mem_file_path: memory_file.as_path().to_path_buf(),
+                mem_file_format: MemoryFileFormat::Raw,
+                live: false,
+                encryption: None,
                 version: None,
             };
 
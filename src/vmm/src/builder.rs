@@ -3,10 +3,12 @@
 
 //! Enables pre-boot setup, instantiation and booting of a Firecracker VMM.
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::{Arc, Mutex};
 
 #[cfg(target_arch = "aarch64")]
@@ -17,6 +19,7 @@ use crate::device_manager::mmio::MMIODeviceManager;
 use crate::device_manager::persist::MMIODevManagerConstructorArgs;
 use crate::persist::{MicrovmState, MicrovmStateError};
 use crate::vmm_config::boot_source::BootConfig;
+use crate::vmm_config::snapshot::DeviceOverrides;
 use crate::vstate::{
     system::KvmContext,
     vcpu::{Vcpu, VcpuConfig},
@@ -26,7 +29,9 @@ use crate::{device_manager, Error, Vmm, VmmEventsObserver};
 
 use arch::InitrdConfig;
 use devices::legacy::Serial;
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostUserBlock, VirtioDevice, Vsock, VsockUnixBackend,
+};
 use kernel::cmdline::Cmdline as KernelCmdline;
 use logger::warn;
 use polly::event_manager::{Error as EventManagerError, EventManager, Subscriber};
@@ -35,7 +40,7 @@ use snapshot::Persist;
 use utils::eventfd::EventFd;
 use utils::terminal::Terminal;
 use utils::time::TimestampUs;
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
 
 /// Errors associated with starting the instance.
 #[derive(Debug)]
@@ -76,6 +81,8 @@ pub enum StartMicrovmError {
     RegisterMmioDevice(device_manager::mmio::Error),
     /// Cannot restore microvm state.
     RestoreMicrovmState(MicrovmStateError),
+    /// Cannot create the file backing the guest memory shared with vhost-user backends.
+    SharedGuestMemory(io::Error),
 }
 
 /// It's convenient to automatically convert `kernel::cmdline::Error`s
@@ -153,6 +160,11 @@ impl Display for StartMicrovmError {
                 )
             }
             RestoreMicrovmState(err) => write!(f, "Cannot restore microvm state. Error: {}", err),
+            SharedGuestMemory(err) => write!(
+                f,
+                "Cannot create the file backing the shared guest memory: {}",
+                err
+            ),
         }
     }
 }
@@ -262,10 +274,14 @@ fn create_vmm_and_vcpus(
     let vmm = Vmm {
         events_observer: Some(Box::new(SerialStdin::get())),
         guest_memory,
+        memory_layers: Some(Vec::new()),
+        uffd_handler: None,
         vcpus_handles: Vec::new(),
         exit_evt,
         vm,
         mmio_device_manager,
+        hotplug_fd_pool: BlockFdPool::default(),
+        hotplugged_blocks: HashMap::new(),
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
     };
@@ -295,6 +311,8 @@ pub fn build_microvm_for_boot(
             .mem_size_mib
             .ok_or(MissingMemSizeConfig)?,
         track_dirty_pages,
+        // The vhost-user backends map the guest memory in their own address space.
+        !vm_resources.block.vhost_user_list.is_empty(),
     )?;
     let vcpu_config = vm_resources.vcpu_config();
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
@@ -330,6 +348,12 @@ pub fn build_microvm_for_boot(
         vm_resources.block.list.iter(),
         event_manager,
     )?;
+    attach_vhost_user_block_devices(
+        &mut vmm,
+        &mut boot_cmdline,
+        vm_resources.block.vhost_user_list.iter(),
+        event_manager,
+    )?;
     attach_net_devices(
         &mut vmm,
         &mut boot_cmdline,
@@ -339,6 +363,13 @@ pub fn build_microvm_for_boot(
     if let Some(unix_vsock) = vm_resources.vsock.get() {
         attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
     }
+    vmm.mmio_device_manager
+        .reserve_hotplug_slots(vm_resources.vm_config().hotplug_slots, &mut boot_cmdline)
+        .map_err(RegisterMmioDevice)?;
+    // The seccomp filter doesn't allow creating file descriptors once the microVM is running.
+    vmm.hotplug_fd_pool = BlockFdPool::new(vm_resources.vm_config().hotplug_slots)
+        .map_err(Error::HotplugFds)
+        .map_err(Internal)?;
 
     #[cfg(target_arch = "aarch64")]
     attach_legacy_devices_aarch64(event_manager, &mut vmm, &mut boot_cmdline).map_err(Internal)?;
@@ -374,7 +405,8 @@ pub fn build_microvm_for_boot(
     Ok(vmm)
 }
 
-/// Builds and starts a microVM based on the provided MicrovmState.
+/// Builds and starts a microVM based on the provided MicrovmState, restoring its devices
+/// with the host resources from `device_overrides` instead of the saved ones.
 ///
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
@@ -384,6 +416,7 @@ pub fn build_microvm_from_snapshot(
     guest_memory: GuestMemoryMmap,
     track_dirty_pages: bool,
     seccomp_filter: BpfProgramRef,
+    device_overrides: &DeviceOverrides,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     use self::StartMicrovmError::*;
     let vcpu_count = u8::try_from(microvm_state.vcpu_states.len())
@@ -398,6 +431,13 @@ pub fn build_microvm_from_snapshot(
         vcpu_count,
     )?;
 
+    // Diff snapshots of the restored microVM are layered on top of the loaded memory.
+    if microvm_state.memory_state.layers.is_empty() {
+        vmm.memory_layers = None;
+    } else {
+        vmm.memory_layers = Some(microvm_state.memory_state.layers.clone());
+    }
+
     #[cfg(target_arch = "aarch64")]
     {
         let mpidrs = construct_kvm_mpidrs(&microvm_state.vcpu_states);
@@ -420,11 +460,16 @@ pub fn build_microvm_from_snapshot(
         mem: guest_memory,
         vm: vmm.vm.fd(),
         event_manager,
+        device_overrides,
     };
     vmm.mmio_device_manager =
         MMIODeviceManager::restore(mmio_ctor_args, &microvm_state.device_states)
             .map_err(MicrovmStateError::RestoreDevices)
             .map_err(RestoreMicrovmState)?;
+    // Enough for all the slots, which may be freed once the microVM is running.
+    vmm.hotplug_fd_pool = BlockFdPool::new(vmm.mmio_device_manager.hotplug_slots().len())
+        .map_err(Error::HotplugFds)
+        .map_err(StartMicrovmError::Internal)?;
 
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
     vmm.start_vcpus(vcpus, seccomp_filter)
@@ -452,11 +497,29 @@ pub fn build_microvm_from_snapshot(
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    shared: bool,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = arch::arch_memory_regions(mem_size);
 
-    if !track_dirty_pages {
+    if shared {
+        // The regions are mapped from consecutive ranges of a single memfd, whose file
+        // descriptor can be passed to other processes.
+        let memfd = create_memfd(mem_size).map_err(StartMicrovmError::SharedGuestMemory)?;
+        let mut offset = 0;
+        let mut ranges = Vec::with_capacity(arch_mem_regions.len());
+        for (addr, size) in arch_mem_regions {
+            let file = memfd
+                .try_clone()
+                .map_err(StartMicrovmError::SharedGuestMemory)?;
+            ranges.push((addr, size, Some(FileOffset::new(file, offset))));
+            offset += size as u64;
+        }
+        Ok(
+            GuestMemoryMmap::from_ranges_with_files(ranges, track_dirty_pages)
+                .map_err(StartMicrovmError::GuestMemoryMmap)?,
+        )
+    } else if !track_dirty_pages {
         Ok(GuestMemoryMmap::from_ranges(&arch_mem_regions)
             .map_err(StartMicrovmError::GuestMemoryMmap)?)
     } else {
@@ -467,6 +530,25 @@ pub fn create_guest_memory(
     }
 }
 
+// Creates an anonymous file of `size` bytes, living in memory.
+fn create_memfd(size: usize) -> io::Result<File> {
+    // Safe because the name is a valid C string, and the return value is checked.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            b"guest_mem\0".as_ptr(),
+            libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the file descriptor was just created, and is owned by nothing else.
+    let file = unsafe { File::from_raw_fd(fd as RawFd) };
+    file.set_len(size as u64)?;
+    Ok(file)
+}
+
 fn load_kernel(
     boot_config: &BootConfig,
     guest_memory: &GuestMemoryMmap,
@@ -723,7 +805,7 @@ pub fn configure_system_for_boot(
             &vmm.guest_memory,
             &boot_cmdline.as_cstring().map_err(LoadCommandline)?,
             vcpu_mpidr,
-            vmm.mmio_device_manager.get_device_info(),
+            &vmm.mmio_device_manager.get_boot_device_info(),
             vmm.vm.get_irqchip(),
             initrd,
         )
@@ -797,6 +879,21 @@ fn attach_block_devices<'a>(
     Ok(())
 }
 
+fn attach_vhost_user_block_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut KernelCmdline,
+    blocks: impl Iterator<Item = &'a Arc<Mutex<VhostUserBlock>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for block in blocks {
+        // vhost-user block devices cannot be root devices.
+        let id = block.lock().expect("Poisoned lock").id().clone();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, block.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
 fn attach_net_devices<'a>(
     vmm: &mut Vmm,
     cmdline: &mut KernelCmdline,
@@ -845,10 +942,13 @@ pub mod tests {
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
     use arch::DeviceType;
-    use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK};
+    use devices::virtio::{
+        CacheType, FileEngineType, ImageFormat, TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK,
+    };
     use kernel::cmdline::Cmdline;
     use polly::event_manager::EventManager;
     use utils::tempfile::TempFile;
+    use vm_memory::{GuestMemory, GuestMemoryRegion};
 
     pub(crate) struct CustomBlockConfig {
         drive_id: String,
@@ -895,7 +995,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         let exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -920,10 +1020,14 @@ pub mod tests {
         Vmm {
             events_observer: Some(Box::new(SerialStdin::get())),
             guest_memory,
+            memory_layers: Some(Vec::new()),
+            uffd_handler: None,
             vcpus_handles: Vec::new(),
             exit_evt,
             vm,
             mmio_device_manager,
+            hotplug_fd_pool: BlockFdPool::default(),
+            hotplugged_blocks: HashMap::new(),
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
         }
@@ -952,6 +1056,14 @@ pub mod tests {
                 partuuid: custom_block_cfg.partuuid.clone(),
                 is_read_only: custom_block_cfg.is_read_only,
                 rate_limiter: None,
+                cache_type: CacheType::Unsafe,
+                io_engine: FileEngineType::Sync,
+                format: ImageFormat::Raw,
+                overlay_path: None,
+                num_queues: 1,
+                vhost_user_socket: None,
+                nbd: None,
+                verity: None,
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1081,21 +1193,36 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, false).unwrap();
             assert!(!guest_memory.is_dirty_tracking_enabled());
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, false).unwrap();
             assert!(guest_memory.is_dirty_tracking_enabled());
         }
+
+        // Case 3: create guest memory which can be shared with other processes
+        {
+            let guest_memory = create_guest_memory(128, false, true).unwrap();
+            let mut offset = 0;
+            guest_memory
+                .with_regions_mut(|_, region| {
+                    let file_offset = region.file_offset().unwrap();
+                    assert_eq!(file_offset.start(), offset);
+                    offset += region.len();
+                    Ok::<(), ()>(())
+                })
+                .unwrap();
+            assert_eq!(offset, 128 << 20);
+        }
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1120,6 +1247,8 @@ pub mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost: false,
         };
 
         let mut cmdline = default_kernel_cmdline();
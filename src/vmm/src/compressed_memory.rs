@@ -0,0 +1,465 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the compressed memory snapshot file format.
+//!
+//! The memory file contents are split in fixed size chunks which are compressed
+//! independently and written back to back; all-zero chunks are not stored at all.
+//! The chunks are followed by an index which records, for every chunk, where it is
+//! stored and the checksum of its uncompressed contents. The file ends with a
+//! fixed size footer pointing to the index:
+//!
+//! | chunks | index | index CRC64 | index offset | index length | magic |
+//!
+//! Since every chunk can be located and verified on its own, the file supports
+//! random access through `CompressedMemoryReader::read_at`.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+
+use versionize::crc::CRC64Writer;
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
+
+/// Identifies a compressed memory file. The last byte is the format version.
+const MAGIC: u64 = 0x0046_434d_454d_5a01;
+/// Size of the footer: index offset, index length and magic.
+const FOOTER_LEN: u64 = 24;
+/// Size of the uncompressed chunks.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Errors associated with compressed memory files.
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk at the given memory offset doesn't match its checksum.
+    ChunkChecksum(u64),
+    /// Failed to decompress the chunk at the given memory offset.
+    Decompress(u64, lz4_flex::block::DecompressError),
+    /// The index doesn't match its checksum.
+    IndexChecksum,
+    /// Failed to deserialize the index.
+    IndexDeserialize(VersionizeError),
+    /// The file is not a compressed memory file.
+    InvalidFile,
+    /// Failed to read from the file.
+    Io(io::Error),
+    /// The requested range is outside of the memory stored in the file.
+    OutOfRange(u64, usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            ChunkChecksum(offset) => write!(
+                f,
+                "The memory chunk at offset {:#x} doesn't match its checksum",
+                offset
+            ),
+            Decompress(offset, err) => write!(
+                f,
+                "Cannot decompress the memory chunk at offset {:#x}: {}",
+                offset, err
+            ),
+            IndexChecksum => write!(f, "The memory file index doesn't match its checksum"),
+            IndexDeserialize(err) => {
+                write!(f, "Cannot deserialize the memory file index: {:?}", err)
+            }
+            InvalidFile => write!(f, "Not a compressed memory file"),
+            Io(err) => write!(f, "Cannot read the compressed memory file: {}", err),
+            OutOfRange(offset, len) => write!(
+                f,
+                "Range [{:#x}, {:#x}) is outside of the memory file",
+                offset,
+                offset + *len as u64
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Describes one chunk of a compressed memory file.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a compressed memory format version bump.
+pub struct ChunkState {
+    /// Offset in the file where the compressed chunk is stored.
+    pub offset: u64,
+    /// Length of the compressed chunk. Zero for all-zero chunks, which are not stored.
+    pub len: u64,
+    /// CRC64 of the uncompressed chunk.
+    pub checksum: u64,
+}
+
+/// Index of a compressed memory file.
+#[derive(Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a compressed memory format version bump.
+pub struct CompressedMemoryIndex {
+    /// Size of the uncompressed chunks.
+    pub chunk_size: u64,
+    /// Size of the uncompressed memory. The last chunk may be shorter than `chunk_size`.
+    pub memory_size: u64,
+    /// The chunks, in memory order.
+    pub chunks: Vec<ChunkState>,
+}
+
+fn crc64(data: &[u8]) -> u64 {
+    let mut crc_writer = CRC64Writer::new(io::sink());
+    // Writing to a sink never fails.
+    let _ = crc_writer.write_all(data);
+    crc_writer.checksum()
+}
+
+/// Writes the data it receives to a compressed memory file.
+///
+/// The memory file is only complete after calling `finish`.
+pub struct CompressedMemoryWriter<W: Write> {
+    writer: W,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    offset: u64,
+    index: CompressedMemoryIndex,
+}
+
+impl<W: Write> CompressedMemoryWriter<W> {
+    /// Creates a new writer which splits the memory in chunks of `chunk_size` bytes.
+    pub fn new(writer: W, chunk_size: usize) -> Self {
+        CompressedMemoryWriter {
+            writer,
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size,
+            offset: 0,
+            index: CompressedMemoryIndex {
+                chunk_size: chunk_size as u64,
+                memory_size: 0,
+                chunks: Vec::new(),
+            },
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let chunk_state = if self.chunk.iter().all(|&byte| byte == 0) {
+            ChunkState {
+                offset: self.offset,
+                len: 0,
+                checksum: crc64(&self.chunk),
+            }
+        } else {
+            let compressed = lz4_flex::compress(&self.chunk);
+            self.writer.write_all(&compressed)?;
+            let chunk_state = ChunkState {
+                offset: self.offset,
+                len: compressed.len() as u64,
+                checksum: crc64(&self.chunk),
+            };
+            self.offset += compressed.len() as u64;
+            chunk_state
+        };
+
+        self.index.memory_size += self.chunk.len() as u64;
+        self.index.chunks.push(chunk_state);
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the last chunk, the index and the footer, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+
+        let mut index = Vec::new();
+        self.index
+            .serialize(&mut index, &VersionMap::new(), 1)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
+        let index_checksum = crc64(&index);
+        // The index length covers the serialized index and its checksum.
+        let index_len = index.len() as u64 + 8;
+
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&index_checksum.to_le_bytes())?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&index_len.to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for CompressedMemoryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), self.chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..len]);
+        if self.chunk.len() == self.chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Incomplete chunks are only written by `finish`.
+        self.writer.flush()
+    }
+}
+
+fn u64_from_le_slice(bytes: &[u8]) -> u64 {
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(le_bytes)
+}
+
+/// Provides access to the memory stored in a compressed memory file.
+pub struct CompressedMemoryReader {
+    file: File,
+    index: CompressedMemoryIndex,
+}
+
+impl CompressedMemoryReader {
+    /// Loads and checks the index of a compressed memory file.
+    ///
+    /// Returns `Error::InvalidFile` if `file` is not a compressed memory file.
+    pub fn new(file: &File) -> Result<Self> {
+        let file = file.try_clone().map_err(Error::Io)?;
+        let file_len = file.metadata().map_err(Error::Io)?.len();
+        if file_len < FOOTER_LEN {
+            return Err(Error::InvalidFile);
+        }
+
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact_at(&mut footer, file_len - FOOTER_LEN)
+            .map_err(Error::Io)?;
+        let index_offset = u64_from_le_slice(&footer[0..8]);
+        let index_len = u64_from_le_slice(&footer[8..16]);
+        if u64_from_le_slice(&footer[16..24]) != MAGIC
+            || index_len < 8
+            || index_offset.checked_add(index_len) != Some(file_len - FOOTER_LEN)
+        {
+            return Err(Error::InvalidFile);
+        }
+
+        let mut index_bytes = vec![0u8; index_len as usize];
+        file.read_exact_at(&mut index_bytes, index_offset)
+            .map_err(Error::Io)?;
+        let (index_bytes, index_checksum) = index_bytes.split_at(index_bytes.len() - 8);
+        if crc64(index_bytes) != u64_from_le_slice(index_checksum) {
+            return Err(Error::IndexChecksum);
+        }
+        let index =
+            CompressedMemoryIndex::deserialize(&mut &index_bytes[..], &VersionMap::new(), 1)
+                .map_err(Error::IndexDeserialize)?;
+
+        // The index must describe the whole memory with chunks stored before it.
+        let chunk_count = if index.chunk_size == 0 {
+            None
+        } else {
+            Some((index.memory_size + index.chunk_size - 1) / index.chunk_size)
+        };
+        if chunk_count != Some(index.chunks.len() as u64)
+            || index.chunks.iter().any(|chunk| {
+                chunk
+                    .offset
+                    .checked_add(chunk.len)
+                    .map_or(true, |end| end > index_offset)
+            })
+        {
+            return Err(Error::InvalidFile);
+        }
+
+        Ok(CompressedMemoryReader { file, index })
+    }
+
+    /// Returns the size of the uncompressed memory.
+    pub fn memory_size(&self) -> u64 {
+        self.index.memory_size
+    }
+
+    /// Returns the uncompressed contents of chunk `idx`, after checking them against
+    /// their checksum.
+    fn read_chunk(&self, idx: usize) -> Result<Vec<u8>> {
+        let chunk = &self.index.chunks[idx];
+        let memory_offset = idx as u64 * self.index.chunk_size;
+        let chunk_len = std::cmp::min(
+            self.index.chunk_size,
+            self.index.memory_size - memory_offset,
+        ) as usize;
+        if chunk.len == 0 {
+            return Ok(vec![0u8; chunk_len]);
+        }
+
+        let mut compressed = vec![0u8; chunk.len as usize];
+        self.file
+            .read_exact_at(&mut compressed, chunk.offset)
+            .map_err(Error::Io)?;
+        let data = lz4_flex::decompress(&compressed, chunk_len)
+            .map_err(|err| Error::Decompress(memory_offset, err))?;
+        if data.len() != chunk_len || crc64(&data) != chunk.checksum {
+            return Err(Error::ChunkChecksum(memory_offset));
+        }
+
+        Ok(data)
+    }
+
+    /// Returns an iterator over the memory offset and the contents of all the chunks that
+    /// hold data, in memory order. All-zero chunks are skipped.
+    pub fn data_chunks(&self) -> impl Iterator<Item = Result<(u64, Vec<u8>)>> + '_ {
+        self.index
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.len != 0)
+            .map(move |(idx, _)| {
+                self.read_chunk(idx)
+                    .map(|data| (idx as u64 * self.index.chunk_size, data))
+            })
+    }
+
+    /// Fills `buf` with the memory found at `offset`, only decompressing the chunks
+    /// overlapping with the requested range.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let end = offset.checked_add(buf.len() as u64);
+        if end.map_or(true, |end| end > self.index.memory_size) {
+            return Err(Error::OutOfRange(offset, buf.len()));
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let idx = (position / self.index.chunk_size) as usize;
+            let chunk_offset = (position % self.index.chunk_size) as usize;
+            let data = self.read_chunk(idx)?;
+            let len = std::cmp::min(buf.len() - done, data.len() - chunk_offset);
+            buf[done..done + len].copy_from_slice(&data[chunk_offset..chunk_offset + len]);
+            done += len;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom};
+
+    use utils::tempfile::TempFile;
+
+    const TEST_CHUNK_SIZE: usize = 4096;
+
+    fn memory_contents() -> Vec<u8> {
+        // Chunks 0 and 3 hold data, chunks 1 and 2 are zero and the last one is partial.
+        let mut contents = vec![0u8; TEST_CHUNK_SIZE * 4 + 100];
+        for (idx, byte) in contents[..TEST_CHUNK_SIZE].iter_mut().enumerate() {
+            *byte = (idx % 7) as u8;
+        }
+        for byte in contents[TEST_CHUNK_SIZE * 3..].iter_mut() {
+            *byte = 0xab;
+        }
+        contents
+    }
+
+    fn write_memory_file(contents: &[u8]) -> TempFile {
+        let memory_file = TempFile::new().unwrap();
+        let mut writer = CompressedMemoryWriter::new(memory_file.as_file(), TEST_CHUNK_SIZE);
+        // Write in pieces that don't line up with the chunks.
+        for piece in contents.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+        memory_file
+    }
+
+    #[test]
+    fn test_compressed_memory() {
+        let contents = memory_contents();
+        let memory_file = write_memory_file(&contents);
+        let file = memory_file.as_file();
+        // Compression and the skipped zero chunks make the file smaller than the memory.
+        assert!(file.metadata().unwrap().len() < contents.len() as u64);
+
+        let reader = CompressedMemoryReader::new(file).unwrap();
+        assert_eq!(reader.memory_size(), contents.len() as u64);
+
+        let chunks: Vec<(u64, Vec<u8>)> = reader.data_chunks().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].0, 0);
+        assert_eq!(chunks[0].1, &contents[..TEST_CHUNK_SIZE]);
+        assert_eq!(chunks[1].0, TEST_CHUNK_SIZE as u64 * 3);
+        assert_eq!(chunks[2].0, TEST_CHUNK_SIZE as u64 * 4);
+        assert_eq!(chunks[2].1, &contents[TEST_CHUNK_SIZE * 4..]);
+
+        // Random access across chunk boundaries.
+        let mut buf = vec![0u8; TEST_CHUNK_SIZE * 3];
+        reader.read_at(100, &mut buf).unwrap();
+        assert_eq!(buf, &contents[100..100 + TEST_CHUNK_SIZE * 3]);
+
+        let mut buf = vec![0u8; 200];
+        match reader.read_at(contents.len() as u64 - 100, &mut buf) {
+            Err(Error::OutOfRange(offset, len)) => {
+                assert_eq!(offset, contents.len() as u64 - 100);
+                assert_eq!(len, 200);
+            }
+            _ => panic!("Expected an OutOfRange error"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_compressed_memory() {
+        // A raw memory file is not mistaken for a compressed one.
+        let raw_file = TempFile::new().unwrap();
+        raw_file.as_file().write_all(&memory_contents()).unwrap();
+        match CompressedMemoryReader::new(raw_file.as_file()) {
+            Err(Error::InvalidFile) => (),
+            _ => panic!("Expected an InvalidFile error"),
+        }
+        let empty_file = TempFile::new().unwrap();
+        match CompressedMemoryReader::new(empty_file.as_file()) {
+            Err(Error::InvalidFile) => (),
+            _ => panic!("Expected an InvalidFile error"),
+        }
+
+        // Corrupt the first chunk.
+        let memory_file = write_memory_file(&memory_contents());
+        let mut file = memory_file.as_file();
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.write_all(&[0xff; 16]).unwrap();
+        let reader = CompressedMemoryReader::new(file).unwrap();
+        match reader.data_chunks().next().unwrap() {
+            Err(Error::ChunkChecksum(0)) | Err(Error::Decompress(0, _)) => (),
+            _ => panic!("Expected a corrupted chunk error"),
+        }
+        // The other chunks can still be read.
+        let mut buf = vec![0u8; 100];
+        reader
+            .read_at(TEST_CHUNK_SIZE as u64 * 3, &mut buf)
+            .unwrap();
+        assert_eq!(buf, vec![0xab; 100]);
+
+        // Corrupt the index.
+        let file_len = file.metadata().unwrap().len();
+        file.seek(SeekFrom::Start(file_len - FOOTER_LEN - 9))
+            .unwrap();
+        file.write_all(&[0xff]).unwrap();
+        match CompressedMemoryReader::new(file) {
+            Err(Error::IndexChecksum) => (),
+            _ => panic!("Expected an IndexChecksum error"),
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            Error::ChunkChecksum(0),
+            Error::IndexChecksum,
+            Error::InvalidFile,
+            Error::Io(io::Error::from_raw_os_error(0)),
+            Error::OutOfRange(0, 1),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -46,6 +46,8 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_epoll_wait),
             allow_syscall(libc::SYS_exit),
             allow_syscall(libc::SYS_exit_group),
+            // Used by the block device for discard and write zeroes requests
+            allow_syscall(libc::SYS_fallocate),
             // Used by snapshotting, drive patching and rescanning
             allow_syscall_if(
                 libc::SYS_fcntl,
@@ -56,7 +58,7 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             ),
             // Used for drive patching & rescanning, for reading the local timezone
             allow_syscall(libc::SYS_fstat),
-            // Used for snapshotting
+            // Used for snapshotting and drive resizing
             allow_syscall(SYS_FTRUNCATE),
             // Used for synchronization
             allow_syscall_if(
@@ -77,6 +79,10 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             // Used by glibc's tgkill
             #[cfg(target_env = "gnu")]
             allow_syscall(libc::SYS_getpid),
+            // Used for generating the nonces of encrypted snapshot files
+            allow_syscall(libc::SYS_getrandom),
+            // Used by the asynchronous block I/O engine
+            allow_syscall(libc::SYS_io_uring_enter),
             allow_syscall_if(libc::SYS_ioctl, super::create_ioctl_seccomp_rule()?),
             // Used by the block device
             allow_syscall(libc::SYS_lseek),
@@ -112,6 +118,9 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_open),
             #[cfg(target_arch = "aarch64")]
             allow_syscall(libc::SYS_openat),
+            // Used by the block device to access qcow2 images
+            allow_syscall(libc::SYS_pread64),
+            allow_syscall(libc::SYS_pwrite64),
             allow_syscall(libc::SYS_read),
             // Used by the API thread and vsock
             allow_syscall(libc::SYS_recvfrom),
@@ -123,19 +132,62 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             // SYS_rt_sigreturn is needed in case a fault does occur, so that the signal handler
             // can return. Otherwise we get stuck in a fault loop.
             allow_syscall(libc::SYS_rt_sigreturn),
-            // Used by the API thread and vsock
+            // Used by vhost-user block devices to pass file descriptors to their backend
+            allow_syscall(libc::SYS_sendmsg),
+            // Used for live migration over TCP
+            allow_syscall(libc::SYS_sendto),
+            // Used to time out the requests of drives served by an NBD server
+            allow_syscall_if(
+                libc::SYS_setsockopt,
+                or![
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_RCVTIMEO as u64)?,
+                    ],
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_SNDTIMEO as u64)?,
+                    ],
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::IPPROTO_TCP as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::TCP_NODELAY as u64)?,
+                    ],
+                ],
+            ),
+            // Used by the API thread and vsock, for live migration over TCP, and by the drives
+            // served by an NBD server
             allow_syscall_if(
                 libc::SYS_socket,
-                or![and![
-                    Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?,
-                    Cond::new(
-                        1,
-                        ArgLen::DWORD,
-                        Eq,
-                        (libc::SOCK_STREAM as u64) | (libc::SOCK_CLOEXEC as u64)
-                    )?,
-                    Cond::new(2, ArgLen::DWORD, Eq, 0u64)?
-                ],],
+                or![
+                    and![
+                        Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?,
+                        Cond::new(
+                            1,
+                            ArgLen::DWORD,
+                            Eq,
+                            (libc::SOCK_STREAM as u64) | (libc::SOCK_CLOEXEC as u64)
+                        )?,
+                        Cond::new(2, ArgLen::DWORD, Eq, 0u64)?
+                    ],
+                    and![
+                        Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?,
+                        Cond::new(
+                            1,
+                            ArgLen::DWORD,
+                            Eq,
+                            (libc::SOCK_STREAM as u64) | (libc::SOCK_CLOEXEC as u64)
+                        )?,
+                    ],
+                    and![
+                        Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?,
+                        Cond::new(
+                            1,
+                            ArgLen::DWORD,
+                            Eq,
+                            (libc::SOCK_STREAM as u64) | (libc::SOCK_CLOEXEC as u64)
+                        )?,
+                    ],
+                ],
             ),
             // Used to kick vcpus
             allow_syscall_if(
@@ -38,6 +38,20 @@ const FIONBIO: u64 = 0x5421;
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
+const TUNSETQUEUE: u64 = 0x4004_54d9;
+
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_GET_FEATURES: u64 = 0x8008_af00;
+const VHOST_SET_FEATURES: u64 = 0x4008_af00;
+const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008_af03;
+const VHOST_SET_VRING_NUM: u64 = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028_af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008_af12;
+const VHOST_GET_VRING_BASE: u64 = 0xc008_af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008_af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008_af21;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008_af30;
 
 // Hardcoded here instead of getting values from kvm-ioctls, so that filtered values cannot be
 // mistakenly or intentionally altered from outside our codebase.
@@ -47,6 +61,8 @@ const KVM_GET_MP_STATE: u64 = 0x8004_ae98;
 const KVM_SET_MP_STATE: u64 = 0x4004_ae99;
 const KVM_GET_VCPU_EVENTS: u64 = 0x8040_ae9f;
 const KVM_SET_VCPU_EVENTS: u64 = 0x4040_aea0;
+const KVM_IRQFD: u64 = 0x4020_ae76;
+const KVM_IOEVENTFD: u64 = 0x4040_ae79;
 
 // Use this mod to define ioctl params that are architecture specific.
 // To add other architectures, add another module declaration with the right cfg attribute.
@@ -136,10 +152,28 @@ fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        // Triggered when the guest changes the number of queue pairs of a network device.
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETQUEUE)?],
+        // Triggered when a network device using vhost-net is restored or activated, and
+        // when its state is saved.
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_OWNER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_MEM_TABLE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_ADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_KICK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_CALL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_NET_SET_BACKEND)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MP_STATE)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MP_STATE)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_VCPU_EVENTS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_VCPU_EVENTS)?],
+        // Triggered when hot-plugging or unplugging a block device.
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_IRQFD)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_IOEVENTFD)?],
     ];
 
     rule.append(&mut create_arch_specific_ioctl_conditions()?);
@@ -14,13 +14,15 @@ use arch::aarch64::DeviceInfoForFDT;
 use arch::DeviceType;
 use devices::pseudo::BootTimer;
 use devices::virtio::{
-    Balloon, Block, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
-    TYPE_VSOCK,
+    Balloon, Block, MmioHotplugSlot, MmioTransport, Net, VhostUserBlock, VirtioDevice,
+    TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK, VIRTIO_MMIO_INT_CONFIG,
 };
 use devices::BusDevice;
 use kernel::cmdline as kernel_cmdline;
 use kvm_ioctls::{IoEventAddress, VmFd};
-use logger::info;
+use logger::{error, info, warn};
+use serde::Serialize;
+use utils::eventfd::EventFd;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
@@ -31,10 +33,14 @@ pub enum Error {
     BusError(devices::BusError),
     /// Appending to kernel command line failed.
     Cmdline(kernel_cmdline::Error),
+    /// A device with the same type and identifier is already registered.
+    DeviceAlreadyExists,
     /// The device couldn't be found.
     DeviceNotFound,
     /// Failure in creating or cloning an event fd.
     EventFd(io::Error),
+    /// All the hot-plug slots are in use.
+    HotplugSlotsExhausted,
     /// Incorrect device type.
     IncorrectDeviceType,
     /// Internal device error.
@@ -43,10 +49,16 @@ pub enum Error {
     InvalidInput,
     /// No more IRQs are available.
     IrqsExhausted,
+    /// The device is not plugged in a hot-plug slot.
+    NotHotplugged,
     /// Registering an IO Event failed.
     RegisterIoEvent(kvm_ioctls::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(kvm_ioctls::Error),
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(kvm_ioctls::Error),
     /// Failed to update the mmio device.
     UpdateFailed,
 }
@@ -56,13 +68,18 @@ impl fmt::Display for Error {
         match self {
             Error::BusError(e) => write!(f, "failed to perform bus operation: {}", e),
             Error::Cmdline(e) => write!(f, "unable to add device to kernel command line: {}", e),
+            Error::DeviceAlreadyExists => write!(f, "the device already exists"),
             Error::EventFd(e) => write!(f, "failed to create or clone event descriptor: {}", e),
+            Error::HotplugSlotsExhausted => write!(f, "no more hot-plug slots are available"),
             Error::IncorrectDeviceType => write!(f, "incorrect device type"),
             Error::InternalDeviceError(e) => write!(f, "device error: {}", e),
             Error::InvalidInput => write!(f, "invalid configuration"),
             Error::IrqsExhausted => write!(f, "no more IRQs are available"),
+            Error::NotHotplugged => write!(f, "the device is not plugged in a hot-plug slot"),
             Error::RegisterIoEvent(e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(e) => write!(f, "failed to register irqfd: {}", e),
+            Error::UnregisterIoEvent(e) => write!(f, "failed to unregister IO event: {}", e),
+            Error::UnregisterIrqFd(e) => write!(f, "failed to unregister irqfd: {}", e),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
@@ -78,7 +95,7 @@ type Result<T> = ::std::result::Result<T, Error>;
 const MMIO_LEN: u64 = 0x1000;
 
 /// Stores the address range and irq allocated to this device.
-#[derive(Clone, Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MMIODeviceInfo {
     /// Mmio address at which the device is registered.
@@ -127,6 +144,20 @@ impl IrqManager {
     }
 }
 
+/// Gets the transport of a virtio bus device, which is either registered directly on the bus
+/// or plugged in a hot-plug slot.
+pub(crate) fn virtio_transport(bus_device: &dyn BusDevice) -> Option<&MmioTransport> {
+    bus_device
+        .as_any()
+        .downcast_ref::<MmioTransport>()
+        .or_else(|| {
+            bus_device
+                .as_any()
+                .downcast_ref::<MmioHotplugSlot>()
+                .and_then(MmioHotplugSlot::transport)
+        })
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub(crate) bus: devices::Bus,
@@ -134,6 +165,8 @@ pub struct MMIODeviceManager {
     next_avail_mmio: u64,
     irqs: IrqManager,
     pub(crate) id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    // Slots reserved at boot time for the devices hot-plugged afterwards, whether in use or not.
+    hotplug_slots: Vec<MMIODeviceInfo>,
 }
 
 impl MMIODeviceManager {
@@ -145,6 +178,7 @@ impl MMIODeviceManager {
             irqs: IrqManager::new(irq_interval.0, irq_interval.1),
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            hotplug_slots: Vec::new(),
         }
     }
 
@@ -199,14 +233,32 @@ impl MMIODeviceManager {
         {
             let locked_device = mmio_device.locked_device();
             identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr =
-                    IoEventAddress::Mmio(slot.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
-                vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                    .map_err(Error::RegisterIoEvent)?;
+            let queue_evts = locked_device.queue_events();
+            // The events already registered are unregistered on failure, so that those of a
+            // hot-plugged device can be registered again.
+            for (i, queue_evt) in queue_evts.iter().enumerate() {
+                if let Err(e) = vm.register_ioevent(queue_evt, &Self::notify_addr(slot), i as u32) {
+                    Self::unregister_ioevents(vm, &queue_evts[..i], slot);
+                    return Err(Error::RegisterIoEvent(e));
+                }
+            }
+            if let Err(e) = vm.register_irqfd(locked_device.interrupt_evt(), slot.irqs[0]) {
+                Self::unregister_ioevents(vm, queue_evts, slot);
+                return Err(Error::RegisterIrqFd(e));
             }
-            vm.register_irqfd(locked_device.interrupt_evt(), slot.irqs[0])
-                .map_err(Error::RegisterIrqFd)?;
+        }
+
+        // Devices registered in a hot-plug slot are plugged behind the slot already on the bus.
+        if let Some(bus_device) = self.hotplug_slot_device(slot.addr) {
+            bus_device
+                .lock()
+                .expect("Poisoned lock")
+                .as_mut_any()
+                .downcast_mut::<MmioHotplugSlot>()
+                .expect("Unexpected BusDevice type")
+                .plug(mmio_device);
+            self.id_to_dev_info.insert(identifier, slot.clone());
+            return Ok(());
         }
 
         self.register_mmio_device(identifier, slot.clone(), Arc::new(Mutex::new(mmio_device)))
@@ -247,6 +299,175 @@ impl MMIODeviceManager {
         Ok(mmio_slot)
     }
 
+    /// Reserve `count` slots in which virtio devices can be hot-plugged once the microVM is
+    /// running. The empty slots are announced to the guest at boot time, like any other
+    /// virtio-over-MMIO device, so that the guest driver can be bound to them later on.
+    pub fn reserve_hotplug_slots(
+        &mut self,
+        count: usize,
+        _cmdline: &mut kernel_cmdline::Cmdline,
+    ) -> Result<()> {
+        for _ in 0..count {
+            let slot = self.allocate_new_slot(1)?;
+            self.register_hotplug_slot(slot.clone())?;
+            #[cfg(target_arch = "x86_64")]
+            Self::add_virtio_device_to_cmdline(_cmdline, &slot)?;
+        }
+        Ok(())
+    }
+
+    /// Register an empty hot-plug slot at a specific address.
+    pub fn register_hotplug_slot(&mut self, slot: MMIODeviceInfo) -> Result<()> {
+        self.bus
+            .insert(
+                Arc::new(Mutex::new(MmioHotplugSlot::new())),
+                slot.addr,
+                slot.len,
+            )
+            .map_err(Error::BusError)?;
+        self.hotplug_slots.push(slot);
+        Ok(())
+    }
+
+    /// Gets the slots reserved for hot-plugging devices, whether in use or not.
+    pub fn hotplug_slots(&self) -> &[MMIODeviceInfo] {
+        &self.hotplug_slots
+    }
+
+    /// Gets the hot-plug slot device registered at `addr`, if any.
+    fn hotplug_slot_device(&self, addr: u64) -> Option<&Mutex<dyn BusDevice>> {
+        if self.hotplug_slots.iter().any(|slot| slot.addr == addr) {
+            return self.bus.get_device(addr).map(|(_, device)| device);
+        }
+        None
+    }
+
+    /// Gets the hot-plug slots in which no device is plugged.
+    fn free_hotplug_slots(&self) -> impl Iterator<Item = &MMIODeviceInfo> {
+        let id_to_dev_info = &self.id_to_dev_info;
+        self.hotplug_slots.iter().filter(move |slot| {
+            id_to_dev_info
+                .values()
+                .all(|dev_info| dev_info.addr != slot.addr)
+        })
+    }
+
+    /// Plug an already created virtio-over-MMIO device in a free hot-plug slot.
+    pub fn hotplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: MmioTransport,
+    ) -> Result<MMIODeviceInfo> {
+        let device_type = DeviceType::Virtio(mmio_device.locked_device().device_type());
+        if self
+            .id_to_dev_info
+            .contains_key(&(device_type, device_id.clone()))
+        {
+            return Err(Error::DeviceAlreadyExists);
+        }
+        let slot = self
+            .free_hotplug_slots()
+            .next()
+            .cloned()
+            .ok_or(Error::HotplugSlotsExhausted)?;
+        self.register_mmio_virtio(vm, device_id, mmio_device, &slot)?;
+
+        // Tell the guest about the new device.
+        if let Err(e) = self
+            .hotplug_slot_device(slot.addr)
+            .ok_or(Error::DeviceNotFound)?
+            .lock()
+            .expect("Poisoned lock")
+            .interrupt(VIRTIO_MMIO_INT_CONFIG)
+        {
+            warn!("Failed to signal the hot-plug of a virtio device: {:?}", e);
+        }
+        Ok(slot)
+    }
+
+    /// Unplug the virtio-over-MMIO device matching `virtio_type` and `device_id` from its
+    /// hot-plug slot, which becomes free again. Returns the transport of the unplugged device.
+    pub fn unplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        virtio_type: u32,
+        device_id: &str,
+    ) -> Result<MmioTransport> {
+        let identifier = (DeviceType::Virtio(virtio_type), device_id.to_string());
+        let slot = self
+            .id_to_dev_info
+            .get(&identifier)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)?;
+        let mut locked_slot = self
+            .hotplug_slot_device(slot.addr)
+            .ok_or(Error::NotHotplugged)?
+            .lock()
+            .expect("Poisoned lock");
+        let hotplug_slot = locked_slot
+            .as_mut_any()
+            .downcast_mut::<MmioHotplugSlot>()
+            .expect("Unexpected BusDevice type");
+
+        // The device is only removed from its slot once its events are unregistered, so that
+        // it is not lost if KVM fails to unregister them.
+        let device = hotplug_slot
+            .transport()
+            .ok_or(Error::DeviceNotFound)?
+            .device();
+        let locked_device = device.lock().expect("Poisoned lock");
+        let queue_evts = locked_device.queue_events();
+        // The events already unregistered are registered again on failure, leaving the device
+        // fully plugged.
+        for (i, queue_evt) in queue_evts.iter().enumerate() {
+            if let Err(e) = vm.unregister_ioevent(queue_evt, &Self::notify_addr(&slot), i as u32) {
+                Self::register_ioevents(vm, &queue_evts[..i], &slot);
+                return Err(Error::UnregisterIoEvent(e));
+            }
+        }
+
+        // Tell the guest about the unplug through the interrupt eventfd of the device, which
+        // KVM delivers before unregistering it.
+        let mmio_device = hotplug_slot.unplug().ok_or(Error::DeviceNotFound)?;
+        if let Err(e) = locked_device.interrupt_evt().write(1) {
+            warn!("Failed to signal the unplug of a virtio device: {:?}", e);
+        }
+        if let Err(e) = vm.unregister_irqfd(locked_device.interrupt_evt(), slot.irqs[0]) {
+            hotplug_slot.plug(mmio_device);
+            Self::register_ioevents(vm, queue_evts, &slot);
+            return Err(Error::UnregisterIrqFd(e));
+        }
+        drop(locked_device);
+        drop(locked_slot);
+        self.id_to_dev_info.remove(&identifier);
+
+        Ok(mmio_device)
+    }
+
+    // Gets the address of the queue notification register of the device in `slot`.
+    fn notify_addr(slot: &MMIODeviceInfo) -> IoEventAddress {
+        IoEventAddress::Mmio(slot.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET))
+    }
+
+    // Registers the queue events of a device whose unplug failed again.
+    fn register_ioevents(vm: &VmFd, queue_evts: &[EventFd], slot: &MMIODeviceInfo) {
+        for (i, queue_evt) in queue_evts.iter().enumerate() {
+            if let Err(e) = vm.register_ioevent(queue_evt, &Self::notify_addr(slot), i as u32) {
+                error!("Failed to register the queue event {} again: {:?}", i, e);
+            }
+        }
+    }
+
+    // Unregisters the queue events of a device whose registration failed.
+    fn unregister_ioevents(vm: &VmFd, queue_evts: &[EventFd], slot: &MMIODeviceInfo) {
+        for (i, queue_evt) in queue_evts.iter().enumerate() {
+            if let Err(e) = vm.unregister_ioevent(queue_evt, &Self::notify_addr(slot), i as u32) {
+                error!("Failed to unregister the queue event {}: {:?}", i, e);
+            }
+        }
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Register an early console at the specified MMIO address if given as parameter,
     /// otherwise allocate a new MMIO slot for it.
@@ -316,13 +537,29 @@ impl MMIODeviceManager {
         &self.id_to_dev_info
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the information of the devices registered and of the free hot-plug slots, which
+    /// are described to the guest like any other virtio device.
+    pub fn get_boot_device_info(&self) -> HashMap<(DeviceType, String), MMIODeviceInfo> {
+        let mut device_info = self.id_to_dev_info.clone();
+        for (index, slot) in self.free_hotplug_slots().enumerate() {
+            device_info.insert(
+                (DeviceType::Virtio(0), format!("hotplug{}", index)),
+                slot.clone(),
+            );
+        }
+        device_info
+    }
+
     #[cfg(target_arch = "x86_64")]
-    /// Gets the number of interrupts used by the devices registered.
+    /// Gets the number of interrupts used by the devices registered and the free hot-plug slots.
     pub fn used_irqs_count(&self) -> usize {
         let mut irq_number = 0;
         self.get_device_info()
             .iter()
             .for_each(|(_, device_info)| irq_number += device_info.irqs.len());
+        self.free_hotplug_slots()
+            .for_each(|slot| irq_number += slot.irqs.len());
         irq_number
     }
 
@@ -370,11 +607,7 @@ impl MMIODeviceManager {
         F: FnOnce(&mut T) -> std::result::Result<(), String>,
     {
         if let Some(busdev) = self.get_device(DeviceType::Virtio(virtio_type), id) {
-            let virtio_device = busdev
-                .lock()
-                .expect("Poisoned lock")
-                .as_any()
-                .downcast_ref::<MmioTransport>()
+            let virtio_device = virtio_transport(&*busdev.lock().expect("Poisoned lock"))
                 .expect("Unexpected BusDevice type")
                 .device();
             let mut dev = virtio_device.lock().expect("Poisoned lock");
@@ -397,7 +630,7 @@ impl MMIODeviceManager {
             if let DeviceType::Virtio(virtio_type) = *devtype {
                 let bus_dev = bus_dev.lock().expect("Poisoned lock");
                 // Virtio devices are guaranteed MmioTransport.
-                let mmio_dev = bus_dev.as_any().downcast_ref::<MmioTransport>().unwrap();
+                let mmio_dev = virtio_transport(&*bus_dev).unwrap();
                 let mut virtio = mmio_dev.locked_device();
                 match virtio_type {
                     TYPE_BALLOON => {
@@ -412,13 +645,16 @@ impl MMIODeviceManager {
                     }
                     TYPE_BLOCK => {
                         info!("kick block {}.", id);
-                        let block = virtio.as_mut_any().downcast_mut::<Block>().unwrap();
-                        // If device is activated, kick the block queue(s) to make up for any
-                        // pending or in-flight epoll events we may have not captured in snapshot.
-                        // No need to kick Ratelimiters because they are restored 'unblocked' so
-                        // any inflight `timer_fd` events can be safely discarded.
-                        if block.is_activated() {
-                            block.process_virtio_queues();
+                        // The queues of vhost-user block devices are processed by their backend.
+                        if let Some(block) = virtio.as_mut_any().downcast_mut::<Block>() {
+                            // If device is activated, kick the block queue(s) to make up for any
+                            // pending or in-flight epoll events we may have not captured in
+                            // snapshot. No need to kick Ratelimiters because they are restored
+                            // 'unblocked' so any inflight `timer_fd` events can be safely
+                            // discarded.
+                            if block.is_activated() {
+                                block.process_virtio_queues();
+                            }
                         }
                     }
                     TYPE_NET => {
@@ -444,6 +680,81 @@ impl MMIODeviceManager {
             Ok(())
         });
     }
+
+    /// Waits for the completion of the I/O requests the devices have in flight, so that
+    /// the guest memory and the device states can be saved.
+    pub fn drain_devices(&self) {
+        let _: Result<()> = self.for_each_device(|devtype, id, _, bus_dev| {
+            match *devtype {
+                DeviceType::Virtio(TYPE_BLOCK) => {
+                    let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                    // Virtio devices are guaranteed MmioTransport.
+                    let mmio_dev = virtio_transport(&*bus_dev).unwrap();
+                    let mut virtio = mmio_dev.locked_device();
+                    info!("drain block {}.", id);
+                    // The requests of vhost-user block devices are in flight in their backend.
+                    if let Some(block) = virtio.as_mut_any().downcast_mut::<Block>() {
+                        block.prepare_save();
+                    }
+                }
+                DeviceType::Virtio(TYPE_NET) => {
+                    let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                    // Virtio devices are guaranteed MmioTransport.
+                    let mmio_dev = virtio_transport(&*bus_dev).unwrap();
+                    let mut virtio = mmio_dev.locked_device();
+                    let net = virtio.as_mut_any().downcast_mut::<Net>().unwrap();
+                    // Only the queues processed by vhost-net have a position to save.
+                    if net.is_vhost() {
+                        info!("drain net {}.", id);
+                        net.prepare_save();
+                    }
+                }
+                _ => (),
+            }
+            Ok(())
+        });
+    }
+
+    /// Returns whether a vhost-user block device is attached, whose state lies in its
+    /// backend.
+    pub fn has_vhost_user_devices(&self) -> bool {
+        self.for_each_device(|devtype, _, _, bus_dev| {
+            if let DeviceType::Virtio(TYPE_BLOCK) = *devtype {
+                let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                // Virtio devices are guaranteed MmioTransport.
+                let mmio_dev = virtio_transport(&*bus_dev).unwrap();
+                // Stops at the first vhost-user block device.
+                if mmio_dev.locked_device().as_any().is::<VhostUserBlock>() {
+                    return Err(());
+                }
+            }
+            Ok(())
+        })
+        .is_err()
+    }
+
+    /// Returns whether a network device using vhost-net is attached, whose writes to the
+    /// guest memory are not tracked by KVM.
+    pub fn has_vhost_net_devices(&self) -> bool {
+        self.for_each_device(|devtype, _, _, bus_dev| {
+            if let DeviceType::Virtio(TYPE_NET) = *devtype {
+                let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                // Virtio devices are guaranteed MmioTransport.
+                let mmio_dev = virtio_transport(&*bus_dev).unwrap();
+                let virtio = mmio_dev.locked_device();
+                // Stops at the first network device using vhost-net.
+                if virtio
+                    .as_any()
+                    .downcast_ref::<Net>()
+                    .map_or(false, Net::is_vhost)
+                {
+                    return Err(());
+                }
+            }
+            Ok(())
+        })
+        .is_err()
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -649,26 +960,36 @@ mod tests {
                 Error::Cmdline(_) => format!("{}{:?}", e, e),
                 Error::DeviceNotFound => format!("{}{:?}", e, e),
                 Error::EventFd(_) => format!("{}{:?}", e, e),
+                Error::DeviceAlreadyExists => format!("{}{:?}", e, e),
+                Error::HotplugSlotsExhausted => format!("{}{:?}", e, e),
                 Error::IncorrectDeviceType => format!("{}{:?}", e, e),
                 Error::InternalDeviceError(_) => format!("{}{:?}", e, e),
                 Error::InvalidInput => format!("{}{:?}", e, e),
                 Error::IrqsExhausted => format!("{}{:?}", e, e),
+                Error::NotHotplugged => format!("{}{:?}", e, e),
                 Error::RegisterIoEvent(_) => format!("{}{:?}", e, e),
                 Error::RegisterIrqFd(_) => format!("{}{:?}", e, e),
+                Error::UnregisterIoEvent(_) => format!("{}{:?}", e, e),
+                Error::UnregisterIrqFd(_) => format!("{}{:?}", e, e),
                 Error::UpdateFailed => format!("{}{:?}", e, e),
             };
             assert!(!msg.is_empty());
         };
         check_fmt_err(Error::BusError(devices::BusError::Overlap));
         check_fmt_err(Error::Cmdline(kernel_cmdline::Error::CommandLineCopy));
+        check_fmt_err(Error::DeviceAlreadyExists);
         check_fmt_err(Error::DeviceNotFound);
         check_fmt_err(Error::EventFd(io::Error::from_raw_os_error(0)));
+        check_fmt_err(Error::HotplugSlotsExhausted);
         check_fmt_err(Error::IncorrectDeviceType);
         check_fmt_err(Error::InternalDeviceError(String::new()));
         check_fmt_err(Error::InvalidInput);
         check_fmt_err(Error::IrqsExhausted);
+        check_fmt_err(Error::NotHotplugged);
         check_fmt_err(Error::RegisterIoEvent(errno::Error::new(0)));
         check_fmt_err(Error::RegisterIrqFd(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIoEvent(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIrqFd(errno::Error::new(0)));
         check_fmt_err(Error::UpdateFailed);
     }
 
@@ -739,6 +1060,129 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hotplug_devices() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let mut device_manager =
+            MMIODeviceManager::new(0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        device_manager
+            .reserve_hotplug_slots(1, &mut cmdline)
+            .unwrap();
+        assert_eq!(device_manager.hotplug_slots().len(), 1);
+        let slot = device_manager.hotplug_slots()[0].clone();
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert!(cmdline.as_str().contains(&format!(
+                "virtio_mmio.device=4K@0x{:08x}:{}",
+                slot.addr, slot.irqs[0]
+            )));
+            assert_eq!(device_manager.used_irqs_count(), 1);
+        }
+
+        // The empty slot is on the bus, but no device is registered in it.
+        let mut buf = [0u8; 4];
+        assert!(device_manager.bus.read(slot.addr + 0x08, &mut buf));
+        assert_eq!(buf, [0u8; 4]);
+        assert!(device_manager.get_device_info().is_empty());
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        let type_id = dummy.lock().unwrap().device_type();
+
+        // The queue events registered before a failure are unregistered.
+        vm.fd()
+            .register_irqfd(dummy.lock().unwrap().interrupt_evt(), slot.irqs[0])
+            .unwrap();
+        let mmio_device = MmioTransport::new(guest_mem.clone(), dummy.clone());
+        assert!(device_manager
+            .hotplug_mmio_virtio(vm.fd(), String::from("foo"), mmio_device)
+            .is_err());
+        vm.fd()
+            .unregister_irqfd(dummy.lock().unwrap().interrupt_evt(), slot.irqs[0])
+            .unwrap();
+        assert_eq!(device_manager.free_hotplug_slots().count(), 1);
+
+        let mmio_device = MmioTransport::new(guest_mem.clone(), dummy);
+        assert_eq!(
+            device_manager
+                .hotplug_mmio_virtio(vm.fd(), String::from("foo"), mmio_device)
+                .unwrap(),
+            slot
+        );
+        assert!(device_manager
+            .with_virtio_device_with_id(type_id, "foo", |_: &mut DummyDevice| Ok(()))
+            .is_ok());
+        // The guest is notified through a configuration change interrupt.
+        assert!(device_manager.bus.read(slot.addr + 0x60, &mut buf));
+        assert_eq!(u32::from_le_bytes(buf), VIRTIO_MMIO_INT_CONFIG);
+
+        // There is no free slot left.
+        let mmio_device =
+            MmioTransport::new(guest_mem.clone(), Arc::new(Mutex::new(DummyDevice::new())));
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .hotplug_mmio_virtio(vm.fd(), String::from("bar"), mmio_device)
+                    .unwrap_err()
+            ),
+            "no more hot-plug slots are available".to_string()
+        );
+
+        // Devices registered at boot time cannot be unplugged.
+        device_manager
+            .register_virtio_test_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                "baz",
+            )
+            .unwrap();
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .unplug_mmio_virtio(vm.fd(), type_id, "baz")
+                    .unwrap_err()
+            ),
+            "the device is not plugged in a hot-plug slot".to_string()
+        );
+
+        // A device whose events cannot be unregistered stays plugged in its slot.
+        let other_vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        assert!(device_manager
+            .unplug_mmio_virtio(other_vm.fd(), type_id, "foo")
+            .is_err());
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_some());
+        assert_eq!(device_manager.free_hotplug_slots().count(), 0);
+
+        // Unplugging the device frees its slot.
+        assert!(device_manager
+            .unplug_mmio_virtio(vm.fd(), type_id, "foo")
+            .is_ok());
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_none());
+        assert_eq!(device_manager.free_hotplug_slots().count(), 1);
+        assert!(device_manager.bus.read(slot.addr + 0x60, &mut buf));
+        assert_eq!(u32::from_le_bytes(buf), VIRTIO_MMIO_INT_CONFIG);
+        assert!(device_manager
+            .unplug_mmio_virtio(vm.fd(), type_id, "foo")
+            .is_err());
+    }
+
     #[test]
     fn test_slot_irq_allocation() {
         let mut device_manager =
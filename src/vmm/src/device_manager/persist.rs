@@ -8,6 +8,7 @@ use std::result::Result;
 use std::sync::{Arc, Mutex};
 
 use super::mmio::*;
+use crate::vmm_config::snapshot::DeviceOverrides;
 
 #[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
@@ -25,6 +26,7 @@ use devices::virtio::{
 };
 use kvm_ioctls::VmFd;
 use polly::event_manager::{Error as EventMgrError, EventManager, Subscriber};
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -41,11 +43,13 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
     Net(NetError),
+    /// A device override targets a device missing from the saved state.
+    UnknownDeviceOverride(String),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the state of a balloon device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedBalloonState {
@@ -59,7 +63,7 @@ pub struct ConnectedBalloonState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the state of a block device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedBlockState {
@@ -73,7 +77,7 @@ pub struct ConnectedBlockState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the state of a net device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedNetState {
@@ -87,7 +91,7 @@ pub struct ConnectedNetState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the state of a vsock device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedVsockState {
@@ -102,7 +106,7 @@ pub struct ConnectedVsockState {
 }
 
 #[cfg(target_arch = "aarch64")]
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the state of a legacy device connected to the MMIO space.
 pub struct ConnectedLegacyState {
     /// Device identifier.
@@ -111,7 +115,7 @@ pub struct ConnectedLegacyState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Holds the device states.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct DeviceStates {
@@ -127,6 +131,13 @@ pub struct DeviceStates {
     /// Balloon device state.
     #[version(start = 2, ser_fn = "balloon_serialize")]
     pub balloon_device: Option<ConnectedBalloonState>,
+    /// Slots reserved for hot-plugging devices, whether in use or not.
+    #[version(
+        start = 3,
+        default_fn = "default_hotplug_slots",
+        ser_fn = "hotplug_slots_serialize"
+    )]
+    pub hotplug_slots: Vec<MMIODeviceInfo>,
 }
 
 impl DeviceStates {
@@ -139,12 +150,94 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn default_hotplug_slots(_: u16) -> Vec<MMIODeviceInfo> {
+        Vec::new()
+    }
+
+    fn hotplug_slots_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && !self.hotplug_slots.is_empty() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement device hot-plug.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every device targeted by `overrides` is part of the saved state.
+    fn check_overrides(&self, overrides: &DeviceOverrides) -> Result<(), Error> {
+        if let Some(drive) = overrides.drives.iter().find(|drive| {
+            !self
+                .block_devices
+                .iter()
+                .any(|state| state.device_id == drive.drive_id)
+        }) {
+            return Err(Error::UnknownDeviceOverride(drive.drive_id.clone()));
+        }
+        if let Some(iface) = overrides.network_interfaces.iter().find(|iface| {
+            !self
+                .net_devices
+                .iter()
+                .any(|state| state.device_id == iface.iface_id)
+        }) {
+            return Err(Error::UnknownDeviceOverride(iface.iface_id.clone()));
+        }
+        if overrides.vsock.is_some() && self.vsock_device.is_none() {
+            return Err(Error::UnknownDeviceOverride(String::from("vsock")));
+        }
+
+        Ok(())
+    }
+
+    /// Gets the number of interrupts used by the saved devices and free hot-plug slots.
+    pub fn used_irqs_count(&self) -> usize {
+        #[cfg(target_arch = "aarch64")]
+        let legacy_irqs_count = self
+            .legacy_devices
+            .iter()
+            .map(|dev| dev.mmio_slot.irqs.len())
+            .sum::<usize>();
+        #[cfg(target_arch = "x86_64")]
+        let legacy_irqs_count = 0;
+
+        legacy_irqs_count
+            + self
+                .block_devices
+                .iter()
+                .map(|dev| dev.mmio_slot.irqs.len())
+                .sum::<usize>()
+            + self
+                .net_devices
+                .iter()
+                .map(|dev| dev.mmio_slot.irqs.len())
+                .sum::<usize>()
+            + self
+                .vsock_device
+                .as_ref()
+                .map_or(0, |dev| dev.mmio_slot.irqs.len())
+            + self
+                .balloon_device
+                .as_ref()
+                .map_or(0, |dev| dev.mmio_slot.irqs.len())
+            + self
+                .hotplug_slots
+                .iter()
+                .filter(|slot| {
+                    self.block_devices
+                        .iter()
+                        .all(|dev| dev.mmio_slot.addr != slot.addr)
+                })
+                .map(|slot| slot.irqs.len())
+                .sum::<usize>()
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
     pub mem: GuestMemoryMmap,
     pub vm: &'a VmFd,
     pub event_manager: &'a mut EventManager,
+    pub device_overrides: &'a DeviceOverrides,
 }
 
 impl<'a> Persist<'a> for MMIODeviceManager {
@@ -160,6 +253,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             vsock_device: None,
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
+            hotplug_slots: self.hotplug_slots().to_vec(),
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, devinfo, bus_dev| {
             if *devtype == arch::DeviceType::BootTimer {
@@ -179,11 +273,10 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             }
 
             let locked_bus_dev = bus_dev.lock().expect("Poisoned lock");
-            let mmio_transport = locked_bus_dev
-                .as_any()
-                // Only MmioTransport implements BusDevice on x86_64 at this point.
-                .downcast_ref::<MmioTransport>()
-                .expect("Unexpected BusDevice type");
+            // Only MmioTransport implements BusDevice on x86_64 at this point, either directly
+            // on the bus or behind a hot-plug slot.
+            let mmio_transport =
+                virtio_transport(&*locked_bus_dev).expect("Unexpected BusDevice type");
 
             let transport_state = mmio_transport.save();
 
@@ -253,6 +346,9 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         constructor_args: Self::ConstructorArgs,
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
+        let overrides = constructor_args.device_overrides;
+        state.check_overrides(overrides)?;
+
         let mut dev_manager =
             MMIODeviceManager::new(arch::MMIO_MEM_START, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mem = &constructor_args.mem;
@@ -282,6 +378,16 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             }
         }
 
+        // The hot-plug slots have to be on the bus before the devices plugged in them.
+        for slot in &state.hotplug_slots {
+            dev_manager
+                .slot_sanity_check(slot)
+                .map_err(Error::DeviceManager)?;
+            dev_manager
+                .register_hotplug_slot(slot.clone())
+                .map_err(Error::DeviceManager)?;
+        }
+
         let mut restore_helper = |device: Arc<Mutex<dyn VirtioDevice>>,
                                   as_subscriber: Arc<Mutex<dyn Subscriber>>,
                                   id: &String,
@@ -330,7 +436,14 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         for block_state in &state.block_devices {
             let device = Arc::new(Mutex::new(
                 Block::restore(
-                    BlockConstructorArgs { mem: mem.clone() },
+                    BlockConstructorArgs {
+                        mem: mem.clone(),
+                        disk_path: overrides
+                            .drives
+                            .iter()
+                            .find(|drive| drive.drive_id == block_state.device_id)
+                            .map(|drive| drive.path_on_host.clone()),
+                    },
                     &block_state.device_state,
                 )
                 .map_err(Error::Block)?,
@@ -348,7 +461,14 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         for net_state in &state.net_devices {
             let device = Arc::new(Mutex::new(
                 Net::restore(
-                    NetConstructorArgs { mem: mem.clone() },
+                    NetConstructorArgs {
+                        mem: mem.clone(),
+                        tap_if_name: overrides
+                            .network_interfaces
+                            .iter()
+                            .find(|iface| iface.iface_id == net_state.device_id)
+                            .map(|iface| iface.host_dev_name.clone()),
+                    },
                     &net_state.device_state,
                 )
                 .map_err(Error::Net)?,
@@ -366,6 +486,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         if let Some(vsock_state) = &state.vsock_device {
             let ctor_args = VsockUdsConstructorArgs {
                 cid: vsock_state.device_state.frontend.cid,
+                uds_path: overrides.vsock.as_ref().map(|vsock| vsock.uds_path.clone()),
             };
             let backend = VsockUnixBackend::restore(ctor_args, &vsock_state.device_state.backend)
                 .map_err(Error::VsockUnixBackend)?;
@@ -399,8 +520,11 @@ mod tests {
     use super::*;
     use crate::builder::tests::*;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
+    use crate::vmm_config::drive::{BlockDeviceConfig, DriveError};
     use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::snapshot::{DriveOverride, VsockOverride};
     use crate::vmm_config::vsock::VsockDeviceConfig;
+    use devices::virtio::{BlockFdPool, CacheType, FileEngineType, ImageFormat};
     use polly::event_manager::EventManager;
     use utils::tempfile::TempFile;
 
@@ -478,6 +602,7 @@ mod tests {
                 && self.block_devices == other.block_devices
                 && self.net_devices == other.net_devices
                 && self.vsock_device == other.vsock_device
+                && self.hotplug_slots == other.hotplug_slots
         }
     }
 
@@ -558,6 +683,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: true,
+                num_queue_pairs: 1,
+                vhost: false,
             };
             insert_net_device(
                 &mut vmm,
@@ -600,14 +727,183 @@ mod tests {
         let vmm = default_vmm();
         let device_states: DeviceStates =
             DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            device_states.used_irqs_count(),
+            original_mmio_device_manager.used_irqs_count()
+        );
         let restore_args = MMIODevManagerConstructorArgs {
             mem: vmm.guest_memory().clone(),
             vm: vmm.vm.fd(),
             event_manager: &mut event_manager,
+            device_overrides: &DeviceOverrides::default(),
         };
         let restored_dev_manager =
             MMIODeviceManager::restore(restore_args, &device_states).unwrap();
 
         assert_eq!(restored_dev_manager, original_mmio_device_manager);
     }
+
+    #[test]
+    fn test_device_manager_restore_overrides() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let drive_id = String::from("root");
+        let block_configs = vec![CustomBlockConfig::new(drive_id.clone(), true, None, true)];
+        let _block_files =
+            insert_block_devices(&mut vmm, &mut cmdline, &mut event_manager, block_configs);
+        let device_states = vmm.mmio_device_manager.save();
+
+        let restore = |device_overrides: &DeviceOverrides| {
+            let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+            let vmm = default_vmm();
+            let restore_args = MMIODevManagerConstructorArgs {
+                mem: vmm.guest_memory().clone(),
+                vm: vmm.vm.fd(),
+                event_manager: &mut event_manager,
+                device_overrides,
+            };
+            MMIODeviceManager::restore(restore_args, &device_states)
+        };
+
+        // Overrides can only target the saved devices.
+        let mut device_overrides = DeviceOverrides::default();
+        device_overrides.drives.push(DriveOverride {
+            drive_id: String::from("foo"),
+            path_on_host: String::from("/foo"),
+        });
+        match restore(&device_overrides) {
+            Err(Error::UnknownDeviceOverride(id)) => assert_eq!(id, "foo"),
+            _ => panic!("Expected an UnknownDeviceOverride error"),
+        }
+        device_overrides.drives.clear();
+        device_overrides.vsock = Some(VsockOverride {
+            uds_path: String::from("/foo"),
+        });
+        match restore(&device_overrides) {
+            Err(Error::UnknownDeviceOverride(id)) => assert_eq!(id, "vsock"),
+            _ => panic!("Expected an UnknownDeviceOverride error"),
+        }
+
+        // The block device is restored with the new backing file.
+        let new_file = TempFile::new().unwrap();
+        let new_path = new_file.as_path().to_str().unwrap().to_string();
+        device_overrides.vsock = None;
+        device_overrides.drives.push(DriveOverride {
+            drive_id,
+            path_on_host: new_path.clone(),
+        });
+        let restored_dev_manager = restore(&device_overrides).unwrap();
+        let restored_states = serde_json::to_value(restored_dev_manager.save()).unwrap();
+        assert_eq!(
+            restored_states["block_devices"][0]["device_state"]["disk_path"],
+            new_path
+        );
+    }
+
+    #[test]
+    fn test_device_manager_hotplug_persistence() {
+        let mut buf = vec![0; 16384];
+        let mut version_map = VersionMap::new();
+        let block_file = TempFile::new().unwrap();
+        let (original_mmio_device_manager, slots) = {
+            let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+            let mut vmm = default_vmm();
+            let mut cmdline = default_kernel_cmdline();
+            vmm.mmio_device_manager
+                .reserve_hotplug_slots(2, &mut cmdline)
+                .unwrap();
+            // Only room for the file descriptors of a single device.
+            vmm.hotplug_fd_pool = BlockFdPool::new(1).unwrap();
+
+            // Hot-plug a block device in the first slot, the second one stays free.
+            let block_config = || BlockDeviceConfig {
+                drive_id: String::from("scratch"),
+                path_on_host: block_file.as_path().to_str().unwrap().to_string(),
+                is_root_device: false,
+                partuuid: None,
+                is_read_only: false,
+                rate_limiter: None,
+                cache_type: CacheType::Unsafe,
+                io_engine: FileEngineType::Sync,
+                format: ImageFormat::Raw,
+                overlay_path: None,
+                num_queues: 1,
+                vhost_user_socket: None,
+                nbd: None,
+                verity: None,
+            };
+            // The drive is validated before being hot-plugged.
+            match vmm.hotplug_block_device(
+                BlockDeviceConfig {
+                    path_on_host: String::new(),
+                    ..block_config()
+                },
+                &mut event_manager,
+            ) {
+                Err(DriveError::InvalidBlockDevicePath) => (),
+                _ => panic!("Expected an InvalidBlockDevicePath error"),
+            }
+            vmm.hotplug_block_device(block_config(), &mut event_manager)
+                .unwrap();
+
+            // Versions older than 3 cannot describe the hot-plug slots.
+            version_map
+                .new_version()
+                .set_type_version(DeviceStates::type_id(), 2);
+            assert_eq!(
+                vmm.mmio_device_manager
+                    .save()
+                    .serialize(&mut buf.as_mut_slice(), &version_map, 2),
+                Err(VersionizeError::Semantic(
+                    "Target version does not implement device hot-plug.".to_string()
+                ))
+            );
+
+            version_map
+                .new_version()
+                .set_type_version(DeviceStates::type_id(), 3);
+            vmm.mmio_device_manager
+                .save()
+                .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+                .unwrap();
+
+            // The file descriptors of the unplugged device are used by the next one.
+            vmm.unplug_block_device("scratch", &mut event_manager)
+                .unwrap();
+            vmm.hotplug_block_device(block_config(), &mut event_manager)
+                .unwrap();
+
+            (
+                vmm.mmio_device_manager.soft_clone(),
+                vmm.mmio_device_manager.hotplug_slots().to_vec(),
+            )
+        };
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let vmm = default_vmm();
+        let device_states: DeviceStates =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(device_states.hotplug_slots, slots);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(device_states.used_irqs_count(), 2);
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            device_overrides: &DeviceOverrides::default(),
+        };
+        let mut restored_dev_manager =
+            MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+
+        assert_eq!(restored_dev_manager, original_mmio_device_manager);
+        assert_eq!(restored_dev_manager.hotplug_slots(), slots.as_slice());
+        // The restored device can be unplugged, which frees its slot.
+        assert!(restored_dev_manager
+            .unplug_mmio_virtio(vmm.vm.fd(), TYPE_BLOCK, "scratch")
+            .is_ok());
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(restored_dev_manager.used_irqs_count(), 2);
+    }
 }
@@ -0,0 +1,668 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the encrypted snapshot file format.
+//!
+//! The plaintext is split in fixed size chunks which are encrypted independently with
+//! AES-256-GCM and written back to back, each preceded by its nonce and followed by its
+//! authentication tag. The chunks are preceded by a fixed size header:
+//!
+//! | magic | chunk size | file id | nonce 0 | chunk 0 | tag 0 | ... | nonce n | chunk n | tag n |
+//!
+//! All the chunks but the last one, `n`, hold `chunk size` bytes of plaintext; the last one
+//! may be empty. Every nonce is made of 96 random bits, since the same key may encrypt many
+//! files. The header, the chunk index and whether the chunk is the last one are
+//! authenticated together with the chunk. Reordering, truncating or mixing the chunks of
+//! different files fails authentication, just like using the wrong key.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+
+/// Identifies an encrypted snapshot file. The last byte is the format version.
+const MAGIC: u64 = 0x0046_4345_4e43_5202;
+/// Size of the header: magic, chunk size and file id.
+const HEADER_LEN: usize = 24;
+/// Size of the nonce stored before every chunk.
+const NONCE_LEN: usize = 12;
+/// Size of the authentication tag stored after every chunk.
+const TAG_LEN: usize = 16;
+/// Size of an AES-256 key.
+pub const KEY_LEN: usize = 32;
+/// Size of the plaintext chunks, which is also the largest chunk size accepted when reading.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Errors associated with encrypted snapshot files.
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk with the given index failed authentication.
+    Authentication(u64),
+    /// Failed to encrypt a chunk.
+    Encrypt,
+    /// The file is not an encrypted snapshot file.
+    InvalidFile,
+    /// The encryption key is invalid.
+    InvalidKey(String),
+    /// Failed to read from or write to the file.
+    Io(io::Error),
+    /// The file is encrypted, but no key was provided.
+    KeyRequired,
+    /// Failed to read the encryption key from its file descriptor.
+    ReadKey(io::Error),
+    /// Failed to generate the file id or a nonce.
+    Random(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Authentication(idx) => write!(
+                f,
+                "Chunk {} failed authentication: the key is wrong or the file was modified",
+                idx
+            ),
+            Encrypt => write!(f, "Cannot encrypt the file contents"),
+            InvalidFile => write!(f, "Not an encrypted snapshot file"),
+            InvalidKey(msg) => write!(f, "Invalid encryption key: {}", msg),
+            Io(err) => write!(f, "Cannot access the encrypted file: {}", err),
+            KeyRequired => write!(f, "The file is encrypted, but no key was provided"),
+            ReadKey(err) => write!(f, "Cannot read the encryption key: {}", err),
+            Random(err) => write!(f, "Cannot generate random bytes: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A 256-bit key used to encrypt snapshot files. The key is wiped from memory on drop.
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Creates a key from its bytes.
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Parses a key given as 64 hexadecimal characters.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != KEY_LEN * 2 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(Error::InvalidKey(format!(
+                "expected {} hexadecimal characters",
+                KEY_LEN * 2
+            )));
+        }
+
+        let mut bytes = [0u8; KEY_LEN];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            // Safe to unwrap because the characters were checked above.
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).unwrap();
+        }
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Reads the key bytes from `fd`, which must be a regular file, read from its start, or a
+    /// pipe. `fd` is left open, since it is not owned by Firecracker.
+    pub fn from_fd(fd: RawFd) -> Result<Self> {
+        if fd < 0 {
+            return Err(Error::InvalidKey(format!("invalid file descriptor {}", fd)));
+        }
+
+        // The key is read through a duplicate of `fd`, which is the only one closed.
+        // Safe because the return value is checked.
+        let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup_fd < 0 {
+            return Err(Error::InvalidKey(format!(
+                "invalid file descriptor {}: {}",
+                fd,
+                io::Error::last_os_error()
+            )));
+        }
+        // Safe because `dup_fd` is a valid file descriptor which nothing else owns.
+        let mut file = unsafe { File::from_raw_fd(dup_fd) };
+
+        let file_type = file.metadata().map_err(Error::ReadKey)?.file_type();
+        let mut bytes = [0u8; KEY_LEN];
+        if file_type.is_file() {
+            file.read_exact_at(&mut bytes, 0)
+        } else if file_type.is_fifo() {
+            file.read_exact(&mut bytes)
+        } else {
+            return Err(Error::InvalidKey(format!(
+                "file descriptor {} is neither a regular file nor a pipe",
+                fd
+            )));
+        }
+        .map_err(Error::ReadKey)?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::from_slice(&self.0))
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "EncryptionKey(<redacted>)")
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Volatile writes are not optimized away, even though the key is no longer read.
+            // Safe because the pointer comes from a valid mutable reference.
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Fills `buf` with bytes from the kernel random number generator.
+pub(crate) fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        // Safe because the kernel only writes within the bounds of the remaining buffer,
+        // and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_getrandom,
+                buf[done..].as_mut_ptr(),
+                buf.len() - done,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else {
+            done += ret as usize;
+        }
+    }
+    Ok(())
+}
+
+fn u64_from_le_slice(bytes: &[u8]) -> u64 {
+    let mut le_bytes = [0u8; 8];
+    le_bytes.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(le_bytes)
+}
+
+/// Returns whether `file` starts like an encrypted snapshot file.
+///
+/// Only meant for snapshot state files, which otherwise start with the snapshot format
+/// header. Memory files start with guest memory, so whether they are encrypted is recorded
+/// in the snapshot state instead.
+pub fn is_encrypted(file: &File) -> io::Result<bool> {
+    let mut magic = [0u8; 8];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(u64::from_le_bytes(magic) == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// The data authenticated together with chunk `idx`.
+fn chunk_aad(header: &[u8; HEADER_LEN], idx: u64, last: bool) -> [u8; HEADER_LEN + 9] {
+    let mut aad = [0u8; HEADER_LEN + 9];
+    aad[..HEADER_LEN].copy_from_slice(header);
+    aad[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&idx.to_le_bytes());
+    aad[HEADER_LEN + 8] = last as u8;
+    aad
+}
+
+/// Encrypts the data it receives to an encrypted snapshot file.
+///
+/// The file is only complete after calling `finish`.
+pub struct EncryptedWriter<W: Write> {
+    writer: W,
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    chunk_idx: u64,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    /// Creates a new writer which encrypts the data in chunks of `chunk_size` bytes, and
+    /// writes the header of the file. `chunk_size` can be at most `CHUNK_SIZE`.
+    pub fn new(mut writer: W, key: &EncryptionKey, chunk_size: usize) -> Result<Self> {
+        debug_assert!(chunk_size > 0 && chunk_size <= CHUNK_SIZE);
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(&MAGIC.to_le_bytes());
+        header[8..16].copy_from_slice(&(chunk_size as u64).to_le_bytes());
+        fill_random(&mut header[16..24]).map_err(Error::Random)?;
+        writer.write_all(&header).map_err(Error::Io)?;
+
+        Ok(EncryptedWriter {
+            writer,
+            cipher: key.cipher(),
+            header,
+            chunk: Vec::with_capacity(chunk_size + TAG_LEN),
+            chunk_size,
+            chunk_idx: 0,
+        })
+    }
+
+    fn write_chunk(&mut self, last: bool) -> Result<()> {
+        // Nonces are random rather than derived from the chunk index, so that they are not
+        // reused across the files encrypted with the same key.
+        let mut nonce = [0u8; NONCE_LEN];
+        fill_random(&mut nonce).map_err(Error::Random)?;
+        let aad = chunk_aad(&self.header, self.chunk_idx, last);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut self.chunk)
+            .map_err(|_| Error::Encrypt)?;
+        self.chunk.extend_from_slice(tag.as_slice());
+        self.writer.write_all(&nonce).map_err(Error::Io)?;
+        self.writer.write_all(&self.chunk).map_err(Error::Io)?;
+
+        self.chunk.clear();
+        self.chunk_idx += 1;
+        Ok(())
+    }
+
+    /// Writes the last chunk and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_chunk(true)?;
+        self.writer.flush().map_err(Error::Io)?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full chunk is only written once more data follows it, since the last chunk
+        // is authenticated as such.
+        if self.chunk.len() == self.chunk_size && !buf.is_empty() {
+            self.write_chunk(false).map_err(|err| match err {
+                Error::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+            })?;
+        }
+        let len = std::cmp::min(buf.len(), self.chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Incomplete chunks are only written by `finish`.
+        self.writer.flush()
+    }
+}
+
+/// Decrypts the contents of an encrypted snapshot file, in order.
+pub struct EncryptedReader {
+    file: File,
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    chunk_size: u64,
+    chunk_count: u64,
+    plaintext_len: u64,
+    next_chunk: u64,
+    chunk: Vec<u8>,
+    chunk_offset: usize,
+}
+
+impl EncryptedReader {
+    /// Checks the header of the encrypted snapshot `file`, which is decrypted with `key`.
+    ///
+    /// Returns `Error::InvalidFile` if `file` is not an encrypted snapshot file.
+    pub fn new(file: &File, key: &EncryptionKey) -> Result<Self> {
+        let file = file.try_clone().map_err(Error::Io)?;
+        let file_len = file.metadata().map_err(Error::Io)?.len();
+        if file_len < (HEADER_LEN + NONCE_LEN + TAG_LEN) as u64 {
+            return Err(Error::InvalidFile);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact_at(&mut header, 0).map_err(Error::Io)?;
+        let chunk_size = u64_from_le_slice(&header[8..16]);
+        if u64_from_le_slice(&header[0..8]) != MAGIC
+            || chunk_size == 0
+            || chunk_size > CHUNK_SIZE as u64
+        {
+            return Err(Error::InvalidFile);
+        }
+
+        // All the chunks but the last one are full, and the last one holds at least its
+        // nonce and its tag.
+        let stored_chunk_len = chunk_size
+            .checked_add((NONCE_LEN + TAG_LEN) as u64)
+            .ok_or(Error::InvalidFile)?;
+        let body_len = file_len - HEADER_LEN as u64;
+        let last_chunk_len = body_len % stored_chunk_len;
+        if last_chunk_len < (NONCE_LEN + TAG_LEN) as u64 {
+            return Err(Error::InvalidFile);
+        }
+        let chunk_count = body_len / stored_chunk_len + 1;
+        let plaintext_len = body_len - chunk_count * (NONCE_LEN + TAG_LEN) as u64;
+
+        Ok(EncryptedReader {
+            file,
+            cipher: key.cipher(),
+            header,
+            chunk_size,
+            chunk_count,
+            plaintext_len,
+            next_chunk: 0,
+            chunk: Vec::new(),
+            chunk_offset: 0,
+        })
+    }
+
+    /// Returns the size of the decrypted contents.
+    pub fn plaintext_len(&self) -> u64 {
+        self.plaintext_len
+    }
+
+    fn read_chunk(&mut self) -> Result<()> {
+        let idx = self.next_chunk;
+        let last = idx + 1 == self.chunk_count;
+        let chunk_len = if last {
+            self.plaintext_len - idx * self.chunk_size
+        } else {
+            self.chunk_size
+        } as usize;
+        let offset = HEADER_LEN as u64 + idx * (self.chunk_size + (NONCE_LEN + TAG_LEN) as u64);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.file
+            .read_exact_at(&mut nonce, offset)
+            .map_err(Error::Io)?;
+        self.chunk.resize(chunk_len + TAG_LEN, 0);
+        self.file
+            .read_exact_at(&mut self.chunk, offset + NONCE_LEN as u64)
+            .map_err(Error::Io)?;
+        let tag = Tag::clone_from_slice(&self.chunk[chunk_len..]);
+        self.chunk.truncate(chunk_len);
+
+        let aad = chunk_aad(&self.header, idx, last);
+        self.cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut self.chunk, &tag)
+            .map_err(|_| {
+                // Don't leave unauthenticated plaintext around.
+                self.chunk.clear();
+                Error::Authentication(idx)
+            })?;
+
+        self.next_chunk += 1;
+        self.chunk_offset = 0;
+        Ok(())
+    }
+
+    /// Fills `buf` with the next decrypted bytes. Every chunk is authenticated before any
+    /// of its contents are returned.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.chunk_offset == self.chunk.len() {
+                if self.next_chunk == self.chunk_count {
+                    return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
+                self.read_chunk()?;
+                continue;
+            }
+
+            let len = std::cmp::min(buf.len() - done, self.chunk.len() - self.chunk_offset);
+            buf[done..done + len]
+                .copy_from_slice(&self.chunk[self.chunk_offset..self.chunk_offset + len]);
+            self.chunk_offset += len;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Authenticates the chunks that weren't read yet, which makes sure that the file
+    /// wasn't truncated.
+    pub fn finish(mut self) -> Result<()> {
+        while self.next_chunk < self.chunk_count {
+            self.read_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Decrypts the whole file, authenticating every chunk.
+    pub fn read_to_end(mut self) -> Result<Vec<u8>> {
+        let mut plaintext = vec![0u8; self.plaintext_len as usize];
+        self.read_exact(&mut plaintext)?;
+        self.finish()?;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use utils::tempfile::TempFile;
+
+    const TEST_CHUNK_SIZE: usize = 4096;
+    const STORED_CHUNK_LEN: usize = NONCE_LEN + TEST_CHUNK_SIZE + TAG_LEN;
+
+    // Creates a pipe, and returns its read and write ends.
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because the kernel only writes the two file descriptors, and we check the
+        // return value.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the file descriptors were just created, and are owned by nothing else.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    fn test_key(byte: u8) -> EncryptionKey {
+        EncryptionKey::new([byte; KEY_LEN])
+    }
+
+    fn write_encrypted_file(key: &EncryptionKey, contents: &[u8]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        let mut writer = EncryptedWriter::new(file.as_file(), key, TEST_CHUNK_SIZE).unwrap();
+        // Write in pieces that don't line up with the chunks.
+        for piece in contents.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_encrypted_file() {
+        let key = test_key(0xa5);
+        // Partial last chunk, full last chunk and empty file.
+        for len in &[TEST_CHUNK_SIZE * 3 + 100, TEST_CHUNK_SIZE * 2, 0] {
+            let contents: Vec<u8> = (0..*len).map(|idx| (idx % 251) as u8).collect();
+            let file = write_encrypted_file(&key, &contents);
+            assert!(is_encrypted(file.as_file()).unwrap());
+
+            let reader = EncryptedReader::new(file.as_file(), &key).unwrap();
+            assert_eq!(reader.plaintext_len(), *len as u64);
+            assert_eq!(reader.read_to_end().unwrap(), contents);
+        }
+
+        // Reading in pieces across chunk boundaries.
+        let contents = vec![0x42u8; TEST_CHUNK_SIZE * 2 + 10];
+        let file = write_encrypted_file(&key, &contents);
+        let mut reader = EncryptedReader::new(file.as_file(), &key).unwrap();
+        let mut buf = vec![0u8; TEST_CHUNK_SIZE + 5];
+        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &contents[TEST_CHUNK_SIZE + 5..]);
+        match reader.read_exact(&mut buf[..1]) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("Expected an UnexpectedEof error"),
+        }
+
+        // The same contents are encrypted differently every time.
+        let other_file = write_encrypted_file(&key, &contents);
+        let encrypted = std::fs::read(file.as_path()).unwrap();
+        let other_encrypted = std::fs::read(other_file.as_path()).unwrap();
+        assert_ne!(encrypted, other_encrypted);
+
+        // The nonces are neither shared by the chunks of a file, nor by the chunks with the
+        // same index in different files.
+        let nonce = |encrypted: &[u8], idx: usize| {
+            let offset = HEADER_LEN + idx * STORED_CHUNK_LEN;
+            encrypted[offset..offset + NONCE_LEN].to_vec()
+        };
+        assert_ne!(nonce(&encrypted, 0), nonce(&encrypted, 1));
+        assert_ne!(nonce(&encrypted, 0), nonce(&other_encrypted, 0));
+    }
+
+    #[test]
+    fn test_authentication() {
+        let key = test_key(1);
+        let contents = vec![0x42u8; TEST_CHUNK_SIZE * 2 + 10];
+
+        // Wrong key.
+        let file = write_encrypted_file(&key, &contents);
+        let reader = EncryptedReader::new(file.as_file(), &test_key(2)).unwrap();
+        match reader.read_to_end() {
+            Err(Error::Authentication(0)) => (),
+            _ => panic!("Expected an Authentication error"),
+        }
+
+        // Modified chunk and modified nonce.
+        for offset in &[NONCE_LEN + 7, 3] {
+            let file = write_encrypted_file(&key, &contents);
+            let mut encrypted = file.as_file();
+            encrypted
+                .seek(SeekFrom::Start(
+                    (HEADER_LEN + STORED_CHUNK_LEN + offset) as u64,
+                ))
+                .unwrap();
+            encrypted.write_all(&[0xff]).unwrap();
+            let mut reader = EncryptedReader::new(file.as_file(), &key).unwrap();
+            let mut buf = vec![0u8; TEST_CHUNK_SIZE];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &contents[..TEST_CHUNK_SIZE]);
+            match reader.read_exact(&mut buf) {
+                Err(Error::Authentication(1)) => (),
+                _ => panic!("Expected an Authentication error"),
+            }
+        }
+
+        // Truncated file.
+        let file = write_encrypted_file(&key, &contents);
+        file.as_file()
+            .set_len((HEADER_LEN + STORED_CHUNK_LEN + NONCE_LEN + TAG_LEN + 20) as u64)
+            .unwrap();
+        let reader = EncryptedReader::new(file.as_file(), &key).unwrap();
+        match reader.read_to_end() {
+            Err(Error::Authentication(1)) => (),
+            _ => panic!("Expected an Authentication error"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let key = test_key(1);
+        let raw_file = TempFile::new().unwrap();
+        assert!(!is_encrypted(raw_file.as_file()).unwrap());
+        raw_file.as_file().write_all(&[0u8; 4096]).unwrap();
+        assert!(!is_encrypted(raw_file.as_file()).unwrap());
+        match EncryptedReader::new(raw_file.as_file(), &key) {
+            Err(Error::InvalidFile) => (),
+            _ => panic!("Expected an InvalidFile error"),
+        }
+
+        // A last chunk shorter than a tag.
+        let file = write_encrypted_file(&key, &[1u8; 100]);
+        file.as_file()
+            .set_len((HEADER_LEN + NONCE_LEN + 10) as u64)
+            .unwrap();
+        match EncryptedReader::new(file.as_file(), &key) {
+            Err(Error::InvalidFile) => (),
+            _ => panic!("Expected an InvalidFile error"),
+        }
+
+        // A corrupted header with a chunk size the writer never produces.
+        let file = write_encrypted_file(&key, &[1u8; 100]);
+        for chunk_size in &[
+            0,
+            CHUNK_SIZE as u64 + 1,
+            u64::MAX - TAG_LEN as u64 + 1,
+            u64::MAX,
+        ] {
+            file.as_file()
+                .write_all_at(&chunk_size.to_le_bytes(), 8)
+                .unwrap();
+            match EncryptedReader::new(file.as_file(), &key) {
+                Err(Error::InvalidFile) => (),
+                _ => panic!("Expected an InvalidFile error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let key = EncryptionKey::from_hex(&"0f".repeat(KEY_LEN)).unwrap();
+        assert_eq!(key.0, [0x0f; KEY_LEN]);
+        assert_eq!(format!("{:?}", key), "EncryptionKey(<redacted>)");
+        assert!(EncryptionKey::from_hex("0f").is_err());
+        assert!(EncryptionKey::from_hex(&"0g".repeat(KEY_LEN)).is_err());
+        assert!(EncryptionKey::from_hex(&"+f".repeat(KEY_LEN)).is_err());
+
+        let key_file = TempFile::new().unwrap();
+        key_file.as_file().write_all(&[7u8; KEY_LEN]).unwrap();
+        // The file descriptor is left open, and the key can be read from it again.
+        let key_fd = File::open(key_file.as_path()).unwrap();
+        for _ in 0..2 {
+            let key = EncryptionKey::from_fd(key_fd.as_raw_fd()).unwrap();
+            assert_eq!(key.0, [7u8; KEY_LEN]);
+        }
+        assert!(key_fd.metadata().is_ok());
+
+        // Pipes are read from as well.
+        let (read_end, mut write_end) = pipe();
+        write_end.write_all(&[9u8; KEY_LEN]).unwrap();
+        let key = EncryptionKey::from_fd(read_end.as_raw_fd()).unwrap();
+        assert_eq!(key.0, [9u8; KEY_LEN]);
+
+        let short_file = TempFile::new().unwrap();
+        short_file.as_file().write_all(&[7u8; 10]).unwrap();
+        match EncryptionKey::from_fd(short_file.as_file().as_raw_fd()) {
+            Err(Error::ReadKey(_)) => (),
+            _ => panic!("Expected a ReadKey error"),
+        }
+
+        // Other kinds of file descriptors, like sockets, are refused.
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let invalid_fds = vec![-1, socket.as_raw_fd(), i32::MAX];
+        for fd in invalid_fds {
+            match EncryptionKey::from_fd(fd) {
+                Err(Error::InvalidKey(_)) => (),
+                _ => panic!("Expected an InvalidKey error"),
+            }
+        }
+        assert!(socket.peer_addr().is_ok());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            Error::Authentication(0),
+            Error::Encrypt,
+            Error::InvalidFile,
+            Error::InvalidKey(String::new()),
+            Error::Io(io::Error::from_raw_os_error(0)),
+            Error::KeyRequired,
+            Error::ReadKey(io::Error::from_raw_os_error(0)),
+            Error::Random(io::Error::from_raw_os_error(0)),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -12,12 +12,17 @@
 
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
+pub mod compressed_memory;
 /// Syscalls allowed through the seccomp filter.
 pub mod default_syscalls;
 pub(crate) mod device_manager;
+pub mod encrypted_file;
 pub mod memory_snapshot;
+/// Live migration of a microVM between Firecracker processes.
+pub mod migration;
 /// Save/restore utilities.
 pub mod persist;
+mod precopy;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// microVM RPC API adapters.
@@ -36,6 +41,8 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Mutex;
 use std::sync::{Arc, Barrier};
@@ -44,8 +51,9 @@ use std::time::Duration;
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
-use crate::memory_snapshot::SnapshotMemory;
+use crate::memory_snapshot::{MemoryLayerState, SnapshotMemory};
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
+use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, DriveError};
 use crate::vstate::vcpu::VcpuState;
 use crate::vstate::{
     vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse},
@@ -54,8 +62,8 @@ use crate::vstate::{
 use arch::DeviceType;
 use devices::virtio::balloon::Error as BalloonError;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, BlockFdPool, MmioTransport, Net, BALLOON_DEV_ID,
+    TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
 };
 use devices::BusDevice;
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
@@ -109,6 +117,8 @@ pub enum Error {
     DirtyBitmap(kvm_ioctls::Error),
     /// Cannot read from an Event file descriptor.
     EventFd(io::Error),
+    /// Cannot create the file descriptors of the hot-plugged devices.
+    HotplugFds(io::Error),
     /// I8042 Error.
     I8042Error(devices::legacy::I8042DeviceError),
     /// Cannot access kernel file.
@@ -123,6 +133,8 @@ pub enum Error {
     Logger(LoggerError),
     /// Internal metrics system error.
     Metrics(MetricsError),
+    /// Cannot add a device to the event manager.
+    RegisterEvent(polly::event_manager::Error),
     /// Cannot add a device to the MMIO Bus.
     RegisterMMIODevice(device_manager::mmio::Error),
     /// Cannot build seccomp filters.
@@ -170,6 +182,11 @@ impl Display for Error {
             DeviceManager(e) => write!(f, "{}", e),
             DirtyBitmap(e) => write!(f, "Error getting the KVM dirty bitmap. {}", e),
             EventFd(e) => write!(f, "Event fd error: {}", e),
+            HotplugFds(e) => write!(
+                f,
+                "Cannot create the file descriptors of the hot-plugged devices: {}",
+                e
+            ),
             I8042Error(e) => write!(f, "I8042 error: {}", e),
             KernelFile(e) => write!(f, "Cannot access kernel file: {}", e),
             KvmContext(e) => write!(f, "Failed to validate KVM support: {}", e),
@@ -177,6 +194,7 @@ impl Display for Error {
             LegacyIOBus(e) => write!(f, "Cannot add devices to the legacy I/O Bus. {}", e),
             Logger(e) => write!(f, "Logger error: {}", e),
             Metrics(e) => write!(f, "Metrics error: {}", e),
+            RegisterEvent(e) => write!(f, "Cannot add a device to the event manager: {:?}", e),
             RegisterMMIODevice(e) => write!(f, "Cannot add a device to the MMIO Bus. {}", e),
             SeccompFilters(e) => write!(f, "Cannot build seccomp filters: {}", e),
             Serial(e) => write!(f, "Error writing to the serial console: {}", e),
@@ -234,6 +252,12 @@ pub struct Vmm {
 
     // Guest VM core resources.
     guest_memory: GuestMemoryMmap,
+    // Memory files describing the guest memory as of the latest snapshot. `None` when the
+    // microVM was restored from a snapshot that doesn't record them.
+    memory_layers: Option<Vec<MemoryLayerState>>,
+    // Connection to the page fault handler populating the guest memory, kept open for the
+    // lifetime of the microVM.
+    uffd_handler: Option<UnixStream>,
 
     vcpus_handles: Vec<VcpuHandle>,
     exit_evt: EventFd,
@@ -241,6 +265,11 @@ pub struct Vmm {
 
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
+    // File descriptors of the devices hot-plugged after the seccomp filter is installed.
+    hotplug_fd_pool: BlockFdPool,
+    // Block devices hot-plugged since boot or restore, whose file descriptors go back to the
+    // pool once they are unplugged.
+    hotplugged_blocks: HashMap<String, Arc<Mutex<Block>>>,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
 }
@@ -367,7 +396,16 @@ impl Vmm {
 
     /// Saves the state of a paused Microvm.
     pub fn save_state(&mut self) -> std::result::Result<MicrovmState, MicrovmStateError> {
-        use self::MicrovmStateError::SaveVmState;
+        use self::MicrovmStateError::{NotAllowed, SaveVmState};
+        // The state of the queues of vhost-user block devices lies in their backend.
+        if self.mmio_device_manager.has_vhost_user_devices() {
+            return Err(NotAllowed(
+                "Cannot save the state of vhost-user block devices".to_string(),
+            ));
+        }
+        // The used ring interrupts of the requests completing now have to reach the
+        // saved interrupt controller state.
+        self.mmio_device_manager.drain_devices();
         let vcpu_states = self.save_vcpu_states()?;
         let vm_state = {
             #[cfg(target_arch = "x86_64")]
@@ -527,6 +565,92 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Creates the block device described by `config` and hot-plugs it in one of the slots
+    /// reserved at boot time. The guest discovers the device once it binds its virtio-mmio
+    /// driver to the slot.
+    pub fn hotplug_block_device(
+        &mut self,
+        config: BlockDeviceConfig,
+        event_manager: &mut EventManager,
+    ) -> std::result::Result<(), DriveError> {
+        let block = Arc::new(Mutex::new(BlockBuilder::create_block(
+            config,
+            Some(&mut self.hotplug_fd_pool),
+        )?));
+        let id = block.lock().expect("Poisoned lock").id().clone();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        let mmio_device = MmioTransport::new(self.guest_memory.clone(), block.clone());
+        if let Err(e) =
+            self.mmio_device_manager
+                .hotplug_mmio_virtio(self.vm.fd(), id.clone(), mmio_device)
+        {
+            self.reclaim_block_fds(block);
+            return Err(DriveError::DeviceHotplug(Error::DeviceManager(e)));
+        }
+
+        if let Err(e) = event_manager.add_subscriber(block.clone()) {
+            // Free the slot, as the device would never process its events.
+            let _ = self
+                .mmio_device_manager
+                .unplug_mmio_virtio(self.vm.fd(), TYPE_BLOCK, &id);
+            self.reclaim_block_fds(block);
+            return Err(DriveError::DeviceHotplug(Error::RegisterEvent(e)));
+        }
+        self.hotplugged_blocks.insert(id, block);
+        Ok(())
+    }
+
+    /// Hot-unplugs the block device with `drive_id` id from its slot. The guest is expected to
+    /// have unbound its virtio-mmio driver from the slot beforehand.
+    pub fn unplug_block_device(
+        &mut self,
+        drive_id: &str,
+        event_manager: &mut EventManager,
+    ) -> Result<()> {
+        let mmio_device = self
+            .mmio_device_manager
+            .unplug_mmio_virtio(self.vm.fd(), TYPE_BLOCK, drive_id)
+            .map_err(Error::DeviceManager)?;
+        {
+            let mut locked_device = mmio_device.locked_device();
+            let block = locked_device
+                .as_mut_any()
+                .downcast_mut::<Block>()
+                .expect("Unexpected VirtioDevice type");
+            // Complete the requests in flight before the backing file gets closed.
+            block.prepare_save();
+            block.unregister_events(event_manager);
+        }
+        METRICS.block_drives.remove(drive_id);
+
+        // The devices restored from a snapshot were created with their own file descriptors.
+        drop(mmio_device);
+        if let Some(block) = self.hotplugged_blocks.remove(drive_id) {
+            self.reclaim_block_fds(block);
+        }
+        Ok(())
+    }
+
+    // Puts the file descriptors of a block device which is no longer plugged back in the pool.
+    fn reclaim_block_fds(&mut self, block: Arc<Mutex<Block>>) {
+        match Arc::try_unwrap(block) {
+            Ok(block) => self
+                .hotplug_fd_pool
+                .reclaim(block.into_inner().expect("Poisoned lock")),
+            Err(_) => warn!("Cannot reuse the file descriptors of an unplugged block device."),
+        }
+    }
+
+    /// Resizes the block device with `drive_id` id, growing its backing file to `size_bytes`
+    /// if provided.
+    pub fn resize_block_device(&mut self, drive_id: &str, size_bytes: Option<u64>) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block.resize(size_bytes).map_err(|e| e.to_string())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Updates the rate limiter parameters for block device with `drive_id` id.
     pub fn update_block_rate_limiter(
         &mut self,
@@ -553,8 +677,27 @@ impl Vmm {
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
-                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
-                Ok(())
+                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Starts capturing the frames of a network interface in the pcapng file at `capture_path`.
+    pub fn start_net_capture(&mut self, net_id: &str, capture_path: &Path) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.start_capture(capture_path)
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Stops capturing the frames of a network interface.
+    pub fn stop_net_capture(&mut self, net_id: &str) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.stop_capture().map_err(|e| format!("{:?}", e))
             })
             .map_err(Error::DeviceManager)
     }
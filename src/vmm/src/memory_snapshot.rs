@@ -5,8 +5,11 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 
+use serde::{Deserialize, Serialize};
+use versionize::crc::CRC64Writer;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
@@ -14,10 +17,12 @@ use vm_memory::{
     GuestMemoryRegion, GuestRegionMmap, MemoryRegionAddress, MmapRegion,
 };
 
+use crate::compressed_memory::{self, CompressedMemoryReader, CompressedMemoryWriter};
+use crate::encrypted_file::{self, EncryptedReader, EncryptedWriter, EncryptionKey};
 use crate::DirtyBitmap;
 
 /// State of a guest memory region saved to file/buffer.
-#[derive(Debug, PartialEq, Versionize)]
+#[derive(Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryRegionState {
     /// Base address.
@@ -28,12 +33,86 @@ pub struct GuestMemoryRegionState {
     pub offset: u64,
 }
 
+/// The format in which a memory file was written.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum MemoryFileFormatState {
+    /// Raw image of the guest memory, with holes in place of the pages it doesn't hold.
+    Raw,
+    /// Compressed chunks of the guest memory (see `compressed_memory`).
+    Compressed,
+    /// Encrypted image of the guest memory (see `encrypted_file`).
+    Encrypted,
+}
+
+impl Default for MemoryFileFormatState {
+    fn default() -> Self {
+        MemoryFileFormatState::Raw
+    }
+}
+
+/// Describes one memory file in a chain of layered (full + diff) memory snapshots.
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct MemoryLayerState {
+    /// Unique identifier of the memory file.
+    pub id: u64,
+    /// Identifier of the memory file this one has to be applied on top of.
+    /// `None` for a base (full) memory file.
+    pub parent_id: Option<u64>,
+    /// Format of the memory file.
+    pub format: MemoryFileFormatState,
+    /// Checksum of the pages stored in the memory file (see `memory_file_checksum`).
+    pub checksum: u64,
+}
+
+impl MemoryLayerState {
+    /// Creates the description of a new memory file, in the given `format`, applied on top
+    /// of `parent`. Its identifier comes from the kernel random number generator, so that the
+    /// memory files of different microVMs don't get mixed up.
+    pub fn new(
+        parent: Option<&MemoryLayerState>,
+        format: MemoryFileFormatState,
+        checksum: u64,
+    ) -> std::result::Result<Self, Error> {
+        let mut id = [0u8; 8];
+        encrypted_file::fill_random(&mut id).map_err(Error::LayerId)?;
+        Ok(MemoryLayerState {
+            id: u64::from_le_bytes(id),
+            parent_id: parent.map(|layer| layer.id),
+            format,
+            checksum,
+        })
+    }
+}
+
 /// Guest memory state.
-#[derive(Debug, Default, PartialEq, Versionize)]
+#[derive(Debug, Default, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryState {
     /// List of regions.
     pub regions: Vec<GuestMemoryRegionState>,
+    /// The chain of memory files describing the guest memory, starting with the base
+    /// (full) memory file. The last entry describes the memory file written together
+    /// with this state.
+    #[version(start = 2)]
+    pub layers: Vec<MemoryLayerState>,
+}
+
+impl GuestMemoryState {
+    /// Gets the format of the first memory file, when the guest memory is loaded from the
+    /// `file_count` most recent memory files of the chain.
+    ///
+    /// Only the base memory file of a whole chain is read in its recorded format. A base
+    /// memory file squashed from older layers is raw, like the diff memory files, and so are
+    /// the memory files of snapshots which don't record their layers. Encrypted snapshots
+    /// always record their layer.
+    pub fn base_file_format(&self, file_count: usize) -> MemoryFileFormatState {
+        match self.layers.first() {
+            Some(layer) if file_count == self.layers.len() => layer.format,
+            _ => MemoryFileFormatState::Raw,
+        }
+    }
 }
 
 /// Defines the interface for snapshotting memory.
@@ -43,21 +122,45 @@ where
 {
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
-    /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, seeking over all-zero pages.
+    /// Returns the checksum of the dumped pages.
+    fn dump<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<u64, Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed memory file format.
+    /// Returns the checksum of the dumped pages.
+    fn dump_compressed<T: std::io::Write>(&self, writer: &mut T)
+        -> std::result::Result<u64, Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, encrypted with `key`.
+    /// Returns the checksum of the dumped pages.
+    fn dump_encrypted<T: std::io::Write>(
+        &self,
+        writer: &mut T,
+        key: &EncryptionKey,
+    ) -> std::result::Result<u64, Error>;
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
+    /// Returns the checksum of the dumped pages.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
-    ) -> std::result::Result<(), Error>;
-    /// Creates a GuestMemoryMmap given a `file` containing the data
+    ) -> std::result::Result<u64, Error>;
+    /// Creates a GuestMemoryMmap given a `file` containing the data, in the given `format`,
     /// and a `state` containing mapping information.
     fn restore(
         file: &File,
+        format: MemoryFileFormatState,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error>;
+    /// Copies the pages stored in the diff memory `file` over the guest memory.
+    /// The copied pages are not reported as dirty.
+    fn apply_diff_layer(
+        &self,
+        file: &File,
+        state: &GuestMemoryState,
+    ) -> std::result::Result<(), Error>;
 }
 
 /// Errors associated with dumping guest memory to file.
@@ -71,6 +174,19 @@ pub enum Error {
     CreateRegion(vm_memory::mmap::MmapRegionError),
     /// Cannot dump memory.
     WriteMemory(GuestMemoryError),
+    /// Cannot load a memory file into guest memory.
+    ReadMemory(GuestMemoryError),
+    /// Cannot read a compressed memory file.
+    CompressedMemory(compressed_memory::Error),
+    /// Cannot encrypt or decrypt an encrypted memory file.
+    Encryption(encrypted_file::Error),
+    /// The number of memory files does not match the layers recorded in the snapshot.
+    LayerCount(usize, usize),
+    /// Cannot generate the identifier of a memory layer.
+    LayerId(std::io::Error),
+    /// The memory layer at the given index, base first, is not part of the recorded chain of
+    /// layers or does not match its memory file.
+    LayerMismatch(usize),
 }
 
 impl Display for Error {
@@ -81,6 +197,20 @@ impl Display for Error {
             CreateMemory(err) => write!(f, "Cannot create memory: {:?}", err),
             CreateRegion(err) => write!(f, "Cannot create memory region: {:?}", err),
             WriteMemory(err) => write!(f, "Cannot dump memory: {:?}", err),
+            ReadMemory(err) => write!(f, "Cannot load memory file: {:?}", err),
+            CompressedMemory(err) => write!(f, "Cannot read compressed memory file: {}", err),
+            Encryption(err) => write!(f, "Encrypted memory file error: {}", err),
+            LayerCount(expected, actual) => write!(
+                f,
+                "The snapshot records {} memory files, but {} were provided",
+                expected, actual
+            ),
+            LayerId(err) => write!(f, "Cannot generate the memory layer identifier: {}", err),
+            LayerMismatch(idx) => write!(
+                f,
+                "Snapshot memory layer {} does not match its memory file",
+                idx
+            ),
         }
     }
 }
@@ -103,21 +233,65 @@ impl SnapshotMemory for GuestMemoryMmap {
         guest_memory_state
     }
 
-    /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error> {
+    /// Dumps all contents of GuestMemoryMmap to a writer, seeking over all-zero pages.
+    /// Returns the checksum of the dumped pages.
+    fn dump<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<u64, Error> {
+        let page_size = sysconf::page::pagesize();
+        let mut page = vec![0u8; page_size];
+        let mut checksum = 0;
+        let mut writer_offset = 0;
+        // Length of the run of zero pages the writer still has to seek over.
+        let mut skipped_len = 0;
+
         self.with_regions_mut(|_, region| {
-            region.write_all_to(MemoryRegionAddress(0), writer, region.len() as usize)
+            for page_offset in (0..region.len()).step_by(page_size) {
+                region.read_slice(&mut page, MemoryRegionAddress(page_offset))?;
+                if is_zero_page(&page) {
+                    // Leave a hole in the output instead of writing the page.
+                    skipped_len += page_size as i64;
+                    continue;
+                }
+
+                if skipped_len > 0 {
+                    writer
+                        .seek(SeekFrom::Current(skipped_len))
+                        .map_err(GuestMemoryError::IOError)?;
+                    skipped_len = 0;
+                }
+                checksum ^= page_checksum(writer_offset + page_offset, &page);
+                writer.write_all(&page).map_err(GuestMemoryError::IOError)?;
+            }
+
+            writer_offset += region.len();
+            Ok(())
         })
-        .map_err(Error::WriteMemory)
+        .map_err(Error::WriteMemory)?;
+
+        // The trailing zero page is written so that the output has the full memory size even
+        // when the writer was not sized beforehand.
+        if skipped_len > 0 {
+            writer
+                .seek(SeekFrom::Current(skipped_len - page_size as i64))
+                .and_then(|_| writer.write_all(&vec![0u8; page_size]))
+                .map_err(|err| Error::WriteMemory(GuestMemoryError::IOError(err)))?;
+        }
+
+        Ok(checksum)
     }
 
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
+    /// Returns the checksum of the dumped pages.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
-    ) -> std::result::Result<(), Error> {
+    ) -> std::result::Result<u64, Error> {
         let page_size = sysconf::page::pagesize();
+        let mut page = vec![0u8; page_size];
+        let mut checksum = 0;
         let mut writer_offset = 0;
 
         self.with_regions_mut(|slot, region| {
@@ -132,6 +306,8 @@ impl SnapshotMemory for GuestMemoryMmap {
                     let page_offset = ((i * 64) + j) * page_size;
                     let is_firecracker_page_dirty = firecracker_bitmap.is_addr_set(page_offset);
                     if is_kvm_page_dirty || is_firecracker_page_dirty {
+                        region.read_slice(&mut page, MemoryRegionAddress(page_offset as u64))?;
+                        checksum ^= page_checksum(writer_offset + page_offset as u64, &page);
                         // We are at the start of a new batch of dirty pages.
                         if write_size == 0 {
                             // Seek forward over the unmodified pages.
@@ -162,16 +338,64 @@ impl SnapshotMemory for GuestMemoryMmap {
 
             Ok(())
         })
-        .map_err(Error::WriteMemory)
+        .map_err(Error::WriteMemory)?;
+
+        Ok(checksum)
     }
 
-    /// Creates a GuestMemoryMmap given a `file` containing the data
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed memory file format.
+    /// Returns the checksum of the dumped pages.
+    fn dump_compressed<T: std::io::Write>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<u64, Error> {
+        let mut compressed_writer =
+            CompressedMemoryWriter::new(writer, compressed_memory::CHUNK_SIZE);
+        let checksum = dump_all_pages(self, &mut compressed_writer)?;
+        compressed_writer
+            .finish()
+            .map_err(|err| Error::WriteMemory(GuestMemoryError::IOError(err)))?;
+
+        Ok(checksum)
+    }
+
+    /// Dumps all contents of GuestMemoryMmap to a writer, encrypted with `key`.
+    /// Returns the checksum of the dumped pages.
+    fn dump_encrypted<T: std::io::Write>(
+        &self,
+        writer: &mut T,
+        key: &EncryptionKey,
+    ) -> std::result::Result<u64, Error> {
+        let mut encrypted_writer = EncryptedWriter::new(writer, key, encrypted_file::CHUNK_SIZE)
+            .map_err(Error::Encryption)?;
+        let checksum = dump_all_pages(self, &mut encrypted_writer)?;
+        encrypted_writer.finish().map_err(Error::Encryption)?;
+
+        Ok(checksum)
+    }
+
+    /// Creates a GuestMemoryMmap given a `file` containing the data, in the given `format`,
     /// and a `state` containing mapping information.
+    ///
+    /// Raw memory files are mapped, while compressed ones are decompressed into
+    /// anonymous memory. Encrypted memory files are loaded through `restore_encrypted`.
     fn restore(
         file: &File,
+        format: MemoryFileFormatState,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error> {
+        match format {
+            MemoryFileFormatState::Raw => (),
+            MemoryFileFormatState::Compressed => {
+                let reader = CompressedMemoryReader::new(file).map_err(Error::CompressedMemory)?;
+                return restore_compressed(&reader, state, track_dirty_pages);
+            }
+            MemoryFileFormatState::Encrypted => {
+                return Err(Error::Encryption(encrypted_file::Error::KeyRequired))
+            }
+        }
+
         let mut mmap_regions = Vec::new();
         for region in state.regions.iter() {
             let mmap_region = MmapRegion::build(
@@ -198,6 +422,344 @@ impl SnapshotMemory for GuestMemoryMmap {
 
         Ok(Self::from_regions(mmap_regions).map_err(Error::CreateMemory)?)
     }
+
+    /// Copies the pages stored in the diff memory `file` over the guest memory.
+    /// The copied pages are not reported as dirty.
+    fn apply_diff_layer(
+        &self,
+        file: &File,
+        state: &GuestMemoryState,
+    ) -> std::result::Result<(), Error> {
+        let mut reader = file;
+        for (offset, len) in data_segments(file).map_err(Error::FileHandle)? {
+            let segment_end = offset + len;
+            for region in state.regions.iter() {
+                let region_end = region.offset + region.size as u64;
+                // Copy the part of the data segment that overlaps with this region.
+                let start = std::cmp::max(offset, region.offset);
+                let end = std::cmp::min(segment_end, region_end);
+                if start >= end {
+                    continue;
+                }
+
+                reader
+                    .seek(SeekFrom::Start(start))
+                    .map_err(Error::FileHandle)?;
+                self.read_exact_from(
+                    GuestAddress(region.base_address + (start - region.offset)),
+                    &mut reader,
+                    (end - start) as usize,
+                )
+                .map_err(Error::ReadMemory)?;
+            }
+        }
+
+        // Loading the layer is part of restoring the memory, not a guest modification.
+        reset_dirty_bitmaps(self);
+
+        Ok(())
+    }
+}
+
+/// Returns whether Firecracker keeps dirty bitmaps for the guest memory, which is only the
+/// case if dirty page tracking was enabled when creating it.
+pub(crate) fn tracks_dirty_pages(guest_memory: &GuestMemoryMmap) -> bool {
+    guest_memory.map_and_fold(
+        true,
+        |(_, region)| region.dirty_bitmap().is_some(),
+        |tracked, region_tracked| tracked && region_tracked,
+    )
+}
+
+/// Marks all the guest pages as clean in the dirty bitmaps kept by Firecracker.
+pub(crate) fn reset_dirty_bitmaps(guest_memory: &GuestMemoryMmap) {
+    let _: std::result::Result<(), ()> = guest_memory.with_regions(|_, region| {
+        if let Some(bitmap) = region.dirty_bitmap() {
+            bitmap.reset();
+        }
+        Ok(())
+    });
+}
+
+/// Creates anonymous guest memory with the layout described by `state`, without
+/// loading any contents. The regions are in the same order as in `state`.
+pub fn anonymous_memory(
+    state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let ranges: Vec<(GuestAddress, usize)> = state
+        .regions
+        .iter()
+        .map(|region| (GuestAddress(region.base_address), region.size))
+        .collect();
+    if track_dirty_pages {
+        GuestMemoryMmap::from_ranges_with_tracking(&ranges)
+    } else {
+        GuestMemoryMmap::from_ranges(&ranges)
+    }
+    .map_err(Error::CreateMemory)
+}
+
+// Writes all the pages of `guest_memory`, in order, and returns their checksum.
+fn dump_all_pages<W: std::io::Write>(
+    guest_memory: &GuestMemoryMmap,
+    writer: &mut W,
+) -> std::result::Result<u64, Error> {
+    let page_size = sysconf::page::pagesize();
+    let mut page = vec![0u8; page_size];
+    let mut checksum = 0;
+    let mut writer_offset = 0;
+
+    guest_memory
+        .with_regions_mut(|_, region| {
+            for page_offset in (0..region.len()).step_by(page_size) {
+                region.read_slice(&mut page, MemoryRegionAddress(page_offset))?;
+                checksum ^= page_checksum(writer_offset + page_offset, &page);
+                writer.write_all(&page).map_err(GuestMemoryError::IOError)?;
+            }
+
+            writer_offset += region.len();
+            Ok(())
+        })
+        .map_err(Error::WriteMemory)?;
+
+    Ok(checksum)
+}
+
+/// Creates a GuestMemoryMmap from the encrypted memory `file`, decrypted with `key` into
+/// anonymous memory, and a `state` containing mapping information.
+pub fn restore_encrypted(
+    file: &File,
+    key: &EncryptionKey,
+    state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let mut reader = EncryptedReader::new(file, key).map_err(Error::Encryption)?;
+    let memory_size: u64 = state.regions.iter().map(|region| region.size as u64).sum();
+    if reader.plaintext_len() != memory_size {
+        return Err(Error::Encryption(encrypted_file::Error::InvalidFile));
+    }
+    let guest_memory = anonymous_memory(state, track_dirty_pages)?;
+
+    // Decrypt one chunk worth of memory at a time.
+    let mut buf = vec![0u8; encrypted_file::CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < reader.plaintext_len() {
+        let len = std::cmp::min(buf.len() as u64, reader.plaintext_len() - offset) as usize;
+        reader
+            .read_exact(&mut buf[..len])
+            .map_err(Error::Encryption)?;
+        write_at_file_offset(&guest_memory, state, offset, &buf[..len])?;
+        offset += len as u64;
+    }
+    reader.finish().map_err(Error::Encryption)?;
+
+    // Decrypting the memory is not a guest modification.
+    reset_dirty_bitmaps(&guest_memory);
+
+    Ok(guest_memory)
+}
+
+// Decompresses the contents of a compressed memory file into anonymous guest memory.
+fn restore_compressed(
+    reader: &CompressedMemoryReader,
+    state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let guest_memory = anonymous_memory(state, track_dirty_pages)?;
+
+    for chunk in reader.data_chunks() {
+        let (offset, data) = chunk.map_err(Error::CompressedMemory)?;
+        write_at_file_offset(&guest_memory, state, offset, &data)?;
+    }
+
+    // Decompressing the memory is not a guest modification.
+    reset_dirty_bitmaps(&guest_memory);
+
+    Ok(guest_memory)
+}
+
+/// Copies `data`, found at `offset` in a memory file described by `state`, to the guest
+/// memory. The parts of `data` that fall outside the memory regions are ignored.
+pub fn write_at_file_offset(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    offset: u64,
+    data: &[u8],
+) -> std::result::Result<(), Error> {
+    let data_end = offset + data.len() as u64;
+    for region in state.regions.iter() {
+        let region_end = region.offset + region.size as u64;
+        // Copy the part of the data that overlaps with this region.
+        let start = std::cmp::max(offset, region.offset);
+        let end = std::cmp::min(data_end, region_end);
+        if start >= end {
+            continue;
+        }
+
+        guest_memory
+            .write_slice(
+                &data[(start - offset) as usize..(end - offset) as usize],
+                GuestAddress(region.base_address + (start - region.offset)),
+            )
+            .map_err(Error::ReadMemory)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn is_zero_page(page: &[u8]) -> bool {
+    page.iter().all(|&byte| byte == 0)
+}
+
+// Checksum contribution of the page found at `offset` in a memory file. All-zero pages don't
+// contribute, so the checksum of a memory file is not influenced by its holes.
+pub(crate) fn page_checksum(offset: u64, page: &[u8]) -> u64 {
+    if is_zero_page(page) {
+        return 0;
+    }
+
+    let mut crc_writer = CRC64Writer::new(std::io::sink());
+    // Writing to a sink never fails.
+    let _ = crc_writer.write_all(&offset.to_le_bytes());
+    let _ = crc_writer.write_all(page);
+    crc_writer.checksum()
+}
+
+/// Returns the `(offset, length)` ranges of `file` that contain data, skipping over holes.
+pub fn data_segments(file: &File) -> std::io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut segments = Vec::new();
+    let mut offset: libc::off_t = 0;
+
+    loop {
+        // Safe because the file descriptor is valid and we check the return value.
+        let data_start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if data_start < 0 {
+            let err = std::io::Error::last_os_error();
+            // ENXIO means there is no more data past `offset`.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+
+        // Safe because the file descriptor is valid and we check the return value.
+        let data_end = unsafe { libc::lseek(fd, data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        segments.push((data_start as u64, (data_end - data_start) as u64));
+        offset = data_end;
+    }
+
+    Ok(segments)
+}
+
+/// Computes the checksum of the pages stored in a memory file, in the given `format`, as
+/// recorded in `MemoryLayerState::checksum` when the file was created.
+pub fn memory_file_checksum(
+    file: &File,
+    format: MemoryFileFormatState,
+) -> std::result::Result<u64, Error> {
+    let page_size = sysconf::page::pagesize() as u64;
+    let mut page = vec![0u8; page_size as usize];
+    let mut checksum = 0;
+
+    match format {
+        MemoryFileFormatState::Compressed => {
+            let reader = CompressedMemoryReader::new(file).map_err(Error::CompressedMemory)?;
+            for chunk in reader.data_chunks() {
+                let (offset, data) = chunk.map_err(Error::CompressedMemory)?;
+                for (idx, chunk_page) in data.chunks(page_size as usize).enumerate() {
+                    // A trailing partial page reads as if padded with zeros.
+                    page[..chunk_page.len()].copy_from_slice(chunk_page);
+                    for byte in page[chunk_page.len()..].iter_mut() {
+                        *byte = 0;
+                    }
+                    checksum ^= page_checksum(offset + idx as u64 * page_size, &page);
+                }
+            }
+            return Ok(checksum);
+        }
+        // The pages of encrypted memory files can only be read with their key.
+        MemoryFileFormatState::Encrypted => {
+            return Err(Error::Encryption(encrypted_file::Error::KeyRequired))
+        }
+        MemoryFileFormatState::Raw => (),
+    }
+
+    let mut reader = file;
+    // Data segments are not necessarily page aligned, so we keep track of the first page
+    // that wasn't yet accounted for.
+    let mut next_page = 0;
+
+    let file_len = file.metadata().map_err(Error::FileHandle)?.len();
+    for (offset, len) in data_segments(file).map_err(Error::FileHandle)? {
+        let first_page = std::cmp::max(next_page, offset - offset % page_size);
+        let segment_end = offset + len;
+
+        for page_offset in (first_page..segment_end).step_by(page_size as usize) {
+            // A trailing partial page reads as if padded with zeros.
+            let page_len = std::cmp::min(page_size, file_len - page_offset) as usize;
+            for byte in page[page_len..].iter_mut() {
+                *byte = 0;
+            }
+
+            reader
+                .seek(SeekFrom::Start(page_offset))
+                .map_err(Error::FileHandle)?;
+            reader
+                .read_exact(&mut page[..page_len])
+                .map_err(Error::FileHandle)?;
+            checksum ^= page_checksum(page_offset, &page);
+            next_page = page_offset + page_size;
+        }
+    }
+
+    Ok(checksum)
+}
+
+/// Checks that the memory `files`, base first, match the most recent entries of `layers`,
+/// which must form a chain of parent links.
+///
+/// The contents of the files are only checked against the checksums of their layers if
+/// `verify_checksums` is set, since it reads every page of them. The diff memory files are
+/// then always checked. The base memory file is only checked when `files` covers the whole
+/// chain, since it may otherwise be the result of squashing the older layers together.
+/// Encrypted memory files are not checked, their contents being authenticated when they are
+/// decrypted.
+pub fn validate_memory_layers(
+    layers: &[MemoryLayerState],
+    files: &[File],
+    verify_checksums: bool,
+) -> std::result::Result<(), Error> {
+    if files.is_empty() || files.len() > layers.len() {
+        return Err(Error::LayerCount(layers.len(), files.len()));
+    }
+
+    // The recorded layers must form a chain on their own.
+    for (idx, layer) in layers.iter().enumerate() {
+        let expected_parent = idx.checked_sub(1).map(|parent_idx| layers[parent_idx].id);
+        if layer.parent_id != expected_parent {
+            return Err(Error::LayerMismatch(idx));
+        }
+    }
+    if !verify_checksums {
+        return Ok(());
+    }
+
+    let first_layer = layers.len() - files.len();
+    for (idx, (file, layer)) in files.iter().zip(&layers[first_layer..]).enumerate() {
+        if (idx == 0 && first_layer != 0) || layer.format == MemoryFileFormatState::Encrypted {
+            continue;
+        }
+        if memory_file_checksum(file, layer.format)? != layer.checksum {
+            return Err(Error::LayerMismatch(first_layer + idx));
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -206,6 +768,7 @@ mod tests {
 
     use super::*;
     use std::io::{Read, Seek};
+    use std::os::unix::fs::FileExt;
     use utils::tempfile::TempFile;
     use vm_memory::GuestAddress;
 
@@ -233,6 +796,7 @@ mod tests {
                     offset: page_size as u64,
                 },
             ],
+            layers: Vec::new(),
         };
 
         let actual_memory_state = guest_memory.describe();
@@ -258,6 +822,7 @@ mod tests {
                     offset: page_size as u64 * 3,
                 },
             ],
+            layers: Vec::new(),
         };
 
         let actual_memory_state = guest_memory.describe();
@@ -299,8 +864,13 @@ mod tests {
             let memory_file = TempFile::new().unwrap();
             guest_memory.dump(&mut memory_file.as_file()).unwrap();
 
-            let restored_guest_memory =
-                GuestMemoryMmap::restore(&memory_file.as_file(), &memory_state, false).unwrap();
+            let restored_guest_memory = GuestMemoryMmap::restore(
+                &memory_file.as_file(),
+                MemoryFileFormatState::Raw,
+                &memory_state,
+                false,
+            )
+            .unwrap();
 
             // Check that the region contents are the same.
             let mut actual_region = vec![0u8; page_size * 2];
@@ -333,8 +903,13 @@ mod tests {
                 .unwrap();
 
             // We can restore from this because this is the first dirty dump.
-            let restored_guest_memory =
-                GuestMemoryMmap::restore(&file.as_file(), &memory_state, false).unwrap();
+            let restored_guest_memory = GuestMemoryMmap::restore(
+                &file.as_file(),
+                MemoryFileFormatState::Raw,
+                &memory_state,
+                false,
+            )
+            .unwrap();
 
             // Check that the region contents are the same.
             let mut actual_region = vec![0u8; page_size * 2];
@@ -381,4 +956,430 @@ mod tests {
             assert_eq!(expected_first_region, diff_file_content);
         }
     }
+
+    #[test]
+    fn test_sparse_dump() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of four pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 5), page_size * 4),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let memory_state = guest_memory.describe();
+
+        // Only the second page of the first region and the first page of the second
+        // region hold data.
+        let data = vec![1u8; page_size];
+        guest_memory
+            .write(&data, GuestAddress(page_size as u64))
+            .unwrap();
+        guest_memory
+            .write(&data, GuestAddress(page_size as u64 * 5))
+            .unwrap();
+
+        let memory_file = TempFile::new().unwrap();
+        let checksum = guest_memory.dump(&mut memory_file.as_file()).unwrap();
+
+        // The zero pages are left as holes, except for the trailing one which gives the file
+        // its full size.
+        let file = memory_file.as_file();
+        assert_eq!(file.metadata().unwrap().len(), page_size as u64 * 8);
+        assert_eq!(
+            data_segments(file).unwrap(),
+            vec![
+                (page_size as u64, page_size as u64),
+                (page_size as u64 * 4, page_size as u64),
+                (page_size as u64 * 7, page_size as u64),
+            ]
+        );
+        assert_eq!(
+            memory_file_checksum(file, MemoryFileFormatState::Raw).unwrap(),
+            checksum
+        );
+
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(file, MemoryFileFormatState::Raw, &memory_state, false)
+                .unwrap();
+        let mut expected = vec![0u8; page_size * 4];
+        expected[page_size..page_size * 2].copy_from_slice(&data);
+        let mut actual = vec![0u8; page_size * 4];
+        restored_guest_memory
+            .read(&mut actual.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(expected, actual);
+
+        let mut expected = vec![0u8; page_size * 4];
+        expected[..page_size].copy_from_slice(&data);
+        restored_guest_memory
+            .read(
+                &mut actual.as_mut_slice(),
+                GuestAddress(page_size as u64 * 5),
+            )
+            .unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_compressed_dump() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of four pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 5), page_size * 4),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let memory_state = guest_memory.describe();
+
+        let first_region: Vec<u8> = (0..page_size * 4).map(|idx| (idx % 13) as u8).collect();
+        guest_memory.write(&first_region, GuestAddress(0)).unwrap();
+        let mut second_region = vec![0u8; page_size * 4];
+        second_region[page_size * 3..].copy_from_slice(&vec![3u8; page_size]);
+        guest_memory
+            .write(&second_region, GuestAddress(page_size as u64 * 5))
+            .unwrap();
+
+        let memory_file = TempFile::new().unwrap();
+        let checksum = guest_memory
+            .dump_compressed(&mut memory_file.as_file())
+            .unwrap();
+
+        let file = memory_file.as_file();
+        assert!(file.metadata().unwrap().len() < page_size as u64 * 8);
+        assert_eq!(
+            memory_file_checksum(file, MemoryFileFormatState::Compressed).unwrap(),
+            checksum
+        );
+
+        // The compressed memory file is decompressed when restoring.
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(file, MemoryFileFormatState::Compressed, &memory_state, true)
+                .unwrap();
+        let mut actual = vec![0u8; page_size * 4];
+        restored_guest_memory
+            .read(&mut actual.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(first_region, actual);
+        restored_guest_memory
+            .read(
+                &mut actual.as_mut_slice(),
+                GuestAddress(page_size as u64 * 5),
+            )
+            .unwrap();
+        assert_eq!(second_region, actual);
+
+        // Restoring the memory doesn't dirty it.
+        let _: std::result::Result<(), ()> = restored_guest_memory.with_regions(|_, region| {
+            let bitmap = region.dirty_bitmap().unwrap();
+            for page in 0..4 {
+                assert!(!bitmap.is_bit_set(page));
+            }
+            Ok(())
+        });
+
+        // A raw memory file is not taken for a compressed one, even when the guest memory
+        // ends with the contents of a compressed memory file.
+        let mut compressed = Vec::new();
+        let mut reader = memory_file.as_file();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut compressed).unwrap();
+        assert!(compressed.len() <= page_size * 4);
+        let other_guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let compressed_addr = GuestAddress((page_size * 9 - compressed.len()) as u64);
+        other_guest_memory
+            .write_slice(&compressed, compressed_addr)
+            .unwrap();
+        let raw_file = TempFile::new().unwrap();
+        let raw_checksum = other_guest_memory.dump(&mut raw_file.as_file()).unwrap();
+        assert_eq!(
+            memory_file_checksum(raw_file.as_file(), MemoryFileFormatState::Raw).unwrap(),
+            raw_checksum
+        );
+        let restored_guest_memory = GuestMemoryMmap::restore(
+            raw_file.as_file(),
+            MemoryFileFormatState::Raw,
+            &memory_state,
+            false,
+        )
+        .unwrap();
+        let mut actual = vec![0u8; compressed.len()];
+        restored_guest_memory
+            .read_slice(&mut actual, compressed_addr)
+            .unwrap();
+        assert_eq!(compressed, actual);
+    }
+
+    #[test]
+    fn test_encrypted_dump() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of four pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 5), page_size * 4),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let memory_state = guest_memory.describe();
+
+        let first_region: Vec<u8> = (0..page_size * 4).map(|idx| (idx % 13) as u8).collect();
+        guest_memory.write(&first_region, GuestAddress(0)).unwrap();
+        let second_region = vec![3u8; page_size * 4];
+        guest_memory
+            .write(&second_region, GuestAddress(page_size as u64 * 5))
+            .unwrap();
+
+        let key = EncryptionKey::new([0x5a; encrypted_file::KEY_LEN]);
+        let memory_file = TempFile::new().unwrap();
+        guest_memory
+            .dump_encrypted(&mut memory_file.as_file(), &key)
+            .unwrap();
+        let file = memory_file.as_file();
+
+        // The encrypted memory file can only be restored with its key.
+        let result =
+            GuestMemoryMmap::restore(file, MemoryFileFormatState::Encrypted, &memory_state, false);
+        match result {
+            Err(Error::Encryption(encrypted_file::Error::KeyRequired)) => (),
+            _ => panic!("Expected a KeyRequired error"),
+        }
+        let wrong_key = EncryptionKey::new([0xa5; encrypted_file::KEY_LEN]);
+        match restore_encrypted(file, &wrong_key, &memory_state, false) {
+            Err(Error::Encryption(encrypted_file::Error::Authentication(0))) => (),
+            _ => panic!("Expected an Authentication error"),
+        }
+
+        let restored_guest_memory = restore_encrypted(file, &key, &memory_state, true).unwrap();
+        let mut actual = vec![0u8; page_size * 4];
+        restored_guest_memory
+            .read(&mut actual.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(first_region, actual);
+        restored_guest_memory
+            .read(
+                &mut actual.as_mut_slice(),
+                GuestAddress(page_size as u64 * 5),
+            )
+            .unwrap();
+        assert_eq!(second_region, actual);
+
+        // Restoring the memory doesn't dirty it.
+        let _: std::result::Result<(), ()> = restored_guest_memory.with_regions(|_, region| {
+            let bitmap = region.dirty_bitmap().unwrap();
+            for page in 0..4 {
+                assert!(!bitmap.is_bit_set(page));
+            }
+            Ok(())
+        });
+
+        // The memory file must hold the whole memory described by the state.
+        let smaller_memory = GuestMemoryMmap::from_ranges(&mem_regions[..1]).unwrap();
+        match restore_encrypted(file, &key, &smaller_memory.describe(), false) {
+            Err(Error::Encryption(encrypted_file::Error::InvalidFile)) => (),
+            _ => panic!("Expected an InvalidFile error"),
+        }
+
+        // A raw memory file is not taken for an encrypted one, even when the guest memory
+        // starts with the contents of an encrypted memory file.
+        let mut encrypted = vec![0u8; page_size];
+        file.read_exact_at(&mut encrypted, 0).unwrap();
+        guest_memory
+            .write_slice(&encrypted, GuestAddress(0))
+            .unwrap();
+        let raw_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut raw_file.as_file()).unwrap();
+        let restored_guest_memory = GuestMemoryMmap::restore(
+            raw_file.as_file(),
+            MemoryFileFormatState::Raw,
+            &memory_state,
+            false,
+        )
+        .unwrap();
+        let mut actual = vec![0u8; page_size];
+        restored_guest_memory
+            .read_slice(&mut actual, GuestAddress(0))
+            .unwrap();
+        assert_eq!(encrypted, actual);
+    }
+
+    #[test]
+    fn test_memory_layers() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 2),
+            (GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges_with_tracking(&mem_regions[..]).unwrap();
+        let memory_state = guest_memory.describe();
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0; 1]);
+        dirty_bitmap.insert(1, vec![0; 1]);
+
+        // The base contains 1s in the first region and 2s in the second one.
+        guest_memory
+            .write(&vec![1u8; page_size * 2], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write(
+                &vec![2u8; page_size * 2],
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        let base_file = TempFile::new().unwrap();
+        let base_checksum = guest_memory.dump(&mut base_file.as_file()).unwrap();
+        assert_eq!(
+            memory_file_checksum(base_file.as_file(), MemoryFileFormatState::Raw).unwrap(),
+            base_checksum
+        );
+        // Clear the Firecracker bitmap, the base holds all the pages.
+        guest_memory
+            .dump_dirty(&mut TempFile::new().unwrap().as_file(), &dirty_bitmap)
+            .unwrap();
+
+        // The diff overwrites the last page of the second region with 3s.
+        let threes = vec![3u8; page_size];
+        guest_memory
+            .write(&threes, GuestAddress(page_size as u64 * 4))
+            .unwrap();
+        let diff_file = TempFile::new().unwrap();
+        diff_file.as_file().set_len(page_size as u64 * 4).unwrap();
+        let diff_checksum = guest_memory
+            .dump_dirty(&mut diff_file.as_file(), &dirty_bitmap)
+            .unwrap();
+        assert_eq!(
+            memory_file_checksum(diff_file.as_file(), MemoryFileFormatState::Raw).unwrap(),
+            diff_checksum
+        );
+        assert_ne!(base_checksum, diff_checksum);
+
+        let base_layer =
+            MemoryLayerState::new(None, MemoryFileFormatState::Raw, base_checksum).unwrap();
+        let diff_layer =
+            MemoryLayerState::new(Some(&base_layer), MemoryFileFormatState::Raw, diff_checksum)
+                .unwrap();
+        assert_eq!(diff_layer.parent_id, Some(base_layer.id));
+        let layers = vec![base_layer, diff_layer];
+
+        let base = base_file.as_file().try_clone().unwrap();
+        let diff = diff_file.as_file().try_clone().unwrap();
+        validate_memory_layers(&layers, &[base, diff], true).unwrap();
+
+        // Layers that don't form a chain are refused.
+        let unchained_layers = vec![
+            layers[0].clone(),
+            MemoryLayerState::new(None, MemoryFileFormatState::Raw, 0).unwrap(),
+        ];
+        let base = base_file.as_file().try_clone().unwrap();
+        let diff = diff_file.as_file().try_clone().unwrap();
+        match validate_memory_layers(&unchained_layers, &[base, diff], false) {
+            Err(Error::LayerMismatch(1)) => (),
+            _ => panic!("Expected a layer mismatch."),
+        }
+
+        // A base squashed from older layers is accepted without checking its checksum.
+        let middle_layer =
+            MemoryLayerState::new(Some(&layers[0]), MemoryFileFormatState::Raw, 0).unwrap();
+        let last_layer = MemoryLayerState {
+            parent_id: Some(middle_layer.id),
+            ..layers[1].clone()
+        };
+        let squashed_layers = vec![layers[0].clone(), middle_layer, last_layer];
+        let squashed_file = TempFile::new().unwrap();
+        let squashed = squashed_file.as_file().try_clone().unwrap();
+        let diff = diff_file.as_file().try_clone().unwrap();
+        validate_memory_layers(&squashed_layers, &[squashed, diff], true).unwrap();
+
+        // Layers are reported by their index in the chain, not in the files.
+        let squashed = squashed_file.as_file().try_clone().unwrap();
+        let base = base_file.as_file().try_clone().unwrap();
+        match validate_memory_layers(&squashed_layers, &[squashed, base], true) {
+            Err(Error::LayerMismatch(2)) => (),
+            _ => panic!("Expected a layer mismatch."),
+        }
+
+        // Layers applied in the wrong order are refused.
+        let base = base_file.as_file().try_clone().unwrap();
+        let diff = diff_file.as_file().try_clone().unwrap();
+        match validate_memory_layers(&layers, &[diff, base], true) {
+            Err(Error::LayerMismatch(0)) => (),
+            _ => panic!("Expected a layer mismatch."),
+        }
+        // Their contents are only checked on demand.
+        let base = base_file.as_file().try_clone().unwrap();
+        let diff = diff_file.as_file().try_clone().unwrap();
+        validate_memory_layers(&layers, &[diff, base], false).unwrap();
+
+        // More files than recorded layers are refused.
+        let files: Vec<File> = (0..3)
+            .map(|_| base_file.as_file().try_clone().unwrap())
+            .collect();
+        match validate_memory_layers(&layers, &files, false) {
+            Err(Error::LayerCount(2, 3)) => (),
+            _ => panic!("Expected a layer count mismatch."),
+        }
+
+        // Restoring the chain yields the latest memory contents.
+        let restored_guest_memory = GuestMemoryMmap::restore(
+            base_file.as_file(),
+            MemoryFileFormatState::Raw,
+            &memory_state,
+            true,
+        )
+        .unwrap();
+        restored_guest_memory
+            .apply_diff_layer(diff_file.as_file(), &memory_state)
+            .unwrap();
+
+        let mut actual_region = vec![0u8; page_size * 2];
+        restored_guest_memory
+            .read(&mut actual_region.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(actual_region, vec![1u8; page_size * 2]);
+        restored_guest_memory
+            .read(
+                &mut actual_region.as_mut_slice(),
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        assert_eq!(actual_region, [vec![2u8; page_size], threes].concat());
+
+        // Applying the layer doesn't dirty the guest memory.
+        let _: std::result::Result<(), Error> = restored_guest_memory.with_regions(|_, r| {
+            assert!(!r.dirty_bitmap().unwrap().is_bit_set(0));
+            assert!(!r.dirty_bitmap().unwrap().is_bit_set(1));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_base_file_format() {
+        let base_layer = MemoryLayerState::new(None, MemoryFileFormatState::Compressed, 0).unwrap();
+        let diff_layer =
+            MemoryLayerState::new(Some(&base_layer), MemoryFileFormatState::Raw, 0).unwrap();
+        let mut memory_state = GuestMemoryState {
+            regions: vec![],
+            layers: vec![base_layer],
+        };
+
+        // The base memory file of a whole chain is read in its recorded format.
+        assert_eq!(
+            memory_state.base_file_format(1),
+            MemoryFileFormatState::Compressed
+        );
+        memory_state.layers.push(diff_layer);
+        assert_eq!(
+            memory_state.base_file_format(2),
+            MemoryFileFormatState::Compressed
+        );
+
+        // A base memory file squashed from older layers is raw.
+        assert_eq!(memory_state.base_file_format(1), MemoryFileFormatState::Raw);
+
+        // So are the memory files of snapshots which don't record their layers.
+        memory_state.layers.clear();
+        assert_eq!(memory_state.base_file_format(1), MemoryFileFormatState::Raw);
+    }
 }
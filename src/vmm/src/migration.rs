@@ -0,0 +1,571 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Live migration of a microVM between two Firecracker processes.
+//!
+//! The source streams the guest memory and the versioned `MicrovmState` to the destination,
+//! which builds the microVM from them. The stream is made of:
+//! - the `MIGRATION_MAGIC` value,
+//! - the `GuestMemoryState` describing the guest memory layout,
+//! - memory messages, each carrying data found at an offset of the equivalent memory file,
+//! - a state message, carrying the `MicrovmState`.
+//!
+//! The destination replies with a single byte telling whether it restored the microVM.
+//! Integers are little endian and versioned structures are saved in the snapshot format.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::builder::{self, StartMicrovmError};
+use crate::memory_snapshot::{self, GuestMemoryState, SnapshotMemory};
+use crate::persist::{self, CreateSnapshotError, LoadSnapshotError, MicrovmStateError};
+use crate::precopy;
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+use crate::vmm_config::snapshot::DeviceOverrides;
+use crate::{Error as VmmError, Vmm};
+use polly::event_manager::EventManager;
+use seccomp::BpfProgramRef;
+use snapshot::Snapshot;
+use versionize::{VersionMap, Versionize};
+use vm_memory::GuestMemoryMmap;
+
+const MIGRATION_MAGIC: u64 = 0x4643_4d49_4752_0001;
+const MSG_MEMORY: u8 = 1;
+const MSG_STATE: u8 = 2;
+const ACK_FAILED: u8 = 0;
+const ACK_OK: u8 = 1;
+// Upper bound of the data carried by a memory message.
+const MAX_MEMORY_MESSAGE_LEN: usize = 1 << 20;
+// Upper bound of a serialized versioned structure.
+const MAX_STATE_LEN: usize = 16 << 20;
+
+/// Errors associated with migrating a microVM.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Failed to accept the connection of the source.
+    Accept(io::Error),
+    /// Failed to build the microVM from the received state.
+    BuildMicroVm(StartMicrovmError),
+    /// Failed to connect to the destination.
+    Connect(io::Error),
+    /// Failed to deserialize a versioned structure.
+    DeserializeState(snapshot::Error),
+    /// The destination failed to restore the microVM.
+    DestinationFailed,
+    /// Exactly one of the Unix domain socket path and the TCP address must be set.
+    InvalidEndpoint,
+    /// The stream does not carry a microVM migration.
+    InvalidMagic(u64),
+    /// The stream carries an invalid message.
+    InvalidMessage(String),
+    /// The received microVM state failed the sanity checks.
+    InvalidState(LoadSnapshotError),
+    /// Failed to send or receive migration data.
+    Io(io::Error),
+    /// Failed to listen for the connection of the source.
+    Listen(io::Error),
+    /// Failed to read or write the guest memory.
+    Memory(memory_snapshot::Error),
+    /// Failed to save the state of the paused microVM.
+    MicrovmState(MicrovmStateError),
+    /// Failed to copy the guest memory of the running microVM.
+    Precopy(CreateSnapshotError),
+    /// Failed to resume the microVM after the migration failed.
+    ResumeMicroVm(VmmError),
+    /// Failed to serialize a versioned structure.
+    SerializeState(snapshot::Error),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::MigrationError::*;
+        match self {
+            Accept(err) => write!(f, "Cannot accept the migration connection: {}", err),
+            BuildMicroVm(err) => write!(f, "Cannot build the migrated microVM: {}", err),
+            Connect(err) => write!(f, "Cannot connect to the migration destination: {}", err),
+            DeserializeState(err) => write!(f, "Cannot deserialize migration state: {:?}", err),
+            DestinationFailed => write!(f, "The destination failed to restore the microVM"),
+            InvalidEndpoint => write!(
+                f,
+                "Exactly one of the Unix domain socket path and the TCP address must be set"
+            ),
+            InvalidMagic(magic) => write!(f, "Invalid migration stream magic: {:#x}", magic),
+            InvalidMessage(msg) => write!(f, "Invalid migration message: {}", msg),
+            InvalidState(err) => write!(f, "Invalid migrated microVM state: {}", err),
+            Io(err) => write!(f, "Cannot transfer migration data: {}", err),
+            Listen(err) => write!(f, "Cannot listen for the migration connection: {}", err),
+            Memory(err) => write!(f, "Cannot transfer guest memory: {:?}", err),
+            MicrovmState(err) => write!(f, "Cannot save microvm state: {}", err),
+            Precopy(err) => write!(f, "Cannot copy the memory of the running microVM: {}", err),
+            ResumeMicroVm(err) => write!(f, "Cannot resume microVM: {}", err),
+            SerializeState(err) => write!(f, "Cannot serialize migration state: {:?}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, MigrationError>;
+
+enum Endpoint<'a> {
+    Unix(&'a PathBuf),
+    Tcp(&'a SocketAddr),
+}
+
+fn endpoint<'a>(
+    uds_path: &'a Option<PathBuf>,
+    tcp_address: &'a Option<SocketAddr>,
+) -> Result<Endpoint<'a>> {
+    match (uds_path, tcp_address) {
+        (Some(path), None) => Ok(Endpoint::Unix(path)),
+        (None, Some(address)) => Ok(Endpoint::Tcp(address)),
+        _ => Err(MigrationError::InvalidEndpoint),
+    }
+}
+
+/// Sends the microVM to the Firecracker process listening on the endpoint from `params`.
+///
+/// A running microVM keeps running while most of its memory is copied, and is paused only
+/// for the final copy round. On success, the microVM is left paused. On failure, a microVM
+/// that was running is resumed.
+pub fn send_microvm(
+    vmm: &mut Vmm,
+    params: &SendMigrationParams,
+    version_map: VersionMap,
+) -> Result<()> {
+    use self::MigrationError::Connect;
+    match endpoint(&params.uds_path, &params.tcp_address)? {
+        Endpoint::Unix(path) => {
+            let mut stream = UnixStream::connect(path).map_err(Connect)?;
+            send_to_stream(vmm, &mut stream, version_map)
+        }
+        Endpoint::Tcp(address) => {
+            let mut stream = TcpStream::connect(address).map_err(Connect)?;
+            send_to_stream(vmm, &mut stream, version_map)
+        }
+    }
+}
+
+fn send_to_stream<S: Read + Write>(
+    vmm: &mut Vmm,
+    stream: &mut S,
+    version_map: VersionMap,
+) -> Result<()> {
+    use self::MigrationError::*;
+    let data_version = version_map.latest_version();
+    stream
+        .write_all(&MIGRATION_MAGIC.to_le_bytes())
+        .map_err(Io)?;
+    write_state(
+        stream,
+        &vmm.guest_memory().describe(),
+        &version_map,
+        data_version,
+    )?;
+
+    let mut writer = MemoryStreamWriter::new(&mut *stream);
+    let (mut microvm_state, was_running) = match vmm.save_state() {
+        Ok(microvm_state) => {
+            vmm.guest_memory().dump(&mut writer).map_err(Memory)?;
+            (microvm_state, false)
+        }
+        // The vCPUs of a running microVM refuse to save their state.
+        Err(MicrovmStateError::NotAllowed(_)) => (
+            precopy::precopy_running_microvm(vmm, &mut writer).map_err(Precopy)?,
+            true,
+        ),
+        Err(err) => return Err(MicrovmState(err)),
+    };
+
+    let result = writer.flush().map_err(Io).and_then(|_| {
+        // The destination has no memory files.
+        microvm_state.memory_state.layers.clear();
+        stream.write_all(&[MSG_STATE]).map_err(Io)?;
+        write_state(stream, &microvm_state, &version_map, data_version)?;
+        stream.flush().map_err(Io)?;
+
+        let mut ack = [0u8];
+        stream.read_exact(&mut ack).map_err(Io)?;
+        match ack[0] {
+            ACK_OK => Ok(()),
+            _ => Err(DestinationFailed),
+        }
+    });
+    if result.is_err() && was_running {
+        vmm.resume_vm().map_err(ResumeMicroVm)?;
+    }
+    result
+}
+
+/// Waits for a Firecracker process to connect to the endpoint from `params` and builds the
+/// microVM it sends, producing a 'paused' microVM.
+pub fn receive_microvm(
+    event_manager: &mut EventManager,
+    seccomp_filter: BpfProgramRef,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+) -> Result<Arc<Mutex<Vmm>>> {
+    use self::MigrationError::{Accept, Listen};
+    match endpoint(&params.uds_path, &params.tcp_address)? {
+        Endpoint::Unix(path) => {
+            let listener = UnixListener::bind(path).map_err(Listen)?;
+            let accepted = listener.accept();
+            // Only one source may connect.
+            let _ = std::fs::remove_file(path);
+            let (mut stream, _) = accepted.map_err(Accept)?;
+            receive_from_stream(
+                event_manager,
+                seccomp_filter,
+                &mut stream,
+                params.enable_diff_snapshots,
+                version_map,
+            )
+        }
+        Endpoint::Tcp(address) => {
+            let listener = TcpListener::bind(address).map_err(Listen)?;
+            let (mut stream, _) = listener.accept().map_err(Accept)?;
+            receive_from_stream(
+                event_manager,
+                seccomp_filter,
+                &mut stream,
+                params.enable_diff_snapshots,
+                version_map,
+            )
+        }
+    }
+}
+
+fn receive_from_stream<S: Read + Write>(
+    event_manager: &mut EventManager,
+    seccomp_filter: BpfProgramRef,
+    stream: &mut S,
+    track_dirty_pages: bool,
+    version_map: VersionMap,
+) -> Result<Arc<Mutex<Vmm>>> {
+    use self::MigrationError::*;
+    let result = receive_microvm_state(stream, track_dirty_pages, version_map).and_then(
+        |(microvm_state, guest_memory)| {
+            builder::build_microvm_from_snapshot(
+                event_manager,
+                microvm_state,
+                guest_memory,
+                track_dirty_pages,
+                seccomp_filter,
+                &DeviceOverrides::default(),
+            )
+            .map_err(BuildMicroVm)
+        },
+    );
+
+    // The source resumes its microVM unless told that it was restored here.
+    let ack = if result.is_ok() { ACK_OK } else { ACK_FAILED };
+    let ack_result = stream.write_all(&[ack]).and_then(|_| stream.flush());
+    let vmm = result?;
+    // Without the acknowledgement, the source may resume the microVM as well.
+    ack_result.map_err(Io)?;
+    Ok(vmm)
+}
+
+fn receive_microvm_state<R: Read>(
+    reader: &mut R,
+    track_dirty_pages: bool,
+    version_map: VersionMap,
+) -> Result<(persist::MicrovmState, GuestMemoryMmap)> {
+    use self::MigrationError::*;
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(Io)?;
+    let magic = u64::from_le_bytes(magic);
+    if magic != MIGRATION_MAGIC {
+        return Err(InvalidMagic(magic));
+    }
+
+    let memory_state: GuestMemoryState = read_state(reader, &version_map)?;
+    let guest_memory =
+        memory_snapshot::anonymous_memory(&memory_state, track_dirty_pages).map_err(Memory)?;
+    receive_memory(reader, &guest_memory, &memory_state)?;
+    let microvm_state: persist::MicrovmState = read_state(reader, &version_map)?;
+
+    if microvm_state.memory_state.regions != memory_state.regions {
+        return Err(InvalidMessage(
+            "the microVM state does not match the guest memory layout".to_string(),
+        ));
+    }
+    persist::snapshot_state_sanity_check(&microvm_state).map_err(InvalidState)?;
+    // Receiving the memory is not a guest modification.
+    memory_snapshot::reset_dirty_bitmaps(&guest_memory);
+
+    Ok((microvm_state, guest_memory))
+}
+
+// Copies the data of the memory messages read from `reader` to the guest memory, up to the
+// state message.
+fn receive_memory<R: Read>(
+    reader: &mut R,
+    guest_memory: &GuestMemoryMmap,
+    memory_state: &GuestMemoryState,
+) -> Result<()> {
+    use self::MigrationError::*;
+    let mut data = Vec::new();
+    loop {
+        match read_u8(reader)? {
+            MSG_MEMORY => (),
+            MSG_STATE => return Ok(()),
+            msg => return Err(InvalidMessage(format!("unexpected message type {}", msg))),
+        }
+
+        let offset = read_u64(reader)?;
+        let len = read_u64(reader)? as usize;
+        if len > MAX_MEMORY_MESSAGE_LEN {
+            return Err(InvalidMessage(format!("memory message too large: {}", len)));
+        }
+        data.resize(len, 0);
+        reader.read_exact(&mut data).map_err(Io)?;
+        memory_snapshot::write_at_file_offset(guest_memory, memory_state, offset, &data)
+            .map_err(Memory)?;
+    }
+}
+
+fn write_state<W: Write, O: Versionize>(
+    writer: &mut W,
+    object: &O,
+    version_map: &VersionMap,
+    data_version: u16,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    Snapshot::new(version_map.clone(), data_version)
+        .save(&mut buf, object)
+        .map_err(MigrationError::SerializeState)?;
+    writer
+        .write_all(&(buf.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write_all(&buf))
+        .map_err(MigrationError::Io)
+}
+
+fn read_state<R: Read, O: Versionize>(reader: &mut R, version_map: &VersionMap) -> Result<O> {
+    let len = read_u64(reader)? as usize;
+    if len > MAX_STATE_LEN {
+        return Err(MigrationError::InvalidMessage(format!(
+            "state too large: {}",
+            len
+        )));
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).map_err(MigrationError::Io)?;
+    Snapshot::load(&mut buf.as_slice(), len, version_map.clone())
+        .map_err(MigrationError::DeserializeState)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0u8];
+    reader.read_exact(&mut buf).map_err(MigrationError::Io)?;
+    Ok(buf[0])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf).map_err(MigrationError::Io)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Turns the output of the memory dumps into memory messages, so that a memory file can be
+// written to a migration stream. Contiguous writes are coalesced into a single message,
+// which is sent when the writer seeks elsewhere, fills up, or is flushed.
+struct MemoryStreamWriter<W: Write> {
+    stream: W,
+    position: u64,
+    pending_offset: u64,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> MemoryStreamWriter<W> {
+    fn new(stream: W) -> Self {
+        MemoryStreamWriter {
+            stream,
+            position: 0,
+            pending_offset: 0,
+            pending: Vec::with_capacity(MAX_MEMORY_MESSAGE_LEN),
+        }
+    }
+
+    fn send_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(&[MSG_MEMORY])?;
+        self.stream.write_all(&self.pending_offset.to_le_bytes())?;
+        self.stream
+            .write_all(&(self.pending.len() as u64).to_le_bytes())?;
+        self.stream.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for MemoryStreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.len() == MAX_MEMORY_MESSAGE_LEN {
+            self.send_pending()?;
+        }
+        if self.pending.is_empty() {
+            self.pending_offset = self.position;
+        }
+        let len = std::cmp::min(buf.len(), MAX_MEMORY_MESSAGE_LEN - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()?;
+        self.stream.flush()
+    }
+}
+
+impl<W: Write> Seek for MemoryStreamWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) if offset >= 0 => self.position.checked_add(offset as u64),
+            SeekFrom::Current(offset) => self.position.checked_sub(offset.wrapping_neg() as u64),
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot seek from the end of a migration stream",
+                ))
+            }
+        }
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        if position != self.position {
+            self.send_pending()?;
+            self.position = position;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress};
+
+    #[test]
+    fn test_endpoint() {
+        let path = Some(PathBuf::from("/tmp/migration.sock"));
+        let address = Some("127.0.0.1:4000".parse::<SocketAddr>().unwrap());
+
+        match endpoint(&path, &None) {
+            Ok(Endpoint::Unix(p)) => assert_eq!(p, path.as_ref().unwrap()),
+            _ => panic!("Expected a Unix domain socket endpoint."),
+        }
+        match endpoint(&None, &address) {
+            Ok(Endpoint::Tcp(a)) => assert_eq!(a, address.as_ref().unwrap()),
+            _ => panic!("Expected a TCP endpoint."),
+        }
+        assert!(matches!(
+            endpoint(&path, &address),
+            Err(MigrationError::InvalidEndpoint)
+        ));
+        assert!(matches!(
+            endpoint(&None, &None),
+            Err(MigrationError::InvalidEndpoint)
+        ));
+    }
+
+    #[test]
+    fn test_memory_stream() {
+        let page_size = sysconf::page::pagesize();
+        let mem_regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 8), page_size * 4),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        guest_memory
+            .write_slice(&vec![1u8; page_size], GuestAddress(page_size as u64))
+            .unwrap();
+        guest_memory
+            .write_slice(
+                &vec![2u8; page_size * 2],
+                GuestAddress(page_size as u64 * 9),
+            )
+            .unwrap();
+        let memory_state = guest_memory.describe();
+
+        let mut stream = Vec::new();
+        let mut writer = MemoryStreamWriter::new(&mut stream);
+        guest_memory.dump(&mut writer).unwrap();
+        assert!(writer.seek(SeekFrom::End(0)).is_err());
+        writer.flush().unwrap();
+        stream.push(MSG_STATE);
+
+        let restored_memory = memory_snapshot::anonymous_memory(&memory_state, false).unwrap();
+        receive_memory(&mut stream.as_slice(), &restored_memory, &memory_state).unwrap();
+        for (addr, len) in mem_regions.iter() {
+            let mut expected = vec![0u8; *len];
+            let mut actual = vec![0u8; *len];
+            guest_memory.read_slice(&mut expected, *addr).unwrap();
+            restored_memory.read_slice(&mut actual, *addr).unwrap();
+            assert_eq!(expected, actual);
+        }
+
+        // The stream ends without a state message.
+        stream.pop();
+        assert!(matches!(
+            receive_memory(&mut stream.as_slice(), &restored_memory, &memory_state),
+            Err(MigrationError::Io(_))
+        ));
+
+        let mut invalid_stream = vec![MSG_MEMORY];
+        invalid_stream.extend_from_slice(&0u64.to_le_bytes());
+        invalid_stream.extend_from_slice(&(MAX_MEMORY_MESSAGE_LEN as u64 + 1).to_le_bytes());
+        assert!(matches!(
+            receive_memory(
+                &mut invalid_stream.as_slice(),
+                &restored_memory,
+                &memory_state
+            ),
+            Err(MigrationError::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            receive_memory(&mut [42u8].as_ref(), &restored_memory, &memory_state),
+            Err(MigrationError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_receive_invalid_magic() {
+        let stream = 0u64.to_le_bytes();
+        assert!(matches!(
+            receive_microvm_state(
+                &mut stream.as_ref(),
+                false,
+                crate::version_map::VERSION_MAP.clone()
+            ),
+            Err(MigrationError::InvalidMagic(0))
+        ));
+    }
+
+    #[test]
+    fn test_error_messages() {
+        use std::io;
+        let err = MigrationError::Connect(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::DestinationFailed;
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::InvalidEndpoint;
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::InvalidMagic(0);
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::InvalidMessage(String::new());
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::Io(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::Listen(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+        let err = MigrationError::SerializeState(snapshot::Error::InvalidSnapshotSize);
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -3,22 +3,33 @@
 
 //! Defines state structures for saving/restoring a Firecracker microVM.
 
+use std::collections::BTreeSet;
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::os::raw::c_void;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use crate::builder::{self, StartMicrovmError};
 use crate::device_manager::persist::Error as DevicePersistError;
+use crate::encrypted_file::{self, EncryptedReader, EncryptedWriter, EncryptionKey};
 use crate::mem_size_mib;
+use crate::precopy::Precopy;
 use crate::vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, MemoryFileFormat, SnapshotEncryptionConfig,
+    SnapshotType,
+};
 use crate::vstate::{self, vcpu::VcpuState, vm::VmState};
 
 use crate::device_manager::persist::DeviceStates;
 use crate::memory_snapshot;
-use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::memory_snapshot::{
+    GuestMemoryState, MemoryFileFormatState, MemoryLayerState, SnapshotMemory,
+};
 use crate::version_map::FC_VERSION_TO_SNAP_VERSION;
 use crate::{Error as VmmError, Vmm};
 #[cfg(target_arch = "x86_64")]
@@ -27,17 +38,20 @@ use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
 use logger::{error, info};
 use polly::event_manager::EventManager;
 use seccomp::BpfProgramRef;
+use serde::{Deserialize, Serialize};
 use snapshot::Snapshot;
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::uffd::Uffd;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 
 const FC_V0_23_SNAP_VERSION: u16 = 1;
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
 
 /// Holds information related to the VM that is not part of VmState.
-#[derive(Debug, PartialEq, Versionize)]
+#[derive(Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VmInfo {
     /// Guest memory size.
@@ -45,7 +59,7 @@ pub struct VmInfo {
 }
 
 /// Contains the necesary state for saving/restoring a microVM.
-#[derive(Versionize)]
+#[derive(Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MicrovmState {
     /// Miscellaneous VM info.
@@ -60,6 +74,29 @@ pub struct MicrovmState {
     pub device_states: DeviceStates,
 }
 
+/// Describes to a page fault handler where a guest memory region is mapped and where its
+/// contents are found in the memory file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GuestRegionUffdMapping {
+    /// Host virtual address where the region is mapped.
+    pub base_host_virt_addr: u64,
+    /// Region size, in bytes.
+    pub size: usize,
+    /// Offset in the memory file where the region contents are saved.
+    pub offset: u64,
+}
+
+/// Message sent over the page fault handler socket, together with the userfaultfd.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UffdMemoryLayout {
+    /// Path to the memory file the guest memory is populated from.
+    pub mem_file_path: PathBuf,
+    /// Format of the memory file.
+    pub mem_file_format: MemoryFileFormatState,
+    /// The guest memory regions registered with the userfaultfd.
+    pub regions: Vec<GuestRegionUffdMapping>,
+}
+
 /// Errors related to saving and restoring Microvm state.
 #[derive(Debug)]
 pub enum MicrovmStateError {
@@ -105,6 +142,10 @@ impl Display for MicrovmStateError {
 pub enum CreateSnapshotError {
     /// Failed to get dirty bitmap.
     DirtyBitmap,
+    /// The memory of a running microVM can only be copied with dirty page tracking enabled.
+    DirtyPageTrackingDisabled,
+    /// Failed to get the encryption key or to encrypt the microVM state.
+    Encryption(encrypted_file::Error),
     /// Failed to translate microVM version to snapshot data version.
     InvalidVersion,
     /// Failed to save VM state.
@@ -117,6 +158,10 @@ pub enum CreateSnapshotError {
     MemoryFileFlush(io::Error),
     /// Failed to save MicrovmState.
     MicrovmState(MicrovmStateError),
+    /// Failed to pause the microVM at the end of a live snapshot.
+    PauseMicroVm(VmmError),
+    /// Failed to resume the microVM at the end of a live snapshot.
+    ResumeMicroVm(VmmError),
     /// Failed to serialize microVM state.
     SerializeMicrovmState(snapshot::Error),
     /// Failed to open the snapshot backing file.
@@ -133,6 +178,13 @@ impl Display for CreateSnapshotError {
         use self::CreateSnapshotError::*;
         match self {
             DirtyBitmap => write!(f, "Cannot get dirty bitmap"),
+            DirtyPageTrackingDisabled => write!(
+                f,
+                "Cannot copy the memory of a running microVM without dirty page tracking. \
+                 Enable track_dirty_pages in the machine configuration, or \
+                 enable_diff_snapshots when loading the snapshot"
+            ),
+            Encryption(err) => write!(f, "Cannot encrypt the snapshot: {}", err),
             InvalidVersion => write!(
                 f,
                 "Cannot translate microVM version to snapshot data version"
@@ -142,6 +194,8 @@ impl Display for CreateSnapshotError {
             MemoryBackingFile(err) => write!(f, "Cannot open memory file: {:?}", err),
             MemoryFileFlush(err) => write!(f, "Cannot flush memory file contents: {:?}", err),
             MicrovmState(err) => write!(f, "Cannot save microvm state: {}", err),
+            PauseMicroVm(err) => write!(f, "Cannot pause microVM: {}", err),
+            ResumeMicroVm(err) => write!(f, "Cannot resume microVM: {}", err),
             SerializeMicrovmState(err) => write!(f, "Cannot serialize MicrovmState: {:?}", err),
             SnapshotBackingFile(err) => write!(f, "Cannot open snapshot file: {:?}", err),
             SnapshotFileFlush(err) => write!(f, "Cannot flush snapshot file: {:?}", err),
@@ -165,8 +219,18 @@ pub enum LoadSnapshotError {
     DeserializeMemory(memory_snapshot::Error),
     /// Failed to deserialize microVM state.
     DeserializeMicrovmState(snapshot::Error),
+    /// Failed to decrypt or authenticate a snapshot file.
+    Decryption(encrypted_file::Error),
+    /// Failed to get the encryption key.
+    InvalidEncryptionKey(encrypted_file::Error),
+    /// The memory files do not match the memory layers recorded in the snapshot.
+    InvalidMemoryLayers(memory_snapshot::Error),
     /// Failed to open memory backing file.
     MemoryBackingFile(io::Error),
+    /// Failed to create the userfaultfd or to register the guest memory with it.
+    CreateUffd(io::Error),
+    /// Failed to send the userfaultfd to the page fault handler.
+    UffdHandler(io::Error),
     /// Failed to resume Vm after loading snapshot.
     ResumeMicroVm(VmmError),
     /// Failed to open the snapshot backing file.
@@ -186,7 +250,12 @@ impl Display for LoadSnapshotError {
             BuildMicroVm(err) => write!(f, "Cannot build a microVM from snapshot: {}", err),
             DeserializeMemory(err) => write!(f, "Cannot deserialize memory: {}", err),
             DeserializeMicrovmState(err) => write!(f, "Cannot deserialize MicrovmState: {:?}", err),
+            Decryption(err) => write!(f, "Cannot decrypt snapshot file: {}", err),
+            InvalidEncryptionKey(err) => write!(f, "Cannot get encryption key: {}", err),
+            InvalidMemoryLayers(err) => write!(f, "Invalid memory file chain: {}", err),
             MemoryBackingFile(err) => write!(f, "Cannot open memory file: {}", err),
+            CreateUffd(err) => write!(f, "Cannot create userfaultfd: {}", err),
+            UffdHandler(err) => write!(f, "Cannot connect to the page fault handler: {}", err),
             ResumeMicroVm(err) => write!(f, "Failed to resume Vm after loading snapshot: {}", err),
             SnapshotBackingFile(err) => write!(f, "Cannot open snapshot file: {}", err),
             SnapshotBackingFileMetadata(err) => write!(f, "Cannot retrieve file metadata: {}", err),
@@ -202,25 +271,118 @@ pub fn create_snapshot(
     params: &CreateSnapshotParams,
     version_map: VersionMap,
 ) -> std::result::Result<(), CreateSnapshotError> {
-    let microvm_state = vmm
-        .save_state()
-        .map_err(CreateSnapshotError::MicrovmState)?;
-
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    if let Some(mut live_snapshot) = start_snapshot(vmm, params, version_map)? {
+        // There is no event loop to return to between the steps.
+        while !live_snapshot.step(vmm)? {}
+    }
+    Ok(())
+}
 
-    let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, &vmm)?;
+/// Creates a Microvm snapshot, unless a live snapshot of a running microVM is requested. The
+/// latter is only started, and completed by calling `LiveSnapshot::step` until it returns
+/// true, from the event loop.
+pub fn start_snapshot(
+    vmm: &mut Vmm,
+    params: &CreateSnapshotParams,
+    version_map: VersionMap,
+) -> std::result::Result<Option<LiveSnapshot>, CreateSnapshotError> {
+    // Diff snapshots only hold the guest memory written by the vCPUs and the device
+    // models, which KVM tracks.
+    if matches!(params.snapshot_type, SnapshotType::Diff)
+        && vmm.mmio_device_manager.has_vhost_net_devices()
+    {
+        return Err(CreateSnapshotError::MicrovmState(
+            MicrovmStateError::NotAllowed(
+                "Cannot create diff snapshots of network interfaces using vhost-net".to_string(),
+            ),
+        ));
+    }
+    let encryption_key =
+        encryption_key(&params.encryption).map_err(CreateSnapshotError::Encryption)?;
+    let microvm_state = match vmm.save_state() {
+        Ok(microvm_state) => microvm_state,
+        // The vCPUs of a running microVM refuse to save their state.
+        Err(MicrovmStateError::NotAllowed(_)) if params.live => {
+            return LiveSnapshot::new(vmm, params, version_map).map(Some);
+        }
+        Err(err) => return Err(CreateSnapshotError::MicrovmState(err)),
+    };
+    let (mem_file_format, checksum) = snapshot_memory_to_file(
+        vmm,
+        &params.mem_file_path,
+        &params.snapshot_type,
+        &params.mem_file_format,
+        encryption_key.as_ref(),
+    )?;
 
-    snapshot_state_to_file(
-        &microvm_state,
-        &params.snapshot_path,
-        snapshot_data_version,
+    save_snapshot_state(
+        vmm,
+        params,
+        microvm_state,
+        mem_file_format,
+        checksum,
+        encryption_key.as_ref(),
         version_map,
     )?;
+    Ok(None)
+}
+
+// Saves the state of the microVM whose memory file was written in `mem_file_format`, with
+// the given checksum, to the snapshot file.
+fn save_snapshot_state(
+    vmm: &mut Vmm,
+    params: &CreateSnapshotParams,
+    mut microvm_state: MicrovmState,
+    mem_file_format: MemoryFileFormatState,
+    checksum: u64,
+    encryption_key: Option<&EncryptionKey>,
+    version_map: VersionMap,
+) -> std::result::Result<(), CreateSnapshotError> {
+    // A full memory file starts a new chain of layers, while a diff one is applied on top of
+    // the memory file created by the previous snapshot.
+    let memory_layers = match (&params.snapshot_type, vmm.memory_layers.as_ref()) {
+        (SnapshotType::Full, _) => {
+            Some(vec![MemoryLayerState::new(None, mem_file_format, checksum)
+                .map_err(CreateSnapshotError::Memory)?])
+        }
+        (SnapshotType::Diff, Some(layers)) => {
+            let mut layers = layers.clone();
+            layers.push(
+                MemoryLayerState::new(layers.last(), mem_file_format, checksum)
+                    .map_err(CreateSnapshotError::Memory)?,
+            );
+            Some(layers)
+        }
+        // The layers this memory file depends on are unknown.
+        (SnapshotType::Diff, None) => None,
+    };
+    microvm_state.memory_state.layers = memory_layers.clone().unwrap_or_default();
+
+    let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, &vmm)?;
+
+    match encryption_key {
+        Some(key) => encrypted_snapshot_state_to_file(
+            &microvm_state,
+            &params.snapshot_path,
+            snapshot_data_version,
+            version_map,
+            key,
+        )?,
+        None => snapshot_state_to_file(
+            &microvm_state,
+            &params.snapshot_path,
+            snapshot_data_version,
+            version_map,
+        )?,
+    }
+
+    vmm.memory_layers = memory_layers;
 
     Ok(())
 }
 
-fn snapshot_state_to_file(
+/// Saves `microvm_state` to `snapshot_path`, in the `snapshot_data_version` format.
+pub fn snapshot_state_to_file(
     microvm_state: &MicrovmState,
     snapshot_path: &PathBuf,
     snapshot_data_version: u16,
@@ -230,6 +392,7 @@ fn snapshot_state_to_file(
     let mut snapshot_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(snapshot_path)
         .map_err(SnapshotBackingFile)?;
 
@@ -241,11 +404,62 @@ fn snapshot_state_to_file(
     snapshot_file.sync_all().map_err(SnapshotFileFlush)
 }
 
+/// Saves `microvm_state` to `snapshot_path`, in the `snapshot_data_version` format,
+/// encrypted with `key`.
+pub fn encrypted_snapshot_state_to_file(
+    microvm_state: &MicrovmState,
+    snapshot_path: &PathBuf,
+    snapshot_data_version: u16,
+    version_map: VersionMap,
+    key: &EncryptionKey,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let snapshot_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(snapshot_path)
+        .map_err(SnapshotBackingFile)?;
+
+    let mut writer = EncryptedWriter::new(&snapshot_file, key, encrypted_file::CHUNK_SIZE)
+        .map_err(Encryption)?;
+    let mut snapshot = Snapshot::new(version_map, snapshot_data_version);
+    snapshot
+        .save(&mut writer, microvm_state)
+        .map_err(SerializeMicrovmState)?;
+    writer.finish().map_err(Encryption)?;
+    snapshot_file.sync_all().map_err(SnapshotFileFlush)
+}
+
+// Gets the snapshot encryption key described by `config`, if any.
+fn encryption_key(
+    config: &Option<SnapshotEncryptionConfig>,
+) -> std::result::Result<Option<EncryptionKey>, encrypted_file::Error> {
+    match config {
+        None => Ok(None),
+        Some(SnapshotEncryptionConfig {
+            key: Some(key),
+            key_fd: None,
+        }) => EncryptionKey::from_hex(key).map(Some),
+        Some(SnapshotEncryptionConfig {
+            key: None,
+            key_fd: Some(key_fd),
+        }) => EncryptionKey::from_fd(*key_fd).map(Some),
+        Some(_) => Err(encrypted_file::Error::InvalidKey(
+            "exactly one of key and key_fd must be set".to_string(),
+        )),
+    }
+}
+
+// Writes the guest memory to `mem_file_path` and returns the format of the memory file and
+// the checksum of the written pages.
 fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &PathBuf,
     snapshot_type: &SnapshotType,
-) -> std::result::Result<(), CreateSnapshotError> {
+    mem_file_format: &MemoryFileFormat,
+    encryption_key: Option<&EncryptionKey>,
+) -> std::result::Result<(MemoryFileFormatState, u64), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
     let mut file = OpenOptions::new()
         .write(true)
@@ -254,22 +468,103 @@ fn snapshot_memory_to_file(
         .open(mem_file_path)
         .map_err(MemoryBackingFile)?;
 
-    // Set the length of the file to the full size of the memory area.
-    let mem_size_mib = mem_size_mib(vmm.guest_memory());
-    file.set_len((mem_size_mib * 1024 * 1024) as u64)
-        .map_err(MemoryBackingFile)?;
+    // Diff snapshots are always saved in the raw format.
+    let compressed =
+        *snapshot_type == SnapshotType::Full && *mem_file_format == MemoryFileFormat::Compressed;
+
+    // Set the length of a raw file to the full size of the memory area.
+    if !compressed && encryption_key.is_none() {
+        let mem_size_mib = mem_size_mib(vmm.guest_memory());
+        file.set_len((mem_size_mib * 1024 * 1024) as u64)
+            .map_err(MemoryBackingFile)?;
+    }
 
-    match snapshot_type {
-        SnapshotType::Diff => {
+    let checksum = match (snapshot_type, encryption_key) {
+        (SnapshotType::Diff, _) => {
             let dirty_bitmap = vmm.get_dirty_bitmap().map_err(|_| DirtyBitmap)?;
             vmm.guest_memory()
                 .dump_dirty(&mut file, &dirty_bitmap)
                 .map_err(Memory)
         }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
+        (SnapshotType::Full, Some(key)) => vmm
+            .guest_memory()
+            .dump_encrypted(&mut file, key)
+            .map_err(Memory),
+        (SnapshotType::Full, None) if compressed => vmm
+            .guest_memory()
+            .dump_compressed(&mut file)
+            .map_err(Memory),
+        (SnapshotType::Full, None) => vmm.guest_memory().dump(&mut file).map_err(Memory),
     }?;
     file.flush().map_err(MemoryFileFlush)?;
-    file.sync_all().map_err(MemoryFileFlush)
+    file.sync_all().map_err(MemoryFileFlush)?;
+
+    let format = match (encryption_key, compressed) {
+        (Some(_), _) => MemoryFileFormatState::Encrypted,
+        (None, true) => MemoryFileFormatState::Compressed,
+        (None, false) => MemoryFileFormatState::Raw,
+    };
+    Ok((format, checksum))
+}
+
+/// A full snapshot of a running microVM, whose guest memory is copied in steps. The event
+/// loop keeps serving the devices of the microVM between them.
+pub struct LiveSnapshot {
+    params: CreateSnapshotParams,
+    version_map: VersionMap,
+    precopy: Precopy<File>,
+}
+
+impl LiveSnapshot {
+    // Starts copying the guest memory of the running microVM to the memory file.
+    fn new(
+        vmm: &mut Vmm,
+        params: &CreateSnapshotParams,
+        version_map: VersionMap,
+    ) -> std::result::Result<Self, CreateSnapshotError> {
+        use self::CreateSnapshotError::*;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&params.mem_file_path)
+            .map_err(MemoryBackingFile)?;
+        let mem_size_mib = mem_size_mib(vmm.guest_memory());
+        file.set_len((mem_size_mib * 1024 * 1024) as u64)
+            .map_err(MemoryBackingFile)?;
+
+        Ok(LiveSnapshot {
+            params: params.clone(),
+            version_map,
+            precopy: Precopy::new(vmm, file)?,
+        })
+    }
+
+    /// Performs the next step of the snapshot, and returns whether the snapshot is complete.
+    /// The microVM is only paused during the last step, and runs again afterwards.
+    pub fn step(&mut self, vmm: &mut Vmm) -> std::result::Result<bool, CreateSnapshotError> {
+        use self::CreateSnapshotError::*;
+        let microvm_state = match self.precopy.step(vmm)? {
+            Some(microvm_state) => microvm_state,
+            None => return Ok(false),
+        };
+        vmm.resume_vm().map_err(ResumeMicroVm)?;
+
+        let (file, checksum) = self.precopy.writer_and_checksum();
+        file.flush().map_err(MemoryFileFlush)?;
+        file.sync_all().map_err(MemoryFileFlush)?;
+        save_snapshot_state(
+            vmm,
+            &self.params,
+            microvm_state,
+            MemoryFileFormatState::Raw,
+            checksum,
+            None,
+            self.version_map.clone(),
+        )?;
+        Ok(true)
+    }
 }
 
 /// Validate the microVM version and translate it to its corresponding snapshot data format.
@@ -277,6 +572,33 @@ pub fn get_snapshot_data_version(
     version: &Option<String>,
     version_map: &VersionMap,
     _vmm: &Vmm,
+) -> std::result::Result<u16, CreateSnapshotError> {
+    #[cfg(target_arch = "x86_64")]
+    let used_irqs_count = _vmm.mmio_device_manager.used_irqs_count();
+    #[cfg(target_arch = "aarch64")]
+    let used_irqs_count = 0;
+
+    translate_version(version, version_map, used_irqs_count)
+}
+
+/// Validate the microVM version a saved microVM state is translated to and return its
+/// corresponding snapshot data format.
+pub fn get_state_data_version(
+    version: &Option<String>,
+    version_map: &VersionMap,
+    microvm_state: &MicrovmState,
+) -> std::result::Result<u16, CreateSnapshotError> {
+    translate_version(
+        version,
+        version_map,
+        microvm_state.device_states.used_irqs_count(),
+    )
+}
+
+fn translate_version(
+    version: &Option<String>,
+    version_map: &VersionMap,
+    _used_irqs_count: usize,
 ) -> std::result::Result<u16, CreateSnapshotError> {
     use self::CreateSnapshotError::InvalidVersion;
     if version.is_none() {
@@ -285,7 +607,7 @@ pub fn get_snapshot_data_version(
     match FC_VERSION_TO_SNAP_VERSION.get(version.as_ref().unwrap()) {
         #[cfg(target_arch = "x86_64")]
         Some(&FC_V0_23_SNAP_VERSION) => {
-            validate_devices_number(_vmm.mmio_device_manager.used_irqs_count())?;
+            validate_devices_number(_used_irqs_count)?;
             Ok(FC_V0_23_SNAP_VERSION)
         }
         #[cfg(target_arch = "aarch64")]
@@ -295,6 +617,80 @@ pub fn get_snapshot_data_version(
     }
 }
 
+/// Saves `microvm_state` in the `data_version` format and loads it back, returning the
+/// state restored from a snapshot of that data version. Fields the data version does not
+/// implement are reset to their default values.
+pub fn translate_snapshot_state(
+    microvm_state: &MicrovmState,
+    data_version: u16,
+    version_map: VersionMap,
+) -> std::result::Result<MicrovmState, snapshot::Error> {
+    let mut buf = Vec::new();
+    Snapshot::new(version_map.clone(), data_version).save_without_crc(&mut buf, microvm_state)?;
+    Snapshot::unchecked_load(&mut buf.as_slice(), version_map)
+}
+
+/// A value which differs between two microVM states described as JSON.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StateDifference {
+    /// JSON pointer of the value.
+    pub path: String,
+    /// The value in the first state, `null` if missing.
+    pub left: serde_json::Value,
+    /// The value in the second state, `null` if missing.
+    pub right: serde_json::Value,
+}
+
+/// Returns the leaf values which differ between two microVM states described as JSON
+/// (e.g. through the `Serialize` implementation of `MicrovmState`).
+pub fn diff_state_values(
+    left: &serde_json::Value,
+    right: &serde_json::Value,
+) -> Vec<StateDifference> {
+    let mut diffs = Vec::new();
+    diff_values(String::new(), left, right, &mut diffs);
+    diffs
+}
+
+fn diff_values(
+    path: String,
+    left: &serde_json::Value,
+    right: &serde_json::Value,
+    diffs: &mut Vec<StateDifference>,
+) {
+    use serde_json::Value;
+
+    match (left, right) {
+        (Value::Object(left), Value::Object(right)) => {
+            let keys: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+            for key in keys {
+                diff_values(
+                    format!("{}/{}", path, key),
+                    left.get(key).unwrap_or(&Value::Null),
+                    right.get(key).unwrap_or(&Value::Null),
+                    diffs,
+                );
+            }
+        }
+        (Value::Array(left), Value::Array(right)) => {
+            for idx in 0..std::cmp::max(left.len(), right.len()) {
+                diff_values(
+                    format!("{}/{}", path, idx),
+                    left.get(idx).unwrap_or(&Value::Null),
+                    right.get(idx).unwrap_or(&Value::Null),
+                    diffs,
+                );
+            }
+        }
+        _ if left != right => diffs.push(StateDifference {
+            path,
+            left: left.clone(),
+            right: right.clone(),
+        }),
+        _ => (),
+    }
+}
+
 /// Validates that snapshot CPU vendor matches the host CPU vendor.
 #[cfg(target_arch = "x86_64")]
 pub fn validate_x86_64_cpu_vendor(
@@ -361,47 +757,188 @@ pub fn restore_from_snapshot(
 ) -> std::result::Result<Arc<Mutex<Vmm>>, LoadSnapshotError> {
     use self::LoadSnapshotError::*;
     let track_dirty_pages = params.enable_diff_snapshots;
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
+    let encryption_key = encryption_key(&params.encryption).map_err(InvalidEncryptionKey)?;
+    let microvm_state = match encryption_key.as_ref() {
+        Some(key) => snapshot_state_from_encrypted_file(&params.snapshot_path, key, version_map)?,
+        None => snapshot_state_from_file(&params.snapshot_path, version_map)?,
+    };
 
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
 
-    let guest_memory = guest_memory_from_file(
-        &params.mem_file_path,
-        &microvm_state.memory_state,
-        track_dirty_pages,
-    )?;
-    builder::build_microvm_from_snapshot(
+    let (guest_memory, uffd_handler) = match params.uffd_socket_path.as_ref() {
+        Some(uffd_socket_path) => {
+            let (guest_memory, uffd_handler) = guest_memory_from_uffd(
+                &params.mem_file_path,
+                uffd_socket_path,
+                &microvm_state.memory_state,
+                track_dirty_pages,
+            )?;
+            (guest_memory, Some(uffd_handler))
+        }
+        None => (
+            guest_memory_from_files(
+                &params.mem_file_path,
+                &params.mem_file_layers,
+                &microvm_state.memory_state,
+                track_dirty_pages,
+                encryption_key.as_ref(),
+                params.verify_memory_checksums,
+            )?,
+            None,
+        ),
+    };
+    let vmm = builder::build_microvm_from_snapshot(
         event_manager,
         microvm_state,
         guest_memory,
         track_dirty_pages,
         seccomp_filter,
+        &params.device_overrides,
     )
-    .map_err(BuildMicroVm)
+    .map_err(BuildMicroVm)?;
+    vmm.lock().expect("Poisoned lock").uffd_handler = uffd_handler;
+
+    Ok(vmm)
 }
 
-fn snapshot_state_from_file(
+/// Loads the microVM state from the snapshot file at `snapshot_path`.
+pub fn snapshot_state_from_file(
     snapshot_path: &PathBuf,
     version_map: VersionMap,
 ) -> std::result::Result<MicrovmState, LoadSnapshotError> {
     use self::LoadSnapshotError::{
-        DeserializeMicrovmState, SnapshotBackingFile, SnapshotBackingFileMetadata,
+        Decryption, DeserializeMicrovmState, SnapshotBackingFile, SnapshotBackingFileMetadata,
     };
     let mut snapshot_reader = File::open(snapshot_path).map_err(SnapshotBackingFile)?;
+    if encrypted_file::is_encrypted(&snapshot_reader).map_err(SnapshotBackingFile)? {
+        return Err(Decryption(encrypted_file::Error::KeyRequired));
+    }
     let metadata = std::fs::metadata(snapshot_path).map_err(SnapshotBackingFileMetadata)?;
     let snapshot_len = metadata.len() as usize;
     Snapshot::load(&mut snapshot_reader, snapshot_len, version_map).map_err(DeserializeMicrovmState)
 }
 
-fn guest_memory_from_file(
+/// Loads the microVM state from the snapshot file at `snapshot_path`, encrypted with `key`.
+/// Fails with `LoadSnapshotError::Decryption` if the file doesn't pass authentication.
+pub fn snapshot_state_from_encrypted_file(
+    snapshot_path: &PathBuf,
+    key: &EncryptionKey,
+    version_map: VersionMap,
+) -> std::result::Result<MicrovmState, LoadSnapshotError> {
+    use self::LoadSnapshotError::{Decryption, DeserializeMicrovmState, SnapshotBackingFile};
+    let snapshot_file = File::open(snapshot_path).map_err(SnapshotBackingFile)?;
+    let snapshot = EncryptedReader::new(&snapshot_file, key)
+        .and_then(EncryptedReader::read_to_end)
+        .map_err(Decryption)?;
+    Snapshot::load(&mut snapshot.as_slice(), snapshot.len(), version_map)
+        .map_err(DeserializeMicrovmState)
+}
+
+// Memory files which fail decryption are reported as such.
+fn memory_load_error(err: memory_snapshot::Error) -> LoadSnapshotError {
+    match err {
+        memory_snapshot::Error::Encryption(err) => LoadSnapshotError::Decryption(err),
+        err => LoadSnapshotError::DeserializeMemory(err),
+    }
+}
+
+fn guest_memory_from_files(
     mem_file_path: &PathBuf,
+    mem_file_layers: &[PathBuf],
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
+    encryption_key: Option<&EncryptionKey>,
+    verify_checksums: bool,
 ) -> std::result::Result<GuestMemoryMmap, LoadSnapshotError> {
-    use self::LoadSnapshotError::{DeserializeMemory, MemoryBackingFile};
-    let mem_file = File::open(mem_file_path).map_err(MemoryBackingFile)?;
-    GuestMemoryMmap::restore(&mem_file, mem_state, track_dirty_pages).map_err(DeserializeMemory)
+    use self::LoadSnapshotError::{DeserializeMemory, InvalidMemoryLayers, MemoryBackingFile};
+    let mut mem_files = vec![File::open(mem_file_path).map_err(MemoryBackingFile)?];
+    for path in mem_file_layers {
+        mem_files.push(File::open(path).map_err(MemoryBackingFile)?);
+    }
+
+    // Snapshots whose memory layers are unknown, such as those created before the layers were
+    // recorded, can only be loaded from a single memory file, which cannot be validated.
+    if !mem_state.layers.is_empty() || mem_files.len() > 1 {
+        memory_snapshot::validate_memory_layers(&mem_state.layers, &mem_files, verify_checksums)
+            .map_err(InvalidMemoryLayers)?;
+    }
+
+    // Whether the memory file is encrypted is recorded in the snapshot state, not guessed
+    // from its contents.
+    if let (MemoryFileFormatState::Encrypted, Some(key)) =
+        (mem_state.base_file_format(mem_files.len()), encryption_key)
+    {
+        return memory_snapshot::restore_encrypted(
+            &mem_files[0],
+            key,
+            mem_state,
+            track_dirty_pages,
+        )
+        .map_err(memory_load_error);
+    }
+
+    let guest_memory = GuestMemoryMmap::restore(
+        &mem_files[0],
+        mem_state.base_file_format(mem_files.len()),
+        mem_state,
+        track_dirty_pages,
+    )
+    .map_err(memory_load_error)?;
+    for layer_file in &mem_files[1..] {
+        guest_memory
+            .apply_diff_layer(layer_file, mem_state)
+            .map_err(DeserializeMemory)?;
+    }
+    Ok(guest_memory)
+}
+
+// Creates guest memory whose pages are populated on first access by the page fault handler
+// listening on `uffd_socket_path`, from the contents of `mem_file_path`. Also returns the
+// connection to the handler, which lets it know when the microVM goes away.
+fn guest_memory_from_uffd(
+    mem_file_path: &PathBuf,
+    uffd_socket_path: &PathBuf,
+    mem_state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<(GuestMemoryMmap, UnixStream), LoadSnapshotError> {
+    use self::LoadSnapshotError::{CreateUffd, Decryption, DeserializeMemory, UffdHandler};
+    // The page fault handler cannot decrypt the memory file.
+    if mem_state.base_file_format(1) == MemoryFileFormatState::Encrypted {
+        return Err(Decryption(encrypted_file::Error::KeyRequired));
+    }
+    let guest_memory = memory_snapshot::anonymous_memory(mem_state, track_dirty_pages)
+        .map_err(DeserializeMemory)?;
+    let uffd = Uffd::new().map_err(CreateUffd)?;
+
+    let mut regions = Vec::new();
+    for region in mem_state.regions.iter() {
+        // Safe to unwrap because the region was just created at this address.
+        let host_addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address))
+            .unwrap();
+        uffd.register(host_addr as *mut c_void, region.size)
+            .map_err(CreateUffd)?;
+        regions.push(GuestRegionUffdMapping {
+            base_host_virt_addr: host_addr as u64,
+            size: region.size,
+            offset: region.offset,
+        });
+    }
+
+    let layout = UffdMemoryLayout {
+        mem_file_path: mem_file_path.clone(),
+        mem_file_format: mem_state.base_file_format(1),
+        regions,
+    };
+    let body = serde_json::to_vec(&layout).map_err(|e| UffdHandler(e.into()))?;
+    let stream = UnixStream::connect(uffd_socket_path).map_err(UffdHandler)?;
+    stream
+        .send_with_fd(&body[..], uffd.as_raw_fd())
+        .map_err(|e| UffdHandler(io::Error::from_raw_os_error(e.errno())))?;
+
+    // The handler keeps its own copy of the userfaultfd, so ours can be closed.
+    Ok((guest_memory, stream))
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -420,7 +957,7 @@ mod tests {
         default_kernel_cmdline, default_vmm, insert_balloon_device, insert_block_devices,
         insert_net_device, insert_vsock_device, CustomBlockConfig,
     };
-    use crate::memory_snapshot::SnapshotMemory;
+    use crate::memory_snapshot::{GuestMemoryRegionState, SnapshotMemory};
     use crate::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
@@ -429,7 +966,8 @@ mod tests {
 
     use polly::event_manager::EventManager;
     use snapshot::Persist;
-    use utils::{errno, tempfile::TempFile};
+    use std::os::unix::net::UnixListener;
+    use utils::{errno, tempdir::TempDir, tempfile::TempFile};
 
     #[cfg(target_arch = "aarch64")]
     const FC_VERSION_0_23_0: &str = "0.23.0";
@@ -460,6 +998,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost: false,
         };
         insert_net_device(
             &mut vmm,
@@ -554,6 +1094,177 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_translate_snapshot_state() {
+        let vmm = default_vmm_with_devices();
+        let mut memory_state = vmm.guest_memory().describe();
+        memory_state.layers =
+            vec![MemoryLayerState::new(None, MemoryFileFormatState::Raw, 0).unwrap()];
+
+        let microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state,
+            vcpu_states: vec![VcpuState::default()],
+            vm_info: VmInfo { mem_size_mib: 1u64 },
+            #[cfg(target_arch = "aarch64")]
+            vm_state: vmm.vm.save_state(&[1]).unwrap(),
+            #[cfg(target_arch = "x86_64")]
+            vm_state: vmm.vm.save_state().unwrap(),
+        };
+        let original = serde_json::to_value(&microvm_state).unwrap();
+
+        assert_eq!(
+            get_state_data_version(&Some(String::from("0.24.0")), &VERSION_MAP, &microvm_state)
+                .unwrap(),
+            2
+        );
+        assert!(
+            get_state_data_version(&Some(String::from("foo")), &VERSION_MAP, &microvm_state)
+                .is_err()
+        );
+
+        // Nothing is lost when translating to the latest data version.
+        let translated = translate_snapshot_state(
+            &microvm_state,
+            VERSION_MAP.latest_version(),
+            VERSION_MAP.clone(),
+        )
+        .unwrap();
+        assert!(
+            diff_state_values(&original, &serde_json::to_value(&translated).unwrap()).is_empty()
+        );
+
+        // Data version 2 does not implement the chain of memory files.
+        let translated = translate_snapshot_state(&microvm_state, 2, VERSION_MAP.clone()).unwrap();
+        let diffs = diff_state_values(&original, &serde_json::to_value(&translated).unwrap());
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "/memory_state/layers/0");
+        assert_eq!(diffs[0].right, serde_json::Value::Null);
+
+        // Data version 1 does not implement the balloon device.
+        assert!(translate_snapshot_state(&microvm_state, 1, VERSION_MAP.clone()).is_err());
+    }
+
+    #[test]
+    fn test_diff_state_values() {
+        use serde_json::json;
+
+        let left = json!({
+            "vm_info": { "mem_size_mib": 128 },
+            "vcpu_states": [{ "regs": { "rip": 1, "rsp": 2 } }],
+            "device_states": { "vsock_device": null },
+        });
+        assert!(diff_state_values(&left, &left).is_empty());
+
+        let right = json!({
+            "vm_info": { "mem_size_mib": 256 },
+            "vcpu_states": [{ "regs": { "rip": 3, "rsp": 2 } }, { "regs": {} }],
+            "device_states": { "vsock_device": { "cid": 3 } },
+        });
+        assert_eq!(
+            diff_state_values(&left, &right),
+            vec![
+                StateDifference {
+                    path: String::from("/device_states/vsock_device"),
+                    left: json!(null),
+                    right: json!({ "cid": 3 }),
+                },
+                StateDifference {
+                    path: String::from("/vcpu_states/0/regs/rip"),
+                    left: json!(1),
+                    right: json!(3),
+                },
+                StateDifference {
+                    path: String::from("/vcpu_states/1"),
+                    left: json!(null),
+                    right: json!({ "regs": {} }),
+                },
+                StateDifference {
+                    path: String::from("/vm_info/mem_size_mib"),
+                    left: json!(128),
+                    right: json!(256),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        assert!(encryption_key(&None).unwrap().is_none());
+
+        let config = SnapshotEncryptionConfig {
+            key: Some("ab".repeat(encrypted_file::KEY_LEN)),
+            key_fd: None,
+        };
+        assert!(encryption_key(&Some(config.clone())).unwrap().is_some());
+        // The key is kept out of the logs.
+        assert!(!format!("{:?}", config).contains("abab"));
+
+        let invalid_configs = vec![
+            SnapshotEncryptionConfig {
+                key: Some("ab".repeat(encrypted_file::KEY_LEN)),
+                key_fd: Some(0),
+            },
+            SnapshotEncryptionConfig {
+                key: None,
+                key_fd: None,
+            },
+            SnapshotEncryptionConfig {
+                key: Some(String::from("foo")),
+                key_fd: None,
+            },
+        ];
+        for config in invalid_configs {
+            match encryption_key(&Some(config)) {
+                Err(encrypted_file::Error::InvalidKey(_)) => (),
+                _ => panic!("Expected an InvalidKey error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypted_snapshot_state() {
+        let vmm = default_vmm_with_devices();
+        let microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state: vmm.guest_memory().describe(),
+            vcpu_states: vec![VcpuState::default()],
+            vm_info: VmInfo { mem_size_mib: 1u64 },
+            #[cfg(target_arch = "aarch64")]
+            vm_state: vmm.vm.save_state(&[1]).unwrap(),
+            #[cfg(target_arch = "x86_64")]
+            vm_state: vmm.vm.save_state().unwrap(),
+        };
+        let key = EncryptionKey::new([1u8; encrypted_file::KEY_LEN]);
+        let snapshot_file = TempFile::new().unwrap();
+        let snapshot_path = snapshot_file.as_path().to_path_buf();
+        encrypted_snapshot_state_to_file(
+            &microvm_state,
+            &snapshot_path,
+            VERSION_MAP.latest_version(),
+            VERSION_MAP.clone(),
+            &key,
+        )
+        .unwrap();
+
+        match snapshot_state_from_file(&snapshot_path, VERSION_MAP.clone()) {
+            Err(LoadSnapshotError::Decryption(encrypted_file::Error::KeyRequired)) => (),
+            _ => panic!("Expected a KeyRequired error"),
+        }
+        let wrong_key = EncryptionKey::new([2u8; encrypted_file::KEY_LEN]);
+        match snapshot_state_from_encrypted_file(&snapshot_path, &wrong_key, VERSION_MAP.clone()) {
+            Err(LoadSnapshotError::Decryption(encrypted_file::Error::Authentication(0))) => (),
+            _ => panic!("Expected an Authentication error"),
+        }
+
+        let restored_microvm_state =
+            snapshot_state_from_encrypted_file(&snapshot_path, &key, VERSION_MAP.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&restored_microvm_state).unwrap(),
+            serde_json::to_value(&microvm_state).unwrap()
+        );
+    }
+
     #[test]
     fn test_create_snapshot_error_display() {
         use crate::persist::CreateSnapshotError::*;
@@ -562,6 +1273,12 @@ mod tests {
         let err = DirtyBitmap;
         let _ = format!("{}{:?}", err, err);
 
+        let err = DirtyPageTrackingDisabled;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Encryption(encrypted_file::Error::Encrypt);
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersion;
         let _ = format!("{}{:?}", err, err);
 
@@ -582,6 +1299,12 @@ mod tests {
         let err = MicrovmState(MicrovmStateError::UnexpectedVcpuResponse);
         let _ = format!("{}{:?}", err, err);
 
+        let err = PauseMicroVm(VmmError::VcpuPause);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = ResumeMicroVm(VmmError::VcpuResume);
+        let _ = format!("{}{:?}", err, err);
+
         let err = SerializeMicrovmState(snapshot::Error::InvalidMagic(0));
         let _ = format!("{}{:?}", err, err);
 
@@ -598,6 +1321,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_guest_memory_from_uffd() {
+        let mem_state = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 0x2000,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: 0x10000,
+                    size: 0x1000,
+                    offset: 0x2000,
+                },
+            ],
+            layers: Vec::new(),
+        };
+        let tmp_dir = TempDir::new().unwrap();
+        let socket_path = tmp_dir.as_path().join("uffd.sock");
+        let mem_file_path = PathBuf::from("/foo/bar");
+
+        // Nobody is listening on the socket.
+        match guest_memory_from_uffd(&mem_file_path, &socket_path, &mem_state, false) {
+            Err(LoadSnapshotError::UffdHandler(_)) => (),
+            // Unprivileged userfaultfd may be disabled on the host.
+            Err(LoadSnapshotError::CreateUffd(err)) if err.raw_os_error() == Some(libc::EPERM) => {
+                return
+            }
+            _ => panic!("Expected an UffdHandler error"),
+        }
+
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let (guest_memory, _stream) =
+            guest_memory_from_uffd(&mem_file_path, &socket_path, &mem_state, false).unwrap();
+        let (handler_stream, _) = listener.accept().unwrap();
+        let mut buf = vec![0u8; 4096];
+        let (len, uffd) = handler_stream.recv_with_fd(&mut buf).unwrap();
+        assert!(uffd.is_some());
+
+        let layout: UffdMemoryLayout = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(layout.mem_file_path, mem_file_path);
+        assert_eq!(layout.regions.len(), 2);
+        for (mapping, region) in layout.regions.iter().zip(mem_state.regions.iter()) {
+            let host_addr = guest_memory
+                .get_host_address(GuestAddress(region.base_address))
+                .unwrap();
+            assert_eq!(mapping.base_host_virt_addr, host_addr as u64);
+            assert_eq!(mapping.size, region.size);
+            assert_eq!(mapping.offset, region.offset);
+        }
+    }
+
+    #[test]
+    fn test_guest_memory_from_files() {
+        use vm_memory::Bytes;
+
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x10000), 0x1000),
+        ])
+        .unwrap();
+        guest_memory
+            .write(&[1u8; 0x1000], GuestAddress(0x10000))
+            .unwrap();
+        let mem_file = TempFile::new().unwrap();
+        let checksum = guest_memory.dump(&mut mem_file.as_file()).unwrap();
+        let mem_file_path = mem_file.as_path().to_path_buf();
+        let mut mem_state = guest_memory.describe();
+
+        // A memory file with unknown layers is loaded as is.
+        guest_memory_from_files(&mem_file_path, &[], &mem_state, false, None, true).unwrap();
+
+        // A single memory file is checked against its layer.
+        mem_state.layers =
+            vec![MemoryLayerState::new(None, MemoryFileFormatState::Raw, checksum).unwrap()];
+        let restored_memory =
+            guest_memory_from_files(&mem_file_path, &[], &mem_state, false, None, true).unwrap();
+        let mut page = [0u8; 0x1000];
+        restored_memory
+            .read(&mut page, GuestAddress(0x10000))
+            .unwrap();
+        assert_eq!(page, [1u8; 0x1000]);
+
+        mem_state.layers[0].checksum = !checksum;
+        match guest_memory_from_files(&mem_file_path, &[], &mem_state, false, None, true) {
+            Err(LoadSnapshotError::InvalidMemoryLayers(memory_snapshot::Error::LayerMismatch(
+                0,
+            ))) => (),
+            _ => panic!("Expected a layer mismatch"),
+        }
+        // The checksum is only verified on demand.
+        guest_memory_from_files(&mem_file_path, &[], &mem_state, false, None, false).unwrap();
+    }
+
     #[test]
     fn test_load_snapshot_error_display() {
         use crate::persist::LoadSnapshotError::*;
@@ -613,9 +1430,24 @@ mod tests {
         let err = DeserializeMicrovmState(snapshot::Error::Io(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = Decryption(encrypted_file::Error::Authentication(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidEncryptionKey(encrypted_file::Error::InvalidKey(String::new()));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidMemoryLayers(memory_snapshot::Error::LayerMismatch(0));
+        let _ = format!("{}{:?}", err, err);
+
         let err = MemoryBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = CreateUffd(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UffdHandler(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
         let err = SnapshotBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
@@ -0,0 +1,404 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copies the guest memory of a running microVM, for live snapshots and migrations.
+//!
+//! The whole guest memory is copied while the vCPUs run, followed by a few rounds copying
+//! the pages dirtied in the meantime. The microVM is then paused only to copy the pages
+//! dirtied during the last round and to save its state. The rounds are split in steps of
+//! bounded length, so that the event loop keeps serving the devices of the microVM between
+//! them.
+
+use std::io::{Seek, SeekFrom, Write};
+
+use vm_memory::{
+    Bytes, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
+    MemoryRegionAddress,
+};
+
+use crate::memory_snapshot;
+use crate::persist::{CreateSnapshotError, MicrovmState, MicrovmStateError};
+use crate::{DirtyBitmap, Vmm};
+
+// Maximum number of dirty page copy rounds performed while the vCPUs of a microVM run.
+const MAX_DIRTY_ROUNDS: usize = 5;
+// The microVM is paused as soon as a copy round leaves fewer dirty pages than this behind.
+const DIRTY_PAGES_THRESHOLD: usize = 2048;
+// Maximum number of pages read by a step, before returning to the event loop.
+const STEP_PAGES: usize = 4096;
+// Maximum length of the contiguous pages buffered before being written.
+const MAX_PENDING_LEN: usize = 1 << 20;
+
+/// Copy of the guest memory of a running microVM to a writer, at the offsets of the
+/// equivalent raw memory file.
+pub(crate) struct Precopy<W> {
+    copy: MemoryCopy<W>,
+    // Number of dirty page rounds started so far.
+    dirty_rounds: usize,
+    // Number of pages the vCPUs dirtied during the previous round.
+    previous_dirty_pages: usize,
+}
+
+impl<W: Write + Seek> Precopy<W> {
+    /// Starts copying the guest memory of the running microVM to `writer`, which must read
+    /// as zeros where nothing is written.
+    pub fn new(vmm: &mut Vmm, writer: W) -> std::result::Result<Self, CreateSnapshotError> {
+        use self::CreateSnapshotError::*;
+        // The pages written by vhost-net while the vCPUs run are not tracked by KVM.
+        if vmm.mmio_device_manager.has_vhost_net_devices() {
+            return Err(MicrovmState(MicrovmStateError::NotAllowed(
+                "Cannot copy the memory of a running microVM with network interfaces using \
+                 vhost-net"
+                    .to_string(),
+            )));
+        }
+        // The pages written by the device models are only tracked if dirty page tracking was
+        // enabled when the guest memory was created.
+        if !memory_snapshot::tracks_dirty_pages(vmm.guest_memory()) {
+            return Err(DirtyPageTrackingDisabled);
+        }
+
+        // Start from clean dirty bitmaps, so that they record the pages written during the copy.
+        vmm.get_dirty_bitmap().map_err(|_| DirtyBitmap)?;
+        memory_snapshot::reset_dirty_bitmaps(vmm.guest_memory());
+        Ok(Precopy {
+            copy: MemoryCopy::new(writer, vmm.guest_memory()),
+            dirty_rounds: 0,
+            previous_dirty_pages: usize::MAX,
+        })
+    }
+
+    /// Performs the next step of the copy. Once the copy is complete, returns the state of
+    /// the microVM, which is left paused. On failure, the microVM keeps running.
+    pub fn step(
+        &mut self,
+        vmm: &mut Vmm,
+    ) -> std::result::Result<Option<MicrovmState>, CreateSnapshotError> {
+        use self::CreateSnapshotError::*;
+        if !self
+            .copy
+            .copy_pages(vmm.guest_memory(), STEP_PAGES)
+            .map_err(write_memory_error)?
+        {
+            return Ok(None);
+        }
+
+        let dirty_bitmap = vmm.get_dirty_bitmap().map_err(|_| DirtyBitmap)?;
+        let dirty_pages = dirty_page_count(&dirty_bitmap);
+        // Stop once the dirty set is small enough, when it no longer shrinks, or after the last
+        // round.
+        if dirty_pages <= DIRTY_PAGES_THRESHOLD
+            || dirty_pages >= self.previous_dirty_pages
+            || self.dirty_rounds == MAX_DIRTY_ROUNDS
+        {
+            return self.finish(vmm, &dirty_bitmap).map(Some);
+        }
+        self.copy.start_round(Some(dirty_bitmap));
+        self.dirty_rounds += 1;
+        self.previous_dirty_pages = dirty_pages;
+        Ok(None)
+    }
+
+    // Pauses the microVM, copies the pages dirtied since the last round and the ones left
+    // out of it, then saves the state of the microVM.
+    fn finish(
+        &mut self,
+        vmm: &mut Vmm,
+        pending_bitmap: &DirtyBitmap,
+    ) -> std::result::Result<MicrovmState, CreateSnapshotError> {
+        use self::CreateSnapshotError::*;
+        vmm.pause_vm().map_err(PauseMicroVm)?;
+        // The guest memory written by the I/O requests in flight has to be part of the last round.
+        vmm.mmio_device_manager.drain_devices();
+        let result = vmm
+            .get_dirty_bitmap()
+            .map_err(|_| DirtyBitmap)
+            .and_then(|mut dirty_bitmap| {
+                merge_dirty_bitmaps(&mut dirty_bitmap, pending_bitmap);
+                self.copy.start_round(Some(dirty_bitmap));
+                // Nothing runs in the meantime, so the last round is copied at once.
+                self.copy
+                    .copy_pages(vmm.guest_memory(), usize::MAX)
+                    .map_err(write_memory_error)
+            })
+            .and_then(|_| vmm.save_state().map_err(MicrovmState));
+        if result.is_err() {
+            vmm.resume_vm().map_err(ResumeMicroVm)?;
+        }
+        result
+    }
+
+    /// Returns the writer and the checksum of the copied memory, as computed by
+    /// `memory_snapshot::memory_file_checksum` on the equivalent raw memory file.
+    pub fn writer_and_checksum(&mut self) -> (&mut W, u64) {
+        (&mut self.copy.writer, self.copy.checksum)
+    }
+}
+
+/// Copies the guest memory of the running microVM to `writer` without returning to the
+/// event loop in between. On success, the microVM is left paused. On failure, it keeps
+/// running.
+pub(crate) fn precopy_running_microvm<W: Write + Seek>(
+    vmm: &mut Vmm,
+    writer: W,
+) -> std::result::Result<MicrovmState, CreateSnapshotError> {
+    let mut precopy = Precopy::new(vmm, writer)?;
+    loop {
+        if let Some(microvm_state) = precopy.step(vmm)? {
+            return Ok(microvm_state);
+        }
+    }
+}
+
+fn write_memory_error(err: GuestMemoryError) -> CreateSnapshotError {
+    CreateSnapshotError::Memory(memory_snapshot::Error::WriteMemory(err))
+}
+
+fn dirty_page_count(dirty_bitmap: &DirtyBitmap) -> usize {
+    dirty_bitmap
+        .values()
+        .flat_map(|bitmap| bitmap.iter())
+        .map(|word| word.count_ones() as usize)
+        .sum()
+}
+
+// Marks the pages dirty in `other` as dirty in `dirty_bitmap` as well.
+fn merge_dirty_bitmaps(dirty_bitmap: &mut DirtyBitmap, other: &DirtyBitmap) {
+    for (slot, bitmap) in dirty_bitmap.iter_mut() {
+        if let Some(other_bitmap) = other.get(slot) {
+            for (word, other_word) in bitmap.iter_mut().zip(other_bitmap.iter()) {
+                *word |= other_word;
+            }
+        }
+    }
+}
+
+// Rounds copying the pages of the guest memory to a writer, resumable after any page.
+struct MemoryCopy<W> {
+    writer: W,
+    page_size: usize,
+    // Checksum contribution of each page of the memory file, and the checksum they make up.
+    page_checksums: Vec<u64>,
+    checksum: u64,
+    // The KVM dirty bitmap of the pages copied by the current round, which copies every page
+    // when there is none.
+    dirty_bitmap: Option<DirtyBitmap>,
+    // Index, in the memory file, of the next page considered by the current round.
+    next_page: usize,
+    // Contiguous pages waiting to be written, and the memory file offset of the first one.
+    pending: Vec<u8>,
+    pending_offset: u64,
+}
+
+impl<W: Write + Seek> MemoryCopy<W> {
+    fn new(writer: W, guest_memory: &GuestMemoryMmap) -> Self {
+        let page_size = sysconf::page::pagesize();
+        let num_pages = guest_memory.map_and_fold(
+            0,
+            |(_, region)| region.len() as usize / page_size,
+            |pages, region_pages| pages + region_pages,
+        );
+        MemoryCopy {
+            writer,
+            page_size,
+            page_checksums: vec![0; num_pages],
+            checksum: 0,
+            dirty_bitmap: None,
+            next_page: 0,
+            pending: Vec::with_capacity(MAX_PENDING_LEN),
+            pending_offset: 0,
+        }
+    }
+
+    // Starts a round copying the pages marked in `dirty_bitmap`, or every page.
+    fn start_round(&mut self, dirty_bitmap: Option<DirtyBitmap>) {
+        self.dirty_bitmap = dirty_bitmap;
+        self.next_page = 0;
+    }
+
+    // Copies up to `max_pages` pages of the current round. Returns whether the round is
+    // complete.
+    fn copy_pages(
+        &mut self,
+        guest_memory: &GuestMemoryMmap,
+        max_pages: usize,
+    ) -> std::result::Result<bool, GuestMemoryError> {
+        let mut page = vec![0u8; self.page_size];
+        let mut copied_pages = 0;
+        let mut first_page = 0;
+
+        guest_memory.with_regions_mut(|slot, region| {
+            let end_page = first_page + region.len() as usize / self.page_size;
+            while self.next_page < end_page && copied_pages < max_pages {
+                let page_idx = self.next_page;
+                self.next_page += 1;
+                if !self.is_page_dirty(slot, region, page_idx - first_page) {
+                    self.write_pending()?;
+                    continue;
+                }
+
+                let region_offset = (page_idx - first_page) * self.page_size;
+                region.read_slice(&mut page, MemoryRegionAddress(region_offset as u64))?;
+                self.copy_page(page_idx, &page)?;
+                copied_pages += 1;
+            }
+            first_page = end_page;
+            Ok(())
+        })?;
+        self.write_pending()?;
+
+        Ok(self.next_page == self.page_checksums.len())
+    }
+
+    fn is_page_dirty(&self, slot: usize, region: &GuestRegionMmap, region_page: usize) -> bool {
+        let dirty_bitmap = match self.dirty_bitmap.as_ref() {
+            Some(dirty_bitmap) => dirty_bitmap,
+            None => return true,
+        };
+        let kvm_dirty = dirty_bitmap
+            .get(&slot)
+            .and_then(|bitmap| bitmap.get(region_page / 64))
+            .map_or(false, |word| (word >> (region_page % 64)) & 1 != 0);
+        // The pages written by the device models are tracked by Firecracker. That bitmap is
+        // not reset between rounds, since writes racing with a reset would go unnoticed.
+        kvm_dirty
+            || region
+                .dirty_bitmap()
+                .map_or(false, |bitmap| bitmap.is_bit_set(region_page))
+    }
+
+    fn copy_page(
+        &mut self,
+        page_idx: usize,
+        page: &[u8],
+    ) -> std::result::Result<(), GuestMemoryError> {
+        let offset = (page_idx * self.page_size) as u64;
+        let page_checksum = memory_snapshot::page_checksum(offset, page);
+        self.checksum ^= self.page_checksums[page_idx] ^ page_checksum;
+        self.page_checksums[page_idx] = page_checksum;
+
+        // The first round leaves holes in place of the zero pages, while the next ones may
+        // overwrite pages copied before.
+        if self.dirty_bitmap.is_none() && memory_snapshot::is_zero_page(page) {
+            return self.write_pending();
+        }
+        if self.pending.is_empty() {
+            self.pending_offset = offset;
+        }
+        self.pending.extend_from_slice(page);
+        if self.pending.len() >= MAX_PENDING_LEN {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> std::result::Result<(), GuestMemoryError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.writer
+            .seek(SeekFrom::Start(self.pending_offset))
+            .and_then(|_| self.writer.write_all(&self.pending))
+            .map_err(GuestMemoryError::IOError)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_snapshot::{MemoryFileFormatState, SnapshotMemory};
+
+    use utils::tempfile::TempFile;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_dirty_bitmaps() {
+        let mut dirty_bitmap = DirtyBitmap::new();
+        dirty_bitmap.insert(0, vec![0b101, 0]);
+        dirty_bitmap.insert(1, vec![u64::MAX]);
+        assert_eq!(dirty_page_count(&dirty_bitmap), 66);
+
+        let mut other = DirtyBitmap::new();
+        other.insert(0, vec![0b110, 0b1]);
+        merge_dirty_bitmaps(&mut dirty_bitmap, &other);
+        assert_eq!(dirty_bitmap[&0], vec![0b111, 0b1]);
+        assert_eq!(dirty_bitmap[&1], vec![u64::MAX]);
+        assert_eq!(dirty_page_count(&dirty_bitmap), 68);
+    }
+
+    #[test]
+    fn test_memory_copy() {
+        let page_size = sysconf::page::pagesize();
+        let mem_regions = [
+            (GuestAddress(0), page_size * 3),
+            (GuestAddress(page_size as u64 * 4), page_size * 2),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges_with_tracking(&mem_regions).unwrap();
+        assert!(memory_snapshot::tracks_dirty_pages(&guest_memory));
+        assert!(!memory_snapshot::tracks_dirty_pages(
+            &GuestMemoryMmap::from_ranges(&mem_regions).unwrap()
+        ));
+        guest_memory
+            .write_slice(&vec![1u8; page_size], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write_slice(&vec![2u8; page_size], GuestAddress(page_size as u64 * 5))
+            .unwrap();
+        memory_snapshot::reset_dirty_bitmaps(&guest_memory);
+
+        let memory_file = TempFile::new().unwrap();
+        let file = memory_file.as_file();
+        file.set_len(page_size as u64 * 5).unwrap();
+        let mut copy = MemoryCopy::new(file, &guest_memory);
+        assert_eq!(copy.page_checksums.len(), 5);
+
+        // The first round is copied in steps of at most two pages.
+        assert!(!copy.copy_pages(&guest_memory, 2).unwrap());
+        assert!(!copy.copy_pages(&guest_memory, 2).unwrap());
+        assert!(copy.copy_pages(&guest_memory, 2).unwrap());
+        assert_eq!(
+            copy.checksum,
+            memory_snapshot::memory_file_checksum(file, MemoryFileFormatState::Raw).unwrap()
+        );
+
+        // The next rounds copy the dirty pages, including the ones zeroed in the meantime.
+        guest_memory
+            .write_slice(&vec![0u8; page_size], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write_slice(&vec![3u8; page_size], GuestAddress(page_size as u64 * 2))
+            .unwrap();
+        // Only the first region has been written by the vCPUs, the second by a device model.
+        guest_memory
+            .write_slice(&vec![4u8; page_size], GuestAddress(page_size as u64 * 4))
+            .unwrap();
+        let mut dirty_bitmap = DirtyBitmap::new();
+        dirty_bitmap.insert(0, vec![0b101]);
+        dirty_bitmap.insert(1, vec![0]);
+        copy.start_round(Some(dirty_bitmap));
+        assert!(copy.copy_pages(&guest_memory, usize::MAX).unwrap());
+        assert_eq!(
+            copy.checksum,
+            memory_snapshot::memory_file_checksum(file, MemoryFileFormatState::Raw).unwrap()
+        );
+
+        let (file, checksum) = (copy.writer, copy.checksum);
+        let restored = GuestMemoryMmap::restore(
+            file,
+            MemoryFileFormatState::Raw,
+            &guest_memory.describe(),
+            false,
+        )
+        .unwrap();
+        for (addr, value) in vec![(0, 0u8), (1, 0), (2, 3), (4, 4), (5, 2)] {
+            let mut page = vec![0xffu8; page_size];
+            restored
+                .read_slice(&mut page, GuestAddress(page_size as u64 * addr))
+                .unwrap();
+            assert!(page.iter().all(|&byte| byte == value));
+        }
+        assert_ne!(checksum, 0);
+    }
+}
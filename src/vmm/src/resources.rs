@@ -221,6 +221,7 @@ impl VmResources {
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
         self.vm_config.track_dirty_pages = machine_config.track_dirty_pages;
+        self.vm_config.hotplug_slots = machine_config.hotplug_slots;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -359,6 +360,7 @@ mod tests {
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
     use crate::vstate::vcpu::VcpuConfig;
+    use devices::virtio::{CacheType, FileEngineType, ImageFormat};
     use logger::{LevelFilter, LOGGER};
     use utils::net::mac::MacAddr;
     use utils::tempfile::TempFile;
@@ -377,6 +379,8 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost: false,
         }
     }
 
@@ -397,6 +401,14 @@ mod tests {
                 partuuid: Some("0eaa91a0-01".to_string()),
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
+                cache_type: CacheType::Unsafe,
+                io_engine: FileEngineType::Sync,
+                format: ImageFormat::Raw,
+                overlay_path: None,
+                num_queues: 1,
+                vhost_user_socket: None,
+                nbd: None,
+                verity: None,
             },
             tmp_file,
         )
@@ -787,6 +799,7 @@ mod tests {
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
             track_dirty_pages: false,
+            hotplug_slots: 2,
         };
 
         assert_ne!(vm_resources.vm_config, aux_vm_config);
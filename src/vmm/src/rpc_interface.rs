@@ -8,10 +8,12 @@ use std::sync::{Arc, Mutex};
 use super::Error as VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_microvm_for_boot, persist::create_snapshot, persist::restore_from_snapshot,
+    builder::build_microvm_for_boot, migration::receive_microvm, migration::send_microvm,
+    persist::restore_from_snapshot, persist::start_snapshot, persist::LiveSnapshot,
     resources::VmResources, Vmm,
 };
 use crate::builder::StartMicrovmError;
+use crate::migration::MigrationError;
 use crate::persist::{CreateSnapshotError, LoadSnapshotError};
 use crate::version_map::VERSION_MAP;
 use crate::vmm_config::balloon::{
@@ -24,11 +26,15 @@ use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceError,
+    NetworkInterfaceUpdateConfig,
+};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, MemoryFileFormat, SnapshotType,
 };
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use logger::{info, update_metric_with_elapsed_time, METRICS};
@@ -36,8 +42,8 @@ use polly::event_manager::EventManager;
 use seccomp::BpfProgram;
 #[cfg(test)]
 use tests::{
-    build_microvm_for_boot, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
-    MockVmm as Vmm,
+    build_microvm_for_boot, receive_microvm, restore_from_snapshot, send_microvm, start_snapshot,
+    MockLiveSnapshot as LiveSnapshot, MockVmRes as VmResources, MockVmm as Vmm,
 };
 
 /// This enum represents the public interface of the VMM. Each action contains various
@@ -65,7 +71,8 @@ pub enum VmmAction {
     /// Flush the metrics. This action can only be called after the logger has been configured.
     FlushMetrics,
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
-    /// input. This action can only be called before the microVM has booted.
+    /// input. After the microVM has booted, this action hot-plugs a new block device in one of
+    /// the slots reserved at boot time.
     InsertBlockDevice(BlockDeviceConfig),
     /// Add a new network interface config or update one that already exists using the
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
@@ -77,8 +84,18 @@ pub enum VmmAction {
     LoadSnapshot(LoadSnapshotParams),
     /// Pause the guest, by pausing the microVM VCPUs.
     Pause,
+    /// Hot-unplug the block device with the given id, after microVM start.
+    RemoveBlockDevice(String),
+    /// Wait for another Firecracker process to send its microVM, using as input the
+    /// `ReceiveMigrationParams`. This action can only be called before the microVM has booted.
+    /// If this action is successful, the received microVM will be in `Paused` state.
+    ReceiveMigration(ReceiveMigrationParams),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
+    /// Send the microVM to another Firecracker process, using as input the
+    /// `SendMigrationParams`. This action can only be called after the microVM has booted.
+    /// If this action is successful, the microVM is left in `Paused` state.
+    SendMigration(SendMigrationParams),
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
@@ -94,6 +111,11 @@ pub enum VmmAction {
     SetVmConfiguration(VmConfig),
     /// Launch the microVM. This action can only be called before the microVM has booted.
     StartMicroVm,
+    /// Start capturing the frames of a network interface, after microVM start.
+    StartNetworkCapture(NetworkCaptureConfig),
+    /// Stop capturing the frames of the network interface with the given id, after microVM
+    /// start.
+    StopNetworkCapture(String),
     /// Send CTRL+ALT+DEL to the microVM, using the i8042 keyboard function. If an AT-keyboard
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     #[cfg(target_arch = "x86_64")]
@@ -143,6 +165,10 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// Receiving a migrated microVM failed.
+    ReceiveMigration(MigrationError),
+    /// Sending the microVM to another Firecracker process failed.
+    SendMigration(MigrationError),
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
     /// The action `SetVsockDevice` failed because of bad user input.
@@ -181,6 +207,8 @@ impl Display for VmmActionError {
                     "The requested operation is not supported before starting the microVM."
                         .to_string()
                 }
+                ReceiveMigration(err) => format!("Receive microVM migration error: {}", err),
+                SendMigration(err) => format!("Send microVM migration error: {}", err),
                 StartMicrovm(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
@@ -297,6 +325,7 @@ impl<'a> PrebootApiController<'a> {
             InsertBlockDevice(config) => self.insert_block_device(config),
             InsertNetworkDevice(config) => self.insert_net_device(config),
             LoadSnapshot(config) => self.load_snapshot(&config),
+            ReceiveMigration(config) => self.receive_migration(&config),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetVmConfiguration(config) => self.set_vm_config(config),
@@ -307,9 +336,13 @@ impl<'a> PrebootApiController<'a> {
             | FlushMetrics
             | Pause
             | Resume
+            | SendMigration(_)
             | GetBalloonStats
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
+            | RemoveBlockDevice(_)
+            | StartNetworkCapture(_)
+            | StopNetworkCapture(_)
             | UpdateBlockDevice(_)
             | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
@@ -407,6 +440,23 @@ impl<'a> PrebootApiController<'a> {
             return Err(err);
         }
 
+        if load_params.uffd_socket_path.is_some() && !load_params.mem_file_layers.is_empty() {
+            return Err(VmmActionError::NotSupported(
+                "Diff memory files cannot be loaded through a page fault handler.".to_string(),
+            ));
+        }
+
+        // Encrypted snapshots have a single, full memory file, which is decrypted upfront.
+        if load_params.encryption.is_some()
+            && (load_params.uffd_socket_path.is_some() || !load_params.mem_file_layers.is_empty())
+        {
+            return Err(VmmActionError::NotSupported(
+                "Encrypted snapshots cannot be loaded with diff memory files or through a page \
+                 fault handler."
+                    .to_string(),
+            ));
+        }
+
         let result = restore_from_snapshot(
             &mut self.event_manager,
             &self.seccomp_filter,
@@ -433,21 +483,78 @@ impl<'a> PrebootApiController<'a> {
 
         result
     }
+
+    // On success, this command will end the pre-boot stage and this controller
+    // will be replaced by a runtime controller.
+    fn receive_migration(&mut self, receive_params: &ReceiveMigrationParams) -> ActionResult {
+        let receive_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        // Like loading a snapshot, receiving a microVM replaces the boot-specific resources.
+        if self.boot_path {
+            let err = VmmActionError::LoadSnapshotNotAllowed;
+            info!("{}", err);
+            return Err(err);
+        }
+
+        let result = receive_microvm(
+            &mut self.event_manager,
+            &self.seccomp_filter,
+            receive_params,
+            VERSION_MAP.clone(),
+        )
+        .and_then(|vmm| {
+            let ret = if receive_params.resume_vm {
+                vmm.lock().expect("Poisoned lock").resume_vm()
+            } else {
+                Ok(())
+            };
+            ret.map(|()| {
+                self.built_vmm = Some(vmm);
+                VmmData::Empty
+            })
+            .map_err(MigrationError::ResumeMicroVm)
+        })
+        .map_err(VmmActionError::ReceiveMigration);
+
+        let elapsed_time_us = update_metric_with_elapsed_time(
+            &METRICS.latencies_us.vmm_receive_migration,
+            receive_start_us,
+        );
+        info!(
+            "'receive migration' VMM action took {} us.",
+            elapsed_time_us
+        );
+
+        result
+    }
 }
 
 /// Enables RPC interaction with a running Firecracker VMM.
 pub struct RuntimeApiController {
     vmm: Arc<Mutex<Vmm>>,
     vm_resources: VmResources,
+    // The live snapshot being created, and the time it was requested at.
+    live_snapshot: Option<(LiveSnapshot, u64)>,
 }
 
 impl RuntimeApiController {
     /// Handles the incoming runtime `VmmAction` request and provides a response for it.
-    pub fn handle_request(&mut self, request: VmmAction) -> ActionResult {
+    /// The `event_manager` drives the devices hot-plugged by the request.
+    ///
+    /// Returns `None` if the request keeps being processed between the iterations of the
+    /// event loop, in which case `continue_request` is called on the next iterations until
+    /// it returns the response. No other request may be handled in the meantime.
+    pub fn handle_request(
+        &mut self,
+        request: VmmAction,
+        event_manager: &mut EventManager,
+    ) -> Option<ActionResult> {
         use self::VmmAction::*;
-        match request {
+        let response = match request {
             // Supported operations allowed post-boot.
-            CreateSnapshot(snapshot_create_cfg) => self.create_snapshot(&snapshot_create_cfg),
+            CreateSnapshot(snapshot_create_cfg) => {
+                return self.create_snapshot(&snapshot_create_cfg)
+            }
             FlushMetrics => self.flush_metrics(),
             GetBalloonConfig => self
                 .vmm
@@ -466,10 +573,36 @@ impl RuntimeApiController {
             GetVmConfiguration => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
+            InsertBlockDevice(config) => self.hotplug_block_device(config, event_manager),
             Pause => self.pause(),
+            RemoveBlockDevice(drive_id) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .unplug_block_device(&drive_id, event_manager)
+                .map(|()| VmmData::Empty)
+                .map_err(DriveError::DeviceHotplug)
+                .map_err(VmmActionError::DriveConfig),
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
+            SendMigration(send_params) => self.send_migration(&send_params),
+            StartNetworkCapture(capture_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .start_net_capture(&capture_cfg.iface_id, &capture_cfg.capture_path)
+                .map(|()| VmmData::Empty)
+                .map_err(NetworkInterfaceError::DeviceCapture)
+                .map_err(VmmActionError::NetworkConfig),
+            StopNetworkCapture(iface_id) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .stop_net_capture(&iface_id)
+                .map(|()| VmmData::Empty)
+                .map_err(NetworkInterfaceError::DeviceCapture)
+                .map_err(VmmActionError::NetworkConfig),
             UpdateBalloon(balloon_update) => self
                 .vmm
                 .lock()
@@ -491,20 +624,43 @@ impl RuntimeApiController {
             ConfigureBootSource(_)
             | ConfigureLogger(_)
             | ConfigureMetrics(_)
-            | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
             | LoadSnapshot(_)
+            | ReceiveMigration(_)
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
             | SetVmConfiguration(_)
             | StartMicroVm => Err(VmmActionError::OperationNotSupportedPostBoot),
+        };
+        Some(response)
+    }
+
+    /// Performs the next step of the request left pending by `handle_request`. Returns its
+    /// response once it is complete, or `None` while it is still pending.
+    pub fn continue_request(&mut self) -> Option<ActionResult> {
+        let (mut live_snapshot, create_start_us) = self.live_snapshot.take()?;
+        let result = live_snapshot.step(&mut self.vmm.lock().expect("Poisoned lock"));
+        match result {
+            Ok(false) => {
+                self.live_snapshot = Some((live_snapshot, create_start_us));
+                None
+            }
+            Ok(true) => {
+                log_create_snapshot_latency(&SnapshotType::Full, create_start_us);
+                Some(Ok(VmmData::Empty))
+            }
+            Err(err) => Some(Err(VmmActionError::CreateSnapshot(err))),
         }
     }
 
     /// Creates a new `RuntimeApiController`.
     pub fn new(vm_resources: VmResources, vmm: Arc<Mutex<Vmm>>) -> Self {
-        Self { vm_resources, vmm }
+        Self {
+            vm_resources,
+            vmm,
+            live_snapshot: None,
+        }
     }
 
     /// Pauses the microVM by pausing the vCPUs.
@@ -565,7 +721,36 @@ impl RuntimeApiController {
             .map_err(VmmActionError::InternalVmm)
     }
 
-    fn create_snapshot(&mut self, create_params: &CreateSnapshotParams) -> ActionResult {
+    // Creates the snapshot, or starts creating it in the case of a live snapshot.
+    fn create_snapshot(&mut self, create_params: &CreateSnapshotParams) -> Option<ActionResult> {
+        let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        let result = self.check_snapshot_params(create_params).and_then(|()| {
+            start_snapshot(
+                &mut self.vmm.lock().expect("Poisoned lock"),
+                create_params,
+                VERSION_MAP.clone(),
+            )
+            .map_err(VmmActionError::CreateSnapshot)
+        });
+
+        match result {
+            // The guest memory is copied between the iterations of the event loop.
+            Ok(Some(live_snapshot)) => {
+                self.live_snapshot = Some((live_snapshot, create_start_us));
+                None
+            }
+            Ok(None) => {
+                log_create_snapshot_latency(&create_params.snapshot_type, create_start_us);
+                Some(Ok(VmmData::Empty))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    fn check_snapshot_params(
+        &self,
+        create_params: &CreateSnapshotParams,
+    ) -> result::Result<(), VmmActionError> {
         // Diff snapshots are not allowed on uVMs with vsock device.
         if create_params.snapshot_type == SnapshotType::Diff
             && self.vm_resources.vsock.get().is_some()
@@ -575,37 +760,81 @@ impl RuntimeApiController {
             ));
         }
 
-        let mut locked_vmm = self.vmm.lock().unwrap();
-        let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        // Diff snapshots rely on the holes of the raw memory file format.
+        if create_params.snapshot_type == SnapshotType::Diff
+            && create_params.mem_file_format == MemoryFileFormat::Compressed
+        {
+            return Err(VmmActionError::NotSupported(
+                "Diff snapshots cannot use the compressed memory file format.".to_string(),
+            ));
+        }
 
-        create_snapshot(&mut locked_vmm, create_params, VERSION_MAP.clone())
-            .map_err(VmmActionError::CreateSnapshot)?;
+        // Live snapshots rewrite the pages dirtied while copying the guest memory.
+        if create_params.live
+            && (create_params.snapshot_type == SnapshotType::Diff
+                || create_params.mem_file_format == MemoryFileFormat::Compressed)
+        {
+            return Err(VmmActionError::NotSupported(
+                "Live snapshots are only supported for full snapshots in the raw memory file \
+                 format."
+                    .to_string(),
+            ));
+        }
 
-        match create_params.snapshot_type {
-            SnapshotType::Full => {
-                let elapsed_time_us = update_metric_with_elapsed_time(
-                    &METRICS.latencies_us.vmm_full_create_snapshot,
-                    create_start_us,
-                );
-                info!(
-                    "'create full snapshot' VMM action took {} us.",
-                    elapsed_time_us
-                );
-            }
-            SnapshotType::Diff => {
-                let elapsed_time_us = update_metric_with_elapsed_time(
-                    &METRICS.latencies_us.vmm_diff_create_snapshot,
-                    create_start_us,
-                );
-                info!(
-                    "'create diff snapshot' VMM action took {} us.",
-                    elapsed_time_us
-                );
-            }
+        // The encrypted memory file is written in a single pass over the guest memory.
+        if create_params.encryption.is_some()
+            && (create_params.live
+                || create_params.snapshot_type == SnapshotType::Diff
+                || create_params.mem_file_format == MemoryFileFormat::Compressed)
+        {
+            return Err(VmmActionError::NotSupported(
+                "Encrypted snapshots are only supported for full, non-live snapshots in the raw \
+                 memory file format."
+                    .to_string(),
+            ));
         }
+
+        Ok(())
+    }
+
+    fn send_migration(&mut self, send_params: &SendMigrationParams) -> ActionResult {
+        let mut locked_vmm = self.vmm.lock().expect("Poisoned lock");
+        let send_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        send_microvm(&mut locked_vmm, send_params, VERSION_MAP.clone())
+            .map_err(VmmActionError::SendMigration)?;
+
+        let elapsed_time_us = update_metric_with_elapsed_time(
+            &METRICS.latencies_us.vmm_send_migration,
+            send_start_us,
+        );
+        info!("'send migration' VMM action took {} us.", elapsed_time_us);
+
         Ok(VmmData::Empty)
     }
 
+    /// Hot-plugs a new block device in one of the slots reserved at boot time.
+    fn hotplug_block_device(
+        &mut self,
+        cfg: BlockDeviceConfig,
+        event_manager: &mut EventManager,
+    ) -> ActionResult {
+        // The guest mounts its root filesystem at boot time.
+        if cfg.is_root_device {
+            return Err(VmmActionError::DriveConfig(DriveError::HotplugRootDevice));
+        }
+        // The guest memory is only shared with vhost-user backends configured at boot time.
+        if cfg.vhost_user_socket.is_some() {
+            return Err(VmmActionError::DriveConfig(DriveError::VhostUserHotplug));
+        }
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotplug_block_device(cfg, event_manager)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::DriveConfig)
+    }
+
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device,
     ///    update the disk image on the device and its virtio configuration
@@ -618,6 +847,11 @@ impl RuntimeApiController {
                 .map_err(DriveError::DeviceUpdate)
                 .map_err(VmmActionError::DriveConfig)?;
         }
+        if let Some(resize) = new_cfg.resize {
+            vmm.resize_block_device(&new_cfg.drive_id, resize.size_bytes)
+                .map_err(DriveError::DeviceUpdate)
+                .map_err(VmmActionError::DriveConfig)?;
+        }
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
                 &new_cfg.drive_id,
@@ -649,17 +883,45 @@ impl RuntimeApiController {
     }
 }
 
+// Records the time it took to create a snapshot of the given type.
+fn log_create_snapshot_latency(snapshot_type: &SnapshotType, create_start_us: u64) {
+    match snapshot_type {
+        SnapshotType::Full => {
+            let elapsed_time_us = update_metric_with_elapsed_time(
+                &METRICS.latencies_us.vmm_full_create_snapshot,
+                create_start_us,
+            );
+            info!(
+                "'create full snapshot' VMM action took {} us.",
+                elapsed_time_us
+            );
+        }
+        SnapshotType::Diff => {
+            let elapsed_time_us = update_metric_with_elapsed_time(
+                &METRICS.latencies_us.vmm_diff_create_snapshot,
+                create_start_us,
+            );
+            info!(
+                "'create diff snapshot' VMM action took {} us.",
+                elapsed_time_us
+            );
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
+    use crate::vmm_config::drive::BlockDeviceResizeConfig;
     use crate::vmm_config::logger::LoggerLevel;
+    use crate::vmm_config::snapshot::{DeviceOverrides, SnapshotEncryptionConfig};
     use crate::vmm_config::vsock::VsockBuilder;
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
-    use devices::virtio::VsockError;
+    use devices::virtio::{CacheType, FileEngineType, ImageFormat, VsockError};
     use seccomp::BpfProgramRef;
 
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     impl PartialEq for VmmActionError {
         fn eq(&self, other: &VmmActionError) -> bool {
@@ -680,6 +942,8 @@ mod tests {
                 (NotSupported(_), NotSupported(_)) => true,
                 (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot) => true,
                 (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot) => true,
+                (ReceiveMigration(_), ReceiveMigration(_)) => true,
+                (SendMigration(_), SendMigration(_)) => true,
                 (StartMicrovm(_), StartMicrovm(_)) => true,
                 (VsockConfig(_), VsockConfig(_)) => true,
                 _ => false,
@@ -800,7 +1064,12 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub resize_block_device_called: bool,
+        pub hotplug_block_device_called: bool,
+        pub unplug_block_device_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub start_net_capture_called: bool,
+        pub stop_net_capture_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -875,6 +1144,44 @@ mod tests {
             Ok(())
         }
 
+        pub fn resize_block_device(&mut self, _: &str, _: Option<u64>) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.resize_block_device_called = true;
+            Ok(())
+        }
+
+        pub fn hotplug_block_device(
+            &mut self,
+            _: BlockDeviceConfig,
+            _: &mut EventManager,
+        ) -> Result<(), DriveError> {
+            if self.force_errors {
+                return Err(DriveError::DeviceHotplug(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::HotplugSlotsExhausted,
+                )));
+            }
+            self.hotplug_block_device_called = true;
+            Ok(())
+        }
+
+        pub fn unplug_block_device(
+            &mut self,
+            _: &str,
+            _: &mut EventManager,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::NotHotplugged,
+                ));
+            }
+            self.unplug_block_device_called = true;
+            Ok(())
+        }
+
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
@@ -900,6 +1207,26 @@ mod tests {
             self.update_net_rate_limiters_called = true;
             Ok(())
         }
+
+        pub fn start_net_capture(&mut self, _: &str, _: &Path) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::DeviceNotFound,
+                ));
+            }
+            self.start_net_capture_called = true;
+            Ok(())
+        }
+
+        pub fn stop_net_capture(&mut self, _: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::DeviceNotFound,
+                ));
+            }
+            self.stop_net_capture_called = true;
+            Ok(())
+        }
     }
 
     // Need to redefine this since the non-test one uses real VmResources
@@ -914,12 +1241,27 @@ mod tests {
 
     // Need to redefine this since the non-test one uses real Vmm
     // instead of our mocks.
-    pub fn create_snapshot(
+    pub fn start_snapshot(
         _: &mut Vmm,
-        _: &CreateSnapshotParams,
+        create_params: &CreateSnapshotParams,
         _: versionize::VersionMap,
-    ) -> std::result::Result<(), CreateSnapshotError> {
-        Ok(())
+    ) -> std::result::Result<Option<MockLiveSnapshot>, CreateSnapshotError> {
+        if create_params.live {
+            return Ok(Some(MockLiveSnapshot { steps_left: 2 }));
+        }
+        Ok(None)
+    }
+
+    // A live snapshot completed by its second step.
+    pub struct MockLiveSnapshot {
+        steps_left: usize,
+    }
+
+    impl MockLiveSnapshot {
+        pub fn step(&mut self, _: &mut Vmm) -> std::result::Result<bool, CreateSnapshotError> {
+            self.steps_left -= 1;
+            Ok(self.steps_left == 0)
+        }
     }
 
     // Need to redefine this since the non-test one uses real Vmm
@@ -933,6 +1275,27 @@ mod tests {
         Ok(Arc::new(Mutex::new(MockVmm::default())))
     }
 
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn send_microvm(
+        _: &mut Vmm,
+        _: &SendMigrationParams,
+        _: versionize::VersionMap,
+    ) -> std::result::Result<(), MigrationError> {
+        Ok(())
+    }
+
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn receive_microvm(
+        _: &mut EventManager,
+        _: BpfProgramRef,
+        _: &ReceiveMigrationParams,
+        _: versionize::VersionMap,
+    ) -> Result<Arc<Mutex<Vmm>>, MigrationError> {
+        Ok(Arc::new(Mutex::new(MockVmm::default())))
+    }
+
     fn default_preboot<'a>(
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
@@ -1055,6 +1418,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1068,6 +1439,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         });
         check_preboot_request_err(
             req,
@@ -1084,6 +1463,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1097,6 +1478,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost: false,
         });
         check_preboot_request_err(
             req,
@@ -1157,8 +1540,13 @@ mod tests {
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
             mem_file_path: PathBuf::new(),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1170,8 +1558,13 @@ mod tests {
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
             mem_file_path: PathBuf::new(),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: false,
             resume_vm: true,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1180,6 +1573,82 @@ mod tests {
         assert!(vmm.resume_called);
         // Extra sanity check - pause was never called.
         assert!(!vmm.pause_called);
+
+        // Diff memory files cannot be served by a page fault handler.
+        let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_layers: vec![PathBuf::new()],
+            verify_memory_checksums: false,
+            uffd_socket_path: Some(PathBuf::new()),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::NotSupported(
+                "Diff memory files cannot be loaded through a page fault handler.".to_string(),
+            ),
+        );
+
+        // Encrypted memory files cannot be served by a page fault handler.
+        let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: Some(PathBuf::new()),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: Some(SnapshotEncryptionConfig {
+                key: None,
+                key_fd: Some(0),
+            }),
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::NotSupported(
+                "Encrypted snapshots cannot be loaded with diff memory files or through a page \
+                 fault handler."
+                    .to_string(),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_preboot_receive_migration() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr);
+
+        // Without resume.
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            uds_path: Some(PathBuf::new()),
+            tcp_address: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+        });
+        // Request should succeed.
+        preboot.handle_preboot_request(req).unwrap();
+        // Should have built default mock vmm.
+        let vmm = preboot.built_vmm.take().unwrap();
+        assert_eq!(*vmm.lock().unwrap(), MockVmm::default());
+
+        // With resume.
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            uds_path: Some(PathBuf::new()),
+            tcp_address: None,
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        });
+        // Request should succeed.
+        preboot.handle_preboot_request(req).unwrap();
+        let vmm = preboot.built_vmm.as_ref().unwrap().lock().unwrap();
+        // Should have built mock vmm then called resume on it.
+        assert!(vmm.resume_called);
     }
 
     #[test]
@@ -1210,10 +1679,25 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::RemoveBlockDevice(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig::default()),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::StartNetworkCapture(NetworkCaptureConfig {
+                iface_id: String::new(),
+                capture_path: PathBuf::new(),
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::StopNetworkCapture(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
                 iface_id: String::new(),
@@ -1227,10 +1711,20 @@ mod tests {
                 snapshot_type: SnapshotType::Full,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemoryFileFormat::Raw,
+                live: false,
+                encryption: None,
                 version: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::SendMigration(SendMigrationParams {
+                uds_path: Some(PathBuf::new()),
+                tcp_address: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         #[cfg(target_arch = "x86_64")]
         check_preboot_request_err(
             VmmAction::SendCtrlAltDel,
@@ -1289,8 +1783,12 @@ mod tests {
     {
         let vmm = Arc::new(Mutex::new(MockVmm::default()));
         let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm.clone());
-        let res = runtime.handle_request(request);
-        check_success(res, &vmm.lock().unwrap());
+        let mut res = runtime.handle_request(request, &mut EventManager::new().unwrap());
+        // The pending requests are completed by the next iterations of the event loop.
+        while res.is_none() {
+            res = runtime.continue_request();
+        }
+        check_success(res.unwrap(), &vmm.lock().unwrap());
     }
 
     // Forces error and validates error kind against expected.
@@ -1300,7 +1798,10 @@ mod tests {
             ..Default::default()
         }));
         let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm);
-        let err = runtime.handle_request(request).unwrap_err();
+        let err = runtime
+            .handle_request(request, &mut EventManager::new().unwrap())
+            .unwrap()
+            .unwrap_err();
         assert_eq!(err, expected_err);
     }
 
@@ -1447,6 +1948,113 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_resize_block_device() {
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            resize: Some(BlockDeviceResizeConfig {
+                size_bytes: Some(0x1000),
+            }),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.resize_block_device_called);
+            assert!(!vmm.update_block_device_path_called);
+        });
+
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            resize: Some(BlockDeviceResizeConfig::default()),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_hotplug_block_device() {
+        let tmp_file = utils::tempfile::TempFile::new().unwrap();
+        let block_config = || BlockDeviceConfig {
+            path_on_host: tmp_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("scratch"),
+            rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+        };
+
+        let req = VmmAction::InsertBlockDevice(block_config());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotplug_block_device_called);
+        });
+
+        let req = VmmAction::InsertBlockDevice(block_config());
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceHotplug(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::HotplugSlotsExhausted,
+            ))),
+        );
+
+        // The root device cannot be hot-plugged.
+        let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
+            is_root_device: true,
+            ..block_config()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                DriveError::HotplugRootDevice.to_string()
+            );
+            assert!(!vmm.hotplug_block_device_called);
+        });
+
+        // vhost-user drives cannot be hot-plugged.
+        let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
+            path_on_host: String::new(),
+            vhost_user_socket: Some(String::from("vhost-user.sock")),
+            nbd: None,
+            verity: None,
+            ..block_config()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                DriveError::VhostUserHotplug.to_string()
+            );
+            assert!(!vmm.hotplug_block_device_called);
+        });
+    }
+
+    #[test]
+    fn test_runtime_remove_block_device() {
+        let req = VmmAction::RemoveBlockDevice(String::from("scratch"));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.unplug_block_device_called);
+        });
+
+        let req = VmmAction::RemoveBlockDevice(String::from("scratch"));
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceHotplug(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::NotHotplugged,
+            ))),
+        );
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
@@ -1472,6 +2080,185 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_net_capture() {
+        let capture_cfg = NetworkCaptureConfig {
+            iface_id: String::new(),
+            capture_path: PathBuf::new(),
+        };
+        let req = VmmAction::StartNetworkCapture(capture_cfg.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.start_net_capture_called)
+        });
+
+        let req = VmmAction::StartNetworkCapture(capture_cfg);
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceCapture(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::DeviceNotFound),
+            )),
+        );
+
+        let req = VmmAction::StopNetworkCapture(String::new());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.stop_net_capture_called)
+        });
+
+        let req = VmmAction::StopNetworkCapture(String::new());
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceCapture(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::DeviceNotFound),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_create_snapshot() {
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemoryFileFormat::Compressed,
+            live: false,
+            encryption: None,
+            version: None,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemoryFileFormat::Compressed,
+            live: false,
+            encryption: None,
+            version: None,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NotSupported(
+                    "Diff snapshots cannot use the compressed memory file format.".to_string()
+                ))
+            );
+        });
+
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: true,
+            encryption: None,
+            version: None,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+
+        // Live snapshots return to the event loop between their steps.
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm);
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: true,
+            encryption: None,
+            version: None,
+        });
+        assert!(runtime
+            .handle_request(req, &mut EventManager::new().unwrap())
+            .is_none());
+        assert!(runtime.continue_request().is_none());
+        assert_eq!(runtime.continue_request(), Some(Ok(VmmData::Empty)));
+
+        for (snapshot_type, mem_file_format) in vec![
+            (SnapshotType::Diff, MemoryFileFormat::Raw),
+            (SnapshotType::Full, MemoryFileFormat::Compressed),
+        ] {
+            let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+                snapshot_type,
+                snapshot_path: PathBuf::new(),
+                mem_file_path: PathBuf::new(),
+                mem_file_format,
+                live: true,
+                encryption: None,
+                version: None,
+            });
+            check_runtime_request(req, |result, _| {
+                assert_eq!(
+                    result,
+                    Err(VmmActionError::NotSupported(
+                        "Live snapshots are only supported for full snapshots in the raw \
+                         memory file format."
+                            .to_string()
+                    ))
+                );
+            });
+        }
+
+        let encryption = SnapshotEncryptionConfig {
+            key: Some("ab".repeat(32)),
+            key_fd: None,
+        };
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemoryFileFormat::Raw,
+            live: false,
+            encryption: Some(encryption.clone()),
+            version: None,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+
+        for (snapshot_type, mem_file_format, live) in vec![
+            (SnapshotType::Diff, MemoryFileFormat::Raw, false),
+            (SnapshotType::Full, MemoryFileFormat::Compressed, false),
+            (SnapshotType::Full, MemoryFileFormat::Raw, true),
+        ] {
+            let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+                snapshot_type,
+                snapshot_path: PathBuf::new(),
+                mem_file_path: PathBuf::new(),
+                mem_file_format,
+                live,
+                encryption: Some(encryption.clone()),
+                version: None,
+            });
+            check_runtime_request(req, |result, _| {
+                assert_eq!(
+                    result,
+                    Err(VmmActionError::NotSupported(
+                        "Encrypted snapshots are only supported for full, non-live snapshots \
+                         in the raw memory file format."
+                            .to_string()
+                    ))
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn test_runtime_send_migration() {
+        let req = VmmAction::SendMigration(SendMigrationParams {
+            uds_path: None,
+            tcp_address: Some("127.0.0.1:4000".parse().unwrap()),
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+    }
+
     #[test]
     fn test_runtime_disallowed() {
         check_runtime_request_err(
@@ -1493,17 +2280,6 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
-        check_runtime_request_err(
-            VmmAction::InsertBlockDevice(BlockDeviceConfig {
-                path_on_host: String::new(),
-                is_root_device: false,
-                partuuid: None,
-                is_read_only: false,
-                drive_id: String::new(),
-                rate_limiter: None,
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
         check_runtime_request_err(
             VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
                 iface_id: String::new(),
@@ -1512,6 +2288,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: false,
+                num_queue_pairs: 1,
+                vhost: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1547,6 +2325,20 @@ mod tests {
             VmmAction::LoadSnapshot(LoadSnapshotParams {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_layers: Vec::new(),
+                verify_memory_checksums: false,
+                uffd_socket_path: None,
+                enable_diff_snapshots: false,
+                resume_vm: false,
+                device_overrides: DeviceOverrides::default(),
+                encryption: None,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+        check_runtime_request_err(
+            VmmAction::ReceiveMigration(ReceiveMigrationParams {
+                uds_path: Some(PathBuf::new()),
+                tcp_address: None,
                 enable_diff_snapshots: false,
                 resume_vm: false,
             }),
@@ -1565,8 +2357,13 @@ mod tests {
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
             mem_file_path: PathBuf::new(),
+            mem_file_layers: Vec::new(),
+            verify_memory_checksums: false,
+            uffd_socket_path: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            encryption: None,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -1590,6 +2387,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -1600,6 +2405,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -6,6 +6,9 @@
 use std::collections::HashMap;
 
 use crate::device_manager::persist::DeviceStates;
+use crate::memory_snapshot::GuestMemoryState;
+use devices::virtio::block::persist::BlockState;
+use devices::virtio::net::persist::NetState;
 
 use lazy_static::lazy_static;
 use versionize::VersionMap;
@@ -18,6 +21,12 @@ lazy_static! {
         let mut version_map = VersionMap::new();
         version_map.new_version().set_type_version(DeviceStates::type_id(), 2);
         version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 3)
+            .set_type_version(GuestMemoryState::type_id(), 2)
+            .set_type_version(BlockState::type_id(), 2)
+            .set_type_version(NetState::type_id(), 2);
+        version_map
     };
 
     /// Static instance used for creating a 1:1 mapping between Firecracker release version
@@ -26,6 +35,7 @@ lazy_static! {
         let mut mapping = HashMap::new();
         mapping.insert(String::from("0.23.0"), 1);
         mapping.insert(String::from("0.24.0"), 2);
+        mapping.insert(String::from("0.25.0"), 3);
 
         mapping
     };
@@ -11,7 +11,12 @@ use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
-use devices::virtio::Block;
+use devices::virtio::block::{DEFAULT_NUM_QUEUES, MAX_NUM_QUEUES};
+use devices::virtio::vhost_user_blk::Error as VhostUserBlockError;
+use devices::virtio::{
+    Block, BlockFdPool, CacheType, FileEngineType, ImageFormat, NbdConfig, OverlayConfig,
+    VerityConfig, VhostUserBlock,
+};
 
 use serde::Deserialize;
 
@@ -27,14 +32,39 @@ pub enum DriveError {
     CreateBlockDevice(io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Unable to connect to the vhost-user backend, or to negotiate with it.
+    CreateVhostUserBlockDevice(VhostUserBlockError),
+    /// The direct I/O cache type cannot be used with the asynchronous I/O engine.
+    DirectAsyncIo,
+    /// Error during drive hot-plug or hot-unplug.
+    DeviceHotplug(VmmError),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// A root block device cannot be hot-plugged.
+    HotplugRootDevice,
     /// The block device path is invalid.
     InvalidBlockDevicePath,
+    /// The number of request queues is zero or exceeds the maximum.
+    InvalidNumQueues(usize),
+    /// Drives served by an NBD server are raw images without an overlay, accessed
+    /// synchronously through the host page cache.
+    NbdOptions,
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
+    /// Overlays are only supported on read-write raw images, with the synchronous I/O
+    /// engine and without direct I/O.
+    OverlayOptions,
+    /// The qcow2 format is only supported with the synchronous I/O engine and without
+    /// direct I/O.
+    Qcow2IoOptions,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// Hash trees are only supported on read-only drives, with the synchronous I/O engine.
+    VerityOptions,
+    /// vhost-user drives cannot be hot-plugged.
+    VhostUserHotplug,
+    /// vhost-user drives are configured by their backend, and cannot be root devices.
+    VhostUserOptions,
 }
 
 impl Display for DriveError {
@@ -49,14 +79,55 @@ impl Display for DriveError {
             ),
             BlockDeviceUpdateFailed(e) => write!(f, "The update operation failed: {}", e),
             CreateRateLimiter(e) => write!(f, "Cannot create RateLimiter: {}", e),
+            CreateVhostUserBlockDevice(e) => {
+                write!(f, "Cannot create the vhost-user block device: {}", e)
+            }
+            DirectAsyncIo => write!(
+                f,
+                "The Direct cache type is not supported by the Async I/O engine."
+            ),
+            DeviceHotplug(e) => write!(f, "Error during drive hot-plug: {}", e),
             DeviceUpdate(e) => write!(f, "Error during drive update (patch): {}", e),
+            HotplugRootDevice => write!(f, "A root block device cannot be hot-plugged."),
             InvalidBlockDevicePath => write!(f, "Invalid block device path!"),
+            InvalidNumQueues(num_queues) => write!(
+                f,
+                "Invalid number of queues: {}. The number of queues must be between 1 and {}.",
+                num_queues, MAX_NUM_QUEUES
+            ),
+            NbdOptions => write!(
+                f,
+                "NBD drives do not support the path on host and overlay options, and only \
+                 support the Raw format with the Sync I/O engine and the Unsafe or Writeback \
+                 cache types."
+            ),
             OpenBlockDevice(e) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 e
             ),
+            OverlayOptions => write!(
+                f,
+                "Overlays are only supported on read-write Raw images, with the Sync I/O \
+                 engine and the Unsafe or Writeback cache types."
+            ),
+            Qcow2IoOptions => write!(
+                f,
+                "The Qcow2 format is not supported by the Direct cache type nor by the Async \
+                 I/O engine."
+            ),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            VerityOptions => write!(
+                f,
+                "Hash trees are only supported on read-only drives, with the Sync I/O engine."
+            ),
+            VhostUserHotplug => write!(f, "vhost-user drives cannot be hot-plugged."),
+            VhostUserOptions => write!(
+                f,
+                "vhost-user drives cannot be root devices, and do not support the path on \
+                 host, rate limiter, cache type, I/O engine, format, overlay, NBD and hash tree \
+                 options."
+            ),
         }
     }
 }
@@ -67,7 +138,8 @@ impl Display for DriveError {
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
-    /// Path of the drive.
+    /// Path of the drive. Empty for vhost-user drives and drives served by an NBD server.
+    #[serde(default)]
     pub path_on_host: String,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
@@ -81,6 +153,41 @@ pub struct BlockDeviceConfig {
     pub is_read_only: bool,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// The caching of the drive data on the host, which determines whether the guest
+    /// flushes make the data durable.
+    #[serde(default)]
+    pub cache_type: CacheType,
+    /// The engine executing the I/O operations of the drive. The asynchronous engine
+    /// relies on io_uring and requires a host kernel supporting its read and write operations.
+    #[serde(default)]
+    pub io_engine: FileEngineType,
+    /// The format of the drive image. The backing files of qcow2 images are opened read
+    /// only, relative to the directory of the image referencing them.
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// Path of a copy-on-write overlay receiving the writes of the drive, in which case the
+    /// image at `path_on_host` is only read. The overlay is created, or emptied, when the
+    /// drive is created.
+    pub overlay_path: Option<String>,
+    /// The number of request queues of the drive. Guests can submit requests from several
+    /// vCPUs at once, each on its own queue.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    /// Path of the Unix domain socket of a vhost-user backend serving the drive, instead of
+    /// Firecracker reading and writing `path_on_host`.
+    pub vhost_user_socket: Option<String>,
+    /// The NBD server and export serving the drive, instead of Firecracker reading and
+    /// writing `path_on_host`.
+    pub nbd: Option<NbdConfig>,
+    /// The hash tree and root hash the reads of a read-only drive are checked against.
+    pub verity: Option<VerityConfig>,
+}
+
+// Serde does not allow specifying a default value for a field
+// that is not required. The workaround is to specify a function
+// that returns the value.
+fn default_num_queues() -> usize {
+    DEFAULT_NUM_QUEUES
 }
 
 /// Only provided fields will be updated. I.e. if any optional fields
@@ -94,6 +201,17 @@ pub struct BlockDeviceUpdateConfig {
     pub path_on_host: Option<String>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// Resizes the drive, and notifies the guest of its new capacity.
+    pub resize: Option<BlockDeviceResizeConfig>,
+}
+
+/// The new size of a drive, applied while the microVM runs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDeviceResizeConfig {
+    /// The size the backing file is grown to, in bytes. When missing, the drive takes the
+    /// current size of its backing file, which may have been grown on the host.
+    pub size_bytes: Option<u64>,
 }
 
 /// Wrapper for the collection that holds all the Block Devices
@@ -105,6 +223,8 @@ pub struct BlockBuilder {
     // specified in order to avoid bugs in case of switching from partuuid boot
     // scenarios to /dev/vda boot type.
     pub list: VecDeque<Arc<Mutex<Block>>>,
+    /// The list of vhost-user block devices, attached after the other block devices.
+    pub vhost_user_list: Vec<Arc<Mutex<VhostUserBlock>>>,
 }
 
 impl BlockBuilder {
@@ -112,6 +232,7 @@ impl BlockBuilder {
     pub fn new() -> Self {
         Self {
             list: VecDeque::<Arc<Mutex<Block>>>::new(),
+            vhost_user_list: Vec::new(),
         }
     }
 
@@ -132,10 +253,21 @@ impl BlockBuilder {
             .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
     }
 
+    /// Gets the index of the vhost-user device with the specified `drive_id` if it exists.
+    fn get_index_of_vhost_user_drive_id(&self, drive_id: &str) -> Option<usize> {
+        self.vhost_user_list
+            .iter()
+            .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
+    }
+
     /// Inserts a `Block` in the block devices list using the specified configuration.
     /// If a block with the same id already exists, it will overwrite it.
     /// Inserting a secondary root block device will fail.
     pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
+        if config.vhost_user_socket.is_some() {
+            return self.insert_vhost_user(config);
+        }
+
         let is_root_device = config.is_root_device;
         let position = self.get_index_of_drive_id(&config.drive_id);
         let has_root_block = self.has_root_device();
@@ -146,7 +278,12 @@ impl BlockBuilder {
             return Err(DriveError::RootBlockDeviceAlreadyAdded);
         }
 
-        let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+        let drive_id = config.drive_id.clone();
+        let block_dev = Arc::new(Mutex::new(Self::create_block(config, None)?));
+        // A vhost-user drive with the same id gets overwritten as well.
+        if let Some(index) = self.get_index_of_vhost_user_drive_id(&drive_id) {
+            self.vhost_user_list.remove(index);
+        }
         // If the id of the drive already exists in the list, the operation is update/overwrite.
         match position {
             // New block device.
@@ -171,12 +308,112 @@ impl BlockBuilder {
         Ok(())
     }
 
-    /// Creates a Block device from a BlockDeviceConfig.
-    pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
-        // check if the path exists
-        let path_on_host = PathBuf::from(&block_device_config.path_on_host);
-        if !path_on_host.exists() {
-            return Err(DriveError::InvalidBlockDevicePath);
+    // Inserts a `VhostUserBlock` in the vhost-user block devices list, overwriting any drive
+    // with the same id.
+    fn insert_vhost_user(&mut self, config: BlockDeviceConfig) -> Result<()> {
+        let drive_id = config.drive_id.clone();
+        let block_dev = Arc::new(Mutex::new(Self::create_vhost_user_block(config)?));
+        if let Some(index) = self.get_index_of_drive_id(&drive_id) {
+            self.list.remove(index);
+        }
+        match self.get_index_of_vhost_user_drive_id(&drive_id) {
+            None => self.vhost_user_list.push(block_dev),
+            Some(index) => self.vhost_user_list[index] = block_dev,
+        }
+        Ok(())
+    }
+
+    /// Creates a vhost-user Block device from a BlockDeviceConfig, connecting to its backend.
+    pub fn create_vhost_user_block(
+        block_device_config: BlockDeviceConfig,
+    ) -> Result<VhostUserBlock> {
+        let socket_path = match block_device_config.vhost_user_socket {
+            Some(socket_path) => socket_path,
+            None => return Err(DriveError::InvalidBlockDevicePath),
+        };
+        // The disk image and the way it is accessed are up to the backend.
+        if block_device_config.is_root_device
+            || !block_device_config.path_on_host.is_empty()
+            || block_device_config.rate_limiter.is_some()
+            || block_device_config.cache_type != CacheType::default()
+            || block_device_config.io_engine != FileEngineType::default()
+            || block_device_config.format != ImageFormat::default()
+            || block_device_config.overlay_path.is_some()
+            || block_device_config.nbd.is_some()
+            || block_device_config.verity.is_some()
+        {
+            return Err(DriveError::VhostUserOptions);
+        }
+        if block_device_config.num_queues == 0 || block_device_config.num_queues > MAX_NUM_QUEUES {
+            return Err(DriveError::InvalidNumQueues(block_device_config.num_queues));
+        }
+
+        VhostUserBlock::new(
+            block_device_config.drive_id,
+            block_device_config.partuuid,
+            socket_path,
+            block_device_config.is_read_only,
+            block_device_config.num_queues,
+        )
+        .map_err(DriveError::CreateVhostUserBlockDevice)
+    }
+
+    /// Creates a Block device from a BlockDeviceConfig, taking its file descriptors from
+    /// `fd_pool` if set.
+    pub fn create_block(
+        block_device_config: BlockDeviceConfig,
+        fd_pool: Option<&mut BlockFdPool>,
+    ) -> Result<Block> {
+        if block_device_config.nbd.is_some() {
+            // The requests to the server are issued synchronously, on raw exports.
+            if !block_device_config.path_on_host.is_empty()
+                || block_device_config.cache_type == CacheType::Direct
+                || block_device_config.io_engine == FileEngineType::Async
+                || block_device_config.format != ImageFormat::Raw
+                || block_device_config.overlay_path.is_some()
+            {
+                return Err(DriveError::NbdOptions);
+            }
+        } else {
+            // check if the path exists
+            let path_on_host = PathBuf::from(&block_device_config.path_on_host);
+            if !path_on_host.exists() {
+                return Err(DriveError::InvalidBlockDevicePath);
+            }
+        }
+
+        if block_device_config.num_queues == 0 || block_device_config.num_queues > MAX_NUM_QUEUES {
+            return Err(DriveError::InvalidNumQueues(block_device_config.num_queues));
+        }
+
+        // The io_uring operations would target the unaligned guest buffers.
+        if block_device_config.cache_type == CacheType::Direct
+            && block_device_config.io_engine == FileEngineType::Async
+        {
+            return Err(DriveError::DirectAsyncIo);
+        }
+        // The qcow2 metadata is only accessed synchronously, through the host page cache.
+        if block_device_config.format == ImageFormat::Qcow2
+            && (block_device_config.cache_type == CacheType::Direct
+                || block_device_config.io_engine == FileEngineType::Async)
+        {
+            return Err(DriveError::Qcow2IoOptions);
+        }
+        // The overlay blocks are copied synchronously, through the host page cache.
+        if block_device_config.overlay_path.is_some()
+            && (block_device_config.is_read_only
+                || block_device_config.format != ImageFormat::Raw
+                || block_device_config.cache_type == CacheType::Direct
+                || block_device_config.io_engine == FileEngineType::Async)
+        {
+            return Err(DriveError::OverlayOptions);
+        }
+        // The asynchronous reads land in the guest memory before they could be checked.
+        if block_device_config.verity.is_some()
+            && (!block_device_config.is_read_only
+                || block_device_config.io_engine == FileEngineType::Async)
+        {
+            return Err(DriveError::VerityOptions);
         }
 
         let rate_limiter = block_device_config
@@ -193,6 +430,16 @@ impl BlockBuilder {
             block_device_config.is_read_only,
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
+            block_device_config.cache_type,
+            block_device_config.io_engine,
+            block_device_config.format,
+            block_device_config
+                .overlay_path
+                .map(|path| OverlayConfig { path, bitmap: None }),
+            block_device_config.num_queues,
+            block_device_config.nbd,
+            block_device_config.verity,
+            fd_pool,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
@@ -202,6 +449,15 @@ impl BlockBuilder {
 mod tests {
 
     use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use devices::virtio::vhost_user::{
+        recv_message, request, send_message, VHOST_USER_F_PROTOCOL_FEATURES,
+        VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_REPLY_MASK,
+    };
+    use devices::virtio::VirtioDevice;
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
 
     impl PartialEq for DriveError {
@@ -221,6 +477,14 @@ mod tests {
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
+                cache_type: self.cache_type,
+                io_engine: self.io_engine,
+                format: self.format,
+                overlay_path: self.overlay_path.clone(),
+                num_queues: self.num_queues,
+                vhost_user_socket: self.vhost_user_socket.clone(),
+                nbd: self.nbd.clone(),
+                verity: self.verity.clone(),
             }
         }
     }
@@ -243,6 +507,14 @@ mod tests {
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -272,6 +544,14 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -298,6 +578,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -309,6 +597,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -331,6 +627,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -342,6 +646,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -353,6 +665,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -389,6 +709,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -400,6 +728,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -411,6 +747,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -448,6 +792,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -459,6 +811,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -517,6 +877,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
@@ -528,6 +896,14 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -550,6 +926,14 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: true,
             rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
         };
 
         assert_eq!(
@@ -562,4 +946,423 @@ mod tests {
         );
         assert_eq!(block_config.is_read_only, expected_is_read_only);
     }
+
+    #[test]
+    fn test_cache_type() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Writeback,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(block_config.clone()).is_ok());
+        assert_eq!(
+            block_devs.list[0].lock().unwrap().cache_type(),
+            CacheType::Writeback
+        );
+
+        // Direct I/O is not supported by the asynchronous I/O engine.
+        block_config.cache_type = CacheType::Direct;
+        block_config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_config),
+            Err(DriveError::DirectAsyncIo)
+        );
+    }
+
+    #[test]
+    fn test_image_format() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Writeback,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Qcow2,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+        };
+
+        // The backing file is not a qcow2 image.
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(block_config.clone()) {
+            Err(DriveError::CreateBlockDevice(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+        block_config.format = ImageFormat::Raw;
+        assert!(block_devs.insert(block_config.clone()).is_ok());
+        assert_eq!(
+            block_devs.list[0].lock().unwrap().image_format(),
+            ImageFormat::Raw
+        );
+
+        // The qcow2 format only supports synchronous, cached I/O.
+        block_config.format = ImageFormat::Qcow2;
+        block_config.cache_type = CacheType::Direct;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::Qcow2IoOptions)
+        );
+        block_config.cache_type = CacheType::Unsafe;
+        block_config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_config),
+            Err(DriveError::Qcow2IoOptions)
+        );
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base_file = TempFile::new().unwrap();
+        base_file.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: base_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Writeback,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: Some(overlay_file.as_path().to_str().unwrap().to_string()),
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(block_config.clone()).is_ok());
+        // The overlay is created with the size of the base image.
+        assert_eq!(overlay_file.as_file().metadata().unwrap().len(), 0x1000);
+
+        // Overlays only support read-write raw images, with synchronous, cached I/O.
+        block_config.is_read_only = true;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::OverlayOptions)
+        );
+        block_config.is_read_only = false;
+        block_config.format = ImageFormat::Qcow2;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::OverlayOptions)
+        );
+        block_config.format = ImageFormat::Raw;
+        block_config.cache_type = CacheType::Direct;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::OverlayOptions)
+        );
+        block_config.cache_type = CacheType::Unsafe;
+        block_config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_config),
+            Err(DriveError::OverlayOptions)
+        );
+    }
+
+    #[test]
+    fn test_num_queues() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: MAX_NUM_QUEUES,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(block_config.clone()).is_ok());
+        assert_eq!(
+            block_devs.list[0].lock().unwrap().queues().len(),
+            MAX_NUM_QUEUES
+        );
+
+        block_config.num_queues = 0;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::InvalidNumQueues(0))
+        );
+        block_config.num_queues = MAX_NUM_QUEUES + 1;
+        assert_eq!(
+            block_devs.insert(block_config),
+            Err(DriveError::InvalidNumQueues(MAX_NUM_QUEUES + 1))
+        );
+    }
+
+    // Answers the requests sent while creating a vhost-user block device, until the device
+    // connected to `listener` goes away.
+    fn spawn_vhost_user_backend(listener: UnixListener) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            while let Ok(msg) = recv_message(&stream) {
+                let reply = match msg.header.request {
+                    request::GET_FEATURES => (1u64 << VHOST_USER_F_PROTOCOL_FEATURES)
+                        .to_ne_bytes()
+                        .to_vec(),
+                    request::GET_PROTOCOL_FEATURES => (1u64 << VHOST_USER_PROTOCOL_F_CONFIG)
+                        .to_ne_bytes()
+                        .to_vec(),
+                    // An empty configuration space.
+                    request::GET_CONFIG => msg.payload.clone(),
+                    _ => continue,
+                };
+                send_message(
+                    &stream,
+                    msg.header.request,
+                    VHOST_USER_REPLY_MASK,
+                    &reply,
+                    &[],
+                )
+                .unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_vhost_user() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("vhost-user.sock");
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: String::new(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: Some(socket_path.to_str().unwrap().to_string()),
+            nbd: None,
+            verity: None,
+        };
+        let local_config = BlockDeviceConfig {
+            path_on_host: dummy_path.clone(),
+            vhost_user_socket: None,
+            nbd: None,
+            verity: None,
+            ..block_config.clone()
+        };
+
+        // No backend is listening yet.
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(block_config.clone()) {
+            Err(DriveError::CreateVhostUserBlockDevice(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+
+        // A vhost-user drive overwrites the drive with the same id, and the other way around.
+        block_devs.insert(local_config.clone()).unwrap();
+        let handle = spawn_vhost_user_backend(UnixListener::bind(&socket_path).unwrap());
+        block_devs.insert(block_config.clone()).unwrap();
+        assert!(block_devs.list.is_empty());
+        assert_eq!(block_devs.vhost_user_list.len(), 1);
+        assert_eq!(
+            block_devs.vhost_user_list[0].lock().unwrap().id(),
+            &block_config.drive_id
+        );
+        block_devs.insert(local_config).unwrap();
+        assert_eq!(block_devs.list.len(), 1);
+        assert!(block_devs.vhost_user_list.is_empty());
+        // The backend returns once the device is dropped.
+        handle.join().unwrap();
+
+        // The disk image and the way it is accessed are up to the backend.
+        block_config.is_root_device = true;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VhostUserOptions)
+        );
+        block_config.is_root_device = false;
+        block_config.path_on_host = dummy_path;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VhostUserOptions)
+        );
+        block_config.path_on_host = String::new();
+        block_config.cache_type = CacheType::Writeback;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VhostUserOptions)
+        );
+        block_config.cache_type = CacheType::Unsafe;
+        block_config.overlay_path = Some("overlay".to_string());
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VhostUserOptions)
+        );
+        block_config.overlay_path = None;
+        block_config.nbd = Some(NbdConfig {
+            socket_path: Some("nbd.sock".to_string()),
+            tcp_address: None,
+            export_name: String::new(),
+        });
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VhostUserOptions)
+        );
+        block_config.nbd = None;
+        block_config.num_queues = 0;
+        assert_eq!(
+            block_devs.insert(block_config),
+            Err(DriveError::InvalidNumQueues(0))
+        );
+    }
+
+    #[test]
+    fn test_nbd() {
+        let dir = TempDir::new().unwrap();
+        let dummy_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: String::new(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            cache_type: CacheType::Writeback,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: Some(NbdConfig {
+                socket_path: Some(dir.as_path().join("nbd.sock").to_str().unwrap().to_string()),
+                tcp_address: None,
+                export_name: String::new(),
+            }),
+            verity: None,
+        };
+
+        // No server is listening.
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(block_config.clone()) {
+            Err(DriveError::CreateBlockDevice(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+
+        // The export replaces the backing file, and is accessed synchronously, through the
+        // host page cache.
+        block_config.path_on_host = dummy_file.as_path().to_str().unwrap().to_string();
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::NbdOptions)
+        );
+        block_config.path_on_host = String::new();
+        block_config.cache_type = CacheType::Direct;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::NbdOptions)
+        );
+        block_config.cache_type = CacheType::Writeback;
+        block_config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::NbdOptions)
+        );
+        block_config.io_engine = FileEngineType::Sync;
+        block_config.format = ImageFormat::Qcow2;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::NbdOptions)
+        );
+        block_config.format = ImageFormat::Raw;
+        block_config.overlay_path = Some("overlay".to_string());
+        assert_eq!(block_devs.insert(block_config), Err(DriveError::NbdOptions));
+        assert!(block_devs.list.is_empty());
+    }
+
+    #[test]
+    fn test_verity() {
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(0x1000).unwrap();
+        // The disk holds a single data block, whose digest is the root hash.
+        let hash_tree_file = TempFile::new().unwrap();
+        let mut block_config = BlockDeviceConfig {
+            drive_id: "dummy_drive".to_string(),
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: true,
+            rate_limiter: None,
+            cache_type: CacheType::Unsafe,
+            io_engine: FileEngineType::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            vhost_user_socket: None,
+            nbd: None,
+            verity: Some(VerityConfig {
+                hash_tree_path: hash_tree_file.as_path().to_str().unwrap().to_string(),
+                hash_offset: 0,
+                root_hash: String::from(
+                    "ad7facb2586fc6e966c004d7d1d16b024f5805ff7cb47c7a85dabd8b48892ca7",
+                ),
+                salt: String::new(),
+                data_block_size: 0x1000,
+                hash_block_size: 0x1000,
+            }),
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(block_config.clone()).is_ok());
+        assert!(block_devs.list[0].lock().unwrap().is_read_only());
+
+        // Only the synchronous reads of read-only drives can be checked.
+        block_config.is_read_only = false;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VerityOptions)
+        );
+        block_config.is_read_only = true;
+        block_config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_config.clone()),
+            Err(DriveError::VerityOptions)
+        );
+        block_config.io_engine = FileEngineType::Sync;
+        block_config.verity.as_mut().unwrap().root_hash = String::from("invalid");
+        match block_devs.insert(block_config) {
+            Err(DriveError::CreateBlockDevice(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
 }
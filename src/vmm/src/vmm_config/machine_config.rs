@@ -73,6 +73,9 @@ pub struct VmConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// Number of MMIO slots reserved at boot time for hot-plugging block devices.
+    #[serde(default)]
+    pub hotplug_slots: usize,
 }
 
 impl Default for VmConfig {
@@ -83,6 +86,7 @@ impl Default for VmConfig {
             ht_enabled: Some(false),
             cpu_template: None,
             track_dirty_pages: false,
+            hotplug_slots: 0,
         }
     }
 }
@@ -98,8 +102,13 @@ impl fmt::Display for VmConfig {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"ht_enabled\": {:?}, \
-             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?} }}",
-            vcpu_count, mem_size, ht_enabled, cpu_template, self.track_dirty_pages
+             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?}, \"hotplug_slots\": {:?} }}",
+            vcpu_count,
+            mem_size,
+            ht_enabled,
+            cpu_template,
+            self.track_dirty_pages,
+            self.hotplug_slots
         )
     }
 }
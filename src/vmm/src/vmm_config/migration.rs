@@ -0,0 +1,38 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used in the live migration context.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Stores the configuration used for sending a microVM to another Firecracker process.
+/// Exactly one of the endpoints must be set.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendMigrationParams {
+    /// Path to the Unix domain socket the destination listens on.
+    pub uds_path: Option<PathBuf>,
+    /// TCP address the destination listens on.
+    pub tcp_address: Option<SocketAddr>,
+}
+
+/// Stores the configuration used for receiving a microVM from another Firecracker process.
+/// Exactly one of the endpoints must be set.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveMigrationParams {
+    /// Path of the Unix domain socket to listen on.
+    pub uds_path: Option<PathBuf>,
+    /// TCP address to listen on.
+    pub tcp_address: Option<SocketAddr>,
+    /// Setting this flag will enable KVM dirty page tracking and will
+    /// allow taking subsequent incremental snapshots.
+    #[serde(default)]
+    pub enable_diff_snapshots: bool,
+    /// When set to true, the vm is also resumed once it was received.
+    #[serde(default)]
+    pub resume_vm: bool,
+}
@@ -3,13 +3,15 @@
 
 use std::convert::TryInto;
 use std::fmt;
+use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
-use devices::virtio::net::TapError;
+use devices::virtio::net::{TapError, DEFAULT_NUM_QUEUE_PAIRS, MAX_NUM_QUEUE_PAIRS};
 use devices::virtio::Net;
+use logger::warn;
 use utils::net::mac::MacAddr;
 
 use serde::Deserialize;
@@ -36,6 +38,16 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
+    /// The number of RX and TX queue pairs of the guest network interface. Guests can
+    /// send and receive frames from several vCPUs at once, each on its own queue pair.
+    /// More than one queue pair requires a multi-queue TAP device.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: usize,
+    /// If this field is set, the frames are moved between the guest and the TAP device by
+    /// the vhost-net kernel driver instead of the device model. Interfaces which allow MMDS
+    /// requests or have rate limiters keep going through the device model.
+    #[serde(default)]
+    pub vhost: bool,
 }
 
 // Serde does not allow specifying a default value for a field
@@ -45,6 +57,10 @@ fn default_allow_mmds_requests() -> bool {
     false
 }
 
+fn default_num_queue_pairs() -> usize {
+    DEFAULT_NUM_QUEUE_PAIRS
+}
+
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
 /// can be updated.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
@@ -60,6 +76,17 @@ pub struct NetworkInterfaceUpdateConfig {
     pub tx_rate_limiter: Option<RateLimiterConfig>,
 }
 
+/// The data fed into a request starting the packet capture of a network iface.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkCaptureConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// Path of the pcapng file the frames received and transmitted by the guest are written to.
+    /// An existing file is overwritten.
+    pub capture_path: PathBuf,
+}
+
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum NetworkInterfaceError {
@@ -69,8 +96,12 @@ pub enum NetworkInterfaceError {
     CreateRateLimiter(std::io::Error),
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
+    /// The number of queue pairs is zero or exceeds the maximum.
+    InvalidNumQueuePairs(usize),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
+    /// Error while starting or stopping the packet capture.
+    DeviceCapture(VmmError),
     /// Cannot open/create tap device.
     OpenTap(TapError),
 }
@@ -86,7 +117,14 @@ impl fmt::Display for NetworkInterfaceError {
                 "{}",
                 format!("The guest MAC address {} is already in use.", mac_addr)
             ),
+            InvalidNumQueuePairs(num_queue_pairs) => write!(
+                f,
+                "Invalid number of queue pairs: {}. The number of queue pairs must be between \
+                 1 and {}.",
+                num_queue_pairs, MAX_NUM_QUEUE_PAIRS
+            ),
             DeviceUpdate(e) => write!(f, "Error during interface update (patch): {}", e),
+            DeviceCapture(e) => write!(f, "Error during interface packet capture: {}", e),
             OpenTap(e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -150,6 +188,12 @@ impl NetBuilder {
             ));
         }
 
+        if netif_config.num_queue_pairs == 0 || netif_config.num_queue_pairs > MAX_NUM_QUEUE_PAIRS {
+            return Err(NetworkInterfaceError::InvalidNumQueuePairs(
+                netif_config.num_queue_pairs,
+            ));
+        }
+
         // If this is an update, just remove the old one.
         if let Some(index) = self
             .net_devices
@@ -168,27 +212,55 @@ impl NetBuilder {
 
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
-        let rx_rate_limiter = cfg
-            .rx_rate_limiter
-            .map(super::RateLimiterConfig::try_into)
-            .transpose()
-            .map_err(NetworkInterfaceError::CreateRateLimiter)?;
-        let tx_rate_limiter = cfg
-            .tx_rate_limiter
-            .map(super::RateLimiterConfig::try_into)
-            .transpose()
-            .map_err(NetworkInterfaceError::CreateRateLimiter)?;
+        // Each queue pair accounts its own traffic against the configured limits.
+        let mut rate_limiters = Vec::with_capacity(cfg.num_queue_pairs);
+        for _ in 0..cfg.num_queue_pairs {
+            let rx_rate_limiter = cfg
+                .rx_rate_limiter
+                .map(super::RateLimiterConfig::try_into)
+                .transpose()
+                .map_err(NetworkInterfaceError::CreateRateLimiter)?;
+            let tx_rate_limiter = cfg
+                .tx_rate_limiter
+                .map(super::RateLimiterConfig::try_into)
+                .transpose()
+                .map_err(NetworkInterfaceError::CreateRateLimiter)?;
+            rate_limiters.push((
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+            ));
+        }
+
+        // The frames moved by vhost-net are neither detoured to the MMDS nor rate limited.
+        let rate_limited = rate_limiters.iter().any(|(rx, tx)| {
+            rx.bandwidth().is_some()
+                || rx.ops().is_some()
+                || tx.bandwidth().is_some()
+                || tx.ops().is_some()
+        });
+        let vhost = cfg.vhost && !cfg.allow_mmds_requests && !rate_limited;
+        if cfg.vhost && !vhost {
+            warn!(
+                "Network interface {} allows MMDS requests or is rate limited, falling back to \
+                 the userspace path instead of vhost-net.",
+                cfg.iface_id
+            );
+        }
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
+        let mut net = devices::virtio::net::Net::new_with_tap(
             cfg.iface_id,
             cfg.host_dev_name.clone(),
             cfg.guest_mac.as_ref(),
-            rx_rate_limiter.unwrap_or_default(),
-            tx_rate_limiter.unwrap_or_default(),
+            rate_limiters,
             cfg.allow_mmds_requests,
         )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if vhost {
+            net.enable_vhost()
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        Ok(net)
     }
 }
 
@@ -197,6 +269,8 @@ mod tests {
     use std::str;
 
     use super::*;
+    use crate::vmm_config::TokenBucketConfig;
+    use devices::virtio::VirtioDevice;
 
     impl NetBuilder {
         pub fn len(&self) -> usize {
@@ -216,6 +290,8 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost: false,
         }
     }
 
@@ -228,6 +304,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: self.allow_mmds_requests,
+                num_queue_pairs: self.num_queue_pairs,
+                vhost: self.vhost,
             }
         }
     }
@@ -326,6 +404,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_num_queue_pairs() {
+        let mut net_builder = NetBuilder::new();
+
+        let mut netif = create_netif("id_1", "mqdev", "01:23:45:67:89:0a");
+        netif.num_queue_pairs = 2;
+        let net = net_builder.build(netif.clone()).unwrap();
+        assert_eq!(net.lock().unwrap().num_queue_pairs(), 2);
+        // The 2 queue pairs are followed by the control queue.
+        assert_eq!(net.lock().unwrap().queues().len(), 5);
+
+        netif.num_queue_pairs = 0;
+        assert_eq!(
+            net_builder.build(netif.clone()).err().unwrap().to_string(),
+            NetworkInterfaceError::InvalidNumQueuePairs(0).to_string()
+        );
+        netif.num_queue_pairs = MAX_NUM_QUEUE_PAIRS + 1;
+        assert_eq!(
+            net_builder.build(netif).err().unwrap().to_string(),
+            NetworkInterfaceError::InvalidNumQueuePairs(MAX_NUM_QUEUE_PAIRS + 1).to_string()
+        );
+        assert_eq!(net_builder.net_devices.len(), 1);
+    }
+
+    #[test]
+    fn test_vhost_fallback() {
+        let mut net_builder = NetBuilder::new();
+
+        // The MMDS requests have to go through the device model.
+        let mut netif = create_netif("id_1", "vhostdev1", "01:23:45:67:89:0a");
+        netif.vhost = true;
+        netif.allow_mmds_requests = true;
+        let net = net_builder.build(netif).unwrap();
+        assert!(!net.lock().unwrap().is_vhost());
+
+        // So do the frames accounted by the rate limiters.
+        let mut netif = create_netif("id_2", "vhostdev2", "01:23:45:67:89:0b");
+        netif.vhost = true;
+        netif.tx_rate_limiter = Some(RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        });
+        let net = net_builder.build(netif).unwrap();
+        assert!(!net.lock().unwrap().is_vhost());
+    }
+
     #[test]
     fn test_error_display() {
         // FIXME: use macro
@@ -340,11 +468,21 @@ mod tests {
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit),
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::DeviceCapture(VmmError::VcpuExit),
+            NetworkInterfaceError::DeviceCapture(VmmError::VcpuExit)
+        );
         let _ = format!(
             "{}{:?}",
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidNumQueuePairs(0),
+            NetworkInterfaceError::InvalidNumQueuePairs(0)
+        );
     }
 
     #[test]
@@ -359,5 +497,6 @@ mod tests {
             MacAddr::parse_str(guest_mac).unwrap()
         );
         assert_eq!(net_if.allow_mmds_requests, false);
+        assert_eq!(net_if.num_queue_pairs, 1);
     }
 }
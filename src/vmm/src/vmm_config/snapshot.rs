@@ -3,13 +3,15 @@
 
 //! Configurations used in the snapshotting context.
 
+use std::fmt::{Debug, Formatter};
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
 /// The snapshot type options that are available when
 /// creating a new snapshot.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum SnapshotType {
     /// Diff snapshot.
     Diff,
@@ -23,8 +25,23 @@ impl Default for SnapshotType {
     }
 }
 
+/// The formats in which the guest memory can be saved when creating a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemoryFileFormat {
+    /// Raw image of the guest memory.
+    Raw,
+    /// Compressed chunks of the guest memory, each with its own checksum.
+    Compressed,
+}
+
+impl Default for MemoryFileFormat {
+    fn default() -> MemoryFileFormat {
+        MemoryFileFormat::Raw
+    }
+}
+
 /// Stores the configuration that will be used for creating a snapshot.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct CreateSnapshotParams {
     /// This marks the type of snapshot we want to create.
@@ -35,6 +52,19 @@ pub struct CreateSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Path to the file that will contain the guest memory.
     pub mem_file_path: PathBuf,
+    /// The format of the guest memory file.
+    /// The default value is `Raw`. Diff snapshots only support `Raw`.
+    #[serde(default = "MemoryFileFormat::default")]
+    pub mem_file_format: MemoryFileFormat,
+    /// When set to true on a running microVM, the guest memory is copied while the vCPUs
+    /// run and the microVM is only paused to save the last dirtied pages and the device
+    /// state. Requires dirty page tracking and is only supported for full, raw snapshots.
+    #[serde(default)]
+    pub live: bool,
+    /// When set, both the microVM state and the guest memory files are encrypted.
+    /// Only supported for full, non-live snapshots.
+    #[serde(default)]
+    pub encryption: Option<SnapshotEncryptionConfig>,
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
@@ -48,6 +78,20 @@ pub struct LoadSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Path to the file that contains the guest memory to be loaded.
     pub mem_file_path: PathBuf,
+    /// Paths to the diff memory files to be applied, in creation order, on top of
+    /// the memory file from `mem_file_path`.
+    #[serde(default)]
+    pub mem_file_layers: Vec<PathBuf>,
+    /// Checks the memory files against the checksums recorded in the snapshot, which reads
+    /// every page of them before loading the snapshot. Otherwise, only the number of memory
+    /// files is checked against the recorded chain of layers.
+    #[serde(default)]
+    pub verify_memory_checksums: bool,
+    /// Path to the Unix domain socket of a page fault handler. When set, the guest memory
+    /// is not loaded upfront; its pages are populated on first access by the handler, which
+    /// receives a userfaultfd and the layout of the memory file from `mem_file_path`.
+    #[serde(default)]
+    pub uffd_socket_path: Option<PathBuf>,
     /// Setting this flag will enable KVM dirty page tracking and will
     /// allow taking subsequent incremental snapshots.
     #[serde(default)]
@@ -56,6 +100,80 @@ pub struct LoadSnapshotParams {
     /// is successful.
     #[serde(default)]
     pub resume_vm: bool,
+    /// Host resources to restore the devices with, instead of the ones saved in the
+    /// snapshot.
+    #[serde(default)]
+    pub device_overrides: DeviceOverrides,
+    /// Must be set to load a snapshot created with encryption, with the same key.
+    #[serde(default)]
+    pub encryption: Option<SnapshotEncryptionConfig>,
+}
+
+/// Where to find the AES-256-GCM key used to encrypt or decrypt the snapshot files.
+/// Exactly one of the fields must be set.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotEncryptionConfig {
+    /// The 256-bit key, as 64 hexadecimal characters.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// File descriptor, inherited by the Firecracker process, from which the 32 bytes
+    /// of the key are read. It must be a regular file or a pipe, and is left open.
+    #[serde(default)]
+    pub key_fd: Option<RawFd>,
+}
+
+impl Debug for SnapshotEncryptionConfig {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        // Keep the key out of the logs.
+        f.debug_struct("SnapshotEncryptionConfig")
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("key_fd", &self.key_fd)
+            .finish()
+    }
+}
+
+/// Host resources to restore the devices of a snapshot with, keyed by device id.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceOverrides {
+    /// Block devices restored with another backing file.
+    #[serde(default)]
+    pub drives: Vec<DriveOverride>,
+    /// Network interfaces restored with another host tap device.
+    #[serde(default)]
+    pub network_interfaces: Vec<NetworkInterfaceOverride>,
+    /// New Unix domain socket of the vsock device.
+    #[serde(default)]
+    pub vsock: Option<VsockOverride>,
+}
+
+/// Overrides the backing file of a restored block device.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveOverride {
+    /// Unique identifier of the drive.
+    pub drive_id: String,
+    /// New path of the drive.
+    pub path_on_host: String,
+}
+
+/// Overrides the host tap device of a restored network interface.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceOverride {
+    /// ID of the guest network interface.
+    pub iface_id: String,
+    /// New host level path for the guest network interface.
+    pub host_dev_name: String,
+}
+
+/// Overrides the Unix domain socket of a restored vsock device.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockOverride {
+    /// New path to local unix socket.
+    pub uds_path: String,
 }
 
 /// The microVM state options.
@@ -4,3 +4,17 @@
 pub(crate) mod system;
 pub(crate) mod vcpu;
 pub(crate) mod vm;
+
+/// Formats the raw memory of a KVM structure as a hex string.
+///
+/// Used when describing a saved state for the KVM structures which are opaque register
+/// pages (e.g. the LAPIC or XSAVE areas) rather than a set of named fields.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn kvm_struct_to_hex<T: Copy>(value: &T) -> String {
+    // Safe because `value` is a valid reference to a plain-old-data KVM structure, so all
+    // of its `size_of::<T>()` bytes are readable.
+    let bytes = unsafe {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    };
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
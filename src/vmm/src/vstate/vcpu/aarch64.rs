@@ -13,6 +13,8 @@ use std::{
 use crate::vstate::{vcpu::VcpuEmulation, vm::Vm};
 use kvm_ioctls::*;
 use logger::{error, IncMetric, METRICS};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
@@ -188,6 +190,25 @@ pub struct VcpuState {
     pub mpidr: u64,
 }
 
+impl Serialize for VcpuState {
+    /// Describes the vCPU state in a human readable form, used for inspecting snapshots.
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        // The register values are stored in place of the userspace address of each register.
+        let regs: Vec<Value> = self
+            .regs
+            .iter()
+            .map(|reg| json!({ "id": reg.id, "value": reg.addr }))
+            .collect();
+
+        json!({
+            "mp_state": self.mp_state.mp_state,
+            "regs": regs,
+            "mpidr": self.mpidr,
+        })
+        .serialize(serializer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::os::unix::io::AsRawFd;
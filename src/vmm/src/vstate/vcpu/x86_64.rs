@@ -12,16 +12,19 @@ use std::{
 
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
 use crate::vstate::{
+    kvm_struct_to_hex,
     vcpu::{VcpuConfig, VcpuEmulation},
     vm::Vm,
 };
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
 use kvm_bindings::{
-    kvm_debugregs, kvm_lapic_state, kvm_mp_state, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs,
-    kvm_xsave, CpuId, MsrList, Msrs,
+    kvm_debugregs, kvm_dtable, kvm_lapic_state, kvm_mp_state, kvm_regs, kvm_segment, kvm_sregs,
+    kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList, Msrs,
 };
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use logger::{error, IncMetric, METRICS};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
@@ -391,6 +394,135 @@ pub struct VcpuState {
     xsave: kvm_xsave,
 }
 
+fn segment_to_json(segment: &kvm_segment) -> Value {
+    json!({
+        "base": segment.base,
+        "limit": segment.limit,
+        "selector": segment.selector,
+        "type": segment.type_,
+        "present": segment.present,
+        "dpl": segment.dpl,
+        "db": segment.db,
+        "s": segment.s,
+        "l": segment.l,
+        "g": segment.g,
+        "avl": segment.avl,
+        "unusable": segment.unusable,
+    })
+}
+
+fn dtable_to_json(dtable: &kvm_dtable) -> Value {
+    json!({ "base": dtable.base, "limit": dtable.limit })
+}
+
+impl Serialize for VcpuState {
+    /// Describes the vCPU state in a human readable form, used for inspecting snapshots.
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let regs = &self.regs;
+        let sregs = &self.sregs;
+        let events = &self.vcpu_events;
+        let cpuid: Vec<Value> = self
+            .cpuid
+            .as_slice()
+            .iter()
+            .map(|entry| {
+                json!({
+                    "function": entry.function,
+                    "index": entry.index,
+                    "flags": entry.flags,
+                    "eax": entry.eax,
+                    "ebx": entry.ebx,
+                    "ecx": entry.ecx,
+                    "edx": entry.edx,
+                })
+            })
+            .collect();
+        let msrs: Vec<Value> = self
+            .msrs
+            .as_slice()
+            .iter()
+            .map(|entry| json!({ "index": entry.index, "data": entry.data }))
+            .collect();
+        let xcrs: Vec<Value> = self
+            .xcrs
+            .xcrs
+            .iter()
+            .take(self.xcrs.nr_xcrs as usize)
+            .map(|xcr| json!({ "xcr": xcr.xcr, "value": xcr.value }))
+            .collect();
+
+        let regs = json!({
+            "rax": regs.rax, "rbx": regs.rbx, "rcx": regs.rcx, "rdx": regs.rdx,
+            "rsi": regs.rsi, "rdi": regs.rdi, "rsp": regs.rsp, "rbp": regs.rbp,
+            "r8": regs.r8, "r9": regs.r9, "r10": regs.r10, "r11": regs.r11,
+            "r12": regs.r12, "r13": regs.r13, "r14": regs.r14, "r15": regs.r15,
+            "rip": regs.rip, "rflags": regs.rflags,
+        });
+        let sregs = json!({
+            "cs": segment_to_json(&sregs.cs),
+            "ds": segment_to_json(&sregs.ds),
+            "es": segment_to_json(&sregs.es),
+            "fs": segment_to_json(&sregs.fs),
+            "gs": segment_to_json(&sregs.gs),
+            "ss": segment_to_json(&sregs.ss),
+            "tr": segment_to_json(&sregs.tr),
+            "ldt": segment_to_json(&sregs.ldt),
+            "gdt": dtable_to_json(&sregs.gdt),
+            "idt": dtable_to_json(&sregs.idt),
+            "cr0": sregs.cr0, "cr2": sregs.cr2, "cr3": sregs.cr3, "cr4": sregs.cr4,
+            "cr8": sregs.cr8, "efer": sregs.efer, "apic_base": sregs.apic_base,
+            "interrupt_bitmap": sregs.interrupt_bitmap,
+        });
+        let vcpu_events = json!({
+            "exception": {
+                "injected": events.exception.injected,
+                "nr": events.exception.nr,
+                "has_error_code": events.exception.has_error_code,
+                "pending": events.exception.pending,
+                "error_code": events.exception.error_code,
+            },
+            "interrupt": {
+                "injected": events.interrupt.injected,
+                "nr": events.interrupt.nr,
+                "soft": events.interrupt.soft,
+                "shadow": events.interrupt.shadow,
+            },
+            "nmi": {
+                "injected": events.nmi.injected,
+                "pending": events.nmi.pending,
+                "masked": events.nmi.masked,
+            },
+            "smi": {
+                "smm": events.smi.smm,
+                "pending": events.smi.pending,
+                "smm_inside_nmi": events.smi.smm_inside_nmi,
+                "latched_init": events.smi.latched_init,
+            },
+            "sipi_vector": events.sipi_vector,
+            "flags": events.flags,
+        });
+
+        json!({
+            "regs": regs,
+            "sregs": sregs,
+            "cpuid": cpuid,
+            "msrs": msrs,
+            "debug_regs": {
+                "db": self.debug_regs.db,
+                "dr6": self.debug_regs.dr6,
+                "dr7": self.debug_regs.dr7,
+                "flags": self.debug_regs.flags,
+            },
+            "mp_state": self.mp_state.mp_state,
+            "vcpu_events": vcpu_events,
+            "xcrs": xcrs,
+            "lapic": kvm_struct_to_hex(&self.lapic),
+            "xsave": kvm_struct_to_hex(&self.xsave),
+        })
+        .serialize(serializer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate cpuid;
@@ -489,4 +621,26 @@ mod tests {
         // Validate the mutated cpuid is saved.
         assert!(vcpu.save_state().unwrap().cpuid.as_slice()[0].eax == 0x1234_5678);
     }
+
+    #[test]
+    fn test_vcpu_state_serialize() {
+        let (_vm, vcpu, _) = setup_vcpu(0x1000);
+        let state = vcpu.save_state().unwrap();
+        let value = serde_json::to_value(&state).unwrap();
+
+        assert_eq!(value["regs"]["rip"], state.regs.rip);
+        assert_eq!(value["sregs"]["cr0"], state.sregs.cr0);
+        assert_eq!(
+            value["cpuid"].as_array().unwrap().len(),
+            state.cpuid.as_slice().len()
+        );
+        assert_eq!(
+            value["msrs"].as_array().unwrap().len(),
+            state.msrs.as_slice().len()
+        );
+        assert_eq!(
+            value["lapic"].as_str().unwrap().len(),
+            2 * std::mem::size_of::<kvm_lapic_state>()
+        );
+    }
 }
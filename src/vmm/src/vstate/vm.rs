@@ -10,6 +10,8 @@ use std::{
     result,
 };
 
+#[cfg(target_arch = "x86_64")]
+use crate::vstate::kvm_struct_to_hex;
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "aarch64")]
@@ -22,6 +24,12 @@ use kvm_bindings::{
 };
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::{Kvm, VmFd};
+#[cfg(target_arch = "aarch64")]
+use serde::Serialize;
+#[cfg(target_arch = "x86_64")]
+use serde::{Serialize, Serializer};
+#[cfg(target_arch = "x86_64")]
+use serde_json::{json, Value};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
@@ -325,9 +333,53 @@ pub struct VmState {
     ioapic: kvm_irqchip,
 }
 
+#[cfg(target_arch = "x86_64")]
+impl Serialize for VmState {
+    /// Describes the VM state in a human readable form, used for inspecting snapshots.
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let channels: Vec<Value> = self
+            .pitstate
+            .channels
+            .iter()
+            .map(|channel| {
+                json!({
+                    "count": channel.count,
+                    "latched_count": channel.latched_count,
+                    "count_latched": channel.count_latched,
+                    "status_latched": channel.status_latched,
+                    "status": channel.status,
+                    "read_state": channel.read_state,
+                    "write_state": channel.write_state,
+                    "write_latch": channel.write_latch,
+                    "rw_mode": channel.rw_mode,
+                    "mode": channel.mode,
+                    "bcd": channel.bcd,
+                    "gate": channel.gate,
+                    "count_load_time": channel.count_load_time,
+                })
+            })
+            .collect();
+        let irqchip = |chip: &kvm_irqchip| {
+            json!({
+                "chip_id": chip.chip_id,
+                "chip": kvm_struct_to_hex(&chip.chip),
+            })
+        };
+
+        json!({
+            "pitstate": { "channels": channels, "flags": self.pitstate.flags },
+            "clock": { "clock": self.clock.clock, "flags": self.clock.flags },
+            "pic_master": irqchip(&self.pic_master),
+            "pic_slave": irqchip(&self.pic_slave),
+            "ioapic": irqchip(&self.ioapic),
+        })
+        .serialize(serializer)
+    }
+}
+
 /// Structure holding an general specific VM state.
 #[cfg(target_arch = "aarch64")]
-#[derive(Default, Versionize)]
+#[derive(Default, Serialize, Versionize)]
 pub struct VmState {
     gic: GicState,
 }
@@ -410,6 +462,11 @@ pub(crate) mod tests {
         assert_eq!(vm_state.pic_slave.chip_id, KVM_IRQCHIP_PIC_SLAVE);
         assert_eq!(vm_state.ioapic.chip_id, KVM_IRQCHIP_IOAPIC);
 
+        let value = serde_json::to_value(&vm_state).unwrap();
+        assert_eq!(value["ioapic"]["chip_id"], KVM_IRQCHIP_IOAPIC);
+        assert_eq!(value["clock"]["flags"], vm_state.clock.flags);
+        assert_eq!(value["pitstate"]["channels"].as_array().unwrap().len(), 3);
+
         let (vm, _mem) = setup_vm(0x1000);
         vm.setup_irqchip().unwrap();
 
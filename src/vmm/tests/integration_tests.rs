@@ -17,7 +17,9 @@ use vmm::persist::{snapshot_state_sanity_check, LoadSnapshotError, MicrovmState}
 use vmm::resources::VmResources;
 use vmm::version_map::VERSION_MAP;
 use vmm::vmm_config::boot_source::BootSourceConfig;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{
+    CreateSnapshotParams, DeviceOverrides, MemoryFileFormat, SnapshotType,
+};
 
 use vmm::utilities::mock_devices::MockSerialInput;
 use vmm::utilities::mock_resources::NOISY_KERNEL_IMAGE;
@@ -294,6 +296,9 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
                 snapshot_type,
                 snapshot_path: snapshot_file.as_path().to_path_buf(),
                 mem_file_path: memory_file.as_path().to_path_buf(),
+                mem_file_format: MemoryFileFormat::Raw,
+                live: false,
+                encryption: None,
                 version: Some(String::from("0.24.0")),
             };
 
@@ -359,9 +364,13 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
                 VERSION_MAP.clone(),
             )
             .unwrap();
-            let mem =
-                GuestMemoryMmap::restore(memory_file.as_file(), &microvm_state.memory_state, false)
-                    .unwrap();
+            let mem = GuestMemoryMmap::restore(
+                memory_file.as_file(),
+                microvm_state.memory_state.base_file_format(1),
+                &microvm_state.memory_state,
+                false,
+            )
+            .unwrap();
 
             // Build microVM from state.
             let vmm = build_microvm_from_snapshot(
@@ -370,6 +379,7 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
                 mem,
                 false,
                 &empty_seccomp_filter,
+                &DeviceOverrides::default(),
             )
             .unwrap();
             // For now we're happy we got this far, we don't test what the guest is actually doing.
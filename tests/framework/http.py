@@ -11,8 +11,8 @@ from framework import decorators
 class Session(requests.Session):
     """Wrapper over requests_unixsocket.Session limiting the call duration.
 
-    Only the API calls relevant to Firecracker (GET, PUT, PATCH) are
-    implemented.
+    Only the API calls relevant to Firecracker (GET, PUT, PATCH, DELETE)
+    are implemented.
     """
 
     def __init__(self):
@@ -71,8 +71,16 @@ class Session(requests.Session):
         # The `untime` method overrides this, and pylint disapproves.
         return super().put(url, data=data, **kwargs)
 
+    @decorators.timed_request
+    def delete(self, url, **kwargs):
+        """Wrap the DELETE call with duration limit."""
+        # pylint: disable=method-hidden
+        # The `untime` method overrides this, and pylint disapproves.
+        return super().delete(url, **kwargs)
+
     def untime(self):
         """Restore the HTTP methods to their un-timed selves."""
         self.get = super().get
         self.patch = super().patch
         self.put = super().put
+        self.delete = super().delete
@@ -539,7 +539,8 @@ class Microvm:
         add_root_device: bool = True,
         boot_args: str = None,
         use_initrd: bool = False,
-        track_dirty_pages: bool = False
+        track_dirty_pages: bool = False,
+        hotplug_slots: int = 0
     ):
         """Shortcut for quickly configuring a microVM.
 
@@ -556,7 +557,8 @@ class Microvm:
             vcpu_count=vcpu_count,
             ht_enabled=ht_enabled,
             mem_size_mib=mem_size_mib,
-            track_dirty_pages=track_dirty_pages
+            track_dirty_pages=track_dirty_pages,
+            hotplug_slots=hotplug_slots
         )
         assert self._api_session.is_status_no_content(response.status_code)
 
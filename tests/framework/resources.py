@@ -224,6 +224,12 @@ class Drive():
             "{}/{}".format(self._drive_cfg_url, drive_id)
         )
 
+    def delete(self, drive_id):
+        """Detach a hot-plugged block device."""
+        return self._api_session.delete(
+            "{}/{}".format(self._drive_cfg_url, drive_id)
+        )
+
     @staticmethod
     def create_json(
             drive_id=None,
@@ -231,7 +237,8 @@ class Drive():
             is_root_device=None,
             partuuid=None,
             is_read_only=None,
-            rate_limiter=None):
+            rate_limiter=None,
+            io_engine=None):
         """Compose the json associated to this type of API request."""
         datax = {}
 
@@ -253,6 +260,9 @@ class Drive():
         if rate_limiter is not None:
             datax['rate_limiter'] = rate_limiter
 
+        if io_engine is not None:
+            datax['io_engine'] = io_engine
+
         return datax
 
 
@@ -515,7 +525,8 @@ class MachineConfigure():
             mem_size_mib=None,
             ht_enabled=None,
             cpu_template=None,
-            track_dirty_pages=None):
+            track_dirty_pages=None,
+            hotplug_slots=None):
         """Compose the json associated to this type of API request."""
         datax = {}
         if vcpu_count is not None:
@@ -533,6 +544,9 @@ class MachineConfigure():
         if track_dirty_pages is not None:
             datax['track_dirty_pages'] = track_dirty_pages
 
+        if hotplug_slots is not None:
+            datax['hotplug_slots'] = hotplug_slots
+
         return datax
 
 
@@ -450,6 +450,61 @@ def _check_block_size(ssh_connection, dev_path, size):
     assert stdout.readline().strip() == str(size)
 
 
+@pytest.mark.parametrize("io_engine", ["Sync", "Async"])
+def test_hotplug_drive(test_microvm_with_ssh, network_config, io_engine):
+    """Test drive hot-plugging with the default seccomp filter in place."""
+    test_microvm = test_microvm_with_ssh
+    test_microvm.spawn()
+
+    # Firecracker runs with the default seccomp filter, which is in place when
+    # the drive is hot-plugged.
+    test_microvm.basic_config(hotplug_slots=1)
+    _tap, _, _ = test_microvm.ssh_network_config(network_config, '1')
+    test_microvm.start()
+
+    fs = drive_tools.FilesystemFile(
+        os.path.join(test_microvm.fsfiles, 'scratch')
+    )
+    response = test_microvm.drive.put(
+        drive_id='scratch',
+        path_on_host=test_microvm.create_jailed_resource(fs.path),
+        is_root_device=False,
+        is_read_only=False,
+        io_engine=io_engine
+    )
+
+    # A syscall missing from the filter kills Firecracker with SIGSYS.
+    os.kill(test_microvm.jailer_clone_pid, 0)
+    if io_engine == "Async" and \
+            test_microvm.api_session.is_status_bad_request(
+                response.status_code):
+        pytest.skip("io_uring is not supported by the host kernel")
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    # The slot is the last virtio-mmio device announced to the guest.
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    slot = "$(ls /sys/bus/platform/devices/ | grep virtio | sort | tail -1)"
+    exit_code, _, _ = ssh_connection.execute_command(
+        "echo {} > /sys/bus/platform/drivers/virtio-mmio/bind".format(slot)
+    )
+    assert exit_code == 0
+
+    exit_code, _, _ = ssh_connection.execute_command(
+        "dd if=/dev/urandom of=/dev/vdb bs=4096 count=16 oflag=direct && "
+        "dd if=/dev/vdb of=/dev/null bs=4096 count=16 iflag=direct"
+    )
+    assert exit_code == 0
+
+    exit_code, _, _ = ssh_connection.execute_command(
+        "echo {} > /sys/bus/platform/drivers/virtio-mmio/unbind".format(slot)
+    )
+    assert exit_code == 0
+
+    response = test_microvm.drive.delete('scratch')
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+    os.kill(test_microvm.jailer_clone_pid, 0)
+
+
 def _check_file_size(ssh_connection, dev_path, size):
     _, stdout, stderr = ssh_connection.execute_command(
         'stat --format=%s {}'.format(dev_path)